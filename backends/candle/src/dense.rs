@@ -0,0 +1,198 @@
+use crate::layers::Linear;
+use crate::WrapErr;
+use candle::{DType, Device, Result, Tensor, D};
+use candle_nn::VarBuilder;
+use serde::Deserialize;
+use std::path::Path;
+use text_embeddings_backend_core::{BackendError, DenseNormalization, ModelLoadError};
+
+/// Module of a SentenceTransformers model as listed in `modules.json`
+#[derive(Deserialize)]
+struct Module {
+    path: String,
+    #[serde(rename = "type")]
+    module_type: String,
+}
+
+/// `config.json` of a SentenceTransformers `Dense` module
+#[derive(Deserialize)]
+struct DenseConfig {
+    in_features: usize,
+    out_features: usize,
+    #[serde(default = "default_bias")]
+    bias: bool,
+    #[serde(default = "default_activation_function")]
+    activation_function: String,
+}
+
+fn default_bias() -> bool {
+    true
+}
+
+fn default_activation_function() -> String {
+    "torch.nn.modules.activation.Tanh".to_string()
+}
+
+#[derive(Debug, PartialEq)]
+enum DenseActivation {
+    Identity,
+    Tanh,
+}
+
+struct DenseLayer {
+    linear: Linear,
+    activation: DenseActivation,
+}
+
+/// `Dense` modules of SentenceTransformers models, applied to the pooled embeddings
+pub(crate) struct DenseHead {
+    layers: Vec<DenseLayer>,
+    /// L2 normalize the pooled embeddings before the first projection
+    normalize_first: bool,
+    out_features: usize,
+    span: tracing::Span,
+}
+
+impl DenseHead {
+    /// Load the `Dense` modules listed in `modules.json`.
+    /// Returns `None` if the model does not have any.
+    pub fn load(
+        model_path: &Path,
+        normalization: DenseNormalization,
+        dtype: DType,
+        device: &Device,
+    ) -> std::result::Result<Option<Self>, BackendError> {
+        let Ok(modules) = std::fs::read_to_string(model_path.join("modules.json")) else {
+            return Ok(None);
+        };
+        let modules: Vec<Module> =
+            serde_json::from_str(&modules).map_err(|err| ModelLoadError::ConfigParse {
+                file: "modules.json".to_string(),
+                message: err.to_string(),
+            })?;
+
+        let position = |name: &str| modules.iter().position(|m| m.module_type.ends_with(name));
+        let Some(first_dense) = position("Dense") else {
+            return Ok(None);
+        };
+        let normalize_first = match normalization {
+            DenseNormalization::Before => true,
+            DenseNormalization::After => false,
+            DenseNormalization::Auto => position("Normalize").is_some_and(|i| i < first_dense),
+        };
+
+        let mut layers = Vec::new();
+        let mut out_features = 0;
+        for module in modules.iter().filter(|m| m.module_type.ends_with("Dense")) {
+            let module_path = model_path.join(&module.path);
+            let config_file = format!("{}/config.json", module.path);
+            let config = std::fs::read_to_string(module_path.join("config.json"))
+                .map_err(|_| ModelLoadError::MissingFile(config_file.clone()))?;
+            let config: DenseConfig =
+                serde_json::from_str(&config).map_err(|err| ModelLoadError::ConfigParse {
+                    file: config_file.clone(),
+                    message: err.to_string(),
+                })?;
+
+            let activation = match config.activation_function.as_str() {
+                "torch.nn.modules.linear.Identity" => DenseActivation::Identity,
+                "torch.nn.modules.activation.Tanh" => DenseActivation::Tanh,
+                activation => {
+                    return Err(ModelLoadError::ConfigParse {
+                        file: config_file,
+                        message: format!("unsupported activation function `{activation}`"),
+                    }
+                    .into())
+                }
+            };
+
+            let safetensors_path = module_path.join("model.safetensors");
+            let vb = if safetensors_path.exists() {
+                unsafe { VarBuilder::from_mmaped_safetensors(&[safetensors_path], dtype, device) }
+            } else {
+                VarBuilder::from_pth(module_path.join("pytorch_model.bin"), dtype, device)
+            }
+            .s()?;
+            let vb = vb.pp("linear");
+            let weight = vb
+                .get((config.out_features, config.in_features), "weight")
+                .s()?;
+            let bias = match config.bias {
+                true => Some(vb.get(config.out_features, "bias").s()?),
+                false => None,
+            };
+
+            layers.push(DenseLayer {
+                linear: Linear::new(weight, bias, None),
+                activation,
+            });
+            out_features = config.out_features;
+        }
+
+        Ok(Some(Self {
+            layers,
+            normalize_first,
+            out_features,
+            span: tracing::span!(tracing::Level::TRACE, "dense_head"),
+        }))
+    }
+
+    /// Dimension of the projected embeddings
+    pub fn out_features(&self) -> usize {
+        self.out_features
+    }
+
+    /// Project pooled embeddings of shape `(batch_size, hidden_size)`
+    pub fn forward(&self, embeddings: &Tensor) -> Result<Tensor> {
+        let _enter = self.span.enter();
+
+        let mut embeddings = if self.normalize_first {
+            let norm = embeddings.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?;
+            embeddings.broadcast_div(&norm)?
+        } else {
+            embeddings.clone()
+        };
+        for layer in &self.layers {
+            embeddings = layer.linear.forward(&embeddings)?;
+            if layer.activation == DenseActivation::Tanh {
+                embeddings = embeddings.tanh()?;
+            }
+        }
+        Ok(embeddings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dense_head_normalization_order() -> Result<()> {
+        let device = Device::Cpu;
+        let head = |normalize_first| -> Result<DenseHead> {
+            Ok(DenseHead {
+                layers: vec![DenseLayer {
+                    linear: Linear::new(
+                        Tensor::new(&[[1.0f32, 0.0], [1.0, 1.0], [0.0, 2.0]], &device)?,
+                        Some(Tensor::new(&[0.5f32, 0.0, -1.0], &device)?),
+                        None,
+                    ),
+                    activation: DenseActivation::Identity,
+                }],
+                normalize_first,
+                out_features: 3,
+                span: tracing::span!(tracing::Level::TRACE, "dense_head"),
+            })
+        };
+        let pooled = Tensor::new(&[[3.0f32, 4.0]], &device)?;
+
+        // Outputs of the `[Pooling, Dense]` and `[Pooling, Normalize, Dense]` module pipelines
+        for (normalize_first, expected) in [(false, [3.5, 7.0, 7.0]), (true, [1.1, 1.4, 0.6])] {
+            let output: Vec<Vec<f32>> = head(normalize_first)?.forward(&pooled)?.to_vec2()?;
+            for (value, expected) in output[0].iter().zip(expected) {
+                assert!((value - expected).abs() < 1e-5, "{output:?}");
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use crate::WrapErr;
+use candle::quantized::{gguf_file, GgmlDType};
+use candle::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use text_embeddings_backend_core::{BackendError, ModelLoadError};
+
+/// GGUF weights file of the model, if any
+pub(crate) fn gguf_path(model_path: &Path) -> Option<PathBuf> {
+    let gguf_path = model_path.join("model.gguf");
+    gguf_path.exists().then_some(gguf_path)
+}
+
+/// Quantized weights loaded from a GGUF file
+pub(crate) struct GgufWeights {
+    pub vb: VarBuilder<'static>,
+    /// Quantization of the weight matrices, e.g. `q4_0`
+    pub quantization: String,
+}
+
+/// Load the tensors of a GGUF file.
+///
+/// The tensors must keep the names of the original safetensors weights, as done by candle's
+/// `tensor-tools quantize`. They are dequantized to `dtype` when they are loaded: quantization
+/// reduces the size of the model artifacts, not the memory used by the model at runtime.
+pub(crate) fn load_gguf(
+    gguf_path: &Path,
+    dtype: DType,
+    device: &Device,
+) -> Result<GgufWeights, BackendError> {
+    let mut file = std::fs::File::open(gguf_path)
+        .map_err(|_| ModelLoadError::MissingFile("model.gguf".to_string()))?;
+    let content =
+        gguf_file::Content::read(&mut file).map_err(|err| ModelLoadError::ConfigParse {
+            file: "model.gguf".to_string(),
+            message: err.to_string(),
+        })?;
+
+    check_tensor_names(content.tensor_infos.keys())?;
+
+    let mut tensors = HashMap::with_capacity(content.tensor_infos.len());
+    for name in content.tensor_infos.keys() {
+        let tensor = content
+            .tensor(&mut file, name)
+            .and_then(|qtensor| qtensor.dequantize(&Device::Cpu))
+            .and_then(|tensor: Tensor| tensor.to_dtype(dtype))
+            .and_then(|tensor| tensor.to_device(device))
+            .s()?;
+        tensors.insert(name.clone(), tensor);
+    }
+
+    // Vectors such as the biases and the layer norms are usually not quantized
+    let quantization = quantization_name(
+        content
+            .tensor_infos
+            .values()
+            .filter(|info| info.shape.rank() == 2)
+            .map(|info| info.ggml_dtype),
+    );
+
+    Ok(GgufWeights {
+        vb: VarBuilder::from_tensors(tensors, dtype, device),
+        quantization,
+    })
+}
+
+/// Reject the files converted by llama.cpp, which rename the tensors, e.g. `blk.0.attn_q.weight`
+fn check_tensor_names<'a>(
+    mut names: impl Iterator<Item = &'a String>,
+) -> Result<(), ModelLoadError> {
+    if names.any(|name| name.starts_with("token_embd.") || name.starts_with("blk.")) {
+        return Err(ModelLoadError::Weights(
+            "`model.gguf` uses the llama.cpp tensor names (`token_embd.*`, `blk.N.*`), which are not supported. \
+             Only GGUF files keeping the names of the original weights are supported, as written by candle's \
+             `tensor-tools quantize`, and their tensors are dequantized when they are loaded"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Name of the most common quantization of the given tensors
+fn quantization_name(dtypes: impl Iterator<Item = GgmlDType>) -> String {
+    let mut counts: Vec<(GgmlDType, usize)> = Vec::new();
+    for dtype in dtypes {
+        match counts.iter_mut().find(|(d, _)| *d == dtype) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((dtype, 1)),
+        }
+    }
+    counts
+        .into_iter()
+        .max_by_key(|(_, count)| *count)
+        .map(|(dtype, _)| format!("{dtype:?}").to_lowercase())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantization_name() {
+        let dtypes = [GgmlDType::Q4_0, GgmlDType::F16, GgmlDType::Q4_0];
+        assert_eq!(quantization_name(dtypes.into_iter()), "q4_0");
+        assert_eq!(quantization_name(std::iter::empty()), "unknown");
+    }
+
+    #[test]
+    fn test_check_tensor_names() {
+        let names = ["embeddings.word_embeddings.weight".to_string()];
+        assert!(check_tensor_names(names.iter()).is_ok());
+        let names = [
+            "token_embd.weight".to_string(),
+            "blk.0.attn_q.weight".to_string(),
+        ];
+        assert!(check_tensor_names(names.iter()).is_err());
+    }
+}
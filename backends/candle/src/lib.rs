@@ -1,80 +1,189 @@
 mod alibi;
 #[cfg(feature = "cuda")]
 mod compute_cap;
+mod dense;
 #[cfg(feature = "cuda")]
 mod flash_attn;
+mod gguf;
 mod layers;
+mod m3;
 mod models;
+mod whitening;
 
 #[cfg(feature = "cuda")]
 use crate::compute_cap::{incompatible_compute_cap, COMPILE_COMPUTE_CAP, RUNTIME_COMPUTE_CAP};
+use crate::dense::DenseHead;
+use crate::gguf::{gguf_path, load_gguf};
+use crate::m3::M3Heads;
 #[cfg(feature = "cuda")]
 use crate::models::FlashBertModel;
 use crate::models::{BertModel, JinaBertModel, Model, PositionEmbeddingType};
-use candle::{DType, Device};
+use crate::whitening::Whitening;
+use candle::{DType, Device, Tensor};
 use candle_nn::VarBuilder;
 use models::Config;
-use std::path::PathBuf;
-use text_embeddings_backend_core::{Backend, BackendError, Batch, Embedding, ModelType};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use text_embeddings_backend_core::{
+    Backend, BackendError, Batch, ConfigOverride, DenseNormalization, Embedding, HiddenStateStats,
+    ModelLoadError, ModelType, MultiEmbedding, Pool,
+};
+
+/// Model types supported by the Bert family of models
+const SUPPORTED_MODEL_TYPES: [&str; 4] = ["bert", "xlm-roberta", "camembert", "roberta"];
 
 pub struct CandleBackend {
     model: Box<dyn Model + Send>,
+    /// `flash` or `eager`
+    attention: &'static str,
+    /// Pooling of embedding models
+    pool: Option<Pool>,
+    /// Sparse and ColBERT heads of M3 models
+    m3_heads: Option<M3Heads>,
+    /// SentenceTransformers `Dense` projection of the pooled embeddings
+    dense: Option<DenseHead>,
+    whitening: Option<Whitening>,
+    /// Quantization of the weights of GGUF models
+    quantization: Option<String>,
 }
 
 impl CandleBackend {
     pub fn new(
         model_path: PathBuf,
         dtype: String,
+        attention: String,
+        dense_normalization: DenseNormalization,
+        config_override: Option<ConfigOverride>,
         model_type: ModelType,
     ) -> Result<Self, BackendError> {
         // Load config
         let config: String = std::fs::read_to_string(model_path.join("config.json"))
-            .map_err(|err| BackendError::Start(err.to_string()))?;
-        let config: Config =
-            serde_json::from_str(&config).map_err(|err| BackendError::Start(err.to_string()))?;
+            .map_err(|_| ModelLoadError::MissingFile("config.json".to_string()))?;
+        let config_parse_error = |err: serde_json::Error| ModelLoadError::ConfigParse {
+            file: "config.json".to_string(),
+            message: err.to_string(),
+        };
+        let mut config: serde_json::Value =
+            serde_json::from_str(&config).map_err(config_parse_error)?;
+        if let Some(config_override) = &config_override {
+            config_override.apply(&mut config);
+        }
+        let config: Config = serde_json::from_value(config).map_err(config_parse_error)?;
 
         // Get candle device
-        let device = match Device::cuda_if_available(0) {
-            Ok(device) => device,
-            Err(err) => return Err(BackendError::Start(err.to_string())),
-        };
+        let device = Device::cuda_if_available(0).map_err(|err| {
+            ModelLoadError::DeviceUnavailable("cuda".to_string(), err.to_string())
+        })?;
 
         // Check model type
-        if config.model_type != Some("bert".to_string())
-            && config.model_type != Some("xlm-roberta".to_string())
-            && config.model_type != Some("camembert".to_string())
-            && config.model_type != Some("roberta".to_string())
-        {
-            return Err(BackendError::Start(format!(
-                "Model {:?} is not supported",
-                config.model_type
-            )));
+        match config.model_type.as_deref() {
+            Some(model_type) if SUPPORTED_MODEL_TYPES.contains(&model_type) => {}
+            model_type => {
+                return Err(ModelLoadError::UnsupportedArchitecture {
+                    architecture: model_type.unwrap_or("unknown").to_string(),
+                    supported: SUPPORTED_MODEL_TYPES.join(", "),
+                }
+                .into());
+            }
         }
 
         // Get candle dtype
-        let dtype = if &dtype == "float32" {
-            Ok(DType::F32)
-        } else if &dtype == "float16" {
-            Ok(DType::F16)
-        } else {
-            Err(BackendError::Start(format!(
-                "DType {dtype} is not supported"
-            )))
-        }?;
-
-        let safetensors_path = model_path.join("model.safetensors");
-        let vb = if safetensors_path.exists() {
-            unsafe {
-                VarBuilder::from_mmaped_safetensors(
-                    &[model_path.join("model.safetensors")],
+        let dtype = match dtype.as_str() {
+            "float32" => DType::F32,
+            "float16" => DType::F16,
+            _ => {
+                return Err(ModelLoadError::DtypeUnsupported {
                     dtype,
-                    &device,
-                )
+                    supported: "float32, float16".to_string(),
+                }
+                .into())
             }
-        } else {
-            VarBuilder::from_pth(model_path.join("pytorch_model.bin"), dtype, &device)
+        };
+
+        // Flash attention is only implemented for Bert models running in float16 on Cuda
+        let flash_attention_supported =
+            cfg!(any(feature = "flash-attn", feature = "flash-attn-v1"))
+                && matches!(device, Device::Cuda(_))
+                && dtype == DType::F16
+                && config.position_embedding_type == PositionEmbeddingType::Absolute;
+        let use_flash_attention = match attention.as_str() {
+            "flash" if !flash_attention_supported => {
+                return Err(BackendError::Start(
+                    "`flash` attention requires a Cuda device, the `flash-attn` feature, the `float16` dtype and a model with absolute position embeddings".to_string(),
+                ))
+            }
+            "flash" => true,
+            "sdpa" => {
+                return Err(BackendError::Start(
+                    "`sdpa` attention is not supported by the candle backend. Use `eager`, `flash` or `auto`".to_string(),
+                ))
+            }
+            // Allow disabling because of flash attention v1 precision problems
+            // See: https://github.com/huggingface/text-embeddings-inference/issues/37
+            "auto" => {
+                flash_attention_supported
+                    && &std::env::var("USE_FLASH_ATTENTION")
+                        .unwrap_or("True".to_string())
+                        .to_lowercase()
+                        == "true"
+            }
+            _ => false,
+        };
+
+        let pool = match &model_type {
+            ModelType::Embedding(pool) => Some(pool.clone()),
+            ModelType::Classifier => None,
+        };
+        let m3_heads = match pool {
+            Some(_) => M3Heads::load(&model_path, config.hidden_size, dtype, &device).s()?,
+            None => None,
+        };
+        if m3_heads.is_some() {
+            tracing::info!("Loaded the sparse and ColBERT heads of the model");
+        }
+        let dense = match pool {
+            Some(_) => DenseHead::load(&model_path, dense_normalization, dtype, &device)?,
+            None => None,
+        };
+        if let Some(dense) = &dense {
+            tracing::info!(
+                "Loaded the Dense projection of the model to {} dimensions",
+                dense.out_features()
+            );
+        }
+        let whitening = match pool {
+            Some(_) => {
+                let in_features = dense
+                    .as_ref()
+                    .map_or(config.hidden_size, |dense| dense.out_features());
+                Whitening::load(&model_path, in_features, dtype, &device)?
+            }
+            None => None,
+        };
+        if let Some(whitening) = &whitening {
+            tracing::info!(
+                "Loaded the whitening transform of the model to {} dimensions",
+                whitening.out_features()
+            );
         }
-        .s()?;
+
+        let mut quantization = None;
+        let vb = if let Some(safetensors_paths) = safetensors_paths(&model_path)? {
+            unsafe { VarBuilder::from_mmaped_safetensors(&safetensors_paths, dtype, &device) }
+                .s()?
+        } else if let Some(gguf_path) = gguf_path(&model_path) {
+            let weights = load_gguf(&gguf_path, dtype, &device)?;
+            tracing::info!("Loaded `{}` quantized GGUF weights", weights.quantization);
+            quantization = Some(weights.quantization);
+            weights.vb
+        } else {
+            let pth_path = model_path.join("pytorch_model.bin");
+            if !pth_path.exists() {
+                return Err(ModelLoadError::MissingFile("model.safetensors".to_string()).into());
+            }
+            VarBuilder::from_pth(pth_path, dtype, &device).s()?
+        };
 
         let model: Box<dyn Model + Send> = match device {
             Device::Cpu => {
@@ -97,13 +206,7 @@ impl CandleBackend {
                         return Err(BackendError::Start(format!("Runtime compute cap {} is not compatible with compile time compute cap {}", *RUNTIME_COMPUTE_CAP, *COMPILE_COMPUTE_CAP)));
                     }
 
-                    if cfg!(any(feature = "flash-attn", feature = "flash-attn-v1"))
-                        && dtype == DType::F16
-                        && config.position_embedding_type == PositionEmbeddingType::Absolute
-                        // Allow disabling because of flash attention v1 precision problems
-                        // See: https://github.com/huggingface/text-embeddings-inference/issues/37
-                        && &std::env::var("USE_FLASH_ATTENTION").unwrap_or("True".to_string()).to_lowercase() == "true"
-                    {
+                    if use_flash_attention {
                         tracing::info!("Starting FlashBert model on Cuda");
                         Box::new(FlashBertModel::load(vb, &config, model_type).s()?)
                     } else if config.position_embedding_type == PositionEmbeddingType::Alibi {
@@ -117,7 +220,36 @@ impl CandleBackend {
             }
         };
 
-        Ok(Self { model })
+        let attention = if use_flash_attention {
+            "flash"
+        } else {
+            "eager"
+        };
+        tracing::info!("Using {attention} attention");
+
+        Ok(Self {
+            model,
+            attention,
+            pool,
+            m3_heads,
+            dense,
+            whitening,
+            quantization,
+        })
+    }
+}
+
+impl CandleBackend {
+    /// Apply the `Dense` projection and the whitening transform to the pooled embeddings
+    fn post_process(&self, embeddings: Tensor) -> candle::Result<Tensor> {
+        let embeddings = match &self.dense {
+            Some(dense) => dense.forward(&embeddings)?,
+            None => embeddings,
+        };
+        match &self.whitening {
+            Some(whitening) => whitening.forward(&embeddings),
+            None => Ok(embeddings),
+        }
     }
 }
 
@@ -126,17 +258,117 @@ impl Backend for CandleBackend {
         Ok(())
     }
 
+    fn attention(&self) -> Option<String> {
+        Some(self.attention.to_string())
+    }
+
+    fn multi_embedding(&self) -> bool {
+        self.m3_heads.is_some()
+    }
+
+    fn embedding_dimension(&self) -> Option<usize> {
+        match &self.whitening {
+            Some(whitening) => Some(whitening.out_features()),
+            None => self.dense.as_ref().map(|dense| dense.out_features()),
+        }
+    }
+
+    fn whitening(&self) -> bool {
+        self.whitening.is_some()
+    }
+
+    fn quantization(&self) -> Option<String> {
+        self.quantization.clone()
+    }
+
     fn embed(&self, batch: Batch) -> Result<Vec<Embedding>, BackendError> {
         let results = self.model.embed(batch).e()?;
+        let results = self.post_process(results).e()?;
+        let results = results.to_dtype(DType::F32).e()?.to_vec2().e()?;
+        Ok(results)
+    }
+
+    fn num_layers(&self) -> Option<usize> {
+        self.model.num_layers()
+    }
+
+    fn embed_layer(&self, batch: Batch, layer: usize) -> Result<Vec<Embedding>, BackendError> {
+        let results = self.model.embed_layer(batch, layer).e()?;
+        let results = self.post_process(results).e()?;
         let results = results.to_dtype(DType::F32).e()?.to_vec2().e()?;
         Ok(results)
     }
 
+    fn embed_all(&self, batch: Batch) -> Result<Vec<MultiEmbedding>, BackendError> {
+        let (Some(m3_heads), Some(pool)) = (&self.m3_heads, &self.pool) else {
+            return Err(BackendError::Inference(
+                "multiple embedding modes are not supported by this model".to_string(),
+            ));
+        };
+
+        // The hidden states are only available for batches of a single sequence
+        batch
+            .sequences()
+            .map(|sequence| {
+                let input_ids = sequence.input_ids.clone();
+                let hidden_states = self.model.hidden_states(sequence).e()?;
+                m3_heads.forward(&hidden_states, &input_ids, pool).e()
+            })
+            .collect()
+    }
+
     fn predict(&self, batch: Batch) -> Result<Vec<Vec<f32>>, BackendError> {
         let results = self.model.predict(batch).e()?;
         let results = results.to_dtype(DType::F32).e()?.to_vec2().e()?;
         Ok(results)
     }
+
+    fn hidden_state_stats(&self, batch: Batch) -> Result<HiddenStateStats, BackendError> {
+        let hidden_states = self.model.hidden_states(batch).e()?;
+        let values: Vec<f32> = hidden_states
+            .to_dtype(DType::F32)
+            .e()?
+            .flatten_all()
+            .e()?
+            .to_vec1()
+            .e()?;
+        Ok(HiddenStateStats::from_values(&values))
+    }
+}
+
+/// Sharded safetensors weights index
+#[derive(Deserialize)]
+struct SafetensorsIndex {
+    weight_map: HashMap<String, String>,
+}
+
+/// The model safetensors files: either `model.safetensors` or all the shards listed in
+/// `model.safetensors.index.json`
+fn safetensors_paths(model_path: &Path) -> Result<Option<Vec<PathBuf>>, BackendError> {
+    let safetensors_path = model_path.join("model.safetensors");
+    if safetensors_path.exists() {
+        return Ok(Some(vec![safetensors_path]));
+    }
+
+    let index_path = model_path.join("model.safetensors.index.json");
+    if !index_path.exists() {
+        return Ok(None);
+    }
+    let index = std::fs::read_to_string(index_path)
+        .map_err(|_| ModelLoadError::MissingFile("model.safetensors.index.json".to_string()))?;
+    let index: SafetensorsIndex =
+        serde_json::from_str(&index).map_err(|err| ModelLoadError::ConfigParse {
+            file: "model.safetensors.index.json".to_string(),
+            message: err.to_string(),
+        })?;
+
+    let shards: BTreeSet<String> = index.weight_map.into_values().collect();
+    Ok(Some(
+        shards
+            .into_iter()
+            .map(|shard| model_path.join(shard))
+            .collect(),
+    ))
 }
 
 pub trait WrapErr<O> {
@@ -146,9 +378,108 @@ pub trait WrapErr<O> {
 
 impl<O> WrapErr<O> for Result<O, candle::Error> {
     fn s(self) -> Result<O, BackendError> {
-        self.map_err(|e| BackendError::Start(e.to_string()))
+        self.map_err(|e| ModelLoadError::Weights(e.to_string()).into())
     }
     fn e(self) -> Result<O, BackendError> {
-        self.map_err(|e| BackendError::Inference(e.to_string()))
+        self.map_err(|e| {
+            if is_out_of_memory(&e) {
+                BackendError::ResourceExhausted(e.to_string())
+            } else {
+                BackendError::Inference(e.to_string())
+            }
+        })
+    }
+}
+
+/// Whether a CUDA allocation failed
+fn is_out_of_memory(err: &candle::Error) -> bool {
+    match err {
+        candle::Error::WithBacktrace { inner, .. } => is_out_of_memory(inner),
+        #[cfg(feature = "cuda")]
+        candle::Error::Cuda(err) => {
+            use candle::cuda_backend::cudarc::driver::{sys::CUresult, DriverError};
+            use candle::cuda_backend::CudaError;
+
+            matches!(
+                err.downcast_ref::<CudaError>(),
+                Some(CudaError::Cuda(DriverError(
+                    CUresult::CUDA_ERROR_OUT_OF_MEMORY
+                )))
+            )
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_error(model_path: PathBuf, dtype: &str) -> BackendError {
+        match CandleBackend::new(
+            model_path,
+            dtype.to_string(),
+            "auto".to_string(),
+            DenseNormalization::Auto,
+            None,
+            ModelType::Embedding(text_embeddings_backend_core::Pool::Mean),
+        ) {
+            Ok(_) => panic!("model loading should fail"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn test_model_load_errors() {
+        let model_path = std::env::temp_dir().join(format!(
+            "tei-candle-model-load-errors-test-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&model_path).unwrap();
+
+        assert!(matches!(
+            load_error(model_path.clone(), "float32"),
+            BackendError::ModelLoad(ModelLoadError::MissingFile(file)) if file == "config.json"
+        ));
+
+        std::fs::write(model_path.join("config.json"), "{").unwrap();
+        assert!(matches!(
+            load_error(model_path.clone(), "float32"),
+            BackendError::ModelLoad(ModelLoadError::ConfigParse { .. })
+        ));
+
+        let mut config = serde_json::json!({
+            "vocab_size": 2,
+            "hidden_size": 2,
+            "num_hidden_layers": 1,
+            "num_attention_heads": 1,
+            "intermediate_size": 2,
+            "hidden_act": "gelu",
+            "hidden_dropout_prob": 0.0,
+            "max_position_embeddings": 2,
+            "type_vocab_size": 1,
+            "initializer_range": 0.02,
+            "layer_norm_eps": 1e-12,
+            "pad_token_id": 0,
+            "model_type": "gpt2"
+        });
+        std::fs::write(model_path.join("config.json"), config.to_string()).unwrap();
+        assert!(matches!(
+            load_error(model_path.clone(), "float32"),
+            BackendError::ModelLoad(ModelLoadError::UnsupportedArchitecture { architecture, .. }) if architecture == "gpt2"
+        ));
+
+        config["model_type"] = "bert".into();
+        std::fs::write(model_path.join("config.json"), config.to_string()).unwrap();
+        assert!(matches!(
+            load_error(model_path.clone(), "bfloat16"),
+            BackendError::ModelLoad(ModelLoadError::DtypeUnsupported { .. })
+        ));
+        assert!(matches!(
+            load_error(model_path.clone(), "float32"),
+            BackendError::ModelLoad(ModelLoadError::MissingFile(file)) if file == "model.safetensors"
+        ));
+
+        std::fs::remove_dir_all(model_path).unwrap();
     }
 }
@@ -0,0 +1,161 @@
+use crate::layers::Linear;
+use candle::{DType, Device, IndexOp, Result, Tensor, D};
+use candle_nn::VarBuilder;
+use std::collections::BTreeMap;
+use std::path::Path;
+use text_embeddings_backend_core::{MultiEmbedding, Pool};
+
+/// Sparse and ColBERT heads of BGE-M3 style models, applied to the last hidden state.
+/// See https://huggingface.co/BAAI/bge-m3
+pub(crate) struct M3Heads {
+    sparse: Linear,
+    colbert: Linear,
+    span: tracing::Span,
+}
+
+impl M3Heads {
+    /// Load the heads from `sparse_linear.pt` and `colbert_linear.pt`.
+    /// Returns `None` if the model does not have them.
+    pub fn load(
+        model_path: &Path,
+        hidden_size: usize,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Option<Self>> {
+        let sparse_path = model_path.join("sparse_linear.pt");
+        let colbert_path = model_path.join("colbert_linear.pt");
+        if !sparse_path.exists() || !colbert_path.exists() {
+            return Ok(None);
+        }
+
+        let vb = VarBuilder::from_pth(sparse_path, dtype, device)?;
+        let sparse = Linear::new(
+            vb.get((1, hidden_size), "weight")?,
+            Some(vb.get(1, "bias")?),
+            None,
+        );
+
+        let vb = VarBuilder::from_pth(colbert_path, dtype, device)?;
+        let colbert = Linear::new(
+            vb.get((hidden_size, hidden_size), "weight")?,
+            Some(vb.get(hidden_size, "bias")?),
+            None,
+        );
+
+        Ok(Some(Self {
+            sparse,
+            colbert,
+            span: tracing::span!(tracing::Level::TRACE, "m3_heads"),
+        }))
+    }
+
+    /// Dense, sparse and ColBERT embeddings of a single sequence from its last hidden state of
+    /// shape `(1, seq_len, hidden_size)`.
+    /// The first and last tokens are expected to be the special tokens added by the tokenizer.
+    pub fn forward(
+        &self,
+        hidden_states: &Tensor,
+        input_ids: &[u32],
+        pool: &Pool,
+    ) -> Result<MultiEmbedding> {
+        let _enter = self.span.enter();
+
+        let hidden_states = hidden_states.squeeze(0)?;
+        let seq_len = hidden_states.dim(0)?;
+
+        let dense = match pool {
+            Pool::Cls => hidden_states.i(0)?,
+            Pool::Mean => hidden_states.mean(0)?,
+            Pool::Pooler => candle::bail!("`pooler` pooling is not supported by M3 models"),
+        };
+
+        // Weight of each token, without the special tokens. Repeated tokens keep their max weight
+        let weights: Vec<f32> = self
+            .sparse
+            .forward(&hidden_states)?
+            .relu()?
+            .squeeze(1)?
+            .to_dtype(DType::F32)?
+            .to_vec1()?;
+        let mut sparse = BTreeMap::new();
+        for (&token_id, &weight) in input_ids
+            .iter()
+            .zip(&weights)
+            .take(seq_len.saturating_sub(1))
+            .skip(1)
+        {
+            if weight > 0.0 {
+                let max_weight = sparse.entry(token_id).or_insert(weight);
+                *max_weight = max_weight.max(weight);
+            }
+        }
+
+        // One normalized vector per token, without the first special token
+        let colbert = if seq_len > 1 {
+            let colbert = self
+                .colbert
+                .forward(&hidden_states.narrow(0, 1, seq_len - 1)?)?
+                .to_dtype(DType::F32)?;
+            let norm = colbert.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?;
+            colbert.broadcast_div(&norm)?.to_vec2()?
+        } else {
+            Vec::new()
+        };
+
+        Ok(MultiEmbedding {
+            dense: dense.to_dtype(DType::F32)?.to_vec1()?,
+            sparse: sparse.into_iter().collect(),
+            colbert,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_m3_heads() -> Result<()> {
+        let device = Device::Cpu;
+        let heads = M3Heads {
+            sparse: Linear::new(
+                Tensor::new(&[[1.0f32, 0.0]], &device)?,
+                Some(Tensor::new(&[0.0f32], &device)?),
+                None,
+            ),
+            colbert: Linear::new(
+                Tensor::new(&[[1.0f32, 0.0], [0.0, 1.0]], &device)?,
+                Some(Tensor::new(&[0.0f32, 0.0], &device)?),
+                None,
+            ),
+            span: tracing::span!(tracing::Level::TRACE, "m3_heads"),
+        };
+
+        // [CLS] 7 7 8 [SEP]
+        let hidden_states = Tensor::new(
+            &[[
+                [1.0f32, 1.0],
+                [0.5, 0.0],
+                [2.0, 0.0],
+                [-1.0, 3.0],
+                [4.0, 0.0],
+            ]],
+            &device,
+        )?;
+        let embeddings = heads.forward(&hidden_states, &[0, 7, 7, 8, 2], &Pool::Cls)?;
+
+        assert_eq!(embeddings.dense, vec![1.0, 1.0]);
+        // Special tokens and tokens with a null weight are dropped
+        assert_eq!(embeddings.sparse, vec![(7, 2.0)]);
+        assert_eq!(
+            embeddings.colbert,
+            vec![
+                vec![1.0, 0.0],
+                vec![1.0, 0.0],
+                vec![-1.0 / 10f32.sqrt(), 3.0 / 10f32.sqrt()],
+                vec![1.0, 0.0]
+            ]
+        );
+        Ok(())
+    }
+}
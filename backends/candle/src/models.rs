@@ -23,7 +23,19 @@ pub(crate) trait Model {
         candle::bail!("`embed` is not implemented for this model");
     }
 
+    fn embed_layer(&self, _batch: Batch, _layer: usize) -> Result<Tensor> {
+        candle::bail!("`embed_layer` is not implemented for this model");
+    }
+
+    fn num_layers(&self) -> Option<usize> {
+        None
+    }
+
     fn predict(&self, _batch: Batch) -> Result<Tensor> {
         candle::bail!("`predict is not implemented for this model");
     }
+
+    fn hidden_states(&self, _batch: Batch) -> Result<Tensor> {
+        candle::bail!("`hidden_states` is not implemented for this model");
+    }
 }
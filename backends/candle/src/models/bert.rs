@@ -343,13 +343,19 @@ impl BertEncoder {
         Ok(BertEncoder { layers, span })
     }
 
-    fn forward(&self, hidden_states: &Tensor, attention_bias: Option<&Tensor>) -> Result<Tensor> {
+    /// Run the first `num_layers` layers
+    fn forward(
+        &self,
+        hidden_states: &Tensor,
+        attention_bias: Option<&Tensor>,
+        num_layers: usize,
+    ) -> Result<Tensor> {
         let _enter = self.span.enter();
 
         let mut hidden_states = hidden_states.clone();
 
         // Use a loop rather than a fold as it's easier to modify when adding debug/...
-        for layer in self.layers.iter() {
+        for layer in self.layers.iter().take(num_layers) {
             hidden_states = layer.forward(&hidden_states, attention_bias)?;
         }
 
@@ -400,10 +406,52 @@ impl BertClassificationHead {
     }
 }
 
+/// Projection of the CLS token hidden state used by the `pooler` pooling
+#[derive(Debug)]
+pub struct BertPooler {
+    dense: Linear,
+    span: tracing::Span,
+}
+
+impl BertPooler {
+    pub fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let dense_weight = vb
+            .pp("dense")
+            .get((config.hidden_size, config.hidden_size), "weight")?;
+        let dense_bias = vb.pp("dense").get(config.hidden_size, "bias")?;
+        let dense = Linear::new(dense_weight, Some(dense_bias), None);
+
+        Ok(Self {
+            dense,
+            span: tracing::span!(tracing::Level::TRACE, "pooler"),
+        })
+    }
+
+    /// The pooler weights can be stored under the model type prefix
+    pub fn load_prefixed(vb: &VarBuilder, config: &Config) -> Result<Self> {
+        let model_type = config.model_type.clone().unwrap_or("bert".to_string());
+
+        Self::load(vb.pp("pooler"), config)
+            .or_else(|err| {
+                Self::load(vb.pp(format!("{model_type}.pooler")), config).map_err(|_| err)
+            })
+            .or_else(|err| Self::load(vb.pp("bert.pooler"), config).map_err(|_| err))
+            .or_else(|err| Self::load(vb.pp("roberta.pooler"), config).map_err(|_| err))
+    }
+
+    /// `cls_hidden_states` has shape (batch_size, hidden_size)
+    pub fn forward(&self, cls_hidden_states: &Tensor) -> Result<Tensor> {
+        let _enter = self.span.enter();
+
+        self.dense.forward(cls_hidden_states)?.tanh()
+    }
+}
+
 pub struct BertModel {
     embeddings: BertEmbeddings,
     encoder: BertEncoder,
     pool: Pool,
+    pooler: Option<BertPooler>,
     classifier: Option<BertClassificationHead>,
 
     num_attention_heads: usize,
@@ -435,11 +483,6 @@ impl BertModel {
             ModelType::Embedding(pool) => (pool, None),
         };
 
-        // Check pool type
-        if pool != Pool::Mean && pool != Pool::Cls {
-            candle::bail!("Pool type {pool:?} is not supported");
-        }
-
         let (embeddings, encoder) = match (
             BertEmbeddings::load(vb.pp("embeddings"), config),
             BertEncoder::load(vb.pp("encoder"), config),
@@ -464,10 +507,16 @@ impl BertModel {
             }
         };
 
+        let pooler = match pool {
+            Pool::Pooler => Some(BertPooler::load_prefixed(&vb, config)?),
+            Pool::Cls | Pool::Mean => None,
+        };
+
         Ok(Self {
             embeddings,
             encoder,
             pool,
+            pooler,
             classifier,
             num_attention_heads: config.num_attention_heads,
             device: vb.device().clone(),
@@ -477,8 +526,20 @@ impl BertModel {
     }
 
     pub fn forward(&self, batch: Batch) -> Result<Tensor> {
+        self.forward_layer(batch, self.encoder.layers.len())
+    }
+
+    /// Pool the hidden states of `layer`, `0` being the output of the embeddings
+    pub fn forward_layer(&self, batch: Batch, layer: usize) -> Result<Tensor> {
         let _enter = self.span.enter();
 
+        if layer > self.encoder.layers.len() {
+            candle::bail!(
+                "layer {layer} is out of range: the model has {} layers",
+                self.encoder.layers.len()
+            );
+        }
+
         let batch_size = batch.cumulative_seq_lengths.len() - 1;
         let max_length = batch.max_length as usize;
 
@@ -595,13 +656,18 @@ impl BertModel {
             .embeddings
             .forward(&input_ids, &type_ids, &position_ids)?;
 
-        let mut outputs = self
-            .encoder
-            .forward(&embedding_output, attention_bias.as_ref())?;
+        let mut outputs =
+            self.encoder
+                .forward(&embedding_output, attention_bias.as_ref(), layer)?;
 
         let results = match self.pool {
             // CLS pooling
             Pool::Cls => outputs.i((.., 0))?,
+            // CLS pooling projected by the pooler
+            Pool::Pooler => match &self.pooler {
+                Some(pooler) => pooler.forward(&outputs.i((.., 0))?)?,
+                None => candle::bail!("`pooler` weights are not loaded"),
+            },
             // Mean pooling
             Pool::Mean => {
                 if let Some(attention_mask) = attention_mask {
@@ -615,6 +681,26 @@ impl BertModel {
 
         Ok(results)
     }
+
+    /// Last hidden state of a batch of a single sequence, before pooling
+    pub fn hidden_states(&self, batch: Batch) -> Result<Tensor> {
+        let _enter = self.span.enter();
+
+        if batch.cumulative_seq_lengths.len() != 2 {
+            candle::bail!("`hidden_states` only supports batches of a single sequence");
+        }
+        let shape = (1, batch.max_length as usize);
+
+        let input_ids = Tensor::from_vec(batch.input_ids, shape, &self.device)?;
+        let type_ids = Tensor::from_vec(batch.token_type_ids, shape, &self.device)?;
+        let position_ids = Tensor::from_vec(batch.position_ids, shape, &self.device)?;
+
+        let embedding_output = self
+            .embeddings
+            .forward(&input_ids, &type_ids, &position_ids)?;
+        self.encoder
+            .forward(&embedding_output, None, self.encoder.layers.len())
+    }
 }
 
 impl Model for BertModel {
@@ -622,6 +708,18 @@ impl Model for BertModel {
         self.forward(batch)
     }
 
+    fn embed_layer(&self, batch: Batch, layer: usize) -> Result<Tensor> {
+        self.forward_layer(batch, layer)
+    }
+
+    fn num_layers(&self) -> Option<usize> {
+        Some(self.encoder.layers.len())
+    }
+
+    fn hidden_states(&self, batch: Batch) -> Result<Tensor> {
+        self.hidden_states(batch)
+    }
+
     fn predict(&self, batch: Batch) -> Result<Tensor> {
         match &self.classifier {
             None => candle::bail!("`predict` is not implemented for this model"),
@@ -632,3 +730,55 @@ impl Model for BertModel {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pooler_projects_cls_hidden_state() -> Result<()> {
+        let device = Device::Cpu;
+        let weight = Tensor::new(&[[1.0f32, 0.0], [0.0, 2.0]], &device)?;
+        let bias = Tensor::new(&[0.5f32, 0.0], &device)?;
+        let vb = VarBuilder::from_tensors(
+            HashMap::from([
+                ("pooler.dense.weight".to_string(), weight),
+                ("pooler.dense.bias".to_string(), bias),
+            ]),
+            DType::F32,
+            &device,
+        );
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "vocab_size": 2,
+            "hidden_size": 2,
+            "num_hidden_layers": 1,
+            "num_attention_heads": 1,
+            "intermediate_size": 2,
+            "hidden_act": "gelu",
+            "hidden_dropout_prob": 0.0,
+            "max_position_embeddings": 2,
+            "type_vocab_size": 1,
+            "initializer_range": 0.02,
+            "layer_norm_eps": 1e-12,
+            "pad_token_id": 0,
+            "model_type": "bert"
+        }))
+        .unwrap();
+        let pooler = BertPooler::load_prefixed(&vb, &config)?;
+
+        // (batch_size, seq_len, hidden_size)
+        let outputs = Tensor::new(&[[[0.1f32, 0.2], [3.0, 4.0]]], &device)?;
+        let cls = outputs.i((.., 0))?;
+
+        // `cls` pooling is the raw CLS token hidden state
+        assert_eq!(cls.to_vec2::<f32>()?, vec![vec![0.1, 0.2]]);
+
+        // `pooler` pooling is tanh(W * cls + b)
+        let pooled = pooler.forward(&cls)?.to_vec2::<f32>()?;
+        let expected = [0.6f32.tanh(), 0.4f32.tanh()];
+        for (p, e) in pooled[0].iter().zip(expected) {
+            assert!((p - e).abs() < 1e-6, "{p} != {e}");
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use crate::flash_attn::flash_attn_varlen;
 use crate::layers::{LayerNorm, Linear};
-use crate::models::bert::{Config, PositionEmbeddingType};
+use crate::models::bert::{BertPooler, Config, PositionEmbeddingType};
 use crate::models::Model;
 use candle::{DType, Device, Result, Tensor};
 use candle_nn::{Embedding, Module, VarBuilder};
@@ -317,6 +317,7 @@ pub struct FlashBertModel {
     embeddings: BertEmbeddings,
     encoder: BertEncoder,
     pool: Pool,
+    pooler: Option<BertPooler>,
     classifier: Option<BertClassificationHead>,
     pub device: Device,
 
@@ -353,11 +354,6 @@ impl FlashBertModel {
             ModelType::Embedding(pool) => (pool, None),
         };
 
-        // Check pool type
-        if pool != Pool::Mean && pool != Pool::Cls {
-            candle::bail!("Pool type {pool:?} is not supported");
-        }
-
         let (embeddings, encoder) = match (
             BertEmbeddings::load(vb.pp("embeddings"), config),
             BertEncoder::load(vb.pp("encoder"), config),
@@ -382,10 +378,16 @@ impl FlashBertModel {
             }
         };
 
+        let pooler = match pool {
+            Pool::Pooler => Some(BertPooler::load_prefixed(&vb, config)?),
+            Pool::Cls | Pool::Mean => None,
+        };
+
         Ok(Self {
             embeddings,
             encoder,
             pool,
+            pooler,
             classifier,
             device: vb.device().clone(),
             span: tracing::span!(tracing::Level::TRACE, "model"),
@@ -419,6 +421,12 @@ impl FlashBertModel {
         let results = match self.pool {
             // CLS pooling
             Pool::Cls => outputs.index_select(&cu_seqlens.narrow(0, 0, batch_size)?, 0)?,
+            // CLS pooling projected by the pooler
+            Pool::Pooler => match &self.pooler {
+                Some(pooler) => pooler
+                    .forward(&outputs.index_select(&cu_seqlens.narrow(0, 0, batch_size)?, 0)?)?,
+                None => candle::bail!("`pooler` weights are not loaded"),
+            },
             // Mean pooling
             Pool::Mean => {
                 if batch_size > 1 {
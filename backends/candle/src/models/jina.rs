@@ -1,5 +1,6 @@
 use crate::alibi::build_alibi_tensor;
 use crate::layers::{HiddenAct, LayerNorm, Linear, CUBLASLT};
+use crate::models::bert::BertPooler;
 use crate::models::Model;
 use crate::models::{Config, PositionEmbeddingType};
 use candle::{DType, Device, IndexOp, Module, Result, Tensor, D};
@@ -339,6 +340,7 @@ pub struct JinaBertModel {
     embeddings: BertEmbeddings,
     encoder: BertEncoder,
     pool: Pool,
+    pooler: Option<BertPooler>,
     alibi: Option<Tensor>,
 
     num_attention_heads: usize,
@@ -369,11 +371,6 @@ impl JinaBertModel {
             ModelType::Embedding(pool) => pool,
         };
 
-        // Check pool type
-        if pool != Pool::Mean && pool != Pool::Cls {
-            candle::bail!("Pool type {pool:?} is not supported");
-        }
-
         let (embeddings, encoder) = match (
             BertEmbeddings::load(vb.pp("embeddings"), config),
             BertEncoder::load(vb.pp("encoder"), config),
@@ -398,10 +395,16 @@ impl JinaBertModel {
             }
         };
 
+        let pooler = match pool {
+            Pool::Pooler => Some(BertPooler::load_prefixed(&vb, config)?),
+            Pool::Cls | Pool::Mean => None,
+        };
+
         Ok(Self {
             embeddings,
             encoder,
             pool,
+            pooler,
             alibi,
             num_attention_heads: config.num_attention_heads,
             device: vb.device().clone(),
@@ -410,6 +413,24 @@ impl JinaBertModel {
         })
     }
 
+    /// Alibi tensor for `max_length` positions.
+    /// It is precomputed for `max_position_embeddings` positions and built on the fly for
+    /// longer inputs, when the maximum input length was extended past the model config.
+    fn alibi(&self, max_length: usize) -> Result<Option<Tensor>> {
+        match &self.alibi {
+            None => Ok(None),
+            Some(alibi) if max_length <= alibi.dim(2)? => {
+                Ok(Some(alibi.i((.., .., 0..max_length, 0..max_length))?))
+            }
+            Some(_) => Ok(Some(build_alibi_tensor(
+                max_length,
+                self.num_attention_heads,
+                &self.device,
+                self.dtype,
+            )?)),
+        }
+    }
+
     pub fn forward(&self, batch: Batch) -> Result<Tensor> {
         let _enter = self.span.enter();
 
@@ -495,15 +516,13 @@ impl JinaBertModel {
                         ))?;
 
                         // Add alibi tensor
-                        if let Some(alibi) = &self.alibi {
-                            let alibi = alibi
-                                .i((.., .., 0..max_length, 0..max_length))?
-                                .broadcast_as((
-                                    batch_size,
-                                    self.num_attention_heads,
-                                    max_length,
-                                    max_length,
-                                ))?;
+                        if let Some(alibi) = self.alibi(max_length)? {
+                            let alibi = alibi.broadcast_as((
+                                batch_size,
+                                self.num_attention_heads,
+                                max_length,
+                                max_length,
+                            ))?;
 
                             attention_bias = attention_bias.add(&alibi)?;
                         }
@@ -511,11 +530,10 @@ impl JinaBertModel {
                         (Some(attention_bias.contiguous()?), attention_mask)
                     }
                     false => {
-                        if let Some(alibi) = &self.alibi {
+                        if let Some(alibi) = self.alibi(max_length)? {
                             (
                                 Some(
                                     alibi
-                                        .i((.., .., 0..max_length, 0..max_length))?
                                         .broadcast_as((
                                             batch_size,
                                             self.num_attention_heads,
@@ -541,12 +559,8 @@ impl JinaBertModel {
                     attention_mask,
                 )
             } else {
-                let attention_bias = if let Some(alibi) = &self.alibi {
-                    Some(
-                        alibi
-                            .i((.., .., 0..max_length, 0..max_length))?
-                            .contiguous()?,
-                    )
+                let attention_bias = if let Some(alibi) = self.alibi(max_length)? {
+                    Some(alibi.contiguous()?)
                 } else {
                     None
                 };
@@ -579,6 +593,11 @@ impl JinaBertModel {
         let results = match self.pool {
             // CLS pooling
             Pool::Cls => outputs.i((.., 0))?,
+            // CLS pooling projected by the pooler
+            Pool::Pooler => match &self.pooler {
+                Some(pooler) => pooler.forward(&outputs.i((.., 0))?)?,
+                None => candle::bail!("`pooler` weights are not loaded"),
+            },
             // Mean pooling
             Pool::Mean => {
                 if let Some(attention_mask) = attention_mask {
@@ -0,0 +1,89 @@
+use crate::WrapErr;
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+use std::path::Path;
+use text_embeddings_backend_core::{BackendError, ModelLoadError};
+
+/// Centering and whitening transform of `whitening.safetensors`, applied to the final embeddings
+/// before they are normalized
+pub(crate) struct Whitening {
+    /// Mean of the embeddings, of shape `(in_features,)`
+    mean: Tensor,
+    /// Transform matrix, of shape `(in_features, out_features)`
+    transform: Tensor,
+    span: tracing::Span,
+}
+
+impl Whitening {
+    /// Load `whitening.safetensors` with its `mean` and `transform` tensors.
+    /// Returns `None` if the model does not have one.
+    pub fn load(
+        model_path: &Path,
+        in_features: usize,
+        dtype: DType,
+        device: &Device,
+    ) -> std::result::Result<Option<Self>, BackendError> {
+        let whitening_path = model_path.join("whitening.safetensors");
+        if !whitening_path.exists() {
+            return Ok(None);
+        }
+        let vb =
+            unsafe { VarBuilder::from_mmaped_safetensors(&[whitening_path], dtype, device) }.s()?;
+
+        let mean = vb.get(in_features, "mean").s()?;
+        let transform = vb.get_unchecked("transform").s()?;
+        match transform.dims() {
+            [rows, _] if *rows == in_features => {}
+            dims => {
+                return Err(ModelLoadError::ConfigParse {
+                    file: "whitening.safetensors".to_string(),
+                    message: format!(
+                        "`transform` must be of shape ({in_features}, out_features), got {dims:?}"
+                    ),
+                }
+                .into())
+            }
+        }
+
+        Ok(Some(Self {
+            mean,
+            transform,
+            span: tracing::span!(tracing::Level::TRACE, "whitening"),
+        }))
+    }
+
+    /// Dimension of the whitened embeddings
+    pub fn out_features(&self) -> usize {
+        self.transform.dims()[1]
+    }
+
+    /// Whiten pooled embeddings of shape `(batch_size, in_features)`
+    pub fn forward(&self, embeddings: &Tensor) -> Result<Tensor> {
+        let _enter = self.span.enter();
+
+        embeddings
+            .broadcast_sub(&self.mean)?
+            .matmul(&self.transform)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_whitening() -> Result<()> {
+        let device = Device::Cpu;
+        let whitening = Whitening {
+            mean: Tensor::new(&[1.0f32, 2.0], &device)?,
+            transform: Tensor::new(&[[2.0f32, 0.0, 1.0], [0.0, 0.5, 1.0]], &device)?,
+            span: tracing::span!(tracing::Level::TRACE, "whitening"),
+        };
+        assert_eq!(whitening.out_features(), 3);
+
+        let embeddings = Tensor::new(&[[3.0f32, 4.0], [1.0, 2.0]], &device)?;
+        let output: Vec<Vec<f32>> = whitening.forward(&embeddings)?.to_vec2()?;
+        assert_eq!(output, vec![vec![4.0, 1.0, 4.0], vec![0.0, 0.0, 0.0]]);
+        Ok(())
+    }
+}
@@ -1,9 +1,11 @@
 #[cfg(feature = "clap")]
 use clap::ValueEnum;
+use serde_json::Value;
 use std::fmt;
+use std::str::FromStr;
 use thiserror::Error;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Batch {
     pub input_ids: Vec<u32>,
     pub token_type_ids: Vec<u32>,
@@ -12,17 +14,157 @@ pub struct Batch {
     pub max_length: u32,
 }
 
+impl Batch {
+    /// Number of sequences in the batch
+    pub fn len(&self) -> usize {
+        self.cumulative_seq_lengths.len().saturating_sub(1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// One batch per sequence of the batch
+    pub fn sequences(&self) -> impl Iterator<Item = Batch> + '_ {
+        (0..self.len()).map(|i| self.slice(i, i + 1))
+    }
+
+    /// Sequences `start..end` of the batch
+    fn slice(&self, start: usize, end: usize) -> Batch {
+        let token_start = self.cumulative_seq_lengths[start];
+        let token_end = self.cumulative_seq_lengths[end];
+        let tokens = token_start as usize..token_end as usize;
+
+        let cumulative_seq_lengths: Vec<u32> = self.cumulative_seq_lengths[start..=end]
+            .iter()
+            .map(|l| l - token_start)
+            .collect();
+        let max_length = cumulative_seq_lengths
+            .windows(2)
+            .map(|w| w[1] - w[0])
+            .max()
+            .unwrap_or(0);
+
+        Batch {
+            input_ids: self.input_ids[tokens.clone()].to_vec(),
+            token_type_ids: self.token_type_ids[tokens.clone()].to_vec(),
+            position_ids: self.position_ids[tokens].to_vec(),
+            cumulative_seq_lengths,
+            max_length,
+        }
+    }
+}
+
 pub type Embedding = Vec<f32>;
 
+/// Embeddings of a sequence in all the modes of hybrid retrieval models
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultiEmbedding {
+    pub dense: Embedding,
+    /// Weight of each token id of the sequence, sorted by token id
+    pub sparse: Vec<(u32, f32)>,
+    /// One vector per token for late interaction
+    pub colbert: Vec<Embedding>,
+}
+
+/// Summary statistics of a last hidden state, before pooling
+#[derive(Debug, Clone, PartialEq)]
+pub struct HiddenStateStats {
+    /// L2 norm of the finite values
+    pub norm: f32,
+    /// Mean of the finite values
+    pub mean: f32,
+    pub nan_count: usize,
+    pub inf_count: usize,
+}
+
+impl HiddenStateStats {
+    pub fn from_values(values: &[f32]) -> Self {
+        let mut sum = 0.0;
+        let mut sum_squares = 0.0;
+        let mut finite_count = 0;
+        let mut nan_count = 0;
+        let mut inf_count = 0;
+
+        for v in values {
+            if v.is_nan() {
+                nan_count += 1;
+            } else if v.is_infinite() {
+                inf_count += 1;
+            } else {
+                let v = *v as f64;
+                sum += v;
+                sum_squares += v * v;
+                finite_count += 1;
+            }
+        }
+
+        Self {
+            norm: sum_squares.sqrt() as f32,
+            mean: (sum / finite_count.max(1) as f64) as f32,
+            nan_count,
+            inf_count,
+        }
+    }
+}
+
 pub trait Backend {
     fn health(&self) -> Result<(), BackendError>;
     fn max_batch_size(&self) -> Option<usize> {
         None
     }
+    /// Attention implementation the model was loaded with
+    fn attention(&self) -> Option<String> {
+        None
+    }
+    /// Whether the backend supports `embed_all`
+    fn multi_embedding(&self) -> bool {
+        false
+    }
+    /// Number of transformer layers of the model, if the backend supports `embed_layer`
+    fn num_layers(&self) -> Option<usize> {
+        None
+    }
+    /// Dimension of the returned embeddings if it differs from the model hidden size, e.g. when
+    /// the pooled embeddings are projected
+    fn embedding_dimension(&self) -> Option<usize> {
+        None
+    }
+    /// Quantization of the model weights, e.g. `q4_0`, if they are quantized
+    fn quantization(&self) -> Option<String> {
+        None
+    }
+    /// Whether a whitening transform is applied to the pooled embeddings
+    fn whitening(&self) -> bool {
+        false
+    }
 
     fn embed(&self, batch: Batch) -> Result<Vec<Embedding>, BackendError>;
 
     fn predict(&self, batch: Batch) -> Result<Vec<Vec<f32>>, BackendError>;
+
+    /// Embeddings pooled from the hidden states of `layer`, where `0` is the output of the
+    /// embeddings layer and `num_layers` the last hidden state
+    fn embed_layer(&self, _batch: Batch, _layer: usize) -> Result<Vec<Embedding>, BackendError> {
+        Err(BackendError::Inference(
+            "layer selection is not supported by this backend".to_string(),
+        ))
+    }
+
+    /// Dense, sparse and ColBERT embeddings of each sequence of the batch, computed from a single
+    /// forward pass
+    fn embed_all(&self, _batch: Batch) -> Result<Vec<MultiEmbedding>, BackendError> {
+        Err(BackendError::Inference(
+            "multiple embedding modes are not supported by this model".to_string(),
+        ))
+    }
+
+    /// Statistics of the last hidden state of a batch of a single sequence
+    fn hidden_state_stats(&self, _batch: Batch) -> Result<HiddenStateStats, BackendError> {
+        Err(BackendError::Inference(
+            "hidden state statistics are not supported by this backend".to_string(),
+        ))
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -34,7 +176,11 @@ pub enum ModelType {
 #[derive(Debug, PartialEq, Clone)]
 #[cfg_attr(feature = "clap", derive(ValueEnum))]
 pub enum Pool {
+    /// Hidden state of the CLS token
     Cls,
+    /// Hidden state of the CLS token projected by the model pooler (dense + tanh)
+    Pooler,
+    /// Mean of the tokens hidden states
     Mean,
 }
 
@@ -42,19 +188,178 @@ impl fmt::Display for Pool {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Pool::Cls => write!(f, "cls"),
+            Pool::Pooler => write!(f, "pooler"),
             Pool::Mean => write!(f, "mean"),
         }
     }
 }
 
+/// Where the pooled embeddings are L2 normalized relative to the `Dense` projection of
+/// SentenceTransformers models
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+#[cfg_attr(feature = "clap", derive(ValueEnum))]
+pub enum DenseNormalization {
+    /// Follow the order of the `Normalize` and `Dense` modules in `modules.json`
+    #[default]
+    Auto,
+    /// Normalize the pooled embeddings, then project them
+    Before,
+    /// Project the pooled embeddings. They are only normalized if the request asks for it
+    After,
+}
+
+impl fmt::Display for DenseNormalization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DenseNormalization::Auto => write!(f, "auto"),
+            DenseNormalization::Before => write!(f, "before"),
+            DenseNormalization::After => write!(f, "after"),
+        }
+    }
+}
+
+/// JSON merge patch (RFC 7396) applied to the `config.json` of the model before it is loaded.
+/// Objects are merged recursively, `null` removes a field and any other value replaces it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigOverride(Value);
+
+impl ConfigOverride {
+    /// Apply the patch to `config`.
+    /// Returns the changed fields with their previous and new values, e.g.
+    /// `max_position_embeddings: 514 -> 512`.
+    pub fn apply(&self, config: &mut Value) -> Vec<String> {
+        let mut changes = Vec::new();
+        merge_patch(config, &self.0, "", &mut changes);
+        changes
+    }
+}
+
+fn merge_patch(target: &mut Value, patch: &Value, path: &str, changes: &mut Vec<String>) {
+    let Value::Object(patch) = patch else {
+        if target != patch {
+            changes.push(format!("{path}: {target} -> {patch}"));
+        }
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        changes.push(format!("{path}: {target} -> {{}}"));
+        *target = Value::Object(Default::default());
+    }
+    let target = target.as_object_mut().unwrap();
+
+    for (key, value) in patch {
+        let path = match path {
+            "" => key.clone(),
+            path => format!("{path}.{key}"),
+        };
+        if value.is_null() {
+            if let Some(previous) = target.remove(key) {
+                changes.push(format!("{path}: {previous} -> removed"));
+            }
+        } else {
+            let field = target.entry(key.clone()).or_insert(Value::Null);
+            merge_patch(field, value, &path, changes);
+        }
+    }
+}
+
+impl FromStr for ConfigOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match serde_json::from_str(s) {
+            Ok(value @ Value::Object(_)) => Ok(Self(value)),
+            Ok(_) => Err("the config override must be a JSON object".to_string()),
+            Err(err) => Err(format!("the config override is not valid JSON: {err}")),
+        }
+    }
+}
+
+impl fmt::Display for ConfigOverride {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Reasons a model cannot be loaded, from the model artifacts or the requested settings
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum ModelLoadError {
+    #[error("`{0}` not found in the model directory. Check that the model repository contains it")]
+    MissingFile(String),
+    #[error("could not parse `{file}`: {message}")]
+    ConfigParse { file: String, message: String },
+    #[error("model type `{architecture}` is not supported. Supported model types: {supported}")]
+    UnsupportedArchitecture {
+        architecture: String,
+        supported: String,
+    },
+    #[error("dtype `{dtype}` is not supported. Supported dtypes: {supported}")]
+    DtypeUnsupported { dtype: String, supported: String },
+    #[error("device `{0}` is not available: {1}")]
+    DeviceUnavailable(String, String),
+    #[error("could not load the model weights: {0}")]
+    Weights(String),
+}
+
 #[derive(Debug, Error, Clone)]
 pub enum BackendError {
     #[error("No backend found")]
     NoBackend,
     #[error("Could not start backend: {0}")]
     Start(String),
+    #[error("Could not load model: {0}")]
+    ModelLoad(#[from] ModelLoadError),
     #[error("Inference error: {0}")]
     Inference(String),
+    #[error("Inference error: out of memory: {0}")]
+    ResourceExhausted(String),
     #[error("Backend is unhealthy")]
     Unhealthy,
 }
+
+impl BackendError {
+    /// Whether the error is likely to go away when the inference is retried on smaller batches.
+    /// Errors caused by the inputs or the model itself are never transient.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, BackendError::ResourceExhausted(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_override() {
+        let mut config = serde_json::json!({
+            "max_position_embeddings": 514,
+            "rope_scaling": {"type": "linear", "factor": 2.0},
+            "pad_token_id": 1
+        });
+        let config_override: ConfigOverride =
+            r#"{"max_position_embeddings": 512, "rope_scaling": {"factor": 4.0}, "pad_token_id": null}"#
+                .parse()
+                .unwrap();
+
+        let changes = config_override.apply(&mut config);
+        assert_eq!(
+            config,
+            serde_json::json!({
+                "max_position_embeddings": 512,
+                "rope_scaling": {"type": "linear", "factor": 4.0}
+            })
+        );
+        assert_eq!(
+            changes,
+            vec![
+                "max_position_embeddings: 514 -> 512",
+                "pad_token_id: 1 -> removed",
+                "rope_scaling.factor: 2.0 -> 4.0",
+            ]
+        );
+
+        assert!("[1]".parse::<ConfigOverride>().is_err());
+        assert!("{".parse::<ConfigOverride>().is_err());
+    }
+}
@@ -9,7 +9,7 @@ pub use pb::embedding::v1::Embedding;
 pub use pb::embedding::v1::HealthResponse;
 use thiserror::Error;
 use tonic::transport;
-use tonic::Status;
+use tonic::{Code, Status};
 
 #[derive(Error, Debug, Clone)]
 pub enum ClientError {
@@ -17,11 +17,16 @@ pub enum ClientError {
     Connection(String),
     #[error("Server error: {0}")]
     Inference(String),
+    #[error("Server error: {0}")]
+    ResourceExhausted(String),
 }
 
 impl From<Status> for ClientError {
     fn from(err: Status) -> Self {
-        let err = Self::Inference(err.message().to_string());
+        let err = match err.code() {
+            Code::ResourceExhausted => Self::ResourceExhausted(err.message().to_string()),
+            _ => Self::Inference(err.message().to_string()),
+        };
         tracing::error!("{err}");
         err
     }
@@ -15,10 +15,18 @@ class Dtype(str, Enum):
     bloat16 = "bfloat16"
 
 
+class Attention(str, Enum):
+    auto = "auto"
+    eager = "eager"
+    sdpa = "sdpa"
+    flash = "flash"
+
+
 @app.command()
 def serve(
     model_path: Path,
     dtype: Dtype = "float32",
+    attention: Attention = "auto",
     uds_path: Path = "/tmp/text-embeddings-server",
     logger_level: str = "INFO",
     json_output: bool = False,
@@ -46,8 +54,9 @@ def serve(
 
     # Downgrade enum into str for easier management later on
     dtype = None if dtype is None else dtype.value
+    attention = attention.value
 
-    server.serve(model_path, dtype, uds_path)
+    server.serve(model_path, dtype, attention, uds_path)
 
 
 if __name__ == "__main__":
@@ -25,7 +25,7 @@ if FLASH_ATTENTION:
     __all__.append(FlashBert)
 
 
-def get_model(model_path: Path, dtype: Optional[str]):
+def get_model(model_path: Path, dtype: Optional[str], attention: str = "auto"):
     if dtype == "float32":
         dtype = torch.float32
     elif dtype == "float16":
@@ -46,14 +46,22 @@ def get_model(model_path: Path, dtype: Optional[str]):
 
     if config.model_type == "bert":
         config: BertConfig
-        if (
+        flash_attention_supported = (
             device.type == "cuda"
             and config.position_embedding_type == "absolute"
             and dtype in [torch.float16, torch.bfloat16]
             and FLASH_ATTENTION
-        ):
+        )
+        if attention == "flash" and not flash_attention_supported:
+            raise ValueError(
+                "`flash` attention requires a Cuda device, a float16 or bfloat16 dtype "
+                "and a model with absolute position embeddings"
+            )
+        if attention in ["auto", "flash"] and flash_attention_supported:
+            logger.info("Using flash attention")
             return FlashBert(model_path, device, dtype)
         else:
-            return DefaultModel(model_path, device, dtype)
+            attn_implementation = None if attention == "auto" else attention
+            return DefaultModel(model_path, device, dtype, attn_implementation)
 
     raise NotImplementedError
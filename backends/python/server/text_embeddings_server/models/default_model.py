@@ -2,7 +2,7 @@ import inspect
 import torch
 
 from pathlib import Path
-from typing import Type, List
+from typing import Type, List, Optional
 from transformers import AutoModel
 from opentelemetry import trace
 
@@ -13,8 +13,19 @@ tracer = trace.get_tracer(__name__)
 
 
 class DefaultModel(Model):
-    def __init__(self, model_path: Path, device: torch.device, dtype: torch.dtype):
-        model = AutoModel.from_pretrained(model_path).to(dtype).to(device)
+    def __init__(
+        self,
+        model_path: Path,
+        device: torch.device,
+        dtype: torch.dtype,
+        attn_implementation: Optional[str] = None,
+    ):
+        kwargs = {}
+        if attn_implementation is not None:
+            kwargs["attn_implementation"] = attn_implementation
+        model = (
+            AutoModel.from_pretrained(model_path, **kwargs).to(dtype).to(device)
+        )
         self.hidden_size = model.config.hidden_size
 
         self.has_position_ids = (
@@ -36,6 +36,7 @@ class EmbeddingService(embed_pb2_grpc.EmbeddingServiceServicer):
 def serve(
     model_path: Path,
     dtype: Optional[str],
+    attention: str,
     uds_path: Path,
 ):
     async def serve_inner(
@@ -45,7 +46,7 @@ def serve(
         unix_socket = f"unix://{uds_path}"
 
         try:
-            model = get_model(model_path, dtype)
+            model = get_model(model_path, dtype, attention)
         except Exception:
             logger.exception("Error when initializing model")
             raise
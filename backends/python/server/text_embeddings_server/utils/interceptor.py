@@ -26,8 +26,12 @@ class ExceptionInterceptor(AsyncServerInterceptor):
             if torch.cuda.is_available():
                 torch.cuda.empty_cache()
 
+            # Out of memory errors can be retried by the router on smaller batches
+            if isinstance(err, torch.cuda.OutOfMemoryError):
+                code = code_pb2.RESOURCE_EXHAUSTED
+            else:
+                code = code_pb2.INTERNAL
+
             await context.abort_with_status(
-                rpc_status.to_status(
-                    status_pb2.Status(code=code_pb2.INTERNAL, message=str(err))
-                )
+                rpc_status.to_status(status_pb2.Status(code=code, message=str(err)))
             )
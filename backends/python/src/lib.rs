@@ -1,7 +1,7 @@
 mod logging;
 mod management;
 
-use backend_grpc_client::Client;
+use backend_grpc_client::{Client, ClientError};
 use text_embeddings_backend_core::{Backend, BackendError, Batch, Embedding, ModelType, Pool};
 use tokio::runtime::Runtime;
 
@@ -9,12 +9,14 @@ pub struct PythonBackend {
     _backend_process: management::BackendProcess,
     tokio_runtime: Runtime,
     backend_client: Client,
+    attention: String,
 }
 
 impl PythonBackend {
     pub fn new(
         model_path: String,
         dtype: String,
+        attention: String,
         model_type: ModelType,
         uds_path: String,
         otlp_endpoint: Option<String>,
@@ -32,8 +34,13 @@ impl PythonBackend {
             return Err(BackendError::Start(format!("{pool:?} is not supported")));
         }
 
-        let backend_process =
-            management::BackendProcess::new(model_path, dtype, &uds_path, otlp_endpoint)?;
+        let backend_process = management::BackendProcess::new(
+            model_path,
+            dtype,
+            attention.clone(),
+            &uds_path,
+            otlp_endpoint,
+        )?;
         let tokio_runtime = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
@@ -49,6 +56,7 @@ impl PythonBackend {
             _backend_process: backend_process,
             tokio_runtime,
             backend_client,
+            attention,
         })
     }
 }
@@ -65,6 +73,11 @@ impl Backend for PythonBackend {
         Ok(())
     }
 
+    fn attention(&self) -> Option<String> {
+        // The Python server picks the implementation itself in `auto` mode
+        (self.attention != "auto").then(|| self.attention.clone())
+    }
+
     fn embed(&self, batch: Batch) -> Result<Vec<Embedding>, BackendError> {
         let results = self
             .tokio_runtime
@@ -75,7 +88,10 @@ impl Backend for PythonBackend {
                 batch.cumulative_seq_lengths,
                 batch.max_length,
             ))
-            .map_err(|err| BackendError::Inference(err.to_string()))?;
+            .map_err(|err| match err {
+                ClientError::ResourceExhausted(message) => BackendError::ResourceExhausted(message),
+                err => BackendError::Inference(err.to_string()),
+            })?;
         Ok(results.into_iter().map(|r| r.values).collect())
     }
 
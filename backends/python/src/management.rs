@@ -19,6 +19,7 @@ impl BackendProcess {
     pub(crate) fn new(
         model_path: String,
         dtype: String,
+        attention: String,
         uds_path: &str,
         otlp_endpoint: Option<String>,
     ) -> Result<Self, BackendError> {
@@ -35,6 +36,8 @@ impl BackendProcess {
             model_path,
             "--dtype".to_string(),
             dtype,
+            "--attention".to_string(),
+            attention,
             "--uds-path".to_string(),
             uds_path.to_string(),
             "--logger-level".to_string(),
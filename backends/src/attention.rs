@@ -0,0 +1,27 @@
+use std::fmt;
+
+#[cfg(feature = "clap")]
+use clap::ValueEnum;
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+#[cfg_attr(feature = "clap", derive(ValueEnum))]
+pub enum Attention {
+    // Pick the fastest implementation supported by the hardware and the model
+    #[default]
+    Auto,
+    Eager,
+    // PyTorch scaled dot product attention
+    Sdpa,
+    Flash,
+}
+
+impl fmt::Display for Attention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Attention::Auto => write!(f, "auto"),
+            Attention::Eager => write!(f, "eager"),
+            Attention::Sdpa => write!(f, "sdpa"),
+            Attention::Flash => write!(f, "flash"),
+        }
+    }
+}
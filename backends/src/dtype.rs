@@ -1,3 +1,4 @@
+use half::{bf16, f16};
 use std::fmt;
 
 #[cfg(feature = "clap")]
@@ -36,3 +37,61 @@ impl fmt::Display for DType {
         }
     }
 }
+
+/// Precision of the returned embeddings, independent of the dtype the model runs in
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "clap", derive(ValueEnum))]
+pub enum OutputDType {
+    #[default]
+    Float32,
+    Float16,
+    Bfloat16,
+}
+
+impl OutputDType {
+    /// Round each value to the output precision.
+    /// Values are kept as `f32` as they are serialized the same way on the wire.
+    pub fn cast(&self, embedding: &mut [f32]) {
+        match self {
+            OutputDType::Float32 => {}
+            OutputDType::Float16 => embedding
+                .iter_mut()
+                .for_each(|v| *v = f16::from_f32(*v).to_f32()),
+            OutputDType::Bfloat16 => embedding
+                .iter_mut()
+                .for_each(|v| *v = bf16::from_f32(*v).to_f32()),
+        }
+    }
+}
+
+impl fmt::Display for OutputDType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputDType::Float32 => write!(f, "float32"),
+            OutputDType::Float16 => write!(f, "float16"),
+            OutputDType::Bfloat16 => write!(f, "bfloat16"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_dtype_cast() {
+        let embedding = vec![0.1f32, -1.0, 1e5];
+
+        let mut float32 = embedding.clone();
+        OutputDType::Float32.cast(&mut float32);
+        assert_eq!(float32, embedding);
+
+        let mut float16 = embedding.clone();
+        OutputDType::Float16.cast(&mut float16);
+        assert_eq!(float16, vec![0.099975586, -1.0, f32::INFINITY]);
+
+        let mut bfloat16 = embedding.clone();
+        OutputDType::Bfloat16.cast(&mut bfloat16);
+        assert_eq!(bfloat16, vec![0.100097656, -1.0, 99840.0]);
+    }
+}
@@ -1,14 +1,18 @@
+mod attention;
 mod dtype;
 
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use text_embeddings_backend_core::Backend as CoreBackend;
 use tokio::sync::oneshot;
 use tracing::{instrument, Span};
 
-pub use crate::dtype::DType;
-pub use text_embeddings_backend_core::{BackendError, Batch, Embedding, ModelType, Pool};
+pub use crate::attention::Attention;
+pub use crate::dtype::{DType, OutputDType};
+pub use text_embeddings_backend_core::{
+    Backend as CoreBackend, BackendError, Batch, ConfigOverride, DenseNormalization, Embedding,
+    HiddenStateStats, ModelLoadError, ModelType, MultiEmbedding, Pool,
+};
 
 #[cfg(feature = "candle")]
 use text_embeddings_backend_candle::CandleBackend;
@@ -24,35 +28,106 @@ pub struct Backend {
     health: Arc<AtomicBool>,
     pub max_batch_size: Option<usize>,
     pub model_type: ModelType,
+    /// Number of model instances sharing the backend channel
+    pub num_replicas: usize,
+    /// Number of transformer layers, if the backend supports layer selection
+    pub num_layers: Option<usize>,
+    /// Attention implementation the model was loaded with, if reported by the backend
+    pub attention: Option<String>,
+    /// Whether the model returns dense, sparse and ColBERT embeddings with `embed_all`
+    pub multi_embedding: bool,
+    /// Dimension of the embeddings if it differs from the model hidden size
+    pub embedding_dimension: Option<usize>,
+    /// Quantization of the model weights, if they are quantized
+    pub quantization: Option<String>,
+    /// Whether a whitening transform is applied to the pooled embeddings
+    pub whitening: bool,
 }
 
 impl Backend {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         model_path: PathBuf,
         dtype: DType,
+        attention: Attention,
+        dense_normalization: DenseNormalization,
+        config_override: Option<ConfigOverride>,
+        num_replicas: usize,
         model_type: ModelType,
         uds_path: String,
         otlp_endpoint: Option<String>,
     ) -> Result<Self, BackendError> {
+        if num_replicas == 0 {
+            return Err(BackendError::Start(
+                "`num_replicas` must be greater than 0".to_string(),
+            ));
+        }
+        if num_replicas > 1 && !cfg!(feature = "candle") {
+            return Err(BackendError::Start(
+                "Multiple replicas are only supported by the candle backend".to_string(),
+            ));
+        }
+
+        let backends = (0..num_replicas)
+            .map(|_| {
+                init_backend(
+                    model_path.clone(),
+                    &dtype,
+                    attention,
+                    dense_normalization,
+                    config_override.clone(),
+                    model_type.clone(),
+                    uds_path.clone(),
+                    otlp_endpoint.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self::from_backends(backends, model_type))
+    }
+
+    /// Serve already initialized backends, one per model replica
+    pub fn from_backends(
+        backends: Vec<Box<dyn CoreBackend + Send>>,
+        model_type: ModelType,
+    ) -> Self {
         let (backend_sender, backend_receiver) = flume::unbounded();
 
-        let backend = init_backend(
-            model_path,
-            dtype,
-            model_type.clone(),
-            uds_path,
-            otlp_endpoint,
-        )?;
-        let max_batch_size = backend.max_batch_size();
+        let num_replicas = backends.len();
+        let mut max_batch_size = None;
+        let mut num_layers = None;
+        let mut attention = None;
+        let mut multi_embedding = false;
+        let mut embedding_dimension = None;
+        let mut quantization = None;
+        let mut whitening = false;
+        for backend in backends {
+            max_batch_size = backend.max_batch_size();
+            num_layers = backend.num_layers();
+            attention = backend.attention();
+            multi_embedding = backend.multi_embedding();
+            embedding_dimension = backend.embedding_dimension();
+            quantization = backend.quantization();
+            whitening = backend.whitening();
 
-        tokio::task::spawn_blocking(move || backend_blocking_task(backend, backend_receiver));
+            // All replicas pull commands from the same channel
+            let backend_receiver = backend_receiver.clone();
+            tokio::task::spawn_blocking(move || backend_blocking_task(backend, backend_receiver));
+        }
 
-        Ok(Self {
+        Self {
             backend_sender,
             health: Arc::new(AtomicBool::new(false)),
             max_batch_size,
             model_type,
-        })
+            num_replicas,
+            num_layers,
+            attention,
+            multi_embedding,
+            embedding_dimension,
+            quantization,
+            whitening,
+        }
     }
 
     #[instrument(skip(self))]
@@ -106,6 +181,47 @@ impl Backend {
         result
     }
 
+    #[instrument(skip_all)]
+    pub async fn embed_layer(
+        &self,
+        batch: Batch,
+        layer: usize,
+    ) -> Result<Vec<Embedding>, BackendError> {
+        let (sender, receiver) = oneshot::channel();
+
+        self.backend_sender
+            .send(BackendCommand::EmbedLayer(
+                batch,
+                layer,
+                Span::current(),
+                sender,
+            ))
+            .expect("No backend receiver. This is a bug.");
+        let result = receiver.await.expect(
+            "Backend blocking task dropped the sender without send a response. This is a bug.",
+        );
+
+        // Update health
+        self.health.store(result.is_ok(), Ordering::SeqCst);
+        result
+    }
+
+    #[instrument(skip_all)]
+    pub async fn embed_all(&self, batch: Batch) -> Result<Vec<MultiEmbedding>, BackendError> {
+        let (sender, receiver) = oneshot::channel();
+
+        self.backend_sender
+            .send(BackendCommand::EmbedAll(batch, Span::current(), sender))
+            .expect("No backend receiver. This is a bug.");
+        let result = receiver.await.expect(
+            "Backend blocking task dropped the sender without send a response. This is a bug.",
+        );
+
+        // Update health
+        self.health.store(result.is_ok(), Ordering::SeqCst);
+        result
+    }
+
     #[instrument(skip_all)]
     pub async fn predict(&self, batch: Batch) -> Result<Vec<Vec<f32>>, BackendError> {
         let (sender, receiver) = oneshot::channel();
@@ -121,12 +237,33 @@ impl Backend {
         self.health.store(result.is_ok(), Ordering::SeqCst);
         result
     }
+
+    /// Statistics of the last hidden state of a batch of a single sequence.
+    /// Used for debugging only so it does not update the backend health.
+    #[instrument(skip_all)]
+    pub async fn hidden_state_stats(&self, batch: Batch) -> Result<HiddenStateStats, BackendError> {
+        let (sender, receiver) = oneshot::channel();
+
+        self.backend_sender
+            .send(BackendCommand::HiddenStateStats(
+                batch,
+                Span::current(),
+                sender,
+            ))
+            .expect("No backend receiver. This is a bug.");
+        receiver.await.expect(
+            "Backend blocking task dropped the sender without send a response. This is a bug.",
+        )
+    }
 }
 
-#[allow(unused)]
+#[allow(unused, clippy::too_many_arguments)]
 fn init_backend(
     model_path: PathBuf,
-    dtype: DType,
+    dtype: &DType,
+    attention: Attention,
+    dense_normalization: DenseNormalization,
+    config_override: Option<ConfigOverride>,
     model_type: ModelType,
     uds_path: String,
     otlp_endpoint: Option<String>,
@@ -136,6 +273,9 @@ fn init_backend(
         return Ok(Box::new(CandleBackend::new(
             model_path,
             dtype.to_string(),
+            attention.to_string(),
+            dense_normalization,
+            config_override,
             model_type,
         )?));
     } else if cfg!(feature = "python") {
@@ -143,11 +283,20 @@ fn init_backend(
         {
             use std::thread;
 
+            if config_override.is_some() {
+                return Err(BackendError::Start(
+                    "`config_override` is not supported by the python backend".to_string(),
+                ));
+            }
+
+            let dtype = dtype.to_string();
+            let attention = attention.to_string();
             return Ok(Box::new(
                 thread::spawn(move || {
                     PythonBackend::new(
                         model_path.to_str().unwrap().to_string(),
-                        dtype.to_string(),
+                        dtype,
+                        attention,
                         model_type,
                         uds_path,
                         otlp_endpoint,
@@ -175,10 +324,22 @@ fn backend_blocking_task(
                 let _span = span.entered();
                 let _ = sender.send(backend.embed(batch));
             }
+            BackendCommand::EmbedLayer(batch, layer, span, sender) => {
+                let _span = span.entered();
+                let _ = sender.send(backend.embed_layer(batch, layer));
+            }
+            BackendCommand::EmbedAll(batch, span, sender) => {
+                let _span = span.entered();
+                let _ = sender.send(backend.embed_all(batch));
+            }
             BackendCommand::Predict(batch, span, sender) => {
                 let _span = span.entered();
                 let _ = sender.send(backend.predict(batch));
             }
+            BackendCommand::HiddenStateStats(batch, span, sender) => {
+                let _span = span.entered();
+                let _ = sender.send(backend.hidden_state_stats(batch));
+            }
         }
     }
 }
@@ -190,9 +351,25 @@ enum BackendCommand {
         Span,
         oneshot::Sender<Result<Vec<Embedding>, BackendError>>,
     ),
+    EmbedLayer(
+        Batch,
+        usize,
+        Span,
+        oneshot::Sender<Result<Vec<Embedding>, BackendError>>,
+    ),
+    EmbedAll(
+        Batch,
+        Span,
+        oneshot::Sender<Result<Vec<MultiEmbedding>, BackendError>>,
+    ),
     Predict(
         Batch,
         Span,
         oneshot::Sender<Result<Vec<Vec<f32>>, BackendError>>,
     ),
+    HiddenStateStats(
+        Batch,
+        Span,
+        oneshot::Sender<Result<HiddenStateStats, BackendError>>,
+    ),
 }
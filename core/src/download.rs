@@ -1,34 +1,291 @@
 use hf_hub::api::tokio::{ApiError, ApiRepo};
+use hf_hub::CacheRepo;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
 use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::Instant;
 use tracing::instrument;
 
+/// Limits applied to the model artifacts downloads
+#[derive(Debug, Clone, Default)]
+pub struct DownloadLimits {
+    /// Maximum size of a single file in bytes
+    pub max_file_size: Option<u64>,
+    /// Maximum duration of the whole download
+    pub timeout: Option<Duration>,
+    /// Hub token used to query the size of the files of private repositories
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error(transparent)]
+    Api(#[from] ApiError),
+    #[error("`{filename}` is {size} bytes which is larger than the maximum file size of {max_file_size} bytes")]
+    FileTooLarge {
+        filename: String,
+        size: u64,
+        max_file_size: u64,
+    },
+    #[error("could not get the size of `{filename}` to enforce the maximum file size: {reason}")]
+    UnknownSize { filename: String, reason: String },
+    #[error("download timed out after {0:?}")]
+    Timeout(Duration),
+}
+
+/// Treat a missing optional file as `None`. Exceeding the download limits is still an error.
+pub fn optional<T>(result: Result<T, DownloadError>) -> Result<Option<T>, DownloadError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(DownloadError::Api(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
 #[instrument(skip_all)]
-pub async fn download_artifacts(api: &ApiRepo) -> Result<PathBuf, ApiError> {
+pub async fn download_artifacts(download: &mut Download<'_>) -> Result<PathBuf, DownloadError> {
     let start = std::time::Instant::now();
 
     tracing::info!("Starting download");
 
-    api.get("config.json").await?;
-    api.get("tokenizer.json").await?;
+    let result = download.artifacts().await;
+    if let Err(err) = &result {
+        tracing::error!(
+            "Download failed after {} bytes: {err}",
+            download.downloaded_bytes
+        );
+    }
+    let model_root = result?;
+
+    tracing::info!("Model artifacts downloaded in {:?}", start.elapsed());
+    Ok(model_root)
+}
+
+/// Sharded safetensors weights index
+#[derive(Deserialize)]
+struct SafetensorsIndex {
+    weight_map: HashMap<String, String>,
+}
+
+/// Downloads files from a repository while enforcing the download limits.
+/// The timeout covers all the files fetched through the same `Download`.
+pub struct Download<'a> {
+    api: &'a ApiRepo,
+    /// Files already in the cache are not downloaded again and skip the size check
+    cache: CacheRepo,
+    limits: &'a DownloadLimits,
+    client: reqwest::Client,
+    deadline: Option<Instant>,
+    /// Size of the files downloaded so far
+    downloaded_bytes: u64,
+}
 
-    let model_root = match api.get("model.safetensors").await {
-        Ok(p) => p,
-        Err(_) => {
-            let p = api.get("pytorch_model.bin").await?;
-            tracing::warn!("`model.safetensors` not found. Using `pytorch_model.bin` instead. Model loading will be significantly slower.");
-            p
+impl<'a> Download<'a> {
+    pub fn new(api: &'a ApiRepo, cache: CacheRepo, limits: &'a DownloadLimits) -> Self {
+        // Do not follow redirects: the size of LFS files is only given by the Hub response
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("Failed to build the HTTP client. This is a bug.");
+
+        Self {
+            api,
+            cache,
+            limits,
+            client,
+            deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+            downloaded_bytes: 0,
         }
     }
+
+    async fn artifacts(&mut self) -> Result<PathBuf, DownloadError> {
+        self.get("config.json").await?;
+        self.get("tokenizer.json").await?;
+
+        // Only fall back to the other weight formats if the file does not exist
+        let model_root = match self.get("model.safetensors").await {
+            Ok(p) => p,
+            Err(DownloadError::Api(_)) => match self.safetensors_shards().await {
+                Ok(p) => p,
+                Err(DownloadError::Api(_)) => match self.get("model.gguf").await {
+                    Ok(p) => {
+                        tracing::info!("`model.safetensors` not found. Using the quantized `model.gguf` instead.");
+                        p
+                    }
+                    Err(DownloadError::Api(_)) => {
+                        let p = self.get("pytorch_model.bin").await?;
+                        tracing::warn!("`model.safetensors` not found. Using `pytorch_model.bin` instead. Model loading will be significantly slower.");
+                        p
+                    }
+                    Err(err) => return Err(err),
+                },
+                Err(err) => return Err(err),
+            },
+            Err(err) => return Err(err),
+        }
         .parent()
         .unwrap()
         .to_path_buf();
 
-    tracing::info!("Model artifacts downloaded in {:?}", start.elapsed());
-    Ok(model_root)
+        Ok(model_root)
+    }
+
+    /// Download all the shards listed in `model.safetensors.index.json`.
+    /// Returns the path of the index.
+    async fn safetensors_shards(&mut self) -> Result<PathBuf, DownloadError> {
+        let index_path = self.get("model.safetensors.index.json").await?;
+
+        let index = std::fs::read_to_string(&index_path).map_err(ApiError::from)?;
+        let index: SafetensorsIndex = serde_json::from_str(&index)
+            .map_err(|err| ApiError::from(std::io::Error::from(err)))?;
+
+        let shards: BTreeSet<String> = index.weight_map.into_values().collect();
+        tracing::info!("Downloading {} safetensors shards", shards.len());
+        for shard in shards {
+            self.get(&shard).await?;
+        }
+
+        Ok(index_path)
+    }
+
+    async fn get(&mut self, filename: &str) -> Result<PathBuf, DownloadError> {
+        if let Some(path) = self.cache.get(filename) {
+            return Ok(path);
+        }
+
+        if let Some(max_file_size) = self.limits.max_file_size {
+            let size =
+                self.file_size(filename)
+                    .await
+                    .map_err(|reason| DownloadError::UnknownSize {
+                        filename: filename.to_string(),
+                        reason,
+                    })?;
+            // A missing file is reported by the download itself
+            if let Some(size) = size {
+                if size > max_file_size {
+                    return Err(DownloadError::FileTooLarge {
+                        filename: filename.to_string(),
+                        size,
+                        max_file_size,
+                    });
+                }
+            }
+        }
+
+        let path = match (self.deadline, self.limits.timeout) {
+            (Some(deadline), Some(timeout)) => {
+                tokio::time::timeout_at(deadline, self.api.get(filename))
+                    .await
+                    .map_err(|_| DownloadError::Timeout(timeout))??
+            }
+            _ => self.api.get(filename).await?,
+        };
+
+        self.downloaded_bytes += std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        Ok(path)
+    }
+
+    /// Size of a file according to the Hub.
+    /// Returns `None` if the file does not exist.
+    async fn file_size(&self, filename: &str) -> Result<Option<u64>, String> {
+        let mut request = self.client.head(self.api.url(filename));
+        if let Some(token) = &self.limits.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(|err| err.to_string())?;
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !(status.is_success() || status.is_redirection()) {
+            return Err(format!("the Hub answered {status}"));
+        }
+        let headers = response.headers();
+
+        headers
+            .get("x-linked-size")
+            .or_else(|| headers.get(reqwest::header::CONTENT_LENGTH))
+            .and_then(|size| size.to_str().ok())
+            .and_then(|size| size.parse().ok())
+            .map(Some)
+            .ok_or_else(|| "the Hub did not return it".to_string())
+    }
 }
 
 #[instrument(skip_all)]
-pub async fn download_pool_config(api: &ApiRepo) -> Result<PathBuf, ApiError> {
-    let pool_config_path = api.get("1_Pooling/config.json").await?;
+pub async fn download_pool_config(download: &mut Download<'_>) -> Result<PathBuf, DownloadError> {
+    let pool_config_path = download.get("1_Pooling/config.json").await?;
     Ok(pool_config_path)
 }
+
+#[instrument(skip_all)]
+pub async fn download_st_config(download: &mut Download<'_>) -> Result<PathBuf, DownloadError> {
+    let st_config_path = download.get("config_sentence_transformers.json").await?;
+    Ok(st_config_path)
+}
+
+/// Download the sparse and ColBERT heads of BGE-M3 style models
+#[instrument(skip_all)]
+pub async fn download_m3_heads(download: &mut Download<'_>) -> Result<(), DownloadError> {
+    download.get("sparse_linear.pt").await?;
+    download.get("colbert_linear.pt").await?;
+    Ok(())
+}
+
+/// Download the centering and whitening transform applied to the pooled embeddings
+#[instrument(skip_all)]
+pub async fn download_whitening(download: &mut Download<'_>) -> Result<PathBuf, DownloadError> {
+    let whitening_path = download.get("whitening.safetensors").await?;
+    Ok(whitening_path)
+}
+
+/// SentenceTransformers module as listed in `modules.json`
+#[derive(Deserialize)]
+struct Module {
+    path: String,
+    #[serde(rename = "type")]
+    module_type: String,
+}
+
+/// Download `modules.json` and the `Dense` projection modules of SentenceTransformers models
+#[instrument(skip_all)]
+pub async fn download_dense_modules(download: &mut Download<'_>) -> Result<(), DownloadError> {
+    let modules_path = download.get("modules.json").await?;
+    let modules = std::fs::read_to_string(&modules_path).map_err(ApiError::from)?;
+    let modules: Vec<Module> =
+        serde_json::from_str(&modules).map_err(|err| ApiError::from(std::io::Error::from(err)))?;
+
+    for module in modules
+        .iter()
+        .filter(|module| module.module_type.ends_with("Dense"))
+    {
+        download
+            .get(&format!("{}/config.json", module.path))
+            .await?;
+        match download
+            .get(&format!("{}/model.safetensors", module.path))
+            .await
+        {
+            Ok(_) => {}
+            // Only fall back to the other weight format if the file does not exist
+            Err(DownloadError::Api(_)) => {
+                download
+                    .get(&format!("{}/pytorch_model.bin", module.path))
+                    .await?;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+#[instrument(skip_all)]
+pub async fn download_tokenizer_config(
+    download: &mut Download<'_>,
+) -> Result<PathBuf, DownloadError> {
+    let tokenizer_config_path = download.get("tokenizer_config.json").await?;
+    Ok(tokenizer_config_path)
+}
@@ -1,12 +1,26 @@
-use crate::queue::{Entry, Metadata, NextBatch, Queue};
-use crate::tokenization::{EncodingInput, Tokenization};
+use crate::queue::{to_batch, Entry, Metadata, NextBatch, Priority, Queue};
+use crate::tokenization::{Encoding, EncodingInput, Tokenization, TruncationStrategy};
 use crate::TextEmbeddingsError;
-use std::sync::Arc;
+use futures::future::join_all;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use text_embeddings_backend::{Backend, BackendError, ModelType};
+use text_embeddings_backend::{
+    Backend, BackendError, Batch, HiddenStateStats, ModelType, MultiEmbedding,
+};
 use tokio::sync::{mpsc, oneshot, Notify, OwnedSemaphorePermit, Semaphore};
 use tracing::{instrument, Span};
 
+/// Interval at which the slow start admits more concurrent requests
+const SLOW_START_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interval between the backend probes of the requests waiting for the circuit breaker to close
+const HEALTH_PROBE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Weight of the last batch in the average backend time per request
+const DRAIN_RATE_SMOOTHING: f64 = 0.2;
+
 /// Inference struct
 #[derive(Clone)]
 pub struct Infer {
@@ -16,6 +30,19 @@ pub struct Infer {
     notify_batching_task: Arc<Notify>,
     /// Inference limit
     limit_concurrent_requests: Arc<Semaphore>,
+    max_concurrent_requests: usize,
+    /// Permits not yet released by the slow start ramp
+    withheld_permits: Arc<AtomicUsize>,
+    /// Number of consecutive backend failures
+    backend_failures: Arc<AtomicUsize>,
+    /// Number of consecutive backend failures after which the service is unhealthy
+    circuit_breaker_threshold: Option<usize>,
+    /// Only one waiting request probes the backend at a time
+    health_probe: Arc<tokio::sync::Mutex<()>>,
+    /// Average backend time per request
+    drain_rate: Arc<DrainRate>,
+    /// Fail the requests whose model outputs contain NaN or Inf values
+    reject_non_finite: bool,
     backend: Backend,
 }
 
@@ -25,26 +52,42 @@ impl Infer {
         tokenization: Tokenization,
         queue: Queue,
         max_concurrent_requests: usize,
+        circuit_breaker_threshold: Option<usize>,
+        reject_non_finite: bool,
+        microbatch_size: Option<usize>,
         backend: Backend,
     ) -> Self {
         let notify_batching_task = Arc::new(Notify::new());
+        let backend_failures = Arc::new(AtomicUsize::new(0));
+        let drain_rate = Arc::new(DrainRate::default());
 
-        let (embed_sender, embed_receiver) = mpsc::unbounded_channel();
+        // One inference pipeline per model replica so that the replicas run batches concurrently
+        for _ in 0..backend.num_replicas {
+            let (embed_sender, embed_receiver) = mpsc::unbounded_channel();
 
-        // Create two batching tasks to prefetch batches
-        tokio::spawn(batching_task(
-            queue.clone(),
-            notify_batching_task.clone(),
-            embed_sender.clone(),
-        ));
-        tokio::spawn(batching_task(
-            queue.clone(),
-            notify_batching_task.clone(),
-            embed_sender,
-        ));
+            // Create two batching tasks to prefetch batches
+            tokio::spawn(batching_task(
+                queue.clone(),
+                notify_batching_task.clone(),
+                embed_sender.clone(),
+            ));
+            tokio::spawn(batching_task(
+                queue.clone(),
+                notify_batching_task.clone(),
+                embed_sender,
+            ));
 
-        // Create embed task to communicate with backend
-        tokio::spawn(backend_task(backend.clone(), embed_receiver));
+            // Create embed task to communicate with backend
+            tokio::spawn(backend_task(
+                backend.clone(),
+                embed_receiver,
+                backend_failures.clone(),
+                drain_rate.clone(),
+                circuit_breaker_threshold,
+                reject_non_finite,
+                microbatch_size,
+            ));
+        }
 
         // Inference limit with a semaphore
         let semaphore = Arc::new(Semaphore::new(max_concurrent_requests));
@@ -54,6 +97,13 @@ impl Infer {
             queue,
             notify_batching_task,
             limit_concurrent_requests: semaphore,
+            max_concurrent_requests,
+            withheld_permits: Arc::new(AtomicUsize::new(0)),
+            backend_failures,
+            circuit_breaker_threshold,
+            health_probe: Arc::new(tokio::sync::Mutex::new(())),
+            drain_rate,
+            reject_non_finite,
             backend,
         }
     }
@@ -80,22 +130,59 @@ impl Infer {
             .expect("Semaphore has been closed. This is a bug.")
     }
 
+    /// Admit a tenth of `max_concurrent_requests` and ramp up linearly to all of them over
+    /// `duration`. Must be called before the first request is admitted.
+    #[instrument(skip(self))]
+    pub fn slow_start(&self, duration: Duration) {
+        let initial = (self.max_concurrent_requests / 10).max(1);
+        let withheld = self.max_concurrent_requests.saturating_sub(initial);
+        match self
+            .limit_concurrent_requests
+            .try_acquire_many(withheld as u32)
+        {
+            // Withhold the permits until the ramp releases them
+            Ok(permits) => {
+                permits.forget();
+                self.withheld_permits.store(withheld, Ordering::SeqCst);
+            }
+            Err(_) => {
+                tracing::warn!("Requests were already admitted. Skipping slow start");
+                return;
+            }
+        }
+
+        let semaphore = self.limit_concurrent_requests.clone();
+        let withheld_permits = self.withheld_permits.clone();
+        tokio::spawn(async move {
+            let start_time = tokio::time::Instant::now();
+            let mut interval = tokio::time::interval(SLOW_START_INTERVAL);
+            let mut released = 0;
+            while released < withheld {
+                interval.tick().await;
+                let progress =
+                    (start_time.elapsed().as_secs_f64() / duration.as_secs_f64()).min(1.0);
+                let target = (withheld as f64 * progress) as usize;
+                if target > released {
+                    withheld_permits.fetch_sub(target - released, Ordering::SeqCst);
+                    semaphore.add_permits(target - released);
+                    released = target;
+                }
+            }
+            tracing::info!("Slow start done");
+        });
+    }
+
     #[instrument(skip(self, _permit))]
     pub async fn embed<I: Into<EncodingInput> + std::fmt::Debug>(
         &self,
         inputs: I,
         truncate: bool,
+        add_special_tokens: bool,
         normalize: bool,
+        priority: Priority,
         _permit: OwnedSemaphorePermit,
     ) -> Result<InferResponse, TextEmbeddingsError> {
-        if self.is_classifier() {
-            metrics::increment_counter!("te_request_failure", "err" => "model_type");
-            let message = "model is not an embedding model".to_string();
-            tracing::error!("{message}");
-            return Err(TextEmbeddingsError::Backend(BackendError::Inference(
-                message,
-            )));
-        }
+        self.check_model_type(false)?;
 
         let start_time = Instant::now();
         metrics::increment_counter!("te_embed_count");
@@ -103,7 +190,12 @@ impl Infer {
         // Tokenization
         let encoding = self
             .tokenization
-            .encode(inputs.into(), truncate)
+            .encode(
+                inputs.into(),
+                truncate,
+                TruncationStrategy::LongestFirst,
+                add_special_tokens,
+            )
             .await
             .map_err(|err| {
                 metrics::increment_counter!("te_request_failure", "err" => "tokenization");
@@ -111,49 +203,348 @@ impl Infer {
                 err
             })?;
 
-        // MPSC channel to communicate with the background batching task
-        let (response_tx, response_rx) = oneshot::channel();
+        let mut response = self
+            .schedule(encoding, start_time.elapsed(), priority)
+            .await?;
 
-        // Append the request to the queue
-        self.queue.append(Entry {
-            metadata: Metadata {
-                response_tx,
-                span: Span::current(),
-                tokenization: start_time.elapsed(),
-                queue_time: Instant::now(),
-                prompt_tokens: encoding.input_ids.len(),
-            },
-            encoding,
-        });
+        if normalize {
+            normalize_embedding(&mut response.results);
+        }
 
-        self.notify_batching_task.notify_one();
+        // Timings
+        let total_time = start_time.elapsed();
+
+        // Metrics
+        metrics::increment_counter!("te_embed_success");
+        metrics::histogram!("te_embed_duration", total_time.as_secs_f64());
+        metrics::histogram!(
+            "te_embed_tokenization_duration",
+            response.tokenization.as_secs_f64()
+        );
+        metrics::histogram!("te_embed_queue_duration", response.queue.as_secs_f64());
+        metrics::histogram!(
+            "te_embed_inference_duration",
+            response.inference.as_secs_f64()
+        );
+
+        Ok(response)
+    }
 
-        let mut response = response_rx
+    /// Embed the inputs of a client batch, each one with its own priority.
+    /// The inputs are tokenized together and then scheduled separately: a permit is acquired for
+    /// each input once the batch is tokenized. Returns the response, or the error, of each input.
+    #[instrument(skip_all)]
+    pub async fn embed_batch<I: Into<EncodingInput>>(
+        &self,
+        inputs: Vec<(I, Priority)>,
+        truncate: bool,
+        add_special_tokens: bool,
+        normalize: bool,
+    ) -> Result<Vec<Result<InferResponse, TextEmbeddingsError>>, TextEmbeddingsError> {
+        self.check_model_type(false)?;
+
+        let start_time = Instant::now();
+        metrics::counter!("te_embed_count", inputs.len() as u64);
+
+        // Tokenization
+        let (inputs, priorities): (Vec<EncodingInput>, Vec<Priority>) = inputs
+            .into_iter()
+            .map(|(input, priority)| (input.into(), priority))
+            .unzip();
+        let encodings = self
+            .tokenization
+            .encode_batch(
+                inputs,
+                truncate,
+                TruncationStrategy::LongestFirst,
+                add_special_tokens,
+            )
+            .await;
+        let tokenization = start_time.elapsed();
+
+        let futures =
+            encodings
+                .into_iter()
+                .zip(priorities)
+                .map(|(encoding, priority)| async move {
+                    let encoding = encoding.map_err(|err| {
+                        metrics::increment_counter!("te_request_failure", "err" => "tokenization");
+                        tracing::error!("{err}");
+                        err
+                    })?;
+
+                    let _permit = self.acquire_permit().await;
+                    let mut response = self.schedule(encoding, tokenization, priority).await?;
+
+                    if normalize {
+                        normalize_embedding(&mut response.results);
+                    }
+
+                    // Metrics
+                    metrics::increment_counter!("te_embed_success");
+                    metrics::histogram!("te_embed_duration", start_time.elapsed().as_secs_f64());
+                    metrics::histogram!(
+                        "te_embed_tokenization_duration",
+                        response.tokenization.as_secs_f64()
+                    );
+                    metrics::histogram!("te_embed_queue_duration", response.queue.as_secs_f64());
+                    metrics::histogram!(
+                        "te_embed_inference_duration",
+                        response.inference.as_secs_f64()
+                    );
+
+                    Ok(response)
+                });
+
+        Ok(join_all(futures).await)
+    }
+
+    /// Embed a single input by pooling the hidden states of an intermediate layer instead of the
+    /// last one. `layer` indexes the hidden states like `output_hidden_states`: `0` is the output
+    /// of the embeddings layer and negative values count from the last layer.
+    /// The input is sent to the backend on its own, outside of the batching queue.
+    #[instrument(skip(self, _permit))]
+    pub async fn embed_layer<I: Into<EncodingInput> + std::fmt::Debug>(
+        &self,
+        inputs: I,
+        truncate: bool,
+        add_special_tokens: bool,
+        normalize: bool,
+        layer: i32,
+        _permit: OwnedSemaphorePermit,
+    ) -> Result<InferResponse, TextEmbeddingsError> {
+        self.check_model_type(false)?;
+        let layer = self.resolve_layer(layer).map_err(|err| {
+            metrics::increment_counter!("te_request_failure", "err" => "validation");
+            tracing::error!("{err}");
+            err
+        })?;
+
+        let start_time = Instant::now();
+        metrics::increment_counter!("te_embed_count");
+
+        // Tokenization
+        let encoding = self
+            .tokenization
+            .encode(
+                inputs.into(),
+                truncate,
+                TruncationStrategy::LongestFirst,
+                add_special_tokens,
+            )
             .await
-            .expect(
-                "Infer batching task dropped the sender without sending a response. This is a bug.",
+            .map_err(|err| {
+                metrics::increment_counter!("te_request_failure", "err" => "tokenization");
+                tracing::error!("{err}");
+                err
+            })?;
+        let tokenization = start_time.elapsed();
+
+        let prompt_tokens = encoding.input_ids.len();
+        let truncated = encoding.truncated;
+        let batch = Batch {
+            input_ids: encoding.input_ids,
+            token_type_ids: encoding.token_type_ids,
+            position_ids: encoding.position_ids,
+            cumulative_seq_lengths: vec![0, prompt_tokens as u32],
+            max_length: prompt_tokens as u32,
+        };
+
+        let inference_start = Instant::now();
+        let mut results = self
+            .run_unbatched(self.backend.embed_layer(batch, layer))
+            .await?
+            .pop()
+            .ok_or_else(|| {
+                TextEmbeddingsError::Backend(BackendError::Inference(
+                    "backend returned no embedding".to_string(),
+                ))
+            })?;
+        let inference = inference_start.elapsed();
+        self.check_finite_output(|| results.iter().all(|v| v.is_finite()))?;
+
+        if normalize {
+            normalize_embedding(&mut results);
+        }
+
+        metrics::increment_counter!("te_embed_success");
+        metrics::histogram!("te_embed_duration", start_time.elapsed().as_secs_f64());
+
+        Ok(InferResponse {
+            results,
+            prompt_tokens,
+            truncated,
+            tokenization,
+            queue: Duration::ZERO,
+            inference,
+        })
+    }
+
+    /// Dense, sparse and ColBERT embeddings of a single input, computed from a single forward
+    /// pass of hybrid retrieval models such as BGE-M3.
+    /// The input is sent to the backend on its own, outside of the batching queue.
+    #[instrument(skip(self, _permit))]
+    pub async fn embed_all_modes<I: Into<EncodingInput> + std::fmt::Debug>(
+        &self,
+        inputs: I,
+        truncate: bool,
+        normalize: bool,
+        _permit: OwnedSemaphorePermit,
+    ) -> Result<InferMultiResponse, TextEmbeddingsError> {
+        if !self.backend.multi_embedding {
+            metrics::increment_counter!("te_request_failure", "err" => "model_type");
+            let message = "model does not support multiple embedding modes".to_string();
+            tracing::error!("{message}");
+            return Err(TextEmbeddingsError::Validation(message));
+        }
+
+        let start_time = Instant::now();
+        metrics::increment_counter!("te_embed_count");
+
+        // The sparse and ColBERT heads rely on the special tokens
+        let encoding = self
+            .tokenization
+            .encode(
+                inputs.into(),
+                truncate,
+                TruncationStrategy::LongestFirst,
+                true,
             )
+            .await
             .map_err(|err| {
-                metrics::increment_counter!("te_request_failure", "err" => "inference");
+                metrics::increment_counter!("te_request_failure", "err" => "tokenization");
                 tracing::error!("{err}");
                 err
             })?;
+        let tokenization = start_time.elapsed();
+
+        let prompt_tokens = encoding.input_ids.len();
+        let truncated = encoding.truncated;
+        let batch = Batch {
+            input_ids: encoding.input_ids,
+            token_type_ids: encoding.token_type_ids,
+            position_ids: encoding.position_ids,
+            cumulative_seq_lengths: vec![0, prompt_tokens as u32],
+            max_length: prompt_tokens as u32,
+        };
+
+        let inference_start = Instant::now();
+        let mut results = self
+            .run_unbatched(self.backend.embed_all(batch))
+            .await?
+            .pop()
+            .ok_or_else(|| {
+                TextEmbeddingsError::Backend(BackendError::Inference(
+                    "backend returned no embedding".to_string(),
+                ))
+            })?;
+        let inference = inference_start.elapsed();
+        self.check_finite_output(|| {
+            results
+                .dense
+                .iter()
+                .chain(results.colbert.iter().flatten())
+                .chain(results.sparse.iter().map(|(_, value)| value))
+                .all(|v| v.is_finite())
+        })?;
 
+        // The ColBERT vectors are always normalized
         if normalize {
-            // Normalize embedding
-            let scale = (1.0
-                / response
-                    .results
-                    .iter()
-                    .map(|v| {
-                        let v = *v as f64;
-                        v * v
-                    })
-                    .sum::<f64>()
-                    .sqrt()) as f32;
-            for v in response.results.iter_mut() {
-                *v *= scale;
+            normalize_embedding(&mut results.dense);
+        }
+
+        metrics::increment_counter!("te_embed_success");
+        metrics::histogram!("te_embed_duration", start_time.elapsed().as_secs_f64());
+
+        Ok(InferMultiResponse {
+            results,
+            prompt_tokens,
+            truncated,
+            tokenization,
+            inference,
+        })
+    }
+
+    /// Index of the hidden states of `layer`, which may count from the last layer if negative
+    fn resolve_layer(&self, layer: i32) -> Result<usize, TextEmbeddingsError> {
+        let num_layers = self.backend.num_layers.ok_or_else(|| {
+            TextEmbeddingsError::Validation("`layer` is not supported by this model".to_string())
+        })?;
+
+        // `num_layers + 1` hidden states: the embeddings output and the output of each layer
+        let index = if layer < 0 {
+            (num_layers as i64 + 1) + layer as i64
+        } else {
+            layer as i64
+        };
+        if index < 0 || index > num_layers as i64 {
+            return Err(TextEmbeddingsError::Validation(format!(
+                "`layer` must be between -{} and {num_layers}. Given: {layer}",
+                num_layers + 1
+            )));
+        }
+        Ok(index as usize)
+    }
+
+    /// Embed inputs longer than `max_input_length` by splitting them in overlapping windows of
+    /// tokens. The returned embedding is the mean of the embeddings of all windows.
+    #[instrument(skip(self, _permit))]
+    pub async fn embed_windows<I: Into<EncodingInput> + std::fmt::Debug>(
+        &self,
+        inputs: I,
+        stride: usize,
+        add_special_tokens: bool,
+        normalize: bool,
+        priority: Priority,
+        _permit: OwnedSemaphorePermit,
+    ) -> Result<InferResponse, TextEmbeddingsError> {
+        self.check_model_type(false)?;
+
+        let start_time = Instant::now();
+        metrics::increment_counter!("te_embed_count");
+
+        // Tokenization
+        let windows = self
+            .tokenization
+            .encode_windows(inputs.into(), stride, add_special_tokens)
+            .await
+            .map_err(|err| {
+                metrics::increment_counter!("te_request_failure", "err" => "tokenization");
+                tracing::error!("{err}");
+                err
+            })?;
+        let tokenization = start_time.elapsed();
+        let n_windows = windows.len();
+
+        // All windows are scheduled at once and can end up in the same batch
+        let responses = join_all(
+            windows
+                .into_iter()
+                .map(|encoding| self.schedule(encoding, tokenization, priority)),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+
+        let mut response = InferResponse {
+            results: vec![0.0; responses[0].results.len()],
+            prompt_tokens: 0,
+            truncated: false,
+            tokenization,
+            queue: Duration::default(),
+            inference: Duration::default(),
+        };
+        for r in responses {
+            for (acc, v) in response.results.iter_mut().zip(r.results) {
+                *acc += v / n_windows as f32;
             }
+            response.prompt_tokens += r.prompt_tokens;
+            response.queue = response.queue.max(r.queue);
+            response.inference = response.inference.max(r.inference);
+        }
+
+        if normalize {
+            normalize_embedding(&mut response.results);
         }
 
         // Timings
@@ -175,37 +566,89 @@ impl Infer {
         Ok(response)
     }
 
+    /// Embed every window of `ngram_size` tokens of the inputs
     #[instrument(skip(self, _permit))]
-    pub async fn predict<I: Into<EncodingInput> + std::fmt::Debug>(
+    pub async fn embed_ngrams<I: Into<EncodingInput> + std::fmt::Debug>(
         &self,
         inputs: I,
-        truncate: bool,
-        raw_scores: bool,
+        ngram_size: usize,
+        add_special_tokens: bool,
+        normalize: bool,
+        priority: Priority,
         _permit: OwnedSemaphorePermit,
-    ) -> Result<InferResponse, TextEmbeddingsError> {
-        if !self.is_classifier() {
-            metrics::increment_counter!("te_request_failure", "err" => "model_type");
-            let message = "model is not a classifier model".to_string();
-            // tracing::error!("{message}");
-            return Err(TextEmbeddingsError::Backend(BackendError::Inference(
-                message,
-            )));
-        }
+    ) -> Result<InferNgramsResponse, TextEmbeddingsError> {
+        self.check_model_type(false)?;
 
         let start_time = Instant::now();
-        metrics::increment_counter!("te_predict_count");
+        metrics::increment_counter!("te_embed_count");
 
         // Tokenization
-        let encoding = self
+        let ngrams = self
             .tokenization
-            .encode(inputs.into(), truncate)
+            .encode_ngrams(inputs.into(), ngram_size, add_special_tokens)
             .await
             .map_err(|err| {
                 metrics::increment_counter!("te_request_failure", "err" => "tokenization");
                 tracing::error!("{err}");
                 err
             })?;
+        let tokenization = start_time.elapsed();
+        let (encodings, spans): (Vec<_>, Vec<_>) = ngrams.into_iter().unzip();
+
+        // All windows are scheduled at once and can end up in the same batch
+        let responses = join_all(
+            encodings
+                .into_iter()
+                .map(|encoding| self.schedule(encoding, tokenization, priority)),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+
+        let mut response = InferNgramsResponse {
+            results: Vec::with_capacity(responses.len()),
+            spans,
+            prompt_tokens: 0,
+            tokenization,
+            queue: Duration::default(),
+            inference: Duration::default(),
+        };
+        for mut r in responses {
+            if normalize {
+                normalize_embedding(&mut r.results);
+            }
+            response.results.push(r.results);
+            response.prompt_tokens += r.prompt_tokens;
+            response.queue = response.queue.max(r.queue);
+            response.inference = response.inference.max(r.inference);
+        }
+
+        // Timings
+        let total_time = start_time.elapsed();
 
+        // Metrics
+        metrics::increment_counter!("te_embed_success");
+        metrics::histogram!("te_embed_duration", total_time.as_secs_f64());
+        metrics::histogram!(
+            "te_embed_tokenization_duration",
+            response.tokenization.as_secs_f64()
+        );
+        metrics::histogram!("te_embed_queue_duration", response.queue.as_secs_f64());
+        metrics::histogram!(
+            "te_embed_inference_duration",
+            response.inference.as_secs_f64()
+        );
+
+        Ok(response)
+    }
+
+    /// Append an encoding to the queue and wait for the inference result
+    async fn schedule(
+        &self,
+        encoding: Encoding,
+        tokenization: Duration,
+        priority: Priority,
+    ) -> Result<InferResponse, TextEmbeddingsError> {
         // MPSC channel to communicate with the background batching task
         let (response_tx, response_rx) = oneshot::channel();
 
@@ -214,16 +657,25 @@ impl Infer {
             metadata: Metadata {
                 response_tx,
                 span: Span::current(),
-                tokenization: start_time.elapsed(),
+                tokenization,
                 queue_time: Instant::now(),
                 prompt_tokens: encoding.input_ids.len(),
+                truncated: encoding.truncated,
             },
             encoding,
+            priority,
         });
 
         self.notify_batching_task.notify_one();
 
-        let mut response = response_rx
+        // If this future is dropped before the response is received (e.g. because the client
+        // disconnected), the entry is removed from the queue
+        let mut response_rx = ResponseReceiver {
+            receiver: Some(response_rx),
+            queue: &self.queue,
+        };
+        let response = response_rx
+            .recv()
             .await
             .expect(
                 "Infer batching task dropped the sender without sending a response. This is a bug.",
@@ -233,6 +685,38 @@ impl Infer {
                 tracing::error!("{err}");
                 err
             })?;
+        Ok(response)
+    }
+
+    #[instrument(skip(self, _permit))]
+    pub async fn predict<I: Into<EncodingInput> + std::fmt::Debug>(
+        &self,
+        inputs: I,
+        truncate: bool,
+        truncation_strategy: TruncationStrategy,
+        raw_scores: bool,
+        priority: Priority,
+        _permit: OwnedSemaphorePermit,
+    ) -> Result<InferResponse, TextEmbeddingsError> {
+        self.check_model_type(true)?;
+
+        let start_time = Instant::now();
+        metrics::increment_counter!("te_predict_count");
+
+        // Tokenization
+        let encoding = self
+            .tokenization
+            .encode(inputs.into(), truncate, truncation_strategy, true)
+            .await
+            .map_err(|err| {
+                metrics::increment_counter!("te_request_failure", "err" => "tokenization");
+                tracing::error!("{err}");
+                err
+            })?;
+
+        let mut response = self
+            .schedule(encoding, start_time.elapsed(), priority)
+            .await?;
 
         if !raw_scores {
             // Softmax
@@ -277,14 +761,322 @@ impl Infer {
         Ok(response)
     }
 
+    /// Statistics of the last hidden state of a single input, before pooling.
+    /// This is a debugging tool: the input is sent to the backend on its own, outside of the
+    /// batching queue.
+    #[instrument(skip(self, _permit))]
+    pub async fn hidden_state_stats<I: Into<EncodingInput> + std::fmt::Debug>(
+        &self,
+        inputs: I,
+        truncate: bool,
+        add_special_tokens: bool,
+        _permit: OwnedSemaphorePermit,
+    ) -> Result<HiddenStateStats, TextEmbeddingsError> {
+        let encoding = self
+            .tokenization
+            .encode(
+                inputs.into(),
+                truncate,
+                TruncationStrategy::LongestFirst,
+                add_special_tokens,
+            )
+            .await
+            .map_err(|err| {
+                metrics::increment_counter!("te_request_failure", "err" => "tokenization");
+                tracing::error!("{err}");
+                err
+            })?;
+
+        let seq_len = encoding.input_ids.len() as u32;
+        let batch = Batch {
+            input_ids: encoding.input_ids,
+            token_type_ids: encoding.token_type_ids,
+            position_ids: encoding.position_ids,
+            cumulative_seq_lengths: vec![0, seq_len],
+            max_length: seq_len,
+        };
+
+        let stats = self
+            .run_unbatched(self.backend.hidden_state_stats(batch))
+            .await?;
+        self.check_finite_output(|| stats.nan_count + stats.inf_count == 0)?;
+        Ok(stats)
+    }
+
+    /// Run a single input on the backend outside of the batching queue. The backend failures
+    /// count towards the circuit breaker and the backend time towards the drain rate, like the
+    /// batches of the queue. A single input is never split in microbatches.
+    async fn run_unbatched<T>(
+        &self,
+        forward: impl Future<Output = Result<T, BackendError>>,
+    ) -> Result<T, TextEmbeddingsError> {
+        let inference_start = Instant::now();
+        let result = forward.await;
+        record_backend_result(
+            &self.backend_failures,
+            &self.drain_rate,
+            self.circuit_breaker_threshold,
+            1,
+            inference_start.elapsed(),
+            result.as_ref().map(|_| ()),
+        );
+        result.map_err(|err| {
+            metrics::increment_counter!("te_request_failure", "err" => "backend");
+            tracing::error!("{err}");
+            TextEmbeddingsError::from(err)
+        })
+    }
+
+    /// Fail if the model output is not `finite` and NaN or Inf values are rejected, like the
+    /// batches of the queue
+    fn check_finite_output(
+        &self,
+        finite: impl FnOnce() -> bool,
+    ) -> Result<(), TextEmbeddingsError> {
+        check_finite(self.reject_non_finite, finite).map_err(|err| {
+            tracing::error!("{err}");
+            TextEmbeddingsError::from(err)
+        })
+    }
+
+    /// Token of the vocabulary with this id, e.g. to make sparse embeddings readable
+    pub fn id_to_token(&self, id: u32) -> Option<String> {
+        self.tokenization.id_to_token(id)
+    }
+
     #[instrument(skip(self))]
     pub fn is_classifier(&self) -> bool {
         matches!(self.backend.model_type, ModelType::Classifier)
     }
 
+    /// Reject requests for the other model type, e.g. embedding requests sent to a classifier
+    fn check_model_type(&self, classifier: bool) -> Result<(), TextEmbeddingsError> {
+        if self.is_classifier() == classifier {
+            return Ok(());
+        }
+
+        metrics::increment_counter!("te_request_failure", "err" => "model_type");
+        let message = match self.backend.model_type {
+            ModelType::Classifier => {
+                "the model is a classifier (model type `Classifier`) and does not support embedding requests. Use `/predict` instead"
+            }
+            ModelType::Embedding(_) => {
+                "the model is an embedding model (model type `Embedding`) and does not support prediction requests. Use `/embed` instead"
+            }
+        };
+        tracing::error!("{message}");
+        Err(TextEmbeddingsError::Validation(message.to_string()))
+    }
+
     #[instrument(skip(self))]
     pub async fn health(&self) -> bool {
-        self.backend.health().await.is_ok()
+        let healthy = self.backend.health().await.is_ok();
+
+        if let Some(threshold) = self.circuit_breaker_threshold {
+            if self.backend_failures.load(Ordering::SeqCst) >= threshold {
+                // The circuit is open: the backend health check runs the model on a test batch
+                // and only a successful probe closes the circuit
+                if !healthy {
+                    return false;
+                }
+                tracing::info!("Backend probe succeeded. Closing the circuit breaker");
+                self.backend_failures.store(0, Ordering::SeqCst);
+            }
+        }
+        healthy
+    }
+
+    /// Returns true if the circuit breaker is open after too many consecutive backend failures
+    pub fn is_circuit_open(&self) -> bool {
+        self.circuit_breaker_threshold
+            .is_some_and(|threshold| self.backend_failures.load(Ordering::SeqCst) >= threshold)
+    }
+
+    /// Wait up to `grace` for an open circuit breaker to close.
+    /// The waiting requests probe the backend one at a time. Returns false if the circuit is still
+    /// open after `grace`.
+    #[instrument(skip(self))]
+    pub async fn wait_closed_circuit(&self, grace: Duration) -> bool {
+        if !self.is_circuit_open() {
+            return true;
+        }
+
+        let wait = async {
+            loop {
+                {
+                    let _probe = self.health_probe.lock().await;
+                    if !self.is_circuit_open() || self.health().await {
+                        return;
+                    }
+                }
+                tokio::time::sleep(HEALTH_PROBE_INTERVAL).await;
+            }
+        };
+        tokio::time::timeout(grace, wait).await.is_ok()
+    }
+
+    /// Tokenize and run sample inputs before the service starts, so that the first requests do not
+    /// pay for the warmup of the code paths of their shapes. The inputs are truncated and scheduled
+    /// at once to be batched like real traffic. Returns the index and error of each failed input.
+    #[instrument(skip_all)]
+    pub async fn warmup(&self, inputs: Vec<String>) -> Vec<(usize, TextEmbeddingsError)> {
+        // Bypass `embed` and `predict` to not count the warmup in the requests metrics
+        let encodings = self
+            .tokenization
+            .encode_batch(
+                inputs.into_iter().map(EncodingInput::Single).collect(),
+                true,
+                TruncationStrategy::LongestFirst,
+                true,
+            )
+            .await;
+
+        let futures = encodings
+            .into_iter()
+            .enumerate()
+            .map(|(index, encoding)| async move {
+                let result = match encoding {
+                    Ok(encoding) => self
+                        .schedule(encoding, Duration::ZERO, Priority::High)
+                        .await
+                        .map(|_| ()),
+                    Err(err) => Err(err),
+                };
+                result.err().map(|err| (index, err))
+            });
+        join_all(futures).await.into_iter().flatten().collect()
+    }
+
+    /// Run a tiny inference every `interval` while the service is idle so the backend does not
+    /// reclaim its memory or kernels between bursts of traffic
+    pub fn spawn_keep_warm(&self, interval: Duration) {
+        let infer = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if infer.queue_size() > 0 || infer.in_flight_requests() > 0 {
+                    continue;
+                }
+
+                // Bypass `embed` and `predict` to not count the ping in the requests metrics
+                let result = match infer
+                    .tokenization
+                    .encode(
+                        EncodingInput::Single("keep warm".to_string()),
+                        true,
+                        TruncationStrategy::LongestFirst,
+                        true,
+                    )
+                    .await
+                {
+                    Ok(encoding) => infer
+                        .schedule(encoding, Duration::ZERO, Priority::Low)
+                        .await
+                        .map(|_| ()),
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = result {
+                    tracing::warn!("Keep-warm inference failed: {err}");
+                }
+            }
+        });
+    }
+
+    /// Number of requests waiting in the queue
+    pub fn queue_size(&self) -> usize {
+        self.queue.size()
+    }
+
+    /// Number of requests currently holding a permit. The permits withheld by the slow start are
+    /// not requests.
+    pub fn in_flight_requests(&self) -> usize {
+        self.max_concurrent_requests
+            .saturating_sub(self.limit_concurrent_requests.available_permits())
+            .saturating_sub(self.withheld_permits.load(Ordering::SeqCst))
+    }
+
+    /// Estimated time for the replicas to drain the queue, based on the recent backend
+    /// throughput. Returns `None` until the first batch has run.
+    pub fn estimated_drain_time(&self) -> Option<Duration> {
+        let seconds_per_request = self.drain_rate.seconds_per_request()?;
+        let pending = (self.queue.size() + 1) as f64;
+        Some(Duration::from_secs_f64(
+            pending * seconds_per_request / self.backend.num_replicas as f64,
+        ))
+    }
+}
+
+/// Exponentially weighted average of the backend time per request
+#[derive(Debug, Default)]
+struct DrainRate {
+    seconds_per_request: Mutex<Option<f64>>,
+}
+
+impl DrainRate {
+    fn record(&self, requests: usize, elapsed: Duration) {
+        if requests == 0 {
+            return;
+        }
+        let sample = elapsed.as_secs_f64() / requests as f64;
+        let mut seconds_per_request = self.seconds_per_request.lock().unwrap();
+        *seconds_per_request = Some(match *seconds_per_request {
+            Some(average) => average + DRAIN_RATE_SMOOTHING * (sample - average),
+            None => sample,
+        });
+    }
+
+    fn seconds_per_request(&self) -> Option<f64> {
+        *self.seconds_per_request.lock().unwrap()
+    }
+}
+
+/// L2 normalize an embedding in place
+pub fn normalize_embedding(embedding: &mut [f32]) {
+    let scale = (1.0
+        / embedding
+            .iter()
+            .map(|v| {
+                let v = *v as f64;
+                v * v
+            })
+            .sum::<f64>()
+            .sqrt()) as f32;
+    for v in embedding.iter_mut() {
+        *v *= scale;
+    }
+}
+
+/// Receiver of an inference response.
+/// Dropping it before the response is received cancels the queued request.
+struct ResponseReceiver<'a> {
+    receiver: Option<oneshot::Receiver<Result<InferResponse, BackendError>>>,
+    queue: &'a Queue,
+}
+
+impl ResponseReceiver<'_> {
+    async fn recv(
+        &mut self,
+    ) -> Result<Result<InferResponse, BackendError>, oneshot::error::RecvError> {
+        let receiver = self
+            .receiver
+            .as_mut()
+            .expect("response was already received. This is a bug.");
+        let response = receiver.await;
+        self.receiver = None;
+        response
+    }
+}
+
+impl Drop for ResponseReceiver<'_> {
+    fn drop(&mut self) {
+        if let Some(receiver) = self.receiver.take() {
+            // Close the channel before asking the queue to remove the cancelled entries
+            drop(receiver);
+            self.queue.remove_cancelled();
+        }
     }
 }
 
@@ -311,21 +1103,58 @@ async fn batching_task(
 async fn backend_task(
     backend: Backend,
     mut embed_receiver: mpsc::UnboundedReceiver<(NextBatch, oneshot::Sender<()>)>,
+    backend_failures: Arc<AtomicUsize>,
+    drain_rate: Arc<DrainRate>,
+    circuit_breaker_threshold: Option<usize>,
+    reject_non_finite: bool,
+    microbatch_size: Option<usize>,
 ) {
     while let Some((batch, _callback)) = embed_receiver.recv().await {
+        // All the requests of the batch were cancelled while it was waiting for the backend
+        if batch.0.iter().all(|m| m.response_tx.is_closed()) {
+            metrics::counter!("te_request_failure", batch.0.len() as u64, "err" => "dropped");
+            continue;
+        }
+
         let inference_start = Instant::now();
-        let results = match &backend.model_type {
-            ModelType::Classifier => backend.predict(batch.1).await,
-            ModelType::Embedding(_) => backend.embed(batch.1).await,
-        };
+        let mut results = run_batch(&backend, &batch.1, microbatch_size).await;
+
+        // Retry transient errors once, on smaller batches
+        if let Err(err) = &results {
+            if err.is_transient() {
+                tracing::warn!("Transient backend error, retrying: {err}");
+                metrics::increment_counter!("te_backend_retry");
+                results = retry_batch(&backend, &batch.1, microbatch_size).await;
+                if results.is_err() {
+                    metrics::increment_counter!("te_backend_retry_failure");
+                }
+            }
+        }
+
+        record_backend_result(
+            &backend_failures,
+            &drain_rate,
+            circuit_breaker_threshold,
+            batch.0.len(),
+            inference_start.elapsed(),
+            results.as_ref().map(|_| ()),
+        );
 
         // Handle sending responses in another thread to avoid starving the backend
         tokio::task::spawn_blocking(move || match results {
             Ok(embeddings) => {
                 batch.0.into_iter().zip(embeddings).for_each(|(m, e)| {
+                    if let Err(err) =
+                        check_finite(reject_non_finite, || e.iter().all(|v| v.is_finite()))
+                    {
+                        let _ = m.response_tx.send(Err(err));
+                        return;
+                    }
+
                     let _ = m.response_tx.send(Ok(InferResponse {
                         results: e,
                         prompt_tokens: m.prompt_tokens,
+                        truncated: m.truncated,
                         tokenization: m.tokenization,
                         queue: inference_start - m.queue_time,
                         inference: inference_start.elapsed(),
@@ -341,11 +1170,352 @@ async fn backend_task(
     }
 }
 
+/// Reset the count of consecutive backend failures and update the drain rate after a success, or
+/// count the failure and open the circuit breaker after `circuit_breaker_threshold` of them
+fn record_backend_result(
+    backend_failures: &AtomicUsize,
+    drain_rate: &DrainRate,
+    circuit_breaker_threshold: Option<usize>,
+    requests: usize,
+    elapsed: Duration,
+    result: Result<(), &BackendError>,
+) {
+    match result {
+        Ok(()) => {
+            backend_failures.store(0, Ordering::SeqCst);
+            drain_rate.record(requests, elapsed);
+        }
+        Err(err) => {
+            let failures = backend_failures.fetch_add(1, Ordering::SeqCst) + 1;
+            if Some(failures) == circuit_breaker_threshold {
+                tracing::error!(
+                    "{failures} consecutive backend failures. Opening the circuit breaker: {err}"
+                );
+                metrics::increment_counter!("te_circuit_breaker_open");
+            }
+        }
+    }
+}
+
+/// Never return poisoned vectors
+fn check_finite(
+    reject_non_finite: bool,
+    finite: impl FnOnce() -> bool,
+) -> Result<(), BackendError> {
+    if reject_non_finite && !finite() {
+        metrics::increment_counter!("te_request_failure", "err" => "non_finite");
+        return Err(BackendError::Inference(
+            "model output contains NaN or Inf values".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Run a batch in sequential forward passes of at most `microbatch_size` sequences
+async fn run_batch(
+    backend: &Backend,
+    encodings: &[Encoding],
+    microbatch_size: Option<usize>,
+) -> Result<Vec<Vec<f32>>, BackendError> {
+    let microbatch_size = microbatch_size.unwrap_or(encodings.len()).max(1);
+
+    let mut results = Vec::with_capacity(encodings.len());
+    for microbatch in encodings.chunks(microbatch_size) {
+        let batch = to_batch(microbatch);
+        results.extend(match &backend.model_type {
+            ModelType::Classifier => backend.predict(batch).await?,
+            ModelType::Embedding(_) => backend.embed(batch).await?,
+        });
+    }
+    Ok(results)
+}
+
+/// Run a batch again, split in two halves if it contains more than one sequence
+async fn retry_batch(
+    backend: &Backend,
+    encodings: &[Encoding],
+    microbatch_size: Option<usize>,
+) -> Result<Vec<Vec<f32>>, BackendError> {
+    if encodings.len() < 2 {
+        return run_batch(backend, encodings, microbatch_size).await;
+    }
+
+    let (first, second) = encodings.split_at(encodings.len() / 2);
+    let mut results = run_batch(backend, first, microbatch_size).await?;
+    results.extend(run_batch(backend, second, microbatch_size).await?);
+    Ok(results)
+}
+
 #[derive(Debug)]
 pub struct InferResponse {
     pub results: Vec<f32>,
     pub prompt_tokens: usize,
+    pub truncated: bool,
     pub tokenization: Duration,
     pub queue: Duration,
     pub inference: Duration,
 }
+
+/// Embeddings of the n-grams of an input
+#[derive(Debug)]
+pub struct InferNgramsResponse {
+    pub results: Vec<Vec<f32>>,
+    /// Span of tokens of each n-gram in the input, end excluded
+    pub spans: Vec<(usize, usize)>,
+    pub prompt_tokens: usize,
+    pub tokenization: Duration,
+    pub queue: Duration,
+    pub inference: Duration,
+}
+
+#[derive(Debug)]
+pub struct InferMultiResponse {
+    pub results: MultiEmbedding,
+    pub prompt_tokens: usize,
+    pub truncated: bool,
+    pub tokenization: Duration,
+    pub inference: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenization::UnicodeNormalization;
+    use std::collections::HashMap;
+    use text_embeddings_backend::{CoreBackend, Embedding, Pool};
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::Tokenizer;
+
+    /// Model whose outputs are always poisoned
+    struct NanBackend;
+
+    impl CoreBackend for NanBackend {
+        fn health(&self) -> Result<(), BackendError> {
+            Ok(())
+        }
+
+        fn num_layers(&self) -> Option<usize> {
+            Some(1)
+        }
+
+        fn embed(&self, batch: Batch) -> Result<Vec<Embedding>, BackendError> {
+            Ok(vec![vec![f32::NAN, 1.0]; batch.len()])
+        }
+
+        fn embed_layer(&self, batch: Batch, _layer: usize) -> Result<Vec<Embedding>, BackendError> {
+            self.embed(batch)
+        }
+
+        fn predict(&self, batch: Batch) -> Result<Vec<Vec<f32>>, BackendError> {
+            Ok(vec![vec![f32::INFINITY]; batch.len()])
+        }
+    }
+
+    /// Model returning the size of its batch for each sequence
+    struct BatchSizeBackend;
+
+    impl CoreBackend for BatchSizeBackend {
+        fn health(&self) -> Result<(), BackendError> {
+            Ok(())
+        }
+
+        fn embed(&self, batch: Batch) -> Result<Vec<Embedding>, BackendError> {
+            Ok(vec![vec![batch.len() as f32]; batch.len()])
+        }
+
+        fn predict(&self, batch: Batch) -> Result<Vec<Vec<f32>>, BackendError> {
+            self.embed(batch)
+        }
+    }
+
+    /// Model running out of memory on batches of more than two sequences
+    struct OutOfMemoryBackend;
+
+    impl CoreBackend for OutOfMemoryBackend {
+        fn health(&self) -> Result<(), BackendError> {
+            Ok(())
+        }
+
+        fn embed(&self, batch: Batch) -> Result<Vec<Embedding>, BackendError> {
+            if batch.len() > 2 {
+                return Err(BackendError::ResourceExhausted(
+                    "CUDA_ERROR_OUT_OF_MEMORY".to_string(),
+                ));
+            }
+            BatchSizeBackend.embed(batch)
+        }
+
+        fn predict(&self, batch: Batch) -> Result<Vec<Vec<f32>>, BackendError> {
+            self.embed(batch)
+        }
+    }
+
+    fn infer(reject_non_finite: bool) -> Infer {
+        let vocab = HashMap::from([("hello".to_string(), 0), ("[UNK]".to_string(), 1)]);
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let tokenization = Tokenization::new(
+            1,
+            Tokenizer::new(model),
+            8,
+            0,
+            UnicodeNormalization::None,
+            None,
+            None,
+        );
+        let backend =
+            Backend::from_backends(vec![Box::new(NanBackend)], ModelType::Embedding(Pool::Mean));
+
+        Infer::new(
+            tokenization,
+            Queue::new(16, None, 4),
+            4,
+            None,
+            reject_non_finite,
+            None,
+            backend,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_predict_with_embedding_model() {
+        let infer = infer(false);
+        let permit = infer.acquire_permit().await;
+        let err = infer
+            .predict(
+                "hello".to_string(),
+                false,
+                TruncationStrategy::LongestFirst,
+                false,
+                Priority::High,
+                permit,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TextEmbeddingsError::Validation(_)));
+    }
+
+    #[tokio::test]
+    async fn test_slow_start() {
+        tokio::time::pause();
+        let infer = infer(false);
+        infer.slow_start(Duration::from_millis(200));
+        // Let the ramp start before the clock is advanced
+        tokio::task::yield_now().await;
+
+        let permit = infer.try_acquire_permit().unwrap();
+        assert!(infer.try_acquire_permit().is_err());
+        assert_eq!(infer.in_flight_requests(), 1);
+        drop(permit);
+        assert_eq!(infer.in_flight_requests(), 0);
+
+        // The clock is paused: advance it past the ramp, then let the ramp release the permits
+        tokio::time::advance(Duration::from_millis(400)).await;
+        tokio::task::yield_now().await;
+        let permits: Vec<_> = (0..4)
+            .map(|_| infer.try_acquire_permit().unwrap())
+            .collect();
+        assert!(infer.try_acquire_permit().is_err());
+        drop(permits);
+    }
+
+    #[test]
+    fn test_drain_rate() {
+        let drain_rate = DrainRate::default();
+        assert_eq!(drain_rate.seconds_per_request(), None);
+
+        drain_rate.record(4, Duration::from_secs(2));
+        assert_eq!(drain_rate.seconds_per_request(), Some(0.5));
+        drain_rate.record(1, Duration::from_millis(1500));
+        assert_eq!(drain_rate.seconds_per_request(), Some(0.7));
+    }
+
+    #[tokio::test]
+    async fn test_reject_non_finite_embeddings() {
+        let strict = infer(true);
+        let permit = strict.acquire_permit().await;
+        let err = strict
+            .embed(
+                "hello".to_string(),
+                false,
+                true,
+                false,
+                Priority::High,
+                permit,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TextEmbeddingsError::Backend(_)));
+
+        // Inputs sent to the backend outside of the batching queue are checked as well
+        let permit = strict.acquire_permit().await;
+        let err = strict
+            .embed_layer("hello".to_string(), false, true, false, -1, permit)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TextEmbeddingsError::Backend(_)));
+
+        let lenient = infer(false);
+        let permit = lenient.acquire_permit().await;
+        let response = lenient
+            .embed(
+                "hello".to_string(),
+                false,
+                true,
+                false,
+                Priority::High,
+                permit,
+            )
+            .await
+            .unwrap();
+        assert!(response.results[0].is_nan());
+    }
+
+    #[tokio::test]
+    async fn test_run_batch_microbatches() {
+        let backend = Backend::from_backends(
+            vec![Box::new(BatchSizeBackend)],
+            ModelType::Embedding(Pool::Mean),
+        );
+        let encodings: Vec<Encoding> = (0..5)
+            .map(|_| Encoding {
+                input_ids: vec![0],
+                token_type_ids: vec![0],
+                position_ids: vec![0],
+                truncated: false,
+            })
+            .collect();
+
+        let results = run_batch(&backend, &encodings, Some(2)).await.unwrap();
+        assert_eq!(
+            results,
+            vec![vec![2.0], vec![2.0], vec![2.0], vec![2.0], vec![1.0]]
+        );
+        let results = run_batch(&backend, &encodings, None).await.unwrap();
+        assert_eq!(results, vec![vec![5.0]; 5]);
+    }
+
+    #[tokio::test]
+    async fn test_retry_batch_on_smaller_batches() {
+        let backend = Backend::from_backends(
+            vec![Box::new(OutOfMemoryBackend)],
+            ModelType::Embedding(Pool::Mean),
+        );
+        let encodings: Vec<Encoding> = (0..4)
+            .map(|_| Encoding {
+                input_ids: vec![0],
+                token_type_ids: vec![0],
+                position_ids: vec![0],
+                truncated: false,
+            })
+            .collect();
+
+        let err = run_batch(&backend, &encodings, None).await.unwrap_err();
+        assert!(err.is_transient());
+        let results = retry_batch(&backend, &encodings, None).await.unwrap();
+        assert_eq!(results, vec![vec![2.0]; 4]);
+    }
+}
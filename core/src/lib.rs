@@ -13,6 +13,11 @@ pub enum TextEmbeddingsError {
     Tokenizer(#[from] tokenizers::Error),
     #[error("Input validation error: {0}")]
     Validation(String),
+    #[error("Input validation error: `inputs` must have less than {max_input_length} tokens. Given: {tokens}")]
+    InputTooLong {
+        tokens: usize,
+        max_input_length: usize,
+    },
     #[error("Model is overloaded")]
     Overloaded(#[from] TryAcquireError),
     #[error("Backend error: {0}")]
@@ -2,16 +2,30 @@ use crate::infer::InferResponse;
 use crate::tokenization::Encoding;
 use std::cmp::max;
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use text_embeddings_backend::{BackendError, Batch};
 use tokio::sync::{mpsc, oneshot};
 use tracing::{instrument, Span};
 
+/// Scheduling priority of a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Priority {
+    /// Interactive requests, always dispatched first
+    #[default]
+    High,
+    /// Bulk requests, only dispatched when no high priority request is waiting
+    Low,
+}
+
 /// Queue entry
 #[derive(Debug)]
 pub struct Entry {
     /// Payload
     pub encoding: Encoding,
+    /// Scheduling priority
+    pub priority: Priority,
     /// Entry metadata
     pub metadata: Metadata,
 }
@@ -29,6 +43,8 @@ pub struct Metadata {
     pub queue_time: Instant,
     /// Number of tokens in the prompt
     pub prompt_tokens: usize,
+    /// Whether the prompt was truncated
+    pub truncated: bool,
 }
 
 /// Request Queue
@@ -36,6 +52,8 @@ pub struct Metadata {
 pub struct Queue {
     /// Channel to communicate with the background queue task
     queue_sender: mpsc::UnboundedSender<QueueCommand>,
+    /// Number of entries waiting in the queue
+    size: Arc<AtomicUsize>,
 }
 
 impl Queue {
@@ -46,18 +64,26 @@ impl Queue {
     ) -> Self {
         // Create channels
         let (queue_sender, queue_receiver) = mpsc::unbounded_channel();
+        let size = Arc::new(AtomicUsize::new(0));
 
         // Launch background queue task
+        let task_size = size.clone();
         tokio::task::spawn_blocking(move || {
             queue_blocking_task(
                 max_batch_tokens,
                 max_batch_requests,
                 max_concurrent_requests,
+                task_size,
                 queue_receiver,
             )
         });
 
-        Self { queue_sender }
+        Self { queue_sender, size }
+    }
+
+    /// Number of entries waiting in the queue
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
     }
 
     /// Append an entry to the queue
@@ -70,6 +96,15 @@ impl Queue {
             .expect("Queue background task dropped the receiver. This is a bug.");
     }
 
+    /// Remove the entries whose request was cancelled, for example because the client
+    /// disconnected, so that they stop counting towards the queue size
+    #[instrument(skip_all)]
+    pub fn remove_cancelled(&self) {
+        self.queue_sender
+            .send(QueueCommand::RemoveCancelled(Span::current()))
+            .expect("Queue background task dropped the receiver. This is a bug.");
+    }
+
     /// Get the next batch from the queue
     #[instrument(skip(self))]
     pub async fn next_batch(&self) -> Option<NextBatch> {
@@ -96,63 +131,77 @@ fn queue_blocking_task(
     max_batch_tokens: usize,
     max_batch_requests: Option<usize>,
     max_concurrent_requests: usize,
+    size: Arc<AtomicUsize>,
     mut queue_receiver: mpsc::UnboundedReceiver<QueueCommand>,
 ) {
     let capacity = max_batch_requests.unwrap_or(max_concurrent_requests);
 
-    let mut entries: VecDeque<Entry> = VecDeque::with_capacity(max_concurrent_requests);
+    // One FIFO per priority, in dispatch order
+    let mut high_priority_entries: VecDeque<Entry> =
+        VecDeque::with_capacity(max_concurrent_requests);
+    let mut low_priority_entries: VecDeque<Entry> = VecDeque::new();
 
     while let Some(cmd) = queue_receiver.blocking_recv() {
         match cmd {
             QueueCommand::Append(entry, span) => {
                 let _span = span.entered();
-                entries.push_back(*entry);
+                match entry.priority {
+                    Priority::High => high_priority_entries.push_back(*entry),
+                    Priority::Low => low_priority_entries.push_back(*entry),
+                }
+                size.store(
+                    high_priority_entries.len() + low_priority_entries.len(),
+                    Ordering::SeqCst,
+                );
                 metrics::increment_gauge!("te_queue_size", 1.0);
             }
+            QueueCommand::RemoveCancelled(span) => {
+                let _span = span.entered();
+                let len = high_priority_entries.len() + low_priority_entries.len();
+                high_priority_entries.retain(|entry| !entry.metadata.response_tx.is_closed());
+                low_priority_entries.retain(|entry| !entry.metadata.response_tx.is_closed());
+                let new_len = high_priority_entries.len() + low_priority_entries.len();
+                if new_len < len {
+                    metrics::counter!("te_request_failure", (len - new_len) as u64, "err" => "dropped");
+                    size.store(new_len, Ordering::SeqCst);
+                    metrics::gauge!("te_queue_size", new_len as f64);
+                }
+            }
             QueueCommand::NextBatch {
                 response_sender,
                 span,
             } => {
                 let _span = span.entered();
 
-                let mut input_ids = Vec::with_capacity(max_batch_tokens);
-                let mut token_type_ids = Vec::with_capacity(max_batch_tokens);
-                let mut position_ids = Vec::with_capacity(max_batch_tokens);
-
                 let mut metadata = Vec::with_capacity(capacity);
-                let mut cu_seq_lengths = Vec::with_capacity(capacity);
-                cu_seq_lengths.push(0);
+                let mut encodings = Vec::with_capacity(capacity);
 
                 let mut current_tokens = 0;
-                let mut max_length = 0;
-
-                while let Some(entry) = entries.pop_front() {
-                    // Filter entries where the response receiver was dropped (== entries where the request
-                    // was dropped by the client)
-                    if entry.metadata.response_tx.is_closed() {
-                        metrics::increment_counter!("te_request_failure", "err" => "dropped");
-                        continue;
-                    }
-
-                    let entry_tokens = entry.encoding.input_ids.len();
 
-                    if current_tokens + entry_tokens > max_batch_tokens {
-                        entries.push_front(entry);
-                        break;
-                    }
+                // Low priority entries only fill the capacity left by the high priority ones
+                'queues: for entries in [&mut high_priority_entries, &mut low_priority_entries] {
+                    while let Some(entry) = entries.pop_front() {
+                        // Filter entries where the response receiver was dropped (== entries where the request
+                        // was dropped by the client)
+                        if entry.metadata.response_tx.is_closed() {
+                            metrics::increment_counter!("te_request_failure", "err" => "dropped");
+                            continue;
+                        }
 
-                    max_length = max(max_length, entry_tokens as u32);
+                        let entry_tokens = entry.encoding.input_ids.len();
 
-                    input_ids.extend(entry.encoding.input_ids);
-                    token_type_ids.extend(entry.encoding.token_type_ids);
-                    position_ids.extend(entry.encoding.position_ids);
+                        if current_tokens + entry_tokens > max_batch_tokens {
+                            entries.push_front(entry);
+                            break 'queues;
+                        }
 
-                    current_tokens += entry_tokens;
-                    metadata.push(entry.metadata);
-                    cu_seq_lengths.push(current_tokens as u32);
+                        current_tokens += entry_tokens;
+                        metadata.push(entry.metadata);
+                        encodings.push(entry.encoding);
 
-                    if Some(metadata.len()) == max_batch_requests {
-                        break;
+                        if Some(metadata.len()) == max_batch_requests {
+                            break 'queues;
+                        }
                     }
                 }
 
@@ -160,35 +209,194 @@ fn queue_blocking_task(
                 let next_batch = if metadata.is_empty() {
                     None
                 } else {
-                    Some((
-                        metadata,
-                        Batch {
-                            input_ids,
-                            token_type_ids,
-                            position_ids,
-                            cumulative_seq_lengths: cu_seq_lengths,
-                            max_length,
-                        },
-                    ))
+                    Some((metadata, encodings))
                 };
 
                 let _ = response_sender.send(next_batch);
 
                 metrics::histogram!("te_batch_next_size", batch_size as f64);
                 metrics::histogram!("te_batch_next_tokens", current_tokens as f64);
-                metrics::gauge!("te_queue_size", entries.len() as f64);
+                let len = high_priority_entries.len() + low_priority_entries.len();
+                size.store(len, Ordering::SeqCst);
+                metrics::gauge!("te_queue_size", len as f64);
             }
         }
     }
 }
 
-pub type NextBatch = (Vec<Metadata>, Batch);
+/// The encodings are only concatenated in a [`Batch`] by the backend task, which can then build
+/// smaller batches from them without keeping a copy of the whole batch
+pub type NextBatch = (Vec<Metadata>, Vec<Encoding>);
+
+/// Concatenate encodings in a batch
+pub fn to_batch(encodings: &[Encoding]) -> Batch {
+    let tokens = encodings.iter().map(|e| e.input_ids.len()).sum();
+    let mut input_ids = Vec::with_capacity(tokens);
+    let mut token_type_ids = Vec::with_capacity(tokens);
+    let mut position_ids = Vec::with_capacity(tokens);
+
+    let mut cu_seq_lengths = Vec::with_capacity(encodings.len() + 1);
+    cu_seq_lengths.push(0);
+    let mut max_length = 0;
+
+    for encoding in encodings {
+        input_ids.extend_from_slice(&encoding.input_ids);
+        token_type_ids.extend_from_slice(&encoding.token_type_ids);
+        position_ids.extend_from_slice(&encoding.position_ids);
+
+        max_length = max(max_length, encoding.input_ids.len() as u32);
+        cu_seq_lengths.push(input_ids.len() as u32);
+    }
+
+    Batch {
+        input_ids,
+        token_type_ids,
+        position_ids,
+        cumulative_seq_lengths: cu_seq_lengths,
+        max_length,
+    }
+}
 
 #[derive(Debug)]
 enum QueueCommand {
     Append(Box<Entry>, Span),
+    RemoveCancelled(Span),
     NextBatch {
         response_sender: oneshot::Sender<Option<NextBatch>>,
         span: Span,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(
+        input_ids: Vec<u32>,
+        priority: Priority,
+    ) -> (
+        Entry,
+        oneshot::Receiver<Result<InferResponse, BackendError>>,
+    ) {
+        let (response_tx, response_rx) = oneshot::channel();
+        let seq_len = input_ids.len();
+        let entry = Entry {
+            encoding: Encoding {
+                input_ids,
+                token_type_ids: vec![0; seq_len],
+                position_ids: (0..seq_len as u32).collect(),
+                truncated: false,
+            },
+            priority,
+            metadata: Metadata {
+                response_tx,
+                span: Span::none(),
+                tokenization: Duration::default(),
+                queue_time: Instant::now(),
+                prompt_tokens: seq_len,
+                truncated: false,
+            },
+        };
+        (entry, response_rx)
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_splits_on_max_batch_tokens() {
+        let queue = Queue::new(10, None, 16);
+
+        // Keep the receivers alive, otherwise the entries are considered dropped
+        let mut receivers = Vec::new();
+        for i in 0..3 {
+            let (entry, receiver) = entry(vec![i; 4], Priority::High);
+            queue.append(entry);
+            receivers.push(receiver);
+        }
+
+        let (metadata, encodings) = queue.next_batch().await.unwrap();
+        let batch = to_batch(&encodings);
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(batch.cumulative_seq_lengths, vec![0, 4, 8]);
+        assert_eq!(batch.input_ids, vec![0, 0, 0, 0, 1, 1, 1, 1]);
+
+        let (metadata, encodings) = queue.next_batch().await.unwrap();
+        let batch = to_batch(&encodings);
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(batch.cumulative_seq_lengths, vec![0, 4]);
+        assert_eq!(batch.input_ids, vec![2, 2, 2, 2]);
+
+        assert!(queue.next_batch().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_max_batch_requests() {
+        let queue = Queue::new(100, Some(2), 16);
+
+        let mut receivers = Vec::new();
+        for i in 0..3 {
+            let (entry, receiver) = entry(vec![i], Priority::High);
+            queue.append(entry);
+            receivers.push(receiver);
+        }
+
+        let (metadata, _) = queue.next_batch().await.unwrap();
+        assert_eq!(metadata.len(), 2);
+        let (metadata, encodings) = queue.next_batch().await.unwrap();
+        let batch = to_batch(&encodings);
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(batch.input_ids, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_skips_dropped_entries() {
+        let queue = Queue::new(100, None, 16);
+
+        let (entry_dropped, receiver) = entry(vec![0], Priority::High);
+        drop(receiver);
+        let (entry_kept, _receiver) = entry(vec![1], Priority::High);
+        queue.append(entry_dropped);
+        queue.append(entry_kept);
+
+        let (metadata, encodings) = queue.next_batch().await.unwrap();
+        let batch = to_batch(&encodings);
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(batch.input_ids, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_high_priority_first() {
+        let queue = Queue::new(100, Some(2), 16);
+
+        let mut receivers = Vec::new();
+        for (i, priority) in [Priority::Low, Priority::High, Priority::Low, Priority::High]
+            .into_iter()
+            .enumerate()
+        {
+            let (entry, receiver) = entry(vec![i as u32], priority);
+            queue.append(entry);
+            receivers.push(receiver);
+        }
+
+        let (_, encodings) = queue.next_batch().await.unwrap();
+        let batch = to_batch(&encodings);
+        assert_eq!(batch.input_ids, vec![1, 3]);
+        let (_, encodings) = queue.next_batch().await.unwrap();
+        let batch = to_batch(&encodings);
+        assert_eq!(batch.input_ids, vec![0, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_low_priority_fills_batch() {
+        let queue = Queue::new(100, None, 16);
+
+        let mut receivers = Vec::new();
+        for (i, priority) in [Priority::Low, Priority::High].into_iter().enumerate() {
+            let (entry, receiver) = entry(vec![i as u32], priority);
+            queue.append(entry);
+            receivers.push(receiver);
+        }
+
+        let (_, encodings) = queue.next_batch().await.unwrap();
+        let batch = to_batch(&encodings);
+        assert_eq!(batch.input_ids, vec![1, 0]);
+    }
+}
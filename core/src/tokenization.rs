@@ -1,15 +1,33 @@
 /// Payload tokenization logic
 use crate::TextEmbeddingsError;
+#[cfg(feature = "clap")]
+use clap::ValueEnum;
+use std::cmp::min;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokenizers::decoders::metaspace::PrependScheme;
+use tokenizers::normalizers::BertNormalizer;
 use tokenizers::tokenizer::Tokenizer;
-use tokenizers::{EncodeInput, TruncationDirection, TruncationParams, TruncationStrategy};
-use tokio::sync::{mpsc, oneshot};
+use tokenizers::{
+    EncodeInput, NormalizedString, Normalizer, PostProcessor, PreTokenizerWrapper,
+    TruncationDirection, TruncationParams,
+};
+
+pub use tokenizers::TruncationStrategy;
+use tokio::sync::oneshot;
 use tracing::{instrument, Span};
 
+/// A window of tokens with its span of tokens in the inputs
+pub type Ngram = (Encoding, (usize, usize));
+
 /// Validation
 #[derive(Debug, Clone)]
 pub struct Tokenization {
-    /// Channel to communicate with the background tokenization task
-    sender: mpsc::UnboundedSender<TokenizerRequest>,
+    /// Channel shared by all the tokenization workers
+    sender: flume::Sender<TokenizerRequest>,
+    /// Used for the vocabulary lookups that do not need a worker
+    tokenizer: Arc<Tokenizer>,
 }
 
 impl Tokenization {
@@ -18,18 +36,23 @@ impl Tokenization {
         tokenizer: Tokenizer,
         max_input_length: usize,
         position_offset: usize,
+        unicode_normalization: UnicodeNormalization,
+        lowercase: Option<Lowercase>,
+        input_template: Option<String>,
     ) -> Self {
         tracing::info!("Starting {workers} tokenization workers");
 
-        // Create channel
-        let (sender, mut round_robin_receiver) = mpsc::unbounded_channel();
-        let mut senders = Vec::with_capacity(workers);
+        // Create a channel shared by all workers.
+        // An idle worker picks up the next request so a slow input only blocks its own worker.
+        let (sender, receiver) = flume::unbounded();
+
+        let tokenizer = Arc::new(tokenizer);
 
         // Create workers
         for _ in 0..workers {
-            let tokenizer_clone = tokenizer.clone();
-            let (tokenizer_sender, tokenizer_receiver) = mpsc::unbounded_channel();
-            senders.push(tokenizer_sender);
+            let tokenizer_clone = Tokenizer::clone(&tokenizer);
+            let input_template_clone = input_template.clone();
+            let receiver_clone = receiver.clone();
 
             // Spawn worker
             tokio::task::spawn_blocking(move || {
@@ -37,32 +60,30 @@ impl Tokenization {
                     tokenizer_clone,
                     max_input_length,
                     position_offset,
-                    tokenizer_receiver,
+                    unicode_normalization,
+                    lowercase,
+                    input_template_clone,
+                    receiver_clone,
                 )
             });
         }
 
-        // Create tokenization round robin task
-        tokio::spawn(async move {
-            // Loop over requests
-            loop {
-                for sender in &senders {
-                    match round_robin_receiver.recv().await {
-                        None => return,
-                        Some(request) => sender.send(request).unwrap(),
-                    };
-                }
-            }
-        });
+        Self { sender, tokenizer }
+    }
 
-        Self { sender }
+    /// Token of the vocabulary with this id
+    pub fn id_to_token(&self, id: u32) -> Option<String> {
+        self.tokenizer.id_to_token(id)
     }
 
     #[instrument(skip_all)]
+    /// `truncation_strategy` selects which sequence of a pair is truncated.
     pub async fn encode(
         &self,
         inputs: EncodingInput,
         truncate: bool,
+        truncation_strategy: TruncationStrategy,
+        add_special_tokens: bool,
     ) -> Result<Encoding, TextEmbeddingsError> {
         // Check if inputs is empty
         if inputs.is_empty() {
@@ -75,8 +96,137 @@ impl Tokenization {
         let (response_sender, response_receiver) = oneshot::channel();
         // Send request to the background validation task
         // Unwrap is safe here
+        metrics::increment_gauge!("te_tokenization_queue_size", 1.0);
+        self.sender
+            .send(TokenizerRequest::Encode(
+                inputs,
+                truncate,
+                truncation_strategy,
+                add_special_tokens,
+                response_sender,
+                Span::current(),
+            ))
+            .expect("Tokenization background task dropped the receiver. This is a bug.");
+
+        // Await on response channel
+        // Unwrap is safe here
+        response_receiver.await.expect("Tokenization background task dropped the sender without sending a response. This is a bug.")
+    }
+
+    /// Tokenize the inputs of a client batch at once. The tokenizer encodes the inputs in
+    /// parallel, which is much faster than sending them to the workers one by one for large
+    /// batches of short inputs. Returns the encoding, or the error, of each input.
+    #[instrument(skip_all)]
+    pub async fn encode_batch(
+        &self,
+        inputs: Vec<EncodingInput>,
+        truncate: bool,
+        truncation_strategy: TruncationStrategy,
+        add_special_tokens: bool,
+    ) -> Vec<Result<Encoding, TextEmbeddingsError>> {
+        // Check if inputs are empty, only send the other ones to the tokenizer
+        let empty: Vec<bool> = inputs.iter().map(EncodingInput::is_empty).collect();
+        let inputs: Vec<EncodingInput> = inputs.into_iter().filter(|i| !i.is_empty()).collect();
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        // Unwrap is safe here
+        metrics::increment_gauge!("te_tokenization_queue_size", 1.0);
+        self.sender
+            .send(TokenizerRequest::EncodeBatch(
+                inputs,
+                truncate,
+                truncation_strategy,
+                add_special_tokens,
+                response_sender,
+                Span::current(),
+            ))
+            .expect("Tokenization background task dropped the receiver. This is a bug.");
+
+        // Await on response channel
+        // Unwrap is safe here
+        let results = response_receiver.await.expect("Tokenization background task dropped the sender without sending a response. This is a bug.");
+        let mut results = results.into_iter();
+
+        empty
+            .into_iter()
+            .map(|empty| match empty {
+                true => Err(TextEmbeddingsError::Validation(
+                    "`inputs` cannot be empty".to_string(),
+                )),
+                false => results.next().expect(
+                    "Tokenization background task returned too few results. This is a bug.",
+                ),
+            })
+            .collect()
+    }
+
+    /// Split the inputs in overlapping windows of at most `max_input_length` tokens.
+    /// Consecutive windows share `stride` tokens.
+    #[instrument(skip_all)]
+    pub async fn encode_windows(
+        &self,
+        inputs: EncodingInput,
+        stride: usize,
+        add_special_tokens: bool,
+    ) -> Result<Vec<Encoding>, TextEmbeddingsError> {
+        // Check if inputs is empty
+        if inputs.is_empty() {
+            return Err(TextEmbeddingsError::Validation(
+                "`inputs` cannot be empty".to_string(),
+            ));
+        }
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        // Unwrap is safe here
+        metrics::increment_gauge!("te_tokenization_queue_size", 1.0);
+        self.sender
+            .send(TokenizerRequest::EncodeWindows(
+                inputs,
+                stride,
+                add_special_tokens,
+                response_sender,
+                Span::current(),
+            ))
+            .expect("Tokenization background task dropped the receiver. This is a bug.");
+
+        // Await on response channel
+        // Unwrap is safe here
+        response_receiver.await.expect("Tokenization background task dropped the sender without sending a response. This is a bug.")
+    }
+
+    /// Split the inputs in windows of `ngram_size` tokens, each one starting one token after the
+    /// previous one. Returns the windows with their span of tokens in the inputs.
+    #[instrument(skip_all)]
+    pub async fn encode_ngrams(
+        &self,
+        inputs: EncodingInput,
+        ngram_size: usize,
+        add_special_tokens: bool,
+    ) -> Result<Vec<Ngram>, TextEmbeddingsError> {
+        // Check if inputs is empty
+        if inputs.is_empty() {
+            return Err(TextEmbeddingsError::Validation(
+                "`inputs` cannot be empty".to_string(),
+            ));
+        }
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        // Unwrap is safe here
+        metrics::increment_gauge!("te_tokenization_queue_size", 1.0);
         self.sender
-            .send((inputs, truncate, response_sender, Span::current()))
+            .send(TokenizerRequest::EncodeNgrams(
+                inputs,
+                ngram_size,
+                add_special_tokens,
+                response_sender,
+                Span::current(),
+            ))
             .expect("Tokenization background task dropped the receiver. This is a bug.");
 
         // Await on response channel
@@ -90,23 +240,127 @@ fn tokenizer_worker(
     mut tokenizer: Tokenizer,
     max_input_length: usize,
     position_offset: usize,
-    mut receiver: mpsc::UnboundedReceiver<TokenizerRequest>,
+    unicode_normalization: UnicodeNormalization,
+    lowercase: Option<Lowercase>,
+    input_template: Option<String>,
+    receiver: flume::Receiver<TokenizerRequest>,
 ) {
     // Loop over requests
-    while let Some((inputs, truncate, response_tx, parent_span)) = receiver.blocking_recv() {
-        parent_span.in_scope(|| {
-            if !response_tx.is_closed() {
-                // It's possible that the user dropped its request resulting in a send error.
-                // We just discard the error
-                let _ = response_tx.send(encode_input(
-                    inputs,
-                    truncate,
-                    max_input_length,
-                    position_offset,
-                    &mut tokenizer,
-                ));
+    while let Ok(request) = receiver.recv() {
+        metrics::decrement_gauge!("te_tokenization_queue_size", 1.0);
+        match request {
+            TokenizerRequest::Encode(
+                inputs,
+                truncate,
+                truncation_strategy,
+                add_special_tokens,
+                response_tx,
+                parent_span,
+            ) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(encode_input(
+                            inputs
+                                .template(input_template.as_deref())
+                                .normalize(unicode_normalization)
+                                .lowercase(lowercase),
+                            truncate,
+                            truncation_strategy,
+                            add_special_tokens,
+                            max_input_length,
+                            position_offset,
+                            &mut tokenizer,
+                        ));
+                    }
+                })
             }
-        })
+            TokenizerRequest::EncodeBatch(
+                inputs,
+                truncate,
+                truncation_strategy,
+                add_special_tokens,
+                response_tx,
+                parent_span,
+            ) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        let inputs = inputs
+                            .into_iter()
+                            .map(|inputs| {
+                                inputs
+                                    .template(input_template.as_deref())
+                                    .normalize(unicode_normalization)
+                                    .lowercase(lowercase)
+                            })
+                            .collect();
+                        let results = encode_batch(
+                            inputs,
+                            truncate,
+                            truncation_strategy,
+                            add_special_tokens,
+                            max_input_length,
+                            position_offset,
+                            &mut tokenizer,
+                        );
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(results);
+                    }
+                })
+            }
+            TokenizerRequest::EncodeWindows(
+                inputs,
+                stride,
+                add_special_tokens,
+                response_tx,
+                parent_span,
+            ) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(encode_windows(
+                            inputs
+                                .template(input_template.as_deref())
+                                .normalize(unicode_normalization)
+                                .lowercase(lowercase),
+                            stride,
+                            add_special_tokens,
+                            max_input_length,
+                            position_offset,
+                            &mut tokenizer,
+                        ));
+                    }
+                })
+            }
+            TokenizerRequest::EncodeNgrams(
+                inputs,
+                ngram_size,
+                add_special_tokens,
+                response_tx,
+                parent_span,
+            ) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(encode_ngrams(
+                            inputs
+                                .template(input_template.as_deref())
+                                .normalize(unicode_normalization)
+                                .lowercase(lowercase),
+                            ngram_size,
+                            add_special_tokens,
+                            max_input_length,
+                            position_offset,
+                            &mut tokenizer,
+                        ));
+                    }
+                })
+            }
+        }
     }
 }
 
@@ -114,17 +368,13 @@ fn tokenizer_worker(
 fn encode_input(
     inputs: EncodingInput,
     truncate: bool,
+    truncation_strategy: TruncationStrategy,
+    add_special_tokens: bool,
     max_input_length: usize,
     position_offset: usize,
     tokenizer: &mut Tokenizer,
 ) -> Result<Encoding, TextEmbeddingsError> {
-    // Default truncation params
-    let truncate_params = truncate.then_some(TruncationParams {
-        direction: TruncationDirection::Right,
-        max_length: max_input_length,
-        strategy: TruncationStrategy::LongestFirst,
-        stride: 0,
-    });
+    let truncate_params = truncation_params(truncate, truncation_strategy, max_input_length);
 
     let inputs: EncodeInput = match inputs {
         EncodingInput::Single(s) => s.into(),
@@ -133,23 +383,225 @@ fn encode_input(
 
     let encoding = tokenizer
         .with_truncation(truncate_params)?
-        .encode(inputs, true)?;
+        .encode(inputs, add_special_tokens)?;
+
+    check_encoding(&encoding, max_input_length, position_offset)
+}
+
+/// Tokenize a batch of inputs with a single call to the tokenizer
+fn encode_batch(
+    inputs: Vec<EncodingInput>,
+    truncate: bool,
+    truncation_strategy: TruncationStrategy,
+    add_special_tokens: bool,
+    max_input_length: usize,
+    position_offset: usize,
+    tokenizer: &mut Tokenizer,
+) -> Vec<Result<Encoding, TextEmbeddingsError>> {
+    let truncate_params = truncation_params(truncate, truncation_strategy, max_input_length);
+
+    let encodings = tokenizer
+        .with_truncation(truncate_params)
+        .and_then(|tokenizer| {
+            tokenizer.encode_batch(
+                inputs.iter().map(EncodingInput::as_encode_input).collect(),
+                add_special_tokens,
+            )
+        });
+    match encodings {
+        Ok(encodings) => encodings
+            .iter()
+            .map(|encoding| check_encoding(encoding, max_input_length, position_offset))
+            .collect(),
+        // A single input failing to tokenize fails the whole batch: tokenize the inputs one by
+        // one to get the error of each input
+        Err(_) => inputs
+            .into_iter()
+            .map(|inputs| {
+                encode_input(
+                    inputs,
+                    truncate,
+                    truncation_strategy,
+                    add_special_tokens,
+                    max_input_length,
+                    position_offset,
+                    tokenizer,
+                )
+            })
+            .collect(),
+    }
+}
+
+fn truncation_params(
+    truncate: bool,
+    truncation_strategy: TruncationStrategy,
+    max_input_length: usize,
+) -> Option<TruncationParams> {
+    truncate.then_some(TruncationParams {
+        direction: TruncationDirection::Right,
+        max_length: max_input_length,
+        strategy: truncation_strategy,
+        stride: 0,
+    })
+}
+
+/// Check the length of an encoding
+fn check_encoding(
+    encoding: &tokenizers::Encoding,
+    max_input_length: usize,
+    position_offset: usize,
+) -> Result<Encoding, TextEmbeddingsError> {
     let seq_len = encoding.len();
 
     if seq_len > max_input_length {
+        return Err(TextEmbeddingsError::InputTooLong {
+            tokens: seq_len,
+            max_input_length,
+        });
+    }
+
+    metrics::histogram!("te_request_input_length", seq_len as f64);
+
+    Ok(Encoding::from_tokenizer(encoding, position_offset))
+}
+
+/// Tokenize the input in overlapping windows instead of discarding the overflowing tokens
+fn encode_windows(
+    inputs: EncodingInput,
+    stride: usize,
+    add_special_tokens: bool,
+    max_input_length: usize,
+    position_offset: usize,
+    tokenizer: &mut Tokenizer,
+) -> Result<Vec<Encoding>, TextEmbeddingsError> {
+    if stride >= max_input_length {
         return Err(TextEmbeddingsError::Validation(format!(
-            "`inputs` must have less than {max_input_length} tokens. Given: {seq_len}"
+            "`stride` must be smaller than {max_input_length}. Given: {stride}"
         )));
     }
 
-    metrics::histogram!("te_request_input_length", seq_len as f64);
+    let windows: Vec<Encoding> = overflowing_windows(
+        inputs,
+        max_input_length,
+        stride,
+        add_special_tokens,
+        tokenizer,
+    )?
+    .iter()
+    .map(|window| Encoding::from_tokenizer(window, position_offset))
+    .collect();
 
-    Ok(Encoding {
-        input_ids: encoding.get_ids().to_vec(),
-        token_type_ids: encoding.get_type_ids().to_vec(),
-        position_ids: (position_offset as u32..(seq_len + position_offset) as u32)
-            .collect::<Vec<_>>(),
-    })
+    Ok(windows)
+}
+
+/// Tokenize the input in windows of `ngram_size` tokens sliding by one token
+fn encode_ngrams(
+    inputs: EncodingInput,
+    ngram_size: usize,
+    add_special_tokens: bool,
+    max_input_length: usize,
+    position_offset: usize,
+    tokenizer: &mut Tokenizer,
+) -> Result<Vec<Ngram>, TextEmbeddingsError> {
+    let added_tokens = match add_special_tokens {
+        true => tokenizer
+            .get_post_processor()
+            .map_or(0, |post_processor| post_processor.added_tokens(false)),
+        false => 0,
+    };
+    if ngram_size == 0 || ngram_size + added_tokens > max_input_length {
+        return Err(TextEmbeddingsError::Validation(format!(
+            "`ngram_size` must be between 1 and {}. Given: {ngram_size}",
+            max_input_length.saturating_sub(added_tokens)
+        )));
+    }
+
+    let windows = overflowing_windows(
+        inputs,
+        ngram_size + added_tokens,
+        ngram_size - 1,
+        add_special_tokens,
+        tokenizer,
+    )?;
+
+    // Consecutive windows start one token apart
+    let ngrams: Vec<Ngram> = windows
+        .iter()
+        .enumerate()
+        .map(|(start, window)| {
+            let len = window
+                .get_special_tokens_mask()
+                .iter()
+                .filter(|&&special| special == 0)
+                .count();
+            (
+                Encoding::from_tokenizer(window, position_offset),
+                (start, start + len),
+            )
+        })
+        .collect();
+
+    // Every token of the inputs is embedded `ngram_size` times: limit the length of the inputs
+    let seq_len = ngrams.last().map_or(0, |(_, (_, end))| *end);
+    if seq_len > max_input_length {
+        return Err(TextEmbeddingsError::InputTooLong {
+            tokens: seq_len,
+            max_input_length,
+        });
+    }
+
+    Ok(ngrams)
+}
+
+/// Tokenize the input and split it in windows of at most `max_length` tokens where consecutive
+/// windows share `stride` tokens
+fn overflowing_windows(
+    inputs: EncodingInput,
+    max_length: usize,
+    stride: usize,
+    add_special_tokens: bool,
+    tokenizer: &mut Tokenizer,
+) -> Result<Vec<tokenizers::Encoding>, TextEmbeddingsError> {
+    // The tokenizer panics if a truncated sequence is not longer than the stride. Each sequence
+    // of a pair is truncated to at least half of the window
+    let pair = matches!(inputs, EncodingInput::Dual(..));
+    let added_tokens = match add_special_tokens {
+        true => tokenizer
+            .get_post_processor()
+            .map_or(0, |post_processor| post_processor.added_tokens(pair)),
+        false => 0,
+    };
+    let sequence_length = max_length.saturating_sub(added_tokens) / if pair { 2 } else { 1 };
+    if stride >= sequence_length {
+        return Err(TextEmbeddingsError::Validation(format!(
+            "windows of {max_length} tokens cannot overlap by {stride} tokens for this input"
+        )));
+    }
+
+    let truncate_params = Some(TruncationParams {
+        direction: TruncationDirection::Right,
+        max_length,
+        strategy: TruncationStrategy::LongestFirst,
+        stride,
+    });
+
+    let inputs: EncodeInput = match inputs {
+        EncodingInput::Single(s) => s.into(),
+        EncodingInput::Dual(s1, s2) => (s1, s2).into(),
+    };
+
+    let mut encoding = tokenizer
+        .with_truncation(truncate_params)?
+        .encode(inputs, add_special_tokens)?;
+    let overflowing = encoding.take_overflowing();
+
+    let windows: Vec<tokenizers::Encoding> = std::iter::once(encoding).chain(overflowing).collect();
+    for window in &windows {
+        metrics::histogram!("te_request_input_length", window.len() as f64);
+    }
+    metrics::histogram!("te_request_windows", windows.len() as f64);
+
+    Ok(windows)
 }
 
 #[derive(Debug)]
@@ -157,6 +609,21 @@ pub struct Encoding {
     pub input_ids: Vec<u32>,
     pub token_type_ids: Vec<u32>,
     pub position_ids: Vec<u32>,
+    /// Whether tokens were dropped by the truncation
+    pub truncated: bool,
+}
+
+impl Encoding {
+    fn from_tokenizer(encoding: &tokenizers::Encoding, position_offset: usize) -> Self {
+        let seq_len = encoding.len();
+        Self {
+            input_ids: encoding.get_ids().to_vec(),
+            token_type_ids: encoding.get_type_ids().to_vec(),
+            position_ids: (position_offset as u32..(seq_len + position_offset) as u32)
+                .collect::<Vec<_>>(),
+            truncated: !encoding.get_overflowing().is_empty(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -172,6 +639,53 @@ impl EncodingInput {
             EncodingInput::Dual(s1, s2) => s1.is_empty() && s2.is_empty(),
         }
     }
+
+    fn as_encode_input(&self) -> EncodeInput<'_> {
+        match self {
+            EncodingInput::Single(s) => s.as_str().into(),
+            EncodingInput::Dual(s1, s2) => (s1.as_str(), s2.as_str()).into(),
+        }
+    }
+}
+
+impl EncodingInput {
+    /// Substitute the `{query}` placeholder of the template with the input.
+    /// For a pair of sequences, only the first sequence is templated.
+    fn template(self, input_template: Option<&str>) -> Self {
+        match input_template {
+            None => self,
+            Some(template) => match self {
+                EncodingInput::Single(s) => EncodingInput::Single(template.replace("{query}", &s)),
+                EncodingInput::Dual(s1, s2) => {
+                    EncodingInput::Dual(template.replace("{query}", &s1), s2)
+                }
+            },
+        }
+    }
+
+    fn normalize(self, unicode_normalization: UnicodeNormalization) -> Self {
+        match self {
+            EncodingInput::Single(s) => EncodingInput::Single(unicode_normalization.apply(s)),
+            EncodingInput::Dual(s1, s2) => EncodingInput::Dual(
+                unicode_normalization.apply(s1),
+                unicode_normalization.apply(s2),
+            ),
+        }
+    }
+}
+
+impl EncodingInput {
+    fn lowercase(self, lowercase: Option<Lowercase>) -> Self {
+        let Some(lowercase) = lowercase else {
+            return self;
+        };
+        match self {
+            EncodingInput::Single(s) => EncodingInput::Single(lowercase.apply(s)),
+            EncodingInput::Dual(s1, s2) => {
+                EncodingInput::Dual(lowercase.apply(s1), lowercase.apply(s2))
+            }
+        }
+    }
 }
 
 impl From<String> for EncodingInput {
@@ -180,9 +694,561 @@ impl From<String> for EncodingInput {
     }
 }
 
-type TokenizerRequest = (
-    EncodingInput,
-    bool,
-    oneshot::Sender<Result<Encoding, TextEmbeddingsError>>,
-    Span,
-);
+/// Unicode normalization applied to the inputs before they reach the tokenizer
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "clap", derive(ValueEnum))]
+pub enum UnicodeNormalization {
+    Nfc,
+    Nfkc,
+    None,
+}
+
+impl UnicodeNormalization {
+    fn apply(self, input: String) -> String {
+        match self {
+            UnicodeNormalization::Nfc => NormalizedString::from(input).nfc().get().to_string(),
+            UnicodeNormalization::Nfkc => NormalizedString::from(input).nfkc().get().to_string(),
+            UnicodeNormalization::None => input,
+        }
+    }
+}
+
+/// Whether to lowercase the inputs before they reach the tokenizer.
+/// `Auto` lowercases if `tokenizer_config.json` sets `do_lower_case` but the tokenizer does not.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "clap", derive(ValueEnum))]
+pub enum DoLowerCase {
+    Auto,
+    True,
+    False,
+}
+
+/// Lowercasing of the inputs, as done by the normalizer of uncased BERT tokenizers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lowercase {
+    /// Also remove the accents
+    pub strip_accents: bool,
+}
+
+impl Lowercase {
+    /// Lowercasing to apply to the inputs of `tokenizer` according to `do_lower_case` and the
+    /// `tokenizer_config.json` found in `model_root`.
+    /// Accents are stripped unless `tokenizer_config.json` sets `strip_accents` to false.
+    pub fn new(
+        do_lower_case: DoLowerCase,
+        tokenizer: &Tokenizer,
+        model_root: &Path,
+    ) -> Option<Self> {
+        let config = std::fs::read_to_string(model_root.join("tokenizer_config.json"))
+            .ok()
+            .and_then(|config| serde_json::from_str::<serde_json::Value>(&config).ok());
+        let config_flag = |key: &str| config.as_ref()?.get(key)?.as_bool();
+
+        let lowercase = match do_lower_case {
+            DoLowerCase::True => true,
+            DoLowerCase::False => false,
+            DoLowerCase::Auto => {
+                config_flag("do_lower_case") == Some(true) && !normalizer_lowercases(tokenizer)
+            }
+        };
+        lowercase.then(|| Self {
+            strip_accents: config_flag("strip_accents").unwrap_or(true),
+        })
+    }
+
+    fn apply(self, input: String) -> String {
+        let normalizer = BertNormalizer::new(false, false, Some(self.strip_accents), true);
+        let mut normalized = NormalizedString::from(input);
+        match normalizer.normalize(&mut normalized) {
+            Ok(()) => normalized.get().to_string(),
+            Err(_) => normalized.get().to_lowercase(),
+        }
+    }
+}
+
+/// Whether the normalizer of `tokenizer` already lowercases the inputs
+fn normalizer_lowercases(tokenizer: &Tokenizer) -> bool {
+    fn lowercases(normalizer: &serde_json::Value) -> bool {
+        match normalizer.get("type").and_then(|t| t.as_str()) {
+            Some("Lowercase") => true,
+            Some("BertNormalizer") => normalizer.get("lowercase") == Some(&true.into()),
+            Some("Sequence") => normalizer
+                .get("normalizers")
+                .and_then(|n| n.as_array())
+                .is_some_and(|normalizers| normalizers.iter().any(lowercases)),
+            _ => false,
+        }
+    }
+
+    tokenizer
+        .get_normalizer()
+        .and_then(|normalizer| serde_json::to_value(normalizer).ok())
+        .is_some_and(|normalizer| lowercases(&normalizer))
+}
+
+/// Prepend scheme of the Metaspace pre-tokenizers of SentencePiece-based tokenizers
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "clap", derive(ValueEnum))]
+pub enum MetaspacePrepend {
+    First,
+    Always,
+    Never,
+}
+
+impl From<MetaspacePrepend> for PrependScheme {
+    fn from(value: MetaspacePrepend) -> Self {
+        match value {
+            MetaspacePrepend::First => PrependScheme::First,
+            MetaspacePrepend::Always => PrependScheme::Always,
+            MetaspacePrepend::Never => PrependScheme::Never,
+        }
+    }
+}
+
+/// Parse a serialized tokenizer, e.g. the content of a `tokenizer.json` file given inline
+pub fn tokenizer_from_json(json: &str) -> Result<Tokenizer, TextEmbeddingsError> {
+    Ok(Tokenizer::from_str(json)?)
+}
+
+/// Set the prepend scheme of the Metaspace pre-tokenizers of `tokenizer`.
+/// See https://github.com/huggingface/tokenizers/pull/1357
+///
+/// Returns true if the tokenizer has a Metaspace pre-tokenizer.
+pub fn set_metaspace_prepend(tokenizer: &mut Tokenizer, prepend: MetaspacePrepend) -> bool {
+    let prepend_scheme = PrependScheme::from(prepend);
+    match tokenizer.get_pre_tokenizer() {
+        Some(PreTokenizerWrapper::Metaspace(m)) => {
+            // We are forced to clone since `Tokenizer` does not have a `get_mut` for `pre_tokenizer`
+            let mut m = m.clone();
+            m.set_prepend_scheme(prepend_scheme);
+            tokenizer.with_pre_tokenizer(PreTokenizerWrapper::Metaspace(m));
+            true
+        }
+        Some(PreTokenizerWrapper::Sequence(s)) => {
+            // We are forced to clone since `Tokenizer` does not have a `get_mut` for `pre_tokenizer`
+            let mut s = s.clone();
+            let mut applied = false;
+            for pre_tokenizer in s.get_pre_tokenizers_mut() {
+                if let PreTokenizerWrapper::Metaspace(m) = pre_tokenizer {
+                    m.set_prepend_scheme(prepend_scheme);
+                    applied = true;
+                }
+            }
+            tokenizer.with_pre_tokenizer(PreTokenizerWrapper::Sequence(s));
+            applied
+        }
+        _ => false,
+    }
+}
+
+/// Maximum input length of the model, reconciled with the `model_max_length` of the
+/// `tokenizer_config.json` found in `model_root`.
+///
+/// `config.json` and `tokenizer_config.json` sometimes disagree. The smaller value is used so that
+/// inputs are always truncated before they exceed the position embeddings of the model.
+pub fn reconcile_max_input_length(config_max_input_length: usize, model_root: &Path) -> usize {
+    match tokenizer_model_max_length(model_root) {
+        Some(model_max_length) if model_max_length != config_max_input_length => {
+            let max_input_length = min(config_max_input_length, model_max_length);
+            tracing::warn!(
+                "The model maximum input length ({config_max_input_length}) does not match the tokenizer `model_max_length` ({model_max_length}). Using {max_input_length}"
+            );
+            max_input_length
+        }
+        _ => config_max_input_length,
+    }
+}
+
+/// `model_max_length` of `tokenizer_config.json`, if set
+fn tokenizer_model_max_length(model_root: &Path) -> Option<usize> {
+    let config = std::fs::read_to_string(model_root.join("tokenizer_config.json")).ok()?;
+    let config: serde_json::Value = serde_json::from_str(&config).ok()?;
+    let model_max_length = config.get("model_max_length")?.as_f64()?;
+    // `transformers` uses a very large integer when the tokenizer does not set a maximum length
+    if !(1.0..1e12).contains(&model_max_length) {
+        return None;
+    }
+    Some(model_max_length as usize)
+}
+
+/// A special token of the tokenizer
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpecialToken {
+    pub content: String,
+    pub id: u32,
+}
+
+/// Special tokens of the tokenizer, by role
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpecialTokens {
+    pub cls: Option<SpecialToken>,
+    pub sep: Option<SpecialToken>,
+    pub pad: Option<SpecialToken>,
+    pub unk: Option<SpecialToken>,
+    pub bos: Option<SpecialToken>,
+    pub eos: Option<SpecialToken>,
+    /// Language tokens of multilingual tokenizers, e.g. `eng_Latn`, from the
+    /// `additional_special_tokens`
+    pub languages: Vec<SpecialToken>,
+}
+
+impl SpecialTokens {
+    /// Roles declared in the `tokenizer_config.json` or `special_tokens_map.json` found in
+    /// `model_root`, with their id in `tokenizer`. Tokens missing from the vocabulary are ignored.
+    pub fn new(tokenizer: &Tokenizer, model_root: &Path) -> Self {
+        let configs: Vec<serde_json::Value> = ["tokenizer_config.json", "special_tokens_map.json"]
+            .iter()
+            .filter_map(|file| std::fs::read_to_string(model_root.join(file)).ok())
+            .filter_map(|config| serde_json::from_str(&config).ok())
+            .collect();
+
+        // Either the token itself or a serialized `AddedToken`
+        let special_token = |value: &serde_json::Value| {
+            let content = value.as_str().or_else(|| value.get("content")?.as_str())?;
+            Some(SpecialToken {
+                content: content.to_string(),
+                id: tokenizer.token_to_id(content)?,
+            })
+        };
+        let token = |role: &str| {
+            configs
+                .iter()
+                .find_map(|config| special_token(config.get(role)?))
+        };
+        let languages = configs
+            .iter()
+            .find_map(|config| config.get("additional_special_tokens")?.as_array())
+            .map(|tokens| tokens.iter().filter_map(special_token).collect())
+            .unwrap_or_default();
+
+        Self {
+            cls: token("cls_token"),
+            sep: token("sep_token"),
+            pad: token("pad_token"),
+            unk: token("unk_token"),
+            bos: token("bos_token"),
+            eos: token("eos_token"),
+            languages,
+        }
+    }
+}
+
+enum TokenizerRequest {
+    Encode(
+        EncodingInput,
+        bool,
+        TruncationStrategy,
+        bool,
+        oneshot::Sender<Result<Encoding, TextEmbeddingsError>>,
+        Span,
+    ),
+    EncodeBatch(
+        Vec<EncodingInput>,
+        bool,
+        TruncationStrategy,
+        bool,
+        oneshot::Sender<Vec<Result<Encoding, TextEmbeddingsError>>>,
+        Span,
+    ),
+    EncodeWindows(
+        EncodingInput,
+        usize,
+        bool,
+        oneshot::Sender<Result<Vec<Encoding>, TextEmbeddingsError>>,
+        Span,
+    ),
+    EncodeNgrams(
+        EncodingInput,
+        usize,
+        bool,
+        oneshot::Sender<Result<Vec<Ngram>, TextEmbeddingsError>>,
+        Span,
+    ),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::Whitespace;
+
+    fn tokenization() -> Tokenization {
+        // `[UNK]` is not in the vocabulary: unknown inputs fail to tokenize
+        let vocab = HashMap::from([("hello".to_string(), 0), ("world".to_string(), 1)]);
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let tokenizer = Tokenizer::new(model);
+
+        Tokenization::new(1, tokenizer, 8, 0, UnicodeNormalization::None, None, None)
+    }
+
+    /// Whitespace `WordLevel` tokenizer knowing `hello` and `world`
+    fn word_level_tokenizer() -> Tokenizer {
+        let vocab = HashMap::from([
+            ("hello".to_string(), 0),
+            ("world".to_string(), 1),
+            ("[UNK]".to_string(), 2),
+        ]);
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Whitespace);
+        tokenizer
+    }
+
+    #[tokio::test]
+    async fn test_encode_batch() {
+        let tokenization = tokenization();
+
+        // Invalid inputs only fail on their own
+        let results = tokenization
+            .encode_batch(
+                vec![
+                    "hello".to_string().into(),
+                    "".to_string().into(),
+                    "\u{fffd}\u{0}".to_string().into(),
+                    "world".to_string().into(),
+                ],
+                true,
+                TruncationStrategy::LongestFirst,
+                true,
+            )
+            .await;
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap().input_ids, vec![0]);
+        assert!(matches!(
+            results[1],
+            Err(TextEmbeddingsError::Validation(_))
+        ));
+        assert!(matches!(results[2], Err(TextEmbeddingsError::Tokenizer(_))));
+        assert_eq!(results[3].as_ref().unwrap().input_ids, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_tokenizer_from_json() {
+        let tokenizer = word_level_tokenizer();
+        let tokenization =
+            Tokenization::new(1, tokenizer, 8, 0, UnicodeNormalization::None, None, None);
+
+        let encoding = tokenization
+            .encode(
+                "hello world".to_string().into(),
+                true,
+                TruncationStrategy::LongestFirst,
+                true,
+            )
+            .await
+            .unwrap();
+        assert_eq!(encoding.input_ids, vec![0, 1]);
+
+        assert!(tokenizer_from_json("{").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pair_truncation_strategy() {
+        let tokenizer = word_level_tokenizer();
+        let tokenization =
+            Tokenization::new(1, tokenizer, 4, 0, UnicodeNormalization::None, None, None);
+
+        for (strategy, expected) in [
+            (TruncationStrategy::LongestFirst, vec![0, 0, 1, 1]),
+            (TruncationStrategy::OnlyFirst, vec![0, 1, 1, 1]),
+            (TruncationStrategy::OnlySecond, vec![0, 0, 0, 1]),
+        ] {
+            let pair = EncodingInput::Dual(
+                "hello hello hello".to_string(),
+                "world world world".to_string(),
+            );
+            let encoding = tokenization
+                .encode(pair, true, strategy, true)
+                .await
+                .unwrap();
+            assert_eq!(encoding.input_ids, expected, "{strategy:?}");
+        }
+
+        // There is no second sequence to truncate
+        let err = tokenization
+            .encode(
+                "hello hello hello hello hello".to_string().into(),
+                true,
+                TruncationStrategy::OnlySecond,
+                true,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TextEmbeddingsError::Tokenizer(_)));
+    }
+
+    #[tokio::test]
+    async fn test_encode_ngrams() {
+        let tokenizer = word_level_tokenizer();
+        let tokenization =
+            Tokenization::new(1, tokenizer, 8, 0, UnicodeNormalization::None, None, None);
+
+        let ngrams = tokenization
+            .encode_ngrams("hello world world hello".to_string().into(), 2, true)
+            .await
+            .unwrap();
+        let ngrams: Vec<(Vec<u32>, (usize, usize))> = ngrams
+            .into_iter()
+            .map(|(encoding, span)| (encoding.input_ids, span))
+            .collect();
+        assert_eq!(
+            ngrams,
+            vec![
+                (vec![0, 1], (0, 2)),
+                (vec![1, 1], (1, 3)),
+                (vec![1, 0], (2, 4)),
+            ]
+        );
+
+        // A single window for inputs shorter than `ngram_size`
+        let ngrams = tokenization
+            .encode_ngrams("hello".to_string().into(), 3, true)
+            .await
+            .unwrap();
+        assert_eq!(ngrams[0].1, (0, 1));
+
+        for ngram_size in [0, 9] {
+            let err = tokenization
+                .encode_ngrams("hello".to_string().into(), ngram_size, true)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, TextEmbeddingsError::Validation(_)));
+        }
+    }
+
+    #[test]
+    fn test_lowercase() {
+        let tokenizer = |normalizer: &str| {
+            tokenizer_from_json(&format!(
+                r#"{{
+                    "version": "1.0",
+                    "truncation": null,
+                    "padding": null,
+                    "added_tokens": [],
+                    "normalizer": {normalizer},
+                    "pre_tokenizer": {{"type": "Whitespace"}},
+                    "post_processor": null,
+                    "decoder": null,
+                    "model": {{"type": "WordLevel", "vocab": {{"[UNK]": 0}}, "unk_token": "[UNK]"}}
+                }}"#
+            ))
+            .unwrap()
+        };
+        let model_root =
+            std::env::temp_dir().join(format!("tei-lowercase-test-{}", std::process::id()));
+        std::fs::create_dir_all(&model_root).unwrap();
+        std::fs::write(
+            model_root.join("tokenizer_config.json"),
+            r#"{"do_lower_case": true}"#,
+        )
+        .unwrap();
+
+        let lowercase = Lowercase::new(DoLowerCase::Auto, &tokenizer("null"), &model_root);
+        assert_eq!(
+            lowercase,
+            Some(Lowercase {
+                strip_accents: true
+            })
+        );
+        assert_eq!(
+            lowercase.unwrap().apply("Café Crème".to_string()),
+            "cafe creme"
+        );
+
+        // The tokenizer already lowercases its inputs
+        let normalizer =
+            r#"{"type": "Sequence", "normalizers": [{"type": "NFD"}, {"type": "Lowercase"}]}"#;
+        assert_eq!(
+            Lowercase::new(DoLowerCase::Auto, &tokenizer(normalizer), &model_root),
+            None
+        );
+        assert_eq!(
+            Lowercase::new(DoLowerCase::False, &tokenizer("null"), &model_root),
+            None
+        );
+
+        std::fs::remove_dir_all(model_root).unwrap();
+    }
+
+    #[test]
+    fn test_special_tokens() {
+        let vocab = HashMap::from([
+            ("hello".to_string(), 0),
+            ("[CLS]".to_string(), 1),
+            ("[SEP]".to_string(), 2),
+            ("eng_Latn".to_string(), 3),
+        ]);
+        let model = WordLevel::builder().vocab(vocab).build().unwrap();
+        let tokenizer = Tokenizer::new(model);
+
+        let model_root =
+            std::env::temp_dir().join(format!("tei-special-tokens-test-{}", std::process::id()));
+        std::fs::create_dir_all(&model_root).unwrap();
+        std::fs::write(
+            model_root.join("tokenizer_config.json"),
+            r#"{"cls_token": "[CLS]", "sep_token": {"content": "[SEP]", "special": true}, "unk_token": "[UNK]", "additional_special_tokens": ["eng_Latn", "fra_Latn"]}"#,
+        )
+        .unwrap();
+
+        let special_tokens = SpecialTokens::new(&tokenizer, &model_root);
+        assert_eq!(
+            special_tokens.cls,
+            Some(SpecialToken {
+                content: "[CLS]".to_string(),
+                id: 1
+            })
+        );
+        assert_eq!(special_tokens.sep.map(|t| t.id), Some(2));
+        // Not in the vocabulary
+        assert_eq!(special_tokens.unk, None);
+        assert_eq!(special_tokens.pad, None);
+        assert_eq!(
+            special_tokens.languages,
+            vec![SpecialToken {
+                content: "eng_Latn".to_string(),
+                id: 3
+            }]
+        );
+
+        std::fs::remove_dir_all(model_root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_encode_windows_stride() {
+        let tokenization = Tokenization::new(
+            1,
+            word_level_tokenizer(),
+            4,
+            0,
+            UnicodeNormalization::None,
+            None,
+            None,
+        );
+
+        let windows = tokenization
+            .encode_windows("hello world hello world hello".to_string().into(), 1, true)
+            .await
+            .unwrap();
+        assert_eq!(windows.len(), 2);
+
+        // Each sequence of a pair is truncated to 2 tokens, which cannot overlap by 2 tokens
+        let pair = EncodingInput::Dual(
+            "hello hello hello".to_string(),
+            "world world world".to_string(),
+        );
+        let err = tokenization
+            .encode_windows(pair, 2, true)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TextEmbeddingsError::Validation(_)));
+    }
+}
@@ -1,17 +1,21 @@
 use anyhow::{Context, Result};
 use hf_hub::api::tokio::ApiBuilder;
-use hf_hub::{Repo, RepoType};
+use hf_hub::{Cache, Repo, RepoType};
 use lambda_runtime::{service_fn, Error, LambdaEvent};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
-use text_embeddings_backend::{DType, ModelType};
-use text_embeddings_core::download::download_artifacts;
+use text_embeddings_backend::{Attention, DType, DenseNormalization, ModelType};
+use text_embeddings_core::download::{
+    download_artifacts, download_dense_modules, download_tokenizer_config, Download, DownloadLimits,
+};
 use text_embeddings_core::infer::Infer;
-use text_embeddings_core::queue::Queue;
-use text_embeddings_core::tokenization::Tokenization;
-use tokenizers::decoders::metaspace::PrependScheme;
-use tokenizers::{PreTokenizerWrapper, Tokenizer};
+use text_embeddings_core::queue::{Priority, Queue};
+use text_embeddings_core::tokenization::{
+    reconcile_max_input_length, set_metaspace_prepend, DoLowerCase, Lowercase, MetaspacePrepend,
+    Tokenization, UnicodeNormalization,
+};
+use tokenizers::Tokenizer;
 
 #[derive(Debug, Deserialize)]
 pub struct ModelConfig {
@@ -44,7 +48,9 @@ async fn handler(infer: &Infer, event: LambdaEvent<Request>) -> Result<EmbedResp
         .try_acquire_permit()
         .context("Could not acquire permit")?;
 
-    let response = infer.embed(input, truncate, normalize, permit).await?;
+    let response = infer
+        .embed(input, truncate, true, normalize, Priority::High, permit)
+        .await?;
 
     Ok(EmbedResponse(vec![response.results]))
 }
@@ -64,13 +70,15 @@ async fn setup_infer() -> Result<Infer> {
 
     let api = ApiBuilder::new().with_progress(true).build().unwrap();
 
-    let api_repo = api.repo(Repo::with_revision(
-        model_id.to_string(),
-        RepoType::Model,
-        "main".to_string(),
-    ));
+    let repo = Repo::with_revision(model_id.to_string(), RepoType::Model, "main".to_string());
+    let api_repo = api.repo(repo.clone());
 
-    let model_root = download_artifacts(&api_repo)
+    let download_limits = DownloadLimits::default();
+    let mut download = Download::new(&api_repo, Cache::default().repo(repo), &download_limits);
+
+    let _ = download_tokenizer_config(&mut download).await;
+    let _ = download_dense_modules(&mut download).await;
+    let model_root = download_artifacts(&mut download)
         .await
         .context("Could not download model artifacts")?;
 
@@ -90,24 +98,7 @@ async fn setup_infer() -> Result<Infer> {
         "tokenizer.json not found. text-embeddings-inference only supports fast tokenizers",
     );
 
-    // See https://github.com/huggingface/tokenizers/pull/1357
-    if let Some(pre_tokenizer) = tokenizer.get_pre_tokenizer() {
-        if let PreTokenizerWrapper::Metaspace(m) = pre_tokenizer {
-            // We are forced to clone since `Tokenizer` does not have a `get_mut` for `pre_tokenizer`
-            let mut m = m.clone();
-            m.set_prepend_scheme(PrependScheme::First);
-            tokenizer.with_pre_tokenizer(PreTokenizerWrapper::Metaspace(m));
-        } else if let PreTokenizerWrapper::Sequence(s) = pre_tokenizer {
-            // We are forced to clone since `Tokenizer` does not have a `get_mut` for `pre_tokenizer`
-            let mut s = s.clone();
-            for pre_tokenizer in s.get_pre_tokenizers_mut() {
-                if let PreTokenizerWrapper::Metaspace(m) = pre_tokenizer {
-                    m.set_prepend_scheme(PrependScheme::First);
-                }
-            }
-            tokenizer.with_pre_tokenizer(PreTokenizerWrapper::Sequence(s));
-        }
-    }
+    set_metaspace_prepend(&mut tokenizer, MetaspacePrepend::First);
 
     tokenizer.with_padding(None);
 
@@ -120,9 +111,13 @@ async fn setup_infer() -> Result<Infer> {
     } else {
         0
     };
-    let max_input_length = config.max_position_embeddings - position_offset;
+    let max_input_length = reconcile_max_input_length(
+        config.max_position_embeddings - position_offset,
+        &model_root,
+    );
 
     let tokenization_workers = num_cpus::get_physical();
+    let lowercase = Lowercase::new(DoLowerCase::Auto, &tokenizer, &model_root);
 
     // Tokenization logic
     let tokenization = Tokenization::new(
@@ -130,6 +125,9 @@ async fn setup_infer() -> Result<Infer> {
         tokenizer,
         max_input_length,
         position_offset,
+        UnicodeNormalization::None,
+        lowercase,
+        None,
     );
 
     let dtype = DType::Float32;
@@ -137,6 +135,10 @@ async fn setup_infer() -> Result<Infer> {
     let backend = text_embeddings_backend::Backend::new(
         model_root,
         dtype,
+        Attention::Auto,
+        DenseNormalization::Auto,
+        None,
+        1,
         model_type,
         "/tmp/text-embeddings-inference-server".to_string(),
         None,
@@ -159,7 +161,15 @@ async fn setup_infer() -> Result<Infer> {
     );
 
     // Create infer task
-    let infer = Infer::new(tokenization, queue, max_concurrent_requests, backend);
+    let infer = Infer::new(
+        tokenization,
+        queue,
+        max_concurrent_requests,
+        None,
+        false,
+        None,
+        backend,
+    );
 
     println!("health: {}", infer.health().await);
 
@@ -0,0 +1,84 @@
+//! Arrow IPC serialization of embeddings
+use arrow_array::{
+    ArrayRef, FixedSizeListArray, Float32Array, RecordBatch, StringArray, UInt32Array,
+};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{ArrowError, DataType, Field, Schema};
+use std::sync::Arc;
+
+/// Content type of the Arrow IPC streaming format
+pub(crate) const ARROW_STREAM_CONTENT_TYPE: &str = "application/vnd.apache.arrow.stream";
+
+/// Serialize the embeddings as an Arrow IPC stream containing a single record batch with an
+/// `index` column, an optional `custom_id` column and a fixed size list `embedding` column
+pub(crate) fn embeddings_to_arrow_stream(
+    embeddings: Vec<Vec<f32>>,
+    custom_ids: Option<Vec<String>>,
+) -> Result<Vec<u8>, ArrowError> {
+    let num_embeddings = embeddings.len();
+    let dimension = embeddings.first().map(|e| e.len()).unwrap_or(0);
+
+    let item_field = Arc::new(Field::new("item", DataType::Float32, false));
+    let values = Float32Array::from_iter_values(embeddings.into_iter().flatten());
+    let embedding =
+        FixedSizeListArray::try_new(item_field.clone(), dimension as i32, Arc::new(values), None)?;
+
+    let mut fields = vec![Field::new("index", DataType::UInt32, false)];
+    let mut columns: Vec<ArrayRef> = vec![Arc::new(UInt32Array::from_iter_values(
+        0..num_embeddings as u32,
+    ))];
+    if let Some(custom_ids) = custom_ids {
+        fields.push(Field::new("custom_id", DataType::Utf8, false));
+        columns.push(Arc::new(StringArray::from(custom_ids)));
+    }
+    fields.push(Field::new(
+        "embedding",
+        DataType::FixedSizeList(item_field, dimension as i32),
+        false,
+    ));
+    columns.push(Arc::new(embedding));
+
+    let schema = Arc::new(Schema::new(fields));
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+
+    let mut writer = StreamWriter::try_new(Vec::new(), &schema)?;
+    writer.write(&batch)?;
+    writer.finish()?;
+    writer.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::Array;
+    use arrow_ipc::reader::StreamReader;
+
+    #[test]
+    fn test_embeddings_to_arrow_stream() {
+        let embeddings = vec![vec![0.0, 1.0, 2.0], vec![3.0, 4.0, 5.0]];
+        let custom_ids = vec!["a".to_string(), "b".to_string()];
+
+        let bytes = embeddings_to_arrow_stream(embeddings, Some(custom_ids)).unwrap();
+        let batches = StreamReader::try_new(bytes.as_slice(), None)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(batch.schema().field(0).name(), "index");
+        assert_eq!(batch.schema().field(1).name(), "custom_id");
+
+        let embedding = batch
+            .column(2)
+            .as_any()
+            .downcast_ref::<FixedSizeListArray>()
+            .unwrap();
+        assert_eq!(embedding.value_length(), 3);
+        let second = embedding.value(1);
+        let second = second.as_any().downcast_ref::<Float32Array>().unwrap();
+        assert_eq!(second.values().to_vec(), vec![3.0, 4.0, 5.0]);
+        assert_eq!(embedding.null_count(), 0);
+    }
+}
@@ -0,0 +1,134 @@
+/// Benchmark logic
+use futures::future::join_all;
+use std::time::{Duration, Instant};
+use text_embeddings_core::infer::{Infer, InferResponse};
+use text_embeddings_core::queue::Priority;
+use text_embeddings_core::tokenization::TruncationStrategy;
+use text_embeddings_core::TextEmbeddingsError;
+
+/// Results of the benchmark for a given client batch size
+#[derive(Debug)]
+struct BenchResult {
+    batch_size: usize,
+    tokens_per_second: f64,
+    p50: Duration,
+    p95: Duration,
+    p99: Duration,
+}
+
+/// Run `iterations` client batches of synthetic inputs for each batch size through the
+/// `Infer`/`Queue`/`Backend` stack and report throughput and latency.
+pub async fn run(
+    infer: Infer,
+    sequence_length: usize,
+    batch_sizes: Vec<usize>,
+    iterations: usize,
+) -> Result<(), TextEmbeddingsError> {
+    // Synthetic input of roughly `sequence_length` tokens.
+    // Inputs longer than the model maximum input length are truncated.
+    let input = vec!["hello"; sequence_length].join(" ");
+
+    tracing::info!(
+        "Starting benchmark: sequence_length={sequence_length}, batch_sizes={batch_sizes:?}, iterations={iterations}"
+    );
+
+    // Warmup
+    run_batch(&infer, &input, 1).await?;
+
+    let mut results = Vec::with_capacity(batch_sizes.len());
+    for batch_size in batch_sizes {
+        let mut latencies = Vec::with_capacity(iterations);
+        let mut total_tokens = 0;
+
+        let start_time = Instant::now();
+        for _ in 0..iterations {
+            let batch_start_time = Instant::now();
+            total_tokens += run_batch(&infer, &input, batch_size).await?;
+            latencies.push(batch_start_time.elapsed());
+        }
+        let total_time = start_time.elapsed();
+
+        latencies.sort();
+        let result = BenchResult {
+            batch_size,
+            tokens_per_second: total_tokens as f64 / total_time.as_secs_f64(),
+            p50: percentile(&latencies, 50),
+            p95: percentile(&latencies, 95),
+            p99: percentile(&latencies, 99),
+        };
+        tracing::info!(
+            "batch_size={}: {:.0} tokens/s, p50={:?}, p95={:?}, p99={:?}",
+            result.batch_size,
+            result.tokens_per_second,
+            result.p50,
+            result.p95,
+            result.p99
+        );
+        results.push(result);
+    }
+
+    if let Some(best) = results
+        .iter()
+        .max_by(|a, b| a.tokens_per_second.total_cmp(&b.tokens_per_second))
+    {
+        tracing::info!(
+            "Best throughput with a client batch size of {} ({:.0} tokens/s, p99={:?})",
+            best.batch_size,
+            best.tokens_per_second,
+            best.p99
+        );
+    }
+
+    Ok(())
+}
+
+/// Send a client batch and return the number of tokens processed
+async fn run_batch(
+    infer: &Infer,
+    input: &str,
+    batch_size: usize,
+) -> Result<usize, TextEmbeddingsError> {
+    let results = match infer.is_classifier() {
+        true => {
+            let futures = (0..batch_size).map(|_| async {
+                let permit = infer.acquire_permit().await;
+                infer
+                    .predict(
+                        input.to_string(),
+                        true,
+                        TruncationStrategy::LongestFirst,
+                        false,
+                        Priority::High,
+                        permit,
+                    )
+                    .await
+            });
+            join_all(futures).await
+        }
+        // Like the `/embed` route, the inputs of the client batch are tokenized together
+        false => {
+            infer
+                .embed_batch(
+                    vec![(input.to_string(), Priority::High); batch_size],
+                    true,
+                    true,
+                    false,
+                )
+                .await?
+        }
+    };
+
+    let results = results
+        .into_iter()
+        .collect::<Result<Vec<InferResponse>, TextEmbeddingsError>>()?;
+    Ok(results.iter().map(|r| r.prompt_tokens).sum())
+}
+
+/// Nearest-rank percentile of sorted latencies
+fn percentile(latencies: &[Duration], p: usize) -> Duration {
+    if latencies.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p * latencies.len() + 99) / 100;
+    latencies[rank.saturating_sub(1)]
+}
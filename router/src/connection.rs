@@ -0,0 +1,174 @@
+/// Limit the number of open connections at the listener level
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// Incoming connections of the server.
+/// Connections accepted while `max_connections` connections are already open are closed
+/// immediately instead of waiting for a free slot.
+pub(crate) struct LimitedIncoming {
+    incoming: AddrIncoming,
+    max_connections: Option<usize>,
+    open_connections: Arc<AtomicUsize>,
+    write_timeout: Duration,
+}
+
+impl LimitedIncoming {
+    pub(crate) fn new(
+        incoming: AddrIncoming,
+        max_connections: Option<usize>,
+        write_timeout: Duration,
+    ) -> Self {
+        Self {
+            incoming,
+            max_connections,
+            open_connections: Arc::new(AtomicUsize::new(0)),
+            write_timeout,
+        }
+    }
+}
+
+impl Accept for LimitedIncoming {
+    type Conn = CountedConnection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        loop {
+            let stream = match ready!(Pin::new(&mut self.incoming).poll_accept(cx)) {
+                Some(Ok(stream)) => stream,
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => return Poll::Ready(None),
+            };
+
+            let open_connections = self.open_connections.load(Ordering::SeqCst);
+            if let Some(max_connections) = self.max_connections {
+                if open_connections >= max_connections {
+                    // Dropping the stream closes the connection and frees the file descriptor
+                    tracing::debug!(
+                        "Rejecting connection from {}: {open_connections} connections are open",
+                        stream.remote_addr()
+                    );
+                    metrics::increment_counter!("te_connection_rejected");
+                    continue;
+                }
+            }
+
+            return Poll::Ready(Some(Ok(CountedConnection::new(
+                stream,
+                self.open_connections.clone(),
+                self.write_timeout,
+            ))));
+        }
+    }
+}
+
+/// Accepted connection that is counted as open until it is dropped.
+/// Writes that make no progress for `write_timeout`, because the client stopped reading the
+/// response, fail with a `TimedOut` error which closes the connection.
+pub(crate) struct CountedConnection {
+    stream: AddrStream,
+    open_connections: Arc<AtomicUsize>,
+    write_timeout: Duration,
+    /// Started when a write is blocked, reset when a write makes progress
+    write_timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl CountedConnection {
+    fn new(
+        stream: AddrStream,
+        open_connections: Arc<AtomicUsize>,
+        write_timeout: Duration,
+    ) -> Self {
+        let open = open_connections.fetch_add(1, Ordering::SeqCst) + 1;
+        metrics::gauge!("te_connections", open as f64);
+        Self {
+            stream,
+            open_connections,
+            write_timeout,
+            write_timer: None,
+        }
+    }
+
+    /// Fail a blocked write once it has been blocked for `write_timeout`
+    fn poll_write_timeout<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        result: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        if result.is_ready() {
+            self.write_timer = None;
+            return result;
+        }
+
+        let write_timeout = self.write_timeout;
+        let timer = self
+            .write_timer
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(write_timeout)));
+        ready!(timer.as_mut().poll(cx));
+        metrics::increment_counter!("te_connection_write_timeout");
+        Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("write blocked for more than {write_timeout:?}"),
+        )))
+    }
+}
+
+impl Drop for CountedConnection {
+    fn drop(&mut self) {
+        let open = self.open_connections.fetch_sub(1, Ordering::SeqCst) - 1;
+        metrics::gauge!("te_connections", open as f64);
+    }
+}
+
+impl AsyncRead for CountedConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CountedConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.stream).poll_write(cx, buf);
+        self.poll_write_timeout(cx, result)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.stream).poll_write_vectored(cx, bufs);
+        self.poll_write_timeout(cx, result)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
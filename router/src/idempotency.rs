@@ -0,0 +1,191 @@
+use axum::body::Bytes;
+use axum::http::{HeaderMap, StatusCode};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Header used by clients to identify the retries of a request
+pub(crate) const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Header set on the responses replayed from the cache
+pub(crate) const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+/// Maximum length of an idempotency key
+pub(crate) const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+/// Maximum number of responses kept in the cache
+const MAX_ENTRIES: usize = 10_000;
+
+/// Successful response of a request, replayed to its retries
+#[derive(Clone, Debug)]
+pub(crate) struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+#[derive(Debug)]
+enum EntryState {
+    InFlight,
+    Done(CachedResponse),
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Hash of the request the key was first used with
+    fingerprint: u64,
+    expires_at: Instant,
+    state: EntryState,
+}
+
+/// Responses of the requests sent with an `Idempotency-Key` header, kept for `ttl`
+#[derive(Debug)]
+pub(crate) struct IdempotencyCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+pub(crate) enum Lookup {
+    /// First request with this key: the caller computes the response and stores it with the
+    /// reservation
+    Reserved(Reservation),
+    /// A request with this key is still being computed
+    InFlight,
+    /// The key was used with a different request
+    Mismatch,
+    Hit(CachedResponse),
+}
+
+impl IdempotencyCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the response of a previous request with the same key, or reserve the key for this
+    /// request
+    pub(crate) fn begin(self: &Arc<Self>, key: &str, fingerprint: u64) -> Lookup {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        if let Some(entry) = entries.get(key).filter(|entry| entry.expires_at > now) {
+            if entry.fingerprint != fingerprint {
+                return Lookup::Mismatch;
+            }
+            return match &entry.state {
+                EntryState::InFlight => Lookup::InFlight,
+                EntryState::Done(response) => Lookup::Hit(response.clone()),
+            };
+        }
+
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+        if entries.len() >= MAX_ENTRIES {
+            // Evict the entry closest to its expiry
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(
+            key.to_string(),
+            Entry {
+                fingerprint,
+                expires_at: now + self.ttl,
+                state: EntryState::InFlight,
+            },
+        );
+        Lookup::Reserved(Reservation {
+            cache: self.clone(),
+            key: key.to_string(),
+            completed: false,
+        })
+    }
+}
+
+/// Key reserved by an in flight request.
+/// The key is released if the reservation is dropped without a response, e.g. when the request
+/// fails or the client disconnects, so that a retry computes the response again.
+pub(crate) struct Reservation {
+    cache: Arc<IdempotencyCache>,
+    key: String,
+    completed: bool,
+}
+
+impl Reservation {
+    /// Store the response of the request until the cache TTL expires
+    pub(crate) fn complete(mut self, response: CachedResponse) {
+        let mut entries = self.cache.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&self.key) {
+            entry.expires_at = Instant::now() + self.cache.ttl;
+            entry.state = EntryState::Done(response);
+        }
+        self.completed = true;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        let mut entries = self.cache.entries.lock().unwrap();
+        if matches!(
+            entries.get(&self.key),
+            Some(Entry {
+                state: EntryState::InFlight,
+                ..
+            })
+        ) {
+            entries.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(body: &'static str) -> CachedResponse {
+        CachedResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::from(body),
+        }
+    }
+
+    #[test]
+    fn test_idempotency_cache() {
+        let cache = Arc::new(IdempotencyCache::new(Duration::from_secs(60)));
+
+        let Lookup::Reserved(reservation) = cache.begin("a", 1) else {
+            panic!("expected a reservation")
+        };
+        assert!(matches!(cache.begin("a", 1), Lookup::InFlight));
+        assert!(matches!(cache.begin("a", 2), Lookup::Mismatch));
+        reservation.complete(response("first"));
+        match cache.begin("a", 1) {
+            Lookup::Hit(cached) => assert_eq!(cached.body, "first"),
+            _ => panic!("expected a cached response"),
+        }
+
+        // Failed requests release their key
+        let Lookup::Reserved(reservation) = cache.begin("b", 1) else {
+            panic!("expected a reservation")
+        };
+        drop(reservation);
+        assert!(matches!(cache.begin("b", 1), Lookup::Reserved(_)));
+
+        // Expired keys can be reused
+        let cache = Arc::new(IdempotencyCache::new(Duration::ZERO));
+        let Lookup::Reserved(reservation) = cache.begin("a", 1) else {
+            panic!("expected a reservation")
+        };
+        reservation.complete(response("first"));
+        assert!(matches!(cache.begin("a", 2), Lookup::Reserved(_)));
+    }
+}
@@ -1,12 +1,33 @@
 /// Text Embedding Inference Webserver
 pub mod server;
 
+/// Text Embedding Inference Benchmark
+pub mod bench;
+
+/// Model self-test run before rollouts
+pub mod self_test;
+
+/// Shared memory output for colocated clients
+#[cfg(feature = "shm")]
+pub mod shm;
+
+/// Arrow IPC serialization
+mod arrow;
+
+/// Listener connection limit
+mod connection;
+
+/// Replay of retried requests
+mod idempotency;
+
 use serde::de::{SeqAccess, Visitor};
-use serde::{de, Deserialize, Deserializer, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::json;
 use std::collections::HashMap;
 use std::fmt::Formatter;
-use text_embeddings_core::tokenization::EncodingInput;
+use text_embeddings_backend::{HiddenStateStats, OutputDType};
+use text_embeddings_core::infer::normalize_embedding;
+use text_embeddings_core::tokenization::{EncodingInput, SpecialTokens};
 use utoipa::openapi::{RefOr, Schema};
 use utoipa::ToSchema;
 
@@ -14,6 +35,8 @@ use utoipa::ToSchema;
 pub struct EmbeddingModel {
     #[schema(example = "cls")]
     pub pooling: String,
+    #[schema(example = "768")]
+    pub dimension: usize,
 }
 
 #[derive(Clone, Debug, Serialize, ToSchema)]
@@ -22,6 +45,82 @@ pub struct ClassifierModel {
     pub id2label: HashMap<String, String>,
     #[schema(example = json!({"LABEL": "0"}))]
     pub label2id: HashMap<String, usize>,
+    pub problem_type: ProblemType,
+}
+
+/// Task of the classification head
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemType {
+    /// Softmax over all the labels
+    SingleLabelClassification,
+    /// Labels are independent: each score is a sigmoid
+    MultiLabelClassification,
+    /// Raw score of a single output, e.g. the similarity of a pair
+    Regression,
+}
+
+impl ProblemType {
+    /// Parse the `problem_type` of `config.json`. As in `transformers`, models without one are
+    /// regressors if they have a single output and single label classifiers otherwise.
+    pub fn new(problem_type: Option<&str>, num_labels: usize) -> Result<Self, String> {
+        match problem_type {
+            Some("single_label_classification") => Ok(Self::SingleLabelClassification),
+            Some("multi_label_classification") => Ok(Self::MultiLabelClassification),
+            Some("regression") => Ok(Self::Regression),
+            Some(problem_type) => Err(format!("unknown `problem_type` `{problem_type}`")),
+            None if num_labels == 1 => Ok(Self::Regression),
+            None => Ok(Self::SingleLabelClassification),
+        }
+    }
+}
+
+/// Maximum length in characters of a classifier label
+pub const MAX_LABEL_LENGTH: usize = 256;
+
+impl ClassifierModel {
+    /// Validate the labels of the model config.
+    /// The ids of `id2label` must be exactly `0..n_labels` as they index the model logits.
+    pub fn new(
+        id2label: HashMap<String, String>,
+        label2id: HashMap<String, usize>,
+    ) -> Result<Self, String> {
+        if id2label.is_empty() {
+            return Err("`id2label` is empty".to_string());
+        }
+
+        let mut ids = Vec::with_capacity(id2label.len());
+        for (id, label) in &id2label {
+            let id: usize = id
+                .parse()
+                .map_err(|_| format!("`id2label` id `{id}` is not a valid integer"))?;
+            if label.chars().count() > MAX_LABEL_LENGTH {
+                return Err(format!(
+                    "`id2label` label for id {id} is longer than {MAX_LABEL_LENGTH} characters"
+                ));
+            }
+            ids.push(id);
+        }
+        ids.sort_unstable();
+
+        if let Some(missing) = ids
+            .iter()
+            .enumerate()
+            .find(|(i, id)| i != *id)
+            .map(|(i, _)| i)
+        {
+            return Err(format!(
+                "`id2label` ids must be contiguous from 0 to {}, but id {missing} is missing",
+                id2label.len() - 1
+            ));
+        }
+
+        Ok(Self {
+            id2label,
+            label2id,
+            problem_type: ProblemType::SingleLabelClassification,
+        })
+    }
 }
 
 #[derive(Clone, Debug, Serialize, ToSchema)]
@@ -31,6 +130,65 @@ pub enum ModelType {
     Embedding(EmbeddingModel),
 }
 
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct SpecialToken {
+    #[schema(example = "[CLS]")]
+    pub token: String,
+    #[schema(example = "101")]
+    pub id: u32,
+}
+
+impl From<text_embeddings_core::tokenization::SpecialToken> for SpecialToken {
+    fn from(value: text_embeddings_core::tokenization::SpecialToken) -> Self {
+        Self {
+            token: value.content,
+            id: value.id,
+        }
+    }
+}
+
+/// Tokenizer metadata for client-side tokenization
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct TokenizerInfo {
+    /// Size of the vocabulary, including the added tokens
+    #[schema(example = "30522")]
+    pub vocab_size: usize,
+    #[schema(nullable = true)]
+    pub cls_token: Option<SpecialToken>,
+    #[schema(nullable = true)]
+    pub sep_token: Option<SpecialToken>,
+    #[schema(nullable = true)]
+    pub pad_token: Option<SpecialToken>,
+    #[schema(nullable = true)]
+    pub unk_token: Option<SpecialToken>,
+    #[schema(nullable = true)]
+    pub bos_token: Option<SpecialToken>,
+    #[schema(nullable = true)]
+    pub eos_token: Option<SpecialToken>,
+    /// Language tokens accepted by the `language` parameter of `/embed`
+    #[schema(example = json!(["eng_Latn", "fra_Latn"]))]
+    pub language_tokens: Vec<String>,
+}
+
+impl TokenizerInfo {
+    pub fn new(vocab_size: usize, special_tokens: SpecialTokens) -> Self {
+        Self {
+            vocab_size,
+            cls_token: special_tokens.cls.map(SpecialToken::from),
+            sep_token: special_tokens.sep.map(SpecialToken::from),
+            pad_token: special_tokens.pad.map(SpecialToken::from),
+            unk_token: special_tokens.unk.map(SpecialToken::from),
+            bos_token: special_tokens.bos.map(SpecialToken::from),
+            eos_token: special_tokens.eos.map(SpecialToken::from),
+            language_tokens: special_tokens
+                .languages
+                .into_iter()
+                .map(|token| token.content)
+                .collect(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, ToSchema)]
 pub struct Info {
     /// Model info
@@ -38,9 +196,29 @@ pub struct Info {
     pub model_id: String,
     #[schema(nullable = true, example = "fca14538aa9956a46526bd1d0d11d69e19b5a101")]
     pub model_sha: Option<String>,
+    /// Dtype of the model, or the quantization scheme of GGUF models, e.g. `q4_0`
     #[schema(example = "float16")]
     pub model_dtype: String,
+    /// Precision the returned embeddings are rounded to
+    #[schema(value_type = String, example = "float32")]
+    #[serde(serialize_with = "serialize_display")]
+    pub model_output_dtype: OutputDType,
+    /// Attention implementation the model was loaded with
+    #[schema(nullable = true, example = "flash")]
+    pub model_attention: Option<String>,
+    /// Whether a whitening transform from `whitening.safetensors` is applied to the embeddings
+    #[schema(example = "false")]
+    pub model_whitening: bool,
+    #[schema(example = "1")]
+    pub num_model_replicas: usize,
     pub model_type: ModelType,
+    /// Prompts of the model, from `config_sentence_transformers.json`
+    #[schema(example = json!({"query": "query: ", "passage": "passage: "}))]
+    pub prompts: HashMap<String, String>,
+    /// Prompt prepended by default to the inputs of embedding requests
+    #[schema(nullable = true, example = "query")]
+    pub default_prompt_name: Option<String>,
+    pub tokenizer: TokenizerInfo,
     /// Router Parameters
     #[schema(example = "128")]
     pub max_concurrent_requests: usize,
@@ -52,8 +230,26 @@ pub struct Info {
     pub max_batch_requests: Option<usize>,
     #[schema(example = "32")]
     pub max_client_batch_size: usize,
+    #[schema(nullable = true, example = "6", default = "null")]
+    pub embedding_precision: Option<usize>,
+    #[schema(nullable = true, example = "67108864", default = "null")]
+    pub max_response_bytes: Option<usize>,
     #[schema(example = "4")]
     pub tokenization_workers: usize,
+    #[schema(example = "false")]
+    pub allow_debug: bool,
+    #[schema(example = "false")]
+    pub renormalize_probabilities: bool,
+    #[schema(nullable = true, example = "null")]
+    pub max_pair_length_ratio: Option<f32>,
+    #[schema(example = "false")]
+    pub default_raw_scores: bool,
+    #[schema(example = "false")]
+    pub regression_sigmoid: bool,
+    #[schema(example = "false")]
+    pub openai_accept_texts: bool,
+    #[schema(example = "false")]
+    pub auto_truncate: bool,
     /// Router Info
     #[schema(example = "0.5.0")]
     pub version: &'static str,
@@ -63,7 +259,125 @@ pub struct Info {
     pub docker_label: Option<&'static str>,
 }
 
-#[derive(Debug)]
+/// Serialize a value with its `Display` implementation, e.g. the value enums of the arguments
+fn serialize_display<T: std::fmt::Display, S: Serializer>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+/// Effective runtime configuration, after resolving the arguments, the environment and the
+/// model configuration
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct EffectiveConfig {
+    /// Model
+    #[schema(example = "thenlper/gte-base")]
+    pub model_id: String,
+    #[schema(nullable = true, example = "null")]
+    pub revision: Option<String>,
+    #[schema(example = "float16")]
+    pub dtype: String,
+    #[schema(example = "float32")]
+    pub output_dtype: String,
+    /// Backend running the model
+    #[schema(example = "candle")]
+    pub backend: &'static str,
+    pub attention: String,
+    #[schema(nullable = true, example = "cls")]
+    pub pooling: Option<String>,
+    #[schema(example = "auto")]
+    pub dense_normalization: String,
+    #[schema(nullable = true, example = "768")]
+    pub expect_dimension: Option<usize>,
+    #[schema(nullable = true, example = "null")]
+    pub config_override: Option<String>,
+    #[schema(nullable = true, example = json!(["bert", "xlm-roberta"]))]
+    pub allowed_architectures: Option<Vec<String>>,
+    #[schema(example = "1")]
+    pub num_model_replicas: usize,
+    #[schema(nullable = true, example = "null")]
+    pub inference_threads: Option<usize>,
+    #[schema(example = "false")]
+    pub mlock: bool,
+    /// Tokenization
+    #[schema(example = "512")]
+    pub max_input_length: usize,
+    #[schema(example = "4")]
+    pub tokenization_workers: usize,
+    #[schema(example = "none")]
+    pub normalize_unicode: String,
+    #[schema(example = "false")]
+    pub lowercase_inputs: bool,
+    #[schema(example = "first")]
+    pub metaspace_prepend: String,
+    #[schema(nullable = true, example = "null")]
+    pub input_template: Option<String>,
+    /// Batching
+    #[schema(example = "16384")]
+    pub max_batch_tokens: usize,
+    #[schema(nullable = true, example = "null")]
+    pub max_batch_requests: Option<usize>,
+    #[schema(nullable = true, example = "8")]
+    pub microbatch_size: Option<usize>,
+    #[schema(example = "32")]
+    pub max_client_batch_size: usize,
+    #[schema(example = "512")]
+    pub max_concurrent_requests: usize,
+    /// Server
+    #[schema(nullable = true, example = "null")]
+    pub embedding_precision: Option<usize>,
+    #[schema(nullable = true, example = "null")]
+    pub max_response_bytes: Option<usize>,
+    #[schema(example = "10")]
+    pub circuit_breaker_threshold: usize,
+    #[schema(nullable = true, example = "null")]
+    pub unhealthy_grace_ms: Option<u64>,
+    pub reject_nan: bool,
+    #[schema(nullable = true, example = "null")]
+    pub keep_warm_interval: Option<u64>,
+    #[schema(nullable = true, example = "null")]
+    pub slow_start_duration: Option<u64>,
+    #[schema(nullable = true, example = "/data/warmup.txt")]
+    pub warmup_file: Option<String>,
+    #[schema(example = "256")]
+    pub warmup_max_inputs: usize,
+    #[schema(example = "30")]
+    pub max_retry_after: u64,
+    #[schema(nullable = true, example = "null")]
+    pub idempotency_ttl: Option<u64>,
+    #[schema(example = "false")]
+    pub allow_debug: bool,
+    #[schema(example = "false")]
+    pub renormalize_probabilities: bool,
+    #[schema(nullable = true, example = "null")]
+    pub max_pair_length_ratio: Option<f32>,
+    #[schema(example = "false")]
+    pub default_raw_scores: bool,
+    #[schema(example = "false")]
+    pub regression_sigmoid: bool,
+    #[schema(example = "false")]
+    pub openai_accept_texts: bool,
+    #[schema(example = "false")]
+    pub auto_truncate: bool,
+    #[schema(example = "0.0.0.0")]
+    pub hostname: String,
+    #[schema(example = "3000")]
+    pub port: u16,
+    #[schema(nullable = true, example = "null")]
+    pub max_connections: Option<usize>,
+    #[schema(example = "60")]
+    pub http_read_timeout: u64,
+    #[schema(example = "60")]
+    pub http_write_timeout: u64,
+    #[schema(example = "2000000")]
+    pub payload_limit: usize,
+    #[schema(nullable = true, example = "null")]
+    pub shm: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
 pub(crate) enum Sequence {
     Single(String),
     Pair(String, String),
@@ -78,6 +392,16 @@ impl Sequence {
     }
 }
 
+impl Sequence {
+    /// Prepend `prompt` to the first sequence
+    pub(crate) fn with_prompt(self, prompt: &str) -> Self {
+        match self {
+            Sequence::Single(s) => Sequence::Single(format!("{prompt}{s}")),
+            Sequence::Pair(s1, s2) => Sequence::Pair(format!("{prompt}{s1}"), s2),
+        }
+    }
+}
+
 impl From<Sequence> for EncodingInput {
     fn from(value: Sequence) -> Self {
         match value {
@@ -255,9 +579,57 @@ pub(crate) struct PredictRequest {
     #[serde(default)]
     #[schema(default = "false", example = "false")]
     pub truncate: bool,
+    /// Which sequence of a pair is truncated when `truncate` is set
+    #[serde(default)]
+    #[schema(default = "longest_first", example = "only_second")]
+    pub truncation_strategy: TruncationStrategy,
+    /// Return the raw scores instead of the softmax probabilities.
+    /// Default to the `default_raw_scores` of `/info`.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "false")]
+    pub raw_scores: Option<bool>,
+    /// Also return the highest scoring label and its score at the top level of each prediction
     #[serde(default)]
     #[schema(default = "false", example = "false")]
-    pub raw_scores: bool,
+    pub single_label: bool,
+    /// Only return the labels scoring strictly above this threshold, e.g. the labels predicted by a
+    /// multi-label model. Applied before `top_k`: the response contains at most `top_k` labels,
+    /// all above the threshold.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "0.5")]
+    pub threshold: Option<f32>,
+    /// Only return the `top_k` highest scoring labels
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub top_k: Option<usize>,
+    /// Biases added to the logits of the labels before the softmax or sigmoid, keyed by label
+    /// name. Labels without a bias are left unchanged.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json!({"LABEL": 0.5}))]
+    pub logit_bias: Option<HashMap<String, f32>>,
+}
+
+/// Same semantics as the `truncation` argument of `transformers` tokenizers
+#[derive(Deserialize, ToSchema, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TruncationStrategy {
+    /// Remove tokens from the longest sequence of the pair until it fits
+    #[default]
+    LongestFirst,
+    /// Only truncate the first sequence of the pair
+    OnlyFirst,
+    /// Only truncate the second sequence of the pair
+    OnlySecond,
+}
+
+impl From<TruncationStrategy> for text_embeddings_core::tokenization::TruncationStrategy {
+    fn from(value: TruncationStrategy) -> Self {
+        match value {
+            TruncationStrategy::LongestFirst => Self::LongestFirst,
+            TruncationStrategy::OnlyFirst => Self::OnlyFirst,
+            TruncationStrategy::OnlySecond => Self::OnlySecond,
+        }
+    }
 }
 
 #[derive(Serialize, ToSchema)]
@@ -268,11 +640,55 @@ pub(crate) struct Prediction {
     label: String,
 }
 
+#[derive(Serialize, ToSchema)]
+pub(crate) struct LabeledPredictions {
+    #[schema(example = "0.5")]
+    score: f32,
+    #[schema(example = "admiration")]
+    label: String,
+    predictions: Vec<Prediction>,
+}
+
+impl LabeledPredictions {
+    /// `predictions` must be sorted by decreasing score
+    fn new(predictions: Vec<Prediction>) -> Self {
+        let (score, label) = match predictions.first() {
+            Some(top) => (top.score, top.label.clone()),
+            None => (0.0, String::new()),
+        };
+        Self {
+            score,
+            label,
+            predictions,
+        }
+    }
+}
+
 #[derive(Serialize, ToSchema)]
 #[serde(untagged)]
 pub(crate) enum PredictResponse {
     Single(Vec<Prediction>),
     Batch(Vec<Vec<Prediction>>),
+    SingleLabeled(LabeledPredictions),
+    BatchLabeled(Vec<LabeledPredictions>),
+}
+
+impl PredictResponse {
+    /// Add the highest scoring label at the top level of the predictions
+    pub(crate) fn with_top_label(self) -> Self {
+        match self {
+            PredictResponse::Single(predictions) => {
+                PredictResponse::SingleLabeled(LabeledPredictions::new(predictions))
+            }
+            PredictResponse::Batch(predictions) => PredictResponse::BatchLabeled(
+                predictions
+                    .into_iter()
+                    .map(LabeledPredictions::new)
+                    .collect(),
+            ),
+            response => response,
+        }
+    }
 }
 
 #[derive(Deserialize, ToSchema)]
@@ -284,21 +700,137 @@ pub(crate) enum Input {
 
 #[derive(Deserialize, ToSchema)]
 pub(crate) struct OpenAICompatRequest {
-    pub input: Input,
+    /// Required, unless `texts` is set
+    #[serde(default)]
+    #[schema(nullable = false)]
+    pub input: Option<Input>,
+    /// Alias of `input` for clients that send `texts`.
+    /// Only accepted if the router was started with `--openai-accept-texts`.
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub texts: Option<Input>,
     #[allow(dead_code)]
     #[schema(nullable = true, example = "null")]
     model: Option<String>,
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub dimensions: Option<usize>,
+    #[serde(default)]
+    #[schema(default = "float", example = "float")]
+    pub encoding_format: EncodingFormat,
+    /// Return both the raw pooled embedding and the normalized embedding, each encoded with
+    /// `encoding_format`
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub return_raw_and_normalized: bool,
     #[allow(dead_code)]
     #[schema(nullable = true, example = "null")]
     user: Option<String>,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EncodingFormat {
+    #[default]
+    Float,
+    Base64,
+    /// pgvector `vector` literal
+    Pgvector,
+    /// Scalar quantized int8 values, see `EmbeddingData::Int8`
+    Int8,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub(crate) enum EmbeddingData {
+    Float(Vec<f32>),
+    /// Base64 encoded little-endian f32 values
+    Base64(String),
+    /// pgvector literal, e.g. `[0.1,0.2,0.3]`
+    Pgvector(String),
+    /// Int8 values of the embedding, dequantized with `value * scale + offset`.
+    /// The `[min, max]` range of the embedding is split in 256 steps of size `scale`, and
+    /// `offset = min + 128 * scale` so that `-128` is the minimum and `127` the maximum.
+    Int8 {
+        values: Vec<i8>,
+        scale: f32,
+        offset: f32,
+    },
+    RawAndNormalized {
+        raw: Box<EmbeddingData>,
+        normalized: Box<EmbeddingData>,
+    },
+}
+
+impl<'__s> ToSchema<'__s> for EmbeddingData {
+    fn schema() -> (&'__s str, RefOr<Schema>) {
+        (
+            "EmbeddingData",
+            utoipa::openapi::OneOfBuilder::new()
+                .item(
+                    utoipa::openapi::ArrayBuilder::new()
+                        .items(
+                            utoipa::openapi::ObjectBuilder::new()
+                                .schema_type(utoipa::openapi::SchemaType::Number),
+                        )
+                        .description(Some("Float values")),
+                )
+                .item(
+                    utoipa::openapi::ObjectBuilder::new()
+                        .schema_type(utoipa::openapi::SchemaType::String)
+                        .description(Some("Base64 encoded little-endian f32 values")),
+                )
+                .item(
+                    utoipa::openapi::ObjectBuilder::new()
+                        .schema_type(utoipa::openapi::SchemaType::String)
+                        .description(Some("pgvector literal, e.g. `[0.1,0.2,0.3]`")),
+                )
+                .item(
+                    utoipa::openapi::ObjectBuilder::new()
+                        .property(
+                            "values",
+                            utoipa::openapi::ArrayBuilder::new().items(
+                                utoipa::openapi::ObjectBuilder::new()
+                                    .schema_type(utoipa::openapi::SchemaType::Integer),
+                            ),
+                        )
+                        .property(
+                            "scale",
+                            utoipa::openapi::ObjectBuilder::new()
+                                .schema_type(utoipa::openapi::SchemaType::Number),
+                        )
+                        .property(
+                            "offset",
+                            utoipa::openapi::ObjectBuilder::new()
+                                .schema_type(utoipa::openapi::SchemaType::Number),
+                        )
+                        .description(Some(
+                            "Int8 values, dequantized with `value * scale + offset`",
+                        )),
+                )
+                .item(
+                    utoipa::openapi::ObjectBuilder::new()
+                        .property(
+                            "raw",
+                            utoipa::openapi::Ref::from_schema_name("EmbeddingData"),
+                        )
+                        .property(
+                            "normalized",
+                            utoipa::openapi::Ref::from_schema_name("EmbeddingData"),
+                        )
+                        .description(Some("Raw and normalized embeddings")),
+                )
+                .example(Some(json!(["0.0", "1.0", "2.0"])))
+                .into(),
+        )
+    }
+}
+
 #[derive(Serialize, ToSchema)]
 pub(crate) struct OpenAICompatEmbedding {
     #[schema(example = "embedding")]
     object: &'static str,
-    #[schema(example = json!(["0.0", "1.0", "2.0"]))]
-    embedding: Vec<f32>,
+    embedding: EmbeddingData,
     #[schema(example = "0")]
     index: usize,
 }
@@ -321,15 +853,317 @@ pub(crate) struct OpenAICompatResponse {
     usage: OpenAICompatUsage,
 }
 
+#[derive(Clone, Serialize, ToSchema)]
+pub(crate) struct OpenAICompatModel {
+    #[schema(example = "thenlper/gte-base")]
+    pub id: String,
+    #[schema(example = "model")]
+    pub object: &'static str,
+    #[schema(example = "1700000000")]
+    pub created: u64,
+    #[schema(example = "text-embeddings-inference")]
+    pub owned_by: &'static str,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub(crate) struct OpenAICompatModelList {
+    #[schema(example = "list")]
+    pub object: &'static str,
+    pub data: Vec<OpenAICompatModel>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum EmbedInput {
+    Single(SingleInput),
+    // A two strings array is a batch of two strings: single pairs are objects
+    Batch(Vec<Sequence>),
+    // Grouped must be tried before Identified, whose objects do not have a `group_id`
+    Grouped(Vec<GroupedInput>),
+    Identified(Vec<IdentifiedInput>),
+}
+
+impl EmbedInput {
+    /// Prepend `prompt` to each input
+    pub(crate) fn with_prompt(self, prompt: &str) -> Self {
+        match self {
+            EmbedInput::Single(input) => EmbedInput::Single(input.with_prompt(prompt)),
+            EmbedInput::Batch(inputs) => EmbedInput::Batch(
+                inputs
+                    .into_iter()
+                    .map(|input| input.with_prompt(prompt))
+                    .collect(),
+            ),
+            EmbedInput::Identified(inputs) => EmbedInput::Identified(
+                inputs
+                    .into_iter()
+                    .map(|input| IdentifiedInput {
+                        text: format!("{prompt}{}", input.text),
+                        custom_id: input.custom_id,
+                    })
+                    .collect(),
+            ),
+            EmbedInput::Grouped(inputs) => EmbedInput::Grouped(
+                inputs
+                    .into_iter()
+                    .map(|input| GroupedInput {
+                        text: format!("{prompt}{}", input.text),
+                        group_id: input.group_id,
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Extract the `custom_id`s of identified inputs so that they can be embedded as a batch
+    pub(crate) fn split_custom_ids(self) -> (Self, Option<Vec<String>>) {
+        match self {
+            EmbedInput::Identified(inputs) => {
+                let (inputs, custom_ids) = inputs
+                    .into_iter()
+                    .map(|input| (Sequence::Single(input.text), input.custom_id))
+                    .unzip();
+                (EmbedInput::Batch(inputs), Some(custom_ids))
+            }
+            inputs => (inputs, None),
+        }
+    }
+
+    /// Extract the `group_id`s of grouped inputs so that they can be embedded as a batch
+    pub(crate) fn split_group_ids(self) -> (Self, Option<Vec<String>>) {
+        match self {
+            EmbedInput::Grouped(inputs) => {
+                let (inputs, group_ids) = inputs
+                    .into_iter()
+                    .map(|input| (Sequence::Single(input.text), input.group_id))
+                    .unzip();
+                (EmbedInput::Batch(inputs), Some(group_ids))
+            }
+            inputs => (inputs, None),
+        }
+    }
+}
+
+/// A single string, or a single pair sent as `{"text": string, "text_pair": string}`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum SingleInput {
+    Text(String),
+    Pair { text: String, text_pair: String },
+}
+
+impl SingleInput {
+    /// Prepend `prompt` to the first sequence
+    pub(crate) fn with_prompt(self, prompt: &str) -> Self {
+        match self {
+            SingleInput::Text(text) => SingleInput::Text(format!("{prompt}{text}")),
+            SingleInput::Pair { text, text_pair } => SingleInput::Pair {
+                text: format!("{prompt}{text}"),
+                text_pair,
+            },
+        }
+    }
+}
+
+impl From<SingleInput> for Sequence {
+    fn from(value: SingleInput) -> Self {
+        match value {
+            SingleInput::Text(text) => Sequence::Single(text),
+            SingleInput::Pair { text, text_pair } => Sequence::Pair(text, text_pair),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct IdentifiedInput {
+    pub text: String,
+    pub custom_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct GroupedInput {
+    pub text: String,
+    pub group_id: String,
+}
+
+impl<'__s> ToSchema<'__s> for EmbedInput {
+    fn schema() -> (&'__s str, RefOr<Schema>) {
+        (
+            "EmbedInput",
+            utoipa::openapi::OneOfBuilder::new()
+                .item(
+                    utoipa::openapi::ObjectBuilder::new()
+                        .schema_type(utoipa::openapi::SchemaType::String)
+                        .description(Some("A single string")),
+                )
+                .item(
+                    utoipa::openapi::ObjectBuilder::new()
+                        .property(
+                            "text",
+                            utoipa::openapi::ObjectBuilder::new()
+                                .schema_type(utoipa::openapi::SchemaType::String),
+                        )
+                        .required("text")
+                        .property(
+                            "text_pair",
+                            utoipa::openapi::ObjectBuilder::new()
+                                .schema_type(utoipa::openapi::SchemaType::String),
+                        )
+                        .required("text_pair")
+                        .description(Some("A single pair of strings")),
+                )
+                .item(
+                    utoipa::openapi::ArrayBuilder::new()
+                        .items(
+                            utoipa::openapi::OneOfBuilder::new()
+                                .item(
+                                    utoipa::openapi::ObjectBuilder::new()
+                                        .schema_type(utoipa::openapi::SchemaType::String)
+                                        .description(Some("A single string")),
+                                )
+                                .item(
+                                    utoipa::openapi::ArrayBuilder::new()
+                                        .items(
+                                            utoipa::openapi::ObjectBuilder::new()
+                                                .schema_type(utoipa::openapi::SchemaType::String),
+                                        )
+                                        .description(Some("A pair of strings"))
+                                        .min_items(Some(2))
+                                        .max_items(Some(2)),
+                                ),
+                        )
+                        .description(Some("A batch")),
+                )
+                .item(
+                    utoipa::openapi::ArrayBuilder::new()
+                        .items(
+                            utoipa::openapi::ObjectBuilder::new()
+                                .property(
+                                    "text",
+                                    utoipa::openapi::ObjectBuilder::new()
+                                        .schema_type(utoipa::openapi::SchemaType::String),
+                                )
+                                .required("text")
+                                .property(
+                                    "custom_id",
+                                    utoipa::openapi::ObjectBuilder::new()
+                                        .schema_type(utoipa::openapi::SchemaType::String),
+                                )
+                                .required("custom_id"),
+                        )
+                        .description(Some(
+                            "A batch of strings with a `custom_id` echoed back in the response",
+                        )),
+                )
+                .item(
+                    utoipa::openapi::ArrayBuilder::new()
+                        .items(
+                            utoipa::openapi::ObjectBuilder::new()
+                                .property(
+                                    "text",
+                                    utoipa::openapi::ObjectBuilder::new()
+                                        .schema_type(utoipa::openapi::SchemaType::String),
+                                )
+                                .required("text")
+                                .property(
+                                    "group_id",
+                                    utoipa::openapi::ObjectBuilder::new()
+                                        .schema_type(utoipa::openapi::SchemaType::String),
+                                )
+                                .required("group_id"),
+                        )
+                        .description(Some(
+                            "A batch of strings whose embeddings are grouped by `group_id` in the response",
+                        )),
+                )
+                .description(Some(
+                    "Model input. \
+                Can be either a single string, a single {text, text_pair} pair of strings, \
+                a batch of mixed strings and [string, string] pairs, \
+                a batch of {text, custom_id} objects or a batch of {text, group_id} objects.",
+                ))
+                .example(Some(json!("What is Deep Learning?")))
+                .into(),
+        )
+    }
+}
+
 #[derive(Deserialize, ToSchema)]
 pub(crate) struct EmbedRequest {
-    pub inputs: Input,
+    pub inputs: EmbedInput,
     #[serde(default)]
     #[schema(default = "false", example = "false")]
     pub truncate: bool,
+    /// Embed inputs longer than the maximum input length in overlapping windows of tokens instead
+    /// of truncating them. Consecutive windows share `stride` tokens and the returned embedding is
+    /// the mean of the windows embeddings. Takes precedence over `truncate` and cannot be combined
+    /// with `layer`.
+    #[serde(default)]
+    #[schema(default = "null", example = "null", nullable = true)]
+    pub stride: Option<usize>,
+    /// Pool the hidden states of an intermediate layer instead of the last one.
+    /// `0` is the output of the embeddings layer and negative values count from the last layer,
+    /// e.g. `-2` is the second to last layer. Inputs are not batched with other requests.
+    #[serde(default)]
+    #[schema(default = "null", example = "null", nullable = true)]
+    pub layer: Option<i32>,
+    /// Add the special tokens of the model, e.g. `[CLS]` and `[SEP]`, to the inputs.
+    /// Models using CLS pooling rely on them.
+    #[serde(default)]
+    #[schema(default = "true", example = "true", nullable = true)]
+    pub add_special_tokens: Option<bool>,
     #[serde(default = "default_normalize")]
     #[schema(default = "true", example = "true")]
     pub normalize: bool,
+    /// Name of the model prompt to prepend to the inputs, instead of the default prompt.
+    /// See `/info` for the available prompts.
+    #[serde(default)]
+    #[schema(default = "null", example = "null", nullable = true)]
+    pub prompt_name: Option<String>,
+    /// Prompt to prepend to the inputs, instead of the default prompt.
+    /// Set to `""` to disable the default prompt.
+    #[serde(default)]
+    #[schema(default = "null", example = "null", nullable = true)]
+    pub prompt: Option<String>,
+    /// Language token of multilingual models to prepend to the inputs, before the prompt.
+    /// See `tokenizer.language_tokens` in `/info` for the supported languages.
+    #[serde(default)]
+    #[schema(default = "null", example = "eng_Latn", nullable = true)]
+    pub language: Option<String>,
+    /// Also return summary statistics of the hidden states before pooling.
+    /// Requires the server to be started with `--allow-debug`. Not supported for streamed and
+    /// Arrow responses.
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub debug: bool,
+    /// Return the embeddings sorted by the number of tokens of their input, shortest first,
+    /// with the `index` of their input. Default to the order of the inputs.
+    /// Not supported for streamed and Arrow responses.
+    #[serde(default)]
+    #[schema(default = "null", example = "null", nullable = true)]
+    pub sort_by: Option<SortBy>,
+    /// Wrap the embeddings in an object with a `summary` of the number of inputs, truncated
+    /// inputs and tokens of the request. Not supported for streamed and Arrow responses.
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub return_summary: bool,
+    /// Only dispatch the first `priority_prefix` inputs of a batch with the priority of the
+    /// request. The remaining inputs are dispatched with a low priority, after them and after the
+    /// other high priority requests.
+    #[serde(default)]
+    #[schema(default = "null", example = "8", nullable = true)]
+    pub priority_prefix: Option<usize>,
+    /// Also return the mean of the embeddings of each group of inputs sent with a `group_id`.
+    /// The mean is normalized if `normalize` is set.
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub pool_groups: bool,
+}
+
+#[derive(Deserialize, ToSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SortBy {
+    Length,
 }
 
 fn default_normalize() -> bool {
@@ -341,18 +1175,334 @@ fn default_normalize() -> bool {
 pub(crate) struct EmbedResponse(Vec<Vec<f32>>);
 
 #[derive(Serialize, ToSchema)]
+pub(crate) struct HiddenStateSummary {
+    #[schema(example = "27.7")]
+    norm: f32,
+    #[schema(example = "-0.01")]
+    mean: f32,
+    #[schema(example = "0")]
+    nan_count: usize,
+    #[schema(example = "0")]
+    inf_count: usize,
+}
+
+impl From<HiddenStateStats> for HiddenStateSummary {
+    fn from(value: HiddenStateStats) -> Self {
+        Self {
+            norm: value.norm,
+            mean: value.mean,
+            nan_count: value.nan_count,
+            inf_count: value.inf_count,
+        }
+    }
+}
+
+/// Batch level counters of an `/embed` request
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct EmbedSummary {
+    /// Number of inputs embedded
+    #[schema(example = "32")]
+    pub inputs: usize,
+    /// Number of inputs that were truncated to the maximum input length
+    #[schema(example = "2")]
+    pub truncated: usize,
+    /// Number of tokens of all the inputs, after truncation
+    #[schema(example = "4096")]
+    pub tokens: usize,
+}
+
+/// Embeddings returned with the optional debug and summary information of the request
+#[derive(Serialize)]
+pub(crate) struct ExtendedEmbedResponse<T: Serialize> {
+    pub embeddings: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hidden_states: Option<Vec<HiddenStateSummary>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<EmbedSummary>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct IdentifiedEmbedding {
+    #[schema(example = "request-1")]
+    custom_id: String,
+    #[schema(example = json!(["0.0", "1.0", "2.0"]))]
+    embedding: Vec<f32>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct IdentifiedEmbedResponse(Vec<IdentifiedEmbedding>);
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct SortedEmbedding {
+    /// Index of the input in the request
+    #[schema(example = "0")]
+    pub index: usize,
+    #[schema(nullable = true, example = "request-1")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_id: Option<String>,
+    /// Number of tokens of the input
+    #[schema(example = "8")]
+    pub tokens: usize,
+    #[schema(example = json!(["0.0", "1.0", "2.0"]))]
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct SortedEmbedResponse(Vec<SortedEmbedding>);
+
+/// Embeddings of the inputs sharing a `group_id`
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct EmbeddingGroup {
+    #[schema(example = "document-1")]
+    pub group_id: String,
+    /// Indices of the inputs of the group in the request
+    #[schema(example = json!([0, 1]))]
+    pub indices: Vec<usize>,
+    #[schema(example = json!([["0.0", "1.0", "2.0"], ["1.0", "2.0", "3.0"]]))]
+    pub embeddings: Vec<Vec<f32>>,
+    /// Mean of the embeddings of the group, only returned with `pool_groups`
+    #[schema(nullable = true, example = json!(["0.5", "1.5", "2.5"]))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pooled: Option<Vec<f32>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct GroupedEmbedResponse(Vec<EmbeddingGroup>);
+
+impl GroupedEmbedResponse {
+    /// Group the embeddings by `group_id`. Groups are in the order of their first input.
+    pub(crate) fn new(
+        group_ids: Vec<String>,
+        response: EmbedResponse,
+        pool: bool,
+        normalize: bool,
+    ) -> Self {
+        let mut groups: Vec<EmbeddingGroup> = Vec::new();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for (index, (group_id, embedding)) in group_ids.into_iter().zip(response.0).enumerate() {
+            let position = *positions.entry(group_id.clone()).or_insert_with(|| {
+                groups.push(EmbeddingGroup {
+                    group_id,
+                    indices: Vec::new(),
+                    embeddings: Vec::new(),
+                    pooled: None,
+                });
+                groups.len() - 1
+            });
+            groups[position].indices.push(index);
+            groups[position].embeddings.push(embedding);
+        }
+
+        if pool {
+            for group in groups.iter_mut() {
+                let n = group.embeddings.len() as f32;
+                let mut pooled = vec![0.0; group.embeddings[0].len()];
+                for embedding in &group.embeddings {
+                    for (acc, v) in pooled.iter_mut().zip(embedding) {
+                        *acc += v / n;
+                    }
+                }
+                if normalize {
+                    normalize_embedding(&mut pooled);
+                }
+                group.pooled = Some(pooled);
+            }
+        }
+        Self(groups)
+    }
+}
+
+impl SortedEmbedResponse {
+    /// Sort the embeddings by the number of tokens of their input.
+    /// Inputs with the same number of tokens keep their request order.
+    pub(crate) fn by_length(
+        response: EmbedResponse,
+        tokens: Vec<usize>,
+        custom_ids: Option<Vec<String>>,
+    ) -> Self {
+        let mut custom_ids = custom_ids.map(|ids| ids.into_iter());
+        let mut embeddings: Vec<SortedEmbedding> = response
+            .0
+            .into_iter()
+            .zip(tokens)
+            .enumerate()
+            .map(|(index, (embedding, tokens))| SortedEmbedding {
+                index,
+                custom_id: custom_ids.as_mut().and_then(|ids| ids.next()),
+                tokens,
+                embedding,
+            })
+            .collect();
+        embeddings.sort_by_key(|e| e.tokens);
+        Self(embeddings)
+    }
+}
+
+impl IdentifiedEmbedResponse {
+    pub(crate) fn new(custom_ids: Vec<String>, response: EmbedResponse) -> Self {
+        Self(
+            custom_ids
+                .into_iter()
+                .zip(response.0)
+                .map(|(custom_id, embedding)| IdentifiedEmbedding {
+                    custom_id,
+                    embedding,
+                })
+                .collect(),
+        )
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct EmbedProgress {
+    #[schema(example = "8")]
+    pub completed: usize,
+    #[schema(example = "32")]
+    pub total: usize,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct SimilarityMatrixRequest {
+    #[schema(example = json!(["What is Deep Learning?", "Deep Learning is not..."]))]
+    pub inputs: Vec<String>,
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub truncate: bool,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct EmbedAllModesRequest {
+    pub inputs: Input,
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub truncate: bool,
+    /// Normalize the dense embeddings. The ColBERT vectors are always normalized.
+    #[serde(default = "default_normalize")]
+    #[schema(default = "true", example = "true")]
+    pub normalize: bool,
+    /// Drop the sparse weights below this threshold
+    #[serde(default)]
+    #[schema(default = "null", example = "0.1", nullable = true)]
+    pub prune_threshold: Option<f32>,
+    /// Return the token of each sparse weight along with its id
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub return_tokens: bool,
+}
+
+/// Weight of a token of the input
+#[derive(Serialize, ToSchema)]
+pub(crate) struct SparseValue {
+    /// Token id
+    #[schema(example = "2054")]
+    pub index: u32,
+    #[schema(example = "0.25")]
+    pub value: f32,
+    /// Token string, if `return_tokens` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "what")]
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct AllModesEmbedding {
+    #[schema(example = json!([0.0, 1.0, 2.0]))]
+    pub dense: Vec<f32>,
+    pub sparse: Vec<SparseValue>,
+    /// One vector per token of the input
+    #[schema(example = json!([[0.0, 1.0, 2.0]]))]
+    pub colbert: Vec<Vec<f32>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct EmbedAllModesResponse(Vec<AllModesEmbedding>);
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct EmbedNgramsRequest {
+    /// Document to embed. Its length is limited to the maximum input length of the model.
+    #[schema(example = "Deep Learning is a subset of Machine Learning")]
+    pub inputs: String,
+    /// Number of tokens of each window. Consecutive windows start one token apart.
+    #[schema(example = "3")]
+    pub ngram_size: usize,
+    /// Add the special tokens of the model, e.g. `[CLS]` and `[SEP]`, to each window.
+    #[serde(default)]
+    #[schema(default = "true", example = "true", nullable = true)]
+    pub add_special_tokens: Option<bool>,
+    #[serde(default = "default_normalize")]
+    #[schema(default = "true", example = "true")]
+    pub normalize: bool,
+}
+
+/// Embedding of a window of tokens of the document
+#[derive(Serialize, ToSchema)]
+pub(crate) struct NgramEmbedding {
+    /// Index of the first token of the window in the document
+    #[schema(example = "0")]
+    pub start: usize,
+    /// Index of the token following the window
+    #[schema(example = "3")]
+    pub end: usize,
+    #[schema(example = json!([0.0, 1.0, 2.0]))]
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct EmbedNgramsResponse(Vec<NgramEmbedding>);
+
+/// Pairwise cosine similarities: row `i` contains the similarities of input `i` with all inputs
+#[derive(Serialize, ToSchema)]
+#[schema(example = json!([[1.0, 0.8], [0.8, 1.0]]))]
+pub(crate) struct SimilarityMatrixResponse(Vec<Vec<f32>>);
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct HealthParams {
+    #[serde(default)]
+    pub detail: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct HealthDetail {
+    #[schema(example = "true")]
+    pub healthy: bool,
+    #[schema(example = "thenlper/gte-base")]
+    pub model_id: String,
+    #[schema(example = "0")]
+    pub queue_size: usize,
+    #[schema(example = "0")]
+    pub in_flight_requests: usize,
+    #[schema(example = "512")]
+    pub max_concurrent_requests: usize,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
 pub(crate) enum ErrorType {
     Unhealthy,
+    Unauthorized,
     Backend,
     Overloaded,
     Validation,
     Tokenizer,
+    Conflict,
+    Timeout,
 }
 
 #[derive(Serialize, ToSchema)]
 pub(crate) struct ErrorResponse {
     pub error: String,
     pub error_type: ErrorType,
+    /// Errors of each invalid input of a batch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, default = "null")]
+    pub details: Option<Vec<ErrorDetail>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ErrorDetail {
+    #[schema(example = "0")]
+    pub index: usize,
+    pub error: String,
+    pub error_type: ErrorType,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -1,8 +1,8 @@
 use anyhow::{anyhow, Context, Result};
 use axum::http::HeaderValue;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use hf_hub::api::tokio::ApiBuilder;
-use hf_hub::{Repo, RepoType};
+use hf_hub::{Cache, Repo, RepoType};
 use opentelemetry::sdk::propagation::TraceContextPropagator;
 use opentelemetry::sdk::trace::Sampler;
 use opentelemetry::sdk::{trace, Resource};
@@ -11,16 +11,31 @@ use opentelemetry_otlp::WithExportConfig;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs;
+use std::io::{BufRead, Read};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::Path;
-use text_embeddings_backend::DType;
-use text_embeddings_core::download::{download_artifacts, download_pool_config};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use text_embeddings_backend::{
+    Attention, ConfigOverride, DType, DenseNormalization, ModelLoadError, OutputDType,
+};
+use text_embeddings_core::download::{
+    download_artifacts, download_dense_modules, download_m3_heads, download_pool_config,
+    download_st_config, download_tokenizer_config, download_whitening, optional, Download,
+    DownloadLimits,
+};
 use text_embeddings_core::infer::Infer;
 use text_embeddings_core::queue::Queue;
-use text_embeddings_core::tokenization::Tokenization;
-use text_embeddings_router::{server, ClassifierModel, EmbeddingModel, Info, ModelType};
-use tokenizers::decoders::metaspace::PrependScheme;
-use tokenizers::{PreTokenizerWrapper, Tokenizer};
+use text_embeddings_core::tokenization::{
+    reconcile_max_input_length, set_metaspace_prepend, tokenizer_from_json, DoLowerCase, Lowercase,
+    MetaspacePrepend, SpecialTokens, Tokenization, UnicodeNormalization,
+};
+#[cfg(feature = "shm")]
+use text_embeddings_router::shm::ShmRing;
+use text_embeddings_router::{
+    bench, self_test, server, ClassifierModel, EffectiveConfig, EmbeddingModel, Info, ModelType,
+    ProblemType, TokenizerInfo,
+};
+use tokenizers::Tokenizer;
 use tower_http::cors::AllowOrigin;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
@@ -45,6 +60,12 @@ struct Args {
     #[clap(long, env)]
     revision: Option<String>,
 
+    /// Optionally use this serialized tokenizer, i.e. the content of a `tokenizer.json` file,
+    /// instead of the `tokenizer.json` file of the model.
+    #[clap(long, env)]
+    #[redact(partial)]
+    tokenizer_json: Option<String>,
+
     /// Optionally control the number of tokenizer workers used for payload tokenization, validation
     /// and truncation.
     /// Default to the number of CPU cores on the machine.
@@ -55,6 +76,51 @@ struct Args {
     #[clap(long, env, value_enum)]
     dtype: Option<DType>,
 
+    /// The precision of the returned embeddings, independent of `dtype`.
+    ///
+    /// Embeddings are always computed in `dtype`. They are rounded to `output_dtype` by the router as
+    /// the last step before serialization, after the normalization, the `dimensions` truncation
+    /// and the rounding to `embedding_precision`. `float32` keeps all the precision of the model
+    /// outputs, even for models running in `float16`. `float16` and `bfloat16` have a relative
+    /// rounding error of up to 0.05% and 0.4% respectively, and values larger than 65504 overflow
+    /// to infinity in `float16`.
+    #[clap(default_value = "float32", long, env, value_enum)]
+    output_dtype: OutputDType,
+
+    /// The attention implementation of the model.
+    ///
+    /// `auto` picks flash attention when the hardware, dtype and model support it and falls back
+    /// to `eager`. `sdpa` is only supported by the Python backend.
+    #[clap(default_value = "auto", long, env, value_enum)]
+    attention: Attention,
+
+    /// The number of model instances to load.
+    ///
+    /// Each replica runs its own batches concurrently. This increases the throughput on machines
+    /// with many CPU cores for models that do not parallelize well internally.
+    /// Replicas are not pinned to a subset of the cores: they share the inference thread pool and
+    /// the operating system schedules them freely.
+    /// Only supported by the candle backend.
+    #[clap(default_value = "1", long, env)]
+    num_model_replicas: usize,
+
+    /// Optionally control the number of threads used by the forward passes on CPU.
+    ///
+    /// Sets `RAYON_NUM_THREADS` for the candle backend and `OMP_NUM_THREADS` for the Python
+    /// backend. The model replicas share this single pool of threads: keep `inference-threads`
+    /// at or below the number of physical cores, and leave some cores to the tokenization workers.
+    /// Default to the number of CPU cores on the machine.
+    #[clap(long, env)]
+    inference_threads: Option<usize>,
+
+    /// Lock the memory of the process in RAM once the model is loaded, so that the pages of the
+    /// model weights are never swapped out or evicted under memory pressure.
+    ///
+    /// The memory locked by the process is limited by `RLIMIT_MEMLOCK`: in Docker, run the
+    /// container with `--cap-add IPC_LOCK --ulimit memlock=-1`. Only supported on Unix.
+    #[clap(long, env)]
+    mlock: bool,
+
     /// Optionally control the pooling method for embedding models.
     ///
     /// If `pooling` is not set, the pooling configuration will be parsed from the
@@ -64,6 +130,88 @@ struct Args {
     #[clap(long, env, value_enum)]
     pooling: Option<text_embeddings_backend::Pool>,
 
+    /// The pooling method used when `pooling` is not set and the model pooling configuration is
+    /// missing or not supported.
+    ///
+    /// If `fallback_pooling` is not set, BERT-family models use `cls` pooling if their weights
+    /// include a pooler and `mean` pooling otherwise. The other models use `mean` pooling.
+    #[clap(long, env, value_enum)]
+    fallback_pooling: Option<text_embeddings_backend::Pool>,
+
+    /// Where the pooled embeddings are L2 normalized relative to the `Dense` projection of
+    /// sentence-transformers models.
+    ///
+    /// `auto` follows the order of the `Normalize` and `Dense` modules of the model
+    /// `modules.json`. `before` normalizes the pooled embeddings before projecting them.
+    /// `after` projects the pooled embeddings and only normalizes them if the request asks
+    /// for it. Using the wrong order changes the embeddings.
+    #[clap(default_value = "auto", long, env, value_enum)]
+    dense_normalization: DenseNormalization,
+
+    /// Optionally check at startup that the embeddings of the model are of this dimension and
+    /// exit otherwise, e.g. to avoid serving a 384 dimensions model to a 768 dimensions vector
+    /// index. The dimension is the one of the embeddings returned by the model, after its `Dense`
+    /// projection or whitening transform if any.
+    #[clap(long, env)]
+    expect_dimension: Option<usize>,
+
+    /// Optionally patch the model `config.json` before the model is built, e.g.
+    /// `--config-override '{"max_position_embeddings": 512}'` to fix a wrong field.
+    ///
+    /// The JSON object is deep-merged into the config: nested objects are merged, `null` removes a
+    /// field and any other value replaces it. The changed fields are logged at startup.
+    #[clap(long, env)]
+    config_override: Option<ConfigOverride>,
+
+    /// Optionally restrict the models the server loads to these architectures, e.g.
+    /// `--allowed-architectures bert,xlm-roberta`.
+    ///
+    /// A model is allowed if its `model_type` or one of its `architectures` in `config.json`,
+    /// e.g. `BertModel`, is in the list. Other models are refused at startup.
+    /// Default to allow all architectures.
+    #[clap(long, env, value_delimiter = ',')]
+    allowed_architectures: Option<Vec<String>>,
+
+    /// Optionally apply a Unicode normalization form to the inputs before tokenization.
+    ///
+    /// Most tokenizers already normalize their inputs but some don't. Setting this
+    /// guarantees that the same text always produces the same embedding, whatever its
+    /// Unicode representation.
+    #[clap(default_value = "none", long, env, value_enum)]
+    normalize_unicode: UnicodeNormalization,
+
+    /// Lowercase the inputs and strip their accents before tokenization, as uncased models
+    /// expect.
+    ///
+    /// `auto` lowercases when the `tokenizer_config.json` of the model sets `do_lower_case` but the
+    /// normalizer of its `tokenizer.json` does not lowercase. Accents are kept if
+    /// `tokenizer_config.json` sets `strip_accents` to false.
+    #[clap(default_value = "auto", long, env, value_enum)]
+    do_lower_case: DoLowerCase,
+
+    /// The prepend scheme of the Metaspace pre-tokenizer of SentencePiece-based tokenizers.
+    ///
+    /// `first` only prepends the replacement character to the first section of the input and
+    /// matches the behaviour of most models. Some models need `always` or `never`.
+    #[clap(default_value = "first", long, env, value_enum)]
+    metaspace_prepend: MetaspacePrepend,
+
+    /// Optionally apply a template to the inputs before tokenization.
+    /// The template must contain a `{query}` placeholder that is replaced by the input.
+    /// For a pair of sequences, only the first sequence is templated.
+    ///
+    /// This is useful for instruction-tuned models, for example:
+    /// `Instruct: Given a web search query, retrieve relevant passages\nQuery: {query}`
+    #[clap(long, env)]
+    input_template: Option<String>,
+
+    /// Optionally override the maximum number of tokens of an input.
+    ///
+    /// By default, this is derived from the model `max_position_embeddings`. It can only be set
+    /// above this value for models that support position interpolation (alibi or RoPE scaling).
+    #[clap(long, env)]
+    max_input_length: Option<usize>,
+
     /// The maximum amount of concurrent requests for this particular deployment.
     /// Having a low limit will refuse clients requests instead of having them
     /// wait for too long and is usually good to handle backpressure correctly.
@@ -84,19 +232,146 @@ struct Args {
     #[clap(default_value = "16384", long, env)]
     max_batch_tokens: usize,
 
-    /// Optionally control the maximum number of individual requests in a batch
+    /// Optionally control the maximum number of individual requests in a batch.
+    ///
+    /// Some backends only support a limited batch size. In that case, this value
+    /// defaults to the backend maximum and cannot be set above it.
     #[clap(long, env)]
     max_batch_requests: Option<usize>,
 
+    /// Optionally split the batches sent to the backend in sequential forward passes of at most
+    /// `microbatch_size` inputs, independently of the number of requests batched together.
+    /// This lowers the peak memory use of the model, e.g. for long sequences, at the cost of
+    /// throughput.
+    #[clap(long, env)]
+    microbatch_size: Option<usize>,
+
     /// Control the maximum number of inputs that a client can send in a single request
     #[clap(default_value = "32", long, env)]
     max_client_batch_size: usize,
 
+    /// Optionally round the returned embeddings to this number of significant digits.
+    /// This can greatly reduce the size of the JSON responses.
+    /// Default to full precision.
+    #[clap(long, env)]
+    embedding_precision: Option<usize>,
+
+    /// Optionally reject embedding requests whose JSON response is estimated to be larger than
+    /// this number of bytes. The estimate is computed from the number of inputs and the model
+    /// embedding dimension before running the model.
+    /// Default to no limit.
+    #[clap(long, env)]
+    max_response_bytes: Option<usize>,
+
+    /// The number of consecutive backend failures after which the service reports itself as
+    /// unhealthy on `/health`. The service becomes healthy again once a health check
+    /// successfully runs the model on a test batch.
+    /// Set to `0` to disable the circuit breaker.
+    #[clap(default_value = "10", long, env)]
+    circuit_breaker_threshold: usize,
+
+    /// Optionally hold the requests received while the circuit breaker is open for up to
+    /// `unhealthy_grace_ms` milliseconds, probing the backend until it recovers, instead of
+    /// running them right away. Requests still waiting after the grace period fail with a 503
+    /// `unhealthy` error.
+    #[clap(long, env)]
+    unhealthy_grace_ms: Option<u64>,
+
+    /// Fail requests with a backend error when the model outputs contain NaN or Inf values
+    /// instead of returning them to the client.
+    #[clap(long, env)]
+    reject_nan: bool,
+
+    /// Allow clients to request summary statistics of the hidden states before pooling
+    /// with `debug: true` on `/embed`.
+    /// This runs the model a second time for each input and should only be used when
+    /// bringing up a new model.
+    #[clap(long, env)]
+    allow_debug: bool,
+
+    /// Optionally run a tiny inference every `keep_warm_interval` seconds while the server is
+    /// idle. Some backends reclaim memory or kernels after long idle periods, making the next
+    /// request slow.
+    /// Disabled by default.
+    #[clap(long, env)]
+    keep_warm_interval: Option<u64>,
+
+    /// Optionally only admit a tenth of `max_concurrent_requests` once the model is ready and
+    /// ramp up linearly to all of them over `slow_start_duration` seconds. This avoids
+    /// overwhelming the backend with the requests queued up by clients during startup.
+    /// Disabled by default.
+    #[clap(long, env)]
+    slow_start_duration: Option<u64>,
+
+    /// Optionally warm up the model at startup with the inputs of this newline-delimited file,
+    /// e.g. a sample of the production traffic. The inputs are tokenized and embedded (or
+    /// classified) before the server starts. The inputs that fail are logged.
+    #[clap(long, env)]
+    warmup_file: Option<PathBuf>,
+
+    /// The maximum number of inputs of `warmup_file` run at startup. The other lines are ignored.
+    #[clap(default_value = "256", long, env)]
+    warmup_max_inputs: usize,
+
+    /// Maximum delay in seconds of the `Retry-After` header of overloaded (429) responses.
+    /// The delay is estimated from the queue size and the recent backend throughput.
+    #[clap(default_value = "30", long, env)]
+    max_retry_after: u64,
+
+    /// Optionally keep the successful responses of the requests sent with an `Idempotency-Key`
+    /// header for `idempotency_ttl` seconds. Retries with the same key and body get the stored
+    /// response instead of running the model again.
+    /// Disabled by default.
+    #[clap(long, env)]
+    idempotency_ttl: Option<u64>,
+
+    /// Renormalize the softmax probabilities returned by `/predict` so that they sum to exactly
+    /// 1.0 within float precision. Floating point errors can otherwise produce sums such as
+    /// 0.9999994.
+    #[clap(long, env)]
+    renormalize_probabilities: bool,
+
+    /// Return the raw scores of `/predict` instead of the softmax probabilities when the request
+    /// does not set `raw_scores`.
+    #[clap(long, env)]
+    default_raw_scores: bool,
+
+    /// Apply a sigmoid to the scores of regression models, e.g. cross-encoders trained to score
+    /// the similarity of a pair between 0 and 1. Regression models return their raw score by
+    /// default.
+    #[clap(long, env)]
+    regression_sigmoid: bool,
+
+    /// Accept `texts` as an alias of the `input` field of the OpenAI compatible `/embeddings`
+    /// route, for the clients and proxies that send it instead.
+    #[clap(long, env)]
+    openai_accept_texts: bool,
+
+    /// Truncate the inputs of the OpenAI compatible `/embeddings` route that are longer than the
+    /// model maximum input length instead of rejecting them. The `prompt_tokens` of the usage
+    /// count the tokens after truncation.
+    #[clap(long, env)]
+    auto_truncate: bool,
+
+    /// Optionally reject the `/predict` pairs whose first text is more than
+    /// `max_pair_length_ratio` times longer, in characters, than the second one. For reranking,
+    /// this usually means that the query and the document were swapped.
+    /// Pairs with an empty text are always rejected.
+    #[clap(long, env)]
+    max_pair_length_ratio: Option<f32>,
+
     /// Your HuggingFace hub token
     #[clap(long, env)]
     #[redact(partial)]
     hf_api_token: Option<String>,
 
+    /// Optionally set a token that enables the `GET /config` route.
+    /// The route returns the effective runtime configuration to clients sending this token
+    /// in an `Authorization: Bearer` header. The same configuration is always logged at startup.
+    #[clap(long, env)]
+    #[redact]
+    admin_token: Option<String>,
+
     /// The IP address to listen on
     #[clap(default_value = "0.0.0.0", long, env)]
     hostname: String,
@@ -105,6 +380,42 @@ struct Args {
     #[clap(default_value = "3000", long, short, env)]
     port: u16,
 
+    /// Optionally limit the number of open client connections.
+    /// New connections are closed as soon as they are accepted while `max_connections`
+    /// connections are open, instead of piling up and exhausting the file descriptors of the
+    /// process. The number of open connections is reported by the `te_connections` metric.
+    #[clap(long, env)]
+    max_connections: Option<usize>,
+
+    /// Maximum time in seconds to receive the headers of a request, and then its whole body.
+    /// Connections of clients sending their headers too slowly are closed and requests whose
+    /// body is not received in time fail with a 408 error.
+    #[clap(default_value = "60", long, env)]
+    http_read_timeout: u64,
+
+    /// Maximum time in seconds a response write can stay blocked because the client does not
+    /// read it. The connection is closed when it expires.
+    #[clap(default_value = "60", long, env)]
+    http_write_timeout: u64,
+
+    /// Maximum size in bytes of a request body. Larger requests fail with a 413 error.
+    #[clap(default_value = "2000000", long, env)]
+    payload_limit: usize,
+
+    /// Optionally write the embeddings of the `/embed` requests sent with an
+    /// `Accept: application/vnd.tei.shm+json` header to a shared memory ring buffer at this path,
+    /// e.g. `/dev/shm/tei`. The response only contains the location of the embeddings in the
+    /// buffer, for clients running on the same host.
+    ///
+    /// Requires a build with the `shm` feature.
+    #[clap(long, env)]
+    shm: Option<PathBuf>,
+
+    /// The size in bytes of the `--shm` ring buffer. The embeddings of a request must fit in it,
+    /// and are overwritten once it wraps around.
+    #[clap(default_value = "268435456", long, env)]
+    shm_size: u64,
+
     /// The name of the unix socket some text-embeddings-inference backends will use as they
     /// communicate internally with gRPC.
     #[clap(default_value = "/tmp/text-embeddings-inference-server", long, env)]
@@ -115,13 +426,71 @@ struct Args {
     #[clap(long, env)]
     huggingface_hub_cache: Option<String>,
 
-    /// Outputs the logs in JSON format (useful for telemetry)
+    /// Optionally abort the model download if a file is larger than this number of bytes.
+    /// The download is also aborted if the size of a file cannot be determined.
+    /// Files already in the cache are not checked.
+    /// Default to no limit.
+    #[clap(long, env)]
+    max_download_file_size: Option<u64>,
+
+    /// Optionally abort the model download if it takes longer than this number of seconds.
+    /// Default to no timeout.
+    #[clap(long, env)]
+    download_timeout: Option<u64>,
+
+    /// Outputs the logs in JSON format (useful for telemetry).
+    /// Shorthand for `--log-format json`.
     #[clap(long, env)]
     json_output: bool,
 
+    /// The format of the logs.
+    ///
+    /// The log levels can be set per module with the `LOG_LEVEL` or `RUST_LOG`
+    /// environment variables, using the `tracing_subscriber::EnvFilter` syntax
+    /// (e.g. `info,text_embeddings_core=debug`).
+    #[clap(default_value = "text", long, env, value_enum)]
+    log_format: LogFormat,
+
     #[clap(long, env)]
     otlp_endpoint: Option<String>,
 
+    /// Run a benchmark of the model instead of starting the server.
+    ///
+    /// Synthetic inputs are sent through the full inference stack for each client batch size
+    /// in `--bench-batch-sizes`. Throughput (tokens/s) and p50/p95/p99 latencies are reported
+    /// for each of them.
+    #[clap(long, env)]
+    bench: bool,
+
+    /// The number of tokens of the synthetic benchmark inputs
+    #[clap(default_value = "128", long, env)]
+    bench_sequence_length: usize,
+
+    /// The client batch sizes to benchmark
+    #[clap(default_value = "1,8,32", long, env, value_delimiter = ',')]
+    bench_batch_sizes: Vec<usize>,
+
+    /// The number of client batches sent for each benchmarked batch size
+    #[clap(default_value = "20", long, env)]
+    bench_iterations: usize,
+
+    /// Run a self-test of the model instead of starting the server.
+    ///
+    /// A few fixed sentences are embedded (or classified) and the outputs are checked to be
+    /// finite and of the expected dimension. The process exits with a non-zero code if a check
+    /// fails.
+    #[clap(long, env)]
+    self_test: bool,
+
+    /// Optional JSON file with the reference outputs of the self-test sentences, one array per
+    /// sentence. The outputs must match them within `--self-test-tolerance`.
+    #[clap(long, env)]
+    self_test_reference: Option<PathBuf>,
+
+    /// The maximum absolute difference between a self-test output value and its reference
+    #[clap(default_value = "0.001", long, env)]
+    self_test_tolerance: f32,
+
     #[clap(long, env)]
     cors_allow_origin: Option<Vec<String>>,
 }
@@ -132,9 +501,20 @@ pub struct ModelConfig {
     pub model_type: String,
     #[serde(alias = "n_positions")]
     pub max_position_embeddings: usize,
+    pub hidden_size: usize,
     pub pad_token_id: usize,
     pub id2label: Option<HashMap<String, String>>,
     pub label2id: Option<HashMap<String, usize>>,
+    pub problem_type: Option<String>,
+    pub position_embedding_type: Option<String>,
+    pub rope_scaling: Option<serde_json::Value>,
+}
+
+impl ModelConfig {
+    /// Alibi and scaled RoPE models can run on inputs longer than `max_position_embeddings`
+    fn supports_position_interpolation(&self) -> bool {
+        self.position_embedding_type.as_deref() == Some("alibi") || self.rope_scaling.is_some()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize)]
@@ -145,13 +525,212 @@ pub struct PoolConfig {
     pooling_mode_mean_sqrt_len_tokens: bool,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+/// Sharded safetensors weights index
+#[derive(Deserialize)]
+struct SafetensorsIndex {
+    weight_map: HashMap<String, String>,
+}
+
+/// Maximum size of a safetensors header read to detect the model pooler
+const MAX_SAFETENSORS_HEADER_SIZE: usize = 100_000_000;
+
+/// `config_sentence_transformers.json`
+#[derive(Debug, Deserialize)]
+pub struct STConfig {
+    #[serde(default)]
+    prompts: HashMap<String, String>,
+    default_prompt_name: Option<String>,
+}
+
+/// Pooling method of the model `1_Pooling/config.json`.
+/// Returns why it could not be detected otherwise.
+fn model_pooling(model_root: &Path) -> Result<text_embeddings_backend::Pool, String> {
+    let config = fs::read_to_string(model_root.join("1_Pooling/config.json"))
+        .map_err(|_| "the model does not have a pooling configuration (`1_Pooling/config.json`)")?;
+    let config: PoolConfig = serde_json::from_str(&config)
+        .map_err(|err| format!("the model `1_Pooling/config.json` is invalid: {err}"))?;
+    if config.pooling_mode_cls_token {
+        Ok(text_embeddings_backend::Pool::Cls)
+    } else if config.pooling_mode_mean_tokens {
+        Ok(text_embeddings_backend::Pool::Mean)
+    } else {
+        Err(format!(
+            "the model pooling config {config:?} is not supported"
+        ))
+    }
+}
+
+/// Whether the weights of the model include a pooler, according to the tensor names of the
+/// safetensors weights. Returns `None` if the weights are not in the safetensors format.
+fn has_pooler(model_root: &Path) -> Option<bool> {
+    let is_pooler = |name: &String| name.ends_with("pooler.dense.weight");
+
+    if let Ok(mut file) = fs::File::open(model_root.join("model.safetensors")) {
+        // The header is a JSON object of the tensors, prefixed by its size as a little endian u64
+        let mut size = [0u8; 8];
+        file.read_exact(&mut size).ok()?;
+        let size = usize::try_from(u64::from_le_bytes(size)).ok()?;
+        if size > MAX_SAFETENSORS_HEADER_SIZE {
+            return None;
+        }
+        let mut header = vec![0u8; size];
+        file.read_exact(&mut header).ok()?;
+        let tensors: HashMap<String, serde_json::Value> = serde_json::from_slice(&header).ok()?;
+        return Some(tensors.keys().any(is_pooler));
+    }
+
+    let index = fs::read_to_string(model_root.join("model.safetensors.index.json")).ok()?;
+    let index: SafetensorsIndex = serde_json::from_str(&index).ok()?;
+    Some(index.weight_map.keys().any(is_pooler))
+}
+
+/// Pooling method of the models without a pooling configuration, and why it was chosen.
+/// BERT-family models whose weights include a pooler were usually trained on their CLS token.
+fn default_pooling(
+    model_type: &str,
+    has_pooler: Option<bool>,
+) -> (text_embeddings_backend::Pool, &'static str) {
+    let bert_family = matches!(
+        model_type,
+        "bert" | "roberta" | "xlm-roberta" | "camembert" | "mpnet"
+    );
+    match (bert_family, has_pooler) {
+        (true, Some(true)) => (
+            text_embeddings_backend::Pool::Cls,
+            "as the model weights include a pooler",
+        ),
+        (true, Some(false)) => (
+            text_embeddings_backend::Pool::Mean,
+            "as the model weights do not include a pooler",
+        ),
+        _ => (text_embeddings_backend::Pool::Mean, "by default"),
+    }
+}
+
+/// Read the first `max_inputs` non-empty lines of a warmup file, with their line number
+fn read_warmup_file(path: &Path, max_inputs: usize) -> Result<Vec<(usize, String)>> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("Could not open the warmup file `{}`", path.display()))?;
+    let mut inputs = Vec::new();
+    for (index, line) in std::io::BufReader::new(file).lines().enumerate() {
+        if inputs.len() == max_inputs {
+            break;
+        }
+        let line =
+            line.with_context(|| format!("Could not read the warmup file `{}`", path.display()))?;
+        if !line.trim().is_empty() {
+            inputs.push((index + 1, line));
+        }
+    }
+    if inputs.is_empty() {
+        return Err(anyhow!("The warmup file `{}` is empty", path.display()));
+    }
+    Ok(inputs)
+}
+
+/// Whether the `model_type` or one of the `architectures` of a model is in the allowlist
+fn is_allowed_architecture(model_type: &str, architectures: &[String], allowed: &[String]) -> bool {
+    allowed
+        .iter()
+        .any(|allowed| allowed == model_type || architectures.contains(allowed))
+}
+
+fn main() -> Result<()> {
     // Pattern match configuration
     let args: Args = Args::parse();
 
+    // Read when the backends start their thread pools. The environment can only be modified
+    // safely while the process is single threaded: before the runtime and the tokenization
+    // workers start their threads
+    if let Some(inference_threads) = args.inference_threads {
+        std::env::set_var("RAYON_NUM_THREADS", inference_threads.to_string());
+        std::env::set_var("OMP_NUM_THREADS", inference_threads.to_string());
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(args))
+}
+
+async fn run(args: Args) -> Result<()> {
+    if args.embedding_precision == Some(0) {
+        return Err(anyhow!("`--embedding-precision` must be greater than 0"));
+    }
+    if let Some(input_template) = &args.input_template {
+        if !input_template.contains("{query}") {
+            return Err(anyhow!(
+                "`--input-template` must contain a `{{query}}` placeholder"
+            ));
+        }
+    }
+    if args.num_model_replicas == 0 {
+        return Err(anyhow!("`--num-model-replicas` must be greater than 0"));
+    }
+    if args.inference_threads == Some(0) {
+        return Err(anyhow!("`--inference-threads` must be greater than 0"));
+    }
+    if args.microbatch_size == Some(0) {
+        return Err(anyhow!("`--microbatch-size` must be greater than 0"));
+    }
+    if args.max_batch_requests == Some(0) {
+        return Err(anyhow!("`--max-batch-requests` must be greater than 0"));
+    }
+    if args.warmup_max_inputs == 0 {
+        return Err(anyhow!("`--warmup-max-inputs` must be greater than 0"));
+    }
+    let warmup_inputs = args
+        .warmup_file
+        .as_deref()
+        .map(|path| read_warmup_file(path, args.warmup_max_inputs))
+        .transpose()?;
+    if args.keep_warm_interval == Some(0) {
+        return Err(anyhow!("`--keep-warm-interval` must be greater than 0"));
+    }
+    if args.slow_start_duration == Some(0) {
+        return Err(anyhow!("`--slow-start-duration` must be greater than 0"));
+    }
+    if args.unhealthy_grace_ms.is_some() && args.circuit_breaker_threshold == 0 {
+        return Err(anyhow!(
+            "`--unhealthy-grace-ms` requires the circuit breaker: `--circuit-breaker-threshold` must be greater than 0"
+        ));
+    }
+    if args.max_retry_after == 0 {
+        return Err(anyhow!("`--max-retry-after` must be greater than 0"));
+    }
+    if args.idempotency_ttl == Some(0) {
+        return Err(anyhow!("`--idempotency-ttl` must be greater than 0"));
+    }
+    if args
+        .max_pair_length_ratio
+        .is_some_and(|ratio| ratio.is_nan() || ratio <= 0.0)
+    {
+        return Err(anyhow!("`--max-pair-length-ratio` must be greater than 0"));
+    }
+    if args.self_test_tolerance.is_nan() || args.self_test_tolerance < 0.0 {
+        return Err(anyhow!("`--self-test-tolerance` must be positive"));
+    }
+    if args.shm.is_some() && !cfg!(feature = "shm") {
+        return Err(anyhow!(
+            "`--shm` requires the router to be built with the `shm` feature"
+        ));
+    }
+    if args.http_read_timeout == 0 {
+        return Err(anyhow!("`--http-read-timeout` must be greater than 0"));
+    }
+    if args.http_write_timeout == 0 {
+        return Err(anyhow!("`--http-write-timeout` must be greater than 0"));
+    }
+    if args.max_connections == Some(0) {
+        return Err(anyhow!("`--max-connections` must be greater than 0"));
+    }
+
     // Initialize loggin and telemetry
-    init_logging(args.otlp_endpoint.clone(), args.json_output);
+    let log_format = match args.json_output {
+        true => LogFormat::Json,
+        false => args.log_format.clone(),
+    };
+    init_logging(args.otlp_endpoint.clone(), log_format);
 
     tracing::info!("{args:?}");
 
@@ -160,38 +739,93 @@ async fn main() -> Result<()> {
         // Using a local model
         model_id_path.to_path_buf()
     } else {
-        let mut builder = ApiBuilder::new()
-            .with_progress(false)
-            .with_token(args.hf_api_token);
-
-        if let Some(cache_dir) = args.huggingface_hub_cache {
-            builder = builder.with_cache_dir(cache_dir.into());
-        }
+        let download_limits = DownloadLimits {
+            max_file_size: args.max_download_file_size,
+            timeout: args.download_timeout.map(Duration::from_secs),
+            token: args.hf_api_token.clone(),
+        };
 
-        let api = builder.build().unwrap();
-        let api_repo = api.repo(Repo::with_revision(
+        let cache = args
+            .huggingface_hub_cache
+            .map(|cache_dir| Cache::new(cache_dir.into()))
+            .unwrap_or_default();
+        let repo = Repo::with_revision(
             args.model_id.clone(),
             RepoType::Model,
             args.revision.clone().unwrap_or("main".to_string()),
-        ));
+        );
+
+        let api = ApiBuilder::from_cache(cache.clone())
+            .with_progress(false)
+            .with_token(args.hf_api_token)
+            .build()
+            .unwrap();
+        let api_repo = api.repo(repo.clone());
+
+        // The optional files below count towards the download limits as well
+        let mut download = Download::new(&api_repo, cache.repo(repo), &download_limits);
+        let context = "Could not download model artifacts";
 
         // Optionally download the pooling config.
         if args.pooling.is_none() {
             // If a pooling config exist, download it
-            let _ = download_pool_config(&api_repo).await;
+            optional(download_pool_config(&mut download).await).context(context)?;
         }
 
+        // If a sentence-transformers config exist, download it for its default prompts
+        optional(download_st_config(&mut download).await).context(context)?;
+
+        // If a tokenizer config exist, download it to check its `model_max_length`
+        optional(download_tokenizer_config(&mut download).await).context(context)?;
+
+        // If the model has sparse and ColBERT heads, download them for `/embed_all_modes`
+        optional(download_m3_heads(&mut download).await).context(context)?;
+
+        // If the model has sentence-transformers `Dense` projections, download them
+        optional(download_dense_modules(&mut download).await).context(context)?;
+
+        // If the model has a whitening transform, download it
+        optional(download_whitening(&mut download).await).context(context)?;
+
         // Download model from the Hub
-        download_artifacts(&api_repo)
-            .await
-            .context("Could not download model artifacts")?
+        download_artifacts(&mut download).await.context(context)?
     };
 
     // Load config
     let config_path = model_root.join("config.json");
-    let config = fs::read_to_string(config_path).context("`config.json` not found")?;
-    let config: ModelConfig =
-        serde_json::from_str(&config).context("Failed to parse `config.json`")?;
+    let config = fs::read_to_string(config_path)
+        .map_err(|_| ModelLoadError::MissingFile("config.json".to_string()))?;
+    let config_parse_error = |err: serde_json::Error| ModelLoadError::ConfigParse {
+        file: "config.json".to_string(),
+        message: err.to_string(),
+    };
+    let mut config: serde_json::Value =
+        serde_json::from_str(&config).map_err(config_parse_error)?;
+    if let Some(config_override) = &args.config_override {
+        let changes = config_override.apply(&mut config);
+        if changes.is_empty() {
+            tracing::warn!("`--config-override` does not change `config.json`");
+        }
+        for change in changes {
+            tracing::warn!("Overriding `config.json` field {change}");
+        }
+    }
+    let config: ModelConfig = serde_json::from_value(config).map_err(config_parse_error)?;
+
+    if let Some(allowed_architectures) = &args.allowed_architectures {
+        if !is_allowed_architecture(
+            &config.model_type,
+            &config.architectures,
+            allowed_architectures,
+        ) {
+            return Err(anyhow!(
+                "The model architecture is not allowed: `model_type` is `{}` and `architectures` are {:?} but `--allowed-architectures` only allows {:?}",
+                config.model_type,
+                config.architectures,
+                allowed_architectures
+            ));
+        }
+    }
 
     // Set model type from config
     let backend_model_type = {
@@ -212,71 +846,127 @@ async fn main() -> Result<()> {
             }
             text_embeddings_backend::ModelType::Classifier
         } else {
-            // Set pooling
+            // Set pooling: `--pooling`, then the model pooling config, then `--fallback-pooling`
             let pool = match args.pooling {
-                Some(pool) => pool,
-                None => {
-                    // Load pooling config
-                    let config_path = model_root.join("1_Pooling/config.json");
-                    let config = fs::read_to_string(config_path).context("The `--pooling` arg is not set and we could not find a pooling configuration (`1_Pooling/config.json`) for this model.")?;
-                    let config: PoolConfig = serde_json::from_str(&config)
-                        .context("Failed to parse `1_Pooling/config.json`")?;
-                    if config.pooling_mode_cls_token {
-                        text_embeddings_backend::Pool::Cls
-                    } else if config.pooling_mode_mean_tokens {
-                        text_embeddings_backend::Pool::Mean
-                    } else {
-                        return Err(anyhow!("Pooling config {config:?} is not supported"));
-                    }
+                Some(pool) => {
+                    tracing::info!("Using `{pool}` pooling set by `--pooling`");
+                    pool
                 }
+                None => match model_pooling(&model_root) {
+                    Ok(pool) => {
+                        tracing::info!("Using `{pool}` pooling from `1_Pooling/config.json`");
+                        pool
+                    }
+                    Err(reason) => {
+                        let (pool, source) = match args.fallback_pooling.clone() {
+                            Some(pool) => (pool, "set by `--fallback-pooling`"),
+                            None => default_pooling(&config.model_type, has_pooler(&model_root)),
+                        };
+                        tracing::warn!(
+                            "`--pooling` is not set and {reason}. Falling back to `{pool}` pooling {source}: set `--pooling` if the model uses a different one"
+                        );
+                        pool
+                    }
+                },
             };
             text_embeddings_backend::ModelType::Embedding(pool)
         }
     };
 
+    // Read before the classifier labels are moved out of `config`
+    let supports_position_interpolation = config.supports_position_interpolation();
+
     // Info model type
-    let model_type = match &backend_model_type {
-        text_embeddings_backend::ModelType::Classifier => ModelType::Classifier(ClassifierModel {
-            id2label: config
-                .id2label
-                .context("`config.json` does not contain `id2label`")?,
-            label2id: config
-                .label2id
-                .context("`config.json` does not contain `label2id`")?,
-        }),
+    let mut model_type = match &backend_model_type {
+        text_embeddings_backend::ModelType::Classifier => {
+            let mut classifier = ClassifierModel::new(
+                config
+                    .id2label
+                    .context("`config.json` does not contain `id2label`")?,
+                config
+                    .label2id
+                    .context("`config.json` does not contain `label2id`")?,
+            )
+            .map_err(|err| anyhow!("Invalid classifier config in `config.json`: {err}"))?;
+            classifier.problem_type =
+                ProblemType::new(config.problem_type.as_deref(), classifier.id2label.len())
+                    .map_err(|err| anyhow!("Invalid classifier config in `config.json`: {err}"))?;
+            tracing::info!("Classifier problem type: {:?}", classifier.problem_type);
+            ModelType::Classifier(classifier)
+        }
         text_embeddings_backend::ModelType::Embedding(pool) => {
             ModelType::Embedding(EmbeddingModel {
                 pooling: pool.to_string(),
+                dimension: config.hidden_size,
             })
         }
     };
 
-    // Load tokenizer
-    let tokenizer_path = model_root.join("tokenizer.json");
-    let mut tokenizer = Tokenizer::from_file(tokenizer_path).expect(
-        "tokenizer.json not found. text-embeddings-inference only supports fast tokenizers",
-    );
-    // See https://github.com/huggingface/tokenizers/pull/1357
-    if let Some(pre_tokenizer) = tokenizer.get_pre_tokenizer() {
-        if let PreTokenizerWrapper::Metaspace(m) = pre_tokenizer {
-            // We are forced to clone since `Tokenizer` does not have a `get_mut` for `pre_tokenizer`
-            let mut m = m.clone();
-            m.set_prepend_scheme(PrependScheme::First);
-            tokenizer.with_pre_tokenizer(PreTokenizerWrapper::Metaspace(m));
-        } else if let PreTokenizerWrapper::Sequence(s) = pre_tokenizer {
-            // We are forced to clone since `Tokenizer` does not have a `get_mut` for `pre_tokenizer`
-            let mut s = s.clone();
-            for pre_tokenizer in s.get_pre_tokenizers_mut() {
-                if let PreTokenizerWrapper::Metaspace(m) = pre_tokenizer {
-                    m.set_prepend_scheme(PrependScheme::First);
+    // Load the prompts of sentence-transformers models
+    let st_config_path = model_root.join("config_sentence_transformers.json");
+    let (prompts, default_prompt_name) = match fs::read_to_string(st_config_path) {
+        Ok(st_config) if matches!(model_type, ModelType::Embedding(_)) => {
+            let st_config: STConfig = serde_json::from_str(&st_config)
+                .context("Failed to parse `config_sentence_transformers.json`")?;
+            let default_prompt_name = match st_config.default_prompt_name {
+                Some(name) if !st_config.prompts.contains_key(&name) => {
+                    tracing::warn!(
+                        "Default prompt `{name}` not found in `config_sentence_transformers.json` prompts. Ignoring it."
+                    );
+                    None
+                }
+                Some(name) => {
+                    tracing::info!("Using default prompt `{name}`");
+                    Some(name)
                 }
+                None => None,
+            };
+            (st_config.prompts, default_prompt_name)
+        }
+        _ => (HashMap::new(), None),
+    };
+
+    // Load tokenizer
+    let mut tokenizer = match &args.tokenizer_json {
+        Some(tokenizer_json) => {
+            tracing::info!("Using the tokenizer given by `--tokenizer-json`");
+            tokenizer_from_json(tokenizer_json).context("Failed to parse `--tokenizer-json`")?
+        }
+        None => {
+            let tokenizer_path = model_root.join("tokenizer.json");
+            if !tokenizer_path.exists() {
+                return Err(ModelLoadError::MissingFile("tokenizer.json".to_string()))
+                    .context("text-embeddings-inference only supports fast tokenizers");
             }
-            tokenizer.with_pre_tokenizer(PreTokenizerWrapper::Sequence(s));
+            Tokenizer::from_file(tokenizer_path).map_err(|err| ModelLoadError::ConfigParse {
+                file: "tokenizer.json".to_string(),
+                message: err.to_string(),
+            })?
         }
+    };
+    match set_metaspace_prepend(&mut tokenizer, args.metaspace_prepend) {
+        true => tracing::info!(
+            "Metaspace pre-tokenizer prepend scheme set to `{:?}`",
+            args.metaspace_prepend
+        ),
+        false => tracing::debug!("No Metaspace pre-tokenizer found"),
     }
 
     tokenizer.with_padding(None);
 
+    let lowercase = Lowercase::new(args.do_lower_case, &tokenizer, &model_root);
+    if let Some(lowercase) = lowercase {
+        tracing::info!(
+            "Lowercasing the inputs (strip accents: {})",
+            lowercase.strip_accents
+        );
+    }
+
+    let tokenizer_info = TokenizerInfo::new(
+        tokenizer.get_vocab_size(true),
+        SpecialTokens::new(&tokenizer, &model_root),
+    );
+
     // Position IDs offset. Used for Roberta and camembert.
     let position_offset = if &config.model_type == "xlm-roberta"
         || &config.model_type == "camembert"
@@ -286,7 +976,32 @@ async fn main() -> Result<()> {
     } else {
         0
     };
-    let max_input_length = config.max_position_embeddings - position_offset;
+    let config_max_input_length = config.max_position_embeddings - position_offset;
+    let max_input_length = match args.max_input_length {
+        Some(0) => return Err(anyhow!("`--max-input-length` must be greater than 0")),
+        Some(max_input_length) if max_input_length > config_max_input_length => {
+            if !supports_position_interpolation {
+                return Err(anyhow!(
+                    "`--max-input-length` cannot be larger than the model maximum input length {config_max_input_length}: the model does not support position interpolation"
+                ));
+            }
+            tracing::warn!(
+                "`--max-input-length` {max_input_length} is larger than the model maximum input length {config_max_input_length}. The embeddings quality may degrade on long inputs"
+            );
+            max_input_length
+        }
+        Some(max_input_length) => max_input_length,
+        None => reconcile_max_input_length(config_max_input_length, &model_root),
+    };
+
+    // Client batches are split in multiple backend batches of at most `max_batch_tokens` tokens.
+    // A single input must always fit in a backend batch or it will never be scheduled.
+    if args.max_batch_tokens < max_input_length {
+        return Err(anyhow!(
+            "`max_batch_tokens` must be larger or equal to `max_input_length`: {} < {max_input_length}",
+            args.max_batch_tokens
+        ));
+    }
 
     let tokenization_workers = args
         .tokenization_workers
@@ -298,6 +1013,9 @@ async fn main() -> Result<()> {
         tokenizer,
         max_input_length,
         position_offset,
+        args.normalize_unicode,
+        lowercase,
+        args.input_template.clone(),
     );
 
     // Get dtype
@@ -313,10 +1031,24 @@ async fn main() -> Result<()> {
     });
 
     // Create backend
+    // The model replicas share the thread pool
+    if let Some(inference_threads) = args.inference_threads {
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        if inference_threads > cores {
+            tracing::warn!(
+                "{inference_threads} inference threads on {cores} cores: the CPU is oversubscribed"
+            );
+        }
+    }
+
     tracing::info!("Starting model backend");
     let backend = text_embeddings_backend::Backend::new(
         model_root,
         dtype.clone(),
+        args.attention,
+        args.dense_normalization,
+        args.config_override.clone(),
+        args.num_model_replicas,
         backend_model_type,
         args.uds_path,
         args.otlp_endpoint,
@@ -327,11 +1059,60 @@ async fn main() -> Result<()> {
         .await
         .context("Model backend is not healthy")?;
 
-    let max_batch_requests = backend.max_batch_size.map(|s| {
-        tracing::warn!("Backend does not support a batch size > {s}");
-        tracing::warn!("forcing `max_batch_requests={s}`");
-        s
-    });
+    if args.mlock {
+        lock_memory().context("Could not lock the model in memory")?;
+        tracing::info!("Model locked in memory");
+    }
+
+    // The embeddings of models with a `Dense` projection or a whitening transform are not of the
+    // hidden size
+    if let (ModelType::Embedding(model), Some(dimension)) =
+        (&mut model_type, backend.embedding_dimension)
+    {
+        model.dimension = dimension;
+    }
+
+    if let Some(expect_dimension) = args.expect_dimension {
+        match &model_type {
+            ModelType::Embedding(model) if model.dimension != expect_dimension => {
+                return Err(anyhow!(
+                    "The model embeddings are of dimension {} but `--expect-dimension` is {expect_dimension}",
+                    model.dimension
+                ));
+            }
+            ModelType::Embedding(_) => {}
+            ModelType::Classifier(_) => {
+                return Err(anyhow!(
+                    "`--expect-dimension` is only supported for embedding models"
+                ));
+            }
+        }
+    }
+
+    // `max_batch_requests` can only lower the batch size supported by the backend
+    let max_batch_requests = match (args.max_batch_requests, backend.max_batch_size) {
+        (Some(requests), Some(s)) if requests > s => {
+            return Err(anyhow!(
+                "`max_batch_requests` must be smaller or equal to the backend maximum batch size: {requests} > {s}"
+            ));
+        }
+        (Some(requests), _) => Some(requests),
+        (None, Some(s)) => {
+            tracing::warn!("Backend does not support a batch size > {s}");
+            tracing::warn!("forcing `max_batch_requests={s}`");
+            Some(s)
+        }
+        (None, None) => None,
+    };
+
+    // GGUF models report their quantization scheme, e.g. `q4_0`
+    let model_dtype = backend
+        .quantization
+        .clone()
+        .unwrap_or_else(|| dtype.to_string());
+    let model_attention = backend.attention.clone();
+    let model_whitening = backend.whitening;
+    let num_model_replicas = backend.num_replicas;
 
     // Queue logic
     let queue = Queue::new(
@@ -341,20 +1122,167 @@ async fn main() -> Result<()> {
     );
 
     // Create infer task
-    let infer = Infer::new(tokenization, queue, args.max_concurrent_requests, backend);
+    let circuit_breaker_threshold =
+        (args.circuit_breaker_threshold > 0).then_some(args.circuit_breaker_threshold);
+    let infer = Infer::new(
+        tokenization,
+        queue,
+        args.max_concurrent_requests,
+        circuit_breaker_threshold,
+        args.reject_nan,
+        args.microbatch_size,
+        backend,
+    );
+
+    if args.self_test {
+        let dimension = match &model_type {
+            ModelType::Embedding(model) => model.dimension,
+            ModelType::Classifier(model) => model.id2label.len(),
+        };
+        self_test::run(
+            infer,
+            dimension,
+            args.self_test_reference.as_deref(),
+            args.self_test_tolerance,
+        )
+        .await
+        .context("Self-test failed")?;
+        return Ok(());
+    }
+
+    if args.bench {
+        bench::run(
+            infer,
+            args.bench_sequence_length,
+            args.bench_batch_sizes,
+            args.bench_iterations,
+        )
+        .await
+        .context("Benchmark failed")?;
+        return Ok(());
+    }
+
+    if let Some(warmup_inputs) = warmup_inputs {
+        let start_time = std::time::Instant::now();
+        let (lines, inputs): (Vec<usize>, Vec<String>) = warmup_inputs.into_iter().unzip();
+        let total = inputs.len();
+        let failures = infer.warmup(inputs).await;
+        for (index, err) in &failures {
+            tracing::warn!("Warmup input on line {} failed: {err}", lines[*index]);
+        }
+        tracing::info!(
+            "Warmed up with {} of {total} inputs in {:?}",
+            total - failures.len(),
+            start_time.elapsed()
+        );
+    }
+
+    if let Some(keep_warm_interval) = args.keep_warm_interval {
+        infer.spawn_keep_warm(Duration::from_secs(keep_warm_interval));
+    }
+    if let Some(slow_start_duration) = args.slow_start_duration {
+        infer.slow_start(Duration::from_secs(slow_start_duration));
+    }
+
+    // Effective runtime configuration
+    let effective_config = EffectiveConfig {
+        model_id: args.model_id.clone(),
+        revision: args.revision.clone(),
+        dtype: dtype.to_string(),
+        output_dtype: args.output_dtype.to_string(),
+        backend: if cfg!(feature = "candle") {
+            "candle"
+        } else {
+            "python"
+        },
+        attention: args.attention.to_string(),
+        pooling: match &model_type {
+            ModelType::Embedding(model) => Some(model.pooling.clone()),
+            ModelType::Classifier(_) => None,
+        },
+        dense_normalization: args.dense_normalization.to_string(),
+        expect_dimension: args.expect_dimension,
+        config_override: args.config_override.as_ref().map(|c| c.to_string()),
+        allowed_architectures: args.allowed_architectures.clone(),
+        num_model_replicas,
+        inference_threads: args.inference_threads,
+        mlock: args.mlock,
+        max_input_length,
+        tokenization_workers,
+        normalize_unicode: format!("{:?}", args.normalize_unicode).to_lowercase(),
+        lowercase_inputs: lowercase.is_some(),
+        metaspace_prepend: format!("{:?}", args.metaspace_prepend).to_lowercase(),
+        input_template: args.input_template,
+        max_batch_tokens: args.max_batch_tokens,
+        max_batch_requests,
+        microbatch_size: args.microbatch_size,
+        max_client_batch_size: args.max_client_batch_size,
+        max_concurrent_requests: args.max_concurrent_requests,
+        embedding_precision: args.embedding_precision,
+        max_response_bytes: args.max_response_bytes,
+        circuit_breaker_threshold: args.circuit_breaker_threshold,
+        unhealthy_grace_ms: args.unhealthy_grace_ms,
+        reject_nan: args.reject_nan,
+        keep_warm_interval: args.keep_warm_interval,
+        slow_start_duration: args.slow_start_duration,
+        warmup_file: args
+            .warmup_file
+            .as_ref()
+            .map(|path| path.display().to_string()),
+        warmup_max_inputs: args.warmup_max_inputs,
+        max_retry_after: args.max_retry_after,
+        idempotency_ttl: args.idempotency_ttl,
+        allow_debug: args.allow_debug,
+        renormalize_probabilities: args.renormalize_probabilities,
+        max_pair_length_ratio: args.max_pair_length_ratio,
+        default_raw_scores: args.default_raw_scores,
+        regression_sigmoid: args.regression_sigmoid,
+        openai_accept_texts: args.openai_accept_texts,
+        auto_truncate: args.auto_truncate,
+        hostname: args.hostname.clone(),
+        port: args.port,
+        max_connections: args.max_connections,
+        http_read_timeout: args.http_read_timeout,
+        http_write_timeout: args.http_write_timeout,
+        payload_limit: args.payload_limit,
+        shm: args.shm.as_ref().map(|path| path.display().to_string()),
+    };
+
+    // A single line to grep for what the process is actually running. It is the same JSON object
+    // as `/config`.
+    tracing::info!(
+        effective_config = %serde_json::to_string(&effective_config)?,
+        "Effective configuration"
+    );
 
     // Endpoint info
     let info = Info {
         model_id: args.model_id,
         model_sha: args.revision,
-        model_dtype: dtype.to_string(),
+        model_dtype,
+        model_output_dtype: args.output_dtype,
+        model_attention,
+        model_whitening,
+        num_model_replicas,
         model_type,
+        prompts,
+        default_prompt_name,
+        tokenizer: tokenizer_info,
         max_concurrent_requests: args.max_concurrent_requests,
-        max_input_length: config.max_position_embeddings,
+        max_input_length,
         max_batch_tokens: args.max_batch_tokens,
         tokenization_workers,
+        allow_debug: args.allow_debug,
+        renormalize_probabilities: args.renormalize_probabilities,
+        max_pair_length_ratio: args.max_pair_length_ratio,
+        default_raw_scores: args.default_raw_scores,
+        regression_sigmoid: args.regression_sigmoid,
+        openai_accept_texts: args.openai_accept_texts,
+        auto_truncate: args.auto_truncate,
         max_batch_requests,
         max_client_batch_size: args.max_client_batch_size,
+        embedding_precision: args.embedding_precision,
+        max_response_bytes: args.max_response_bytes,
         version: env!("CARGO_PKG_VERSION"),
         sha: option_env!("VERGEN_GIT_SHA"),
         docker_label: option_env!("DOCKER_LABEL"),
@@ -379,20 +1307,71 @@ async fn main() -> Result<()> {
         )
     });
 
+    #[cfg(feature = "shm")]
+    let shm = args
+        .shm
+        .as_deref()
+        .map(|path| ShmRing::create(path, args.shm_size))
+        .transpose()
+        .context("Could not create the shared memory ring buffer")?;
+
     tracing::info!("Ready");
 
     // Run axum server
-    server::run(infer, info, addr, cors_allow_origin)
-        .await
-        .unwrap();
+    server::run(
+        infer,
+        info,
+        effective_config,
+        args.admin_token,
+        addr,
+        args.max_connections,
+        Duration::from_secs(args.max_retry_after),
+        args.idempotency_ttl.map(Duration::from_secs),
+        Duration::from_secs(args.http_read_timeout),
+        Duration::from_secs(args.http_write_timeout),
+        args.payload_limit,
+        args.unhealthy_grace_ms.map(Duration::from_millis),
+        #[cfg(feature = "shm")]
+        shm,
+        cors_allow_origin,
+    )
+    .await
+    .unwrap();
+    Ok(())
+}
+
+/// Lock the pages currently mapped by the process, including the memory mapped weights, in RAM.
+/// This faults in the pages that were not read yet.
+#[cfg(unix)]
+fn lock_memory() -> Result<()> {
+    // Safety: `mlockall` only changes the paging of the process memory
+    if unsafe { libc::mlockall(libc::MCL_CURRENT) } != 0 {
+        return Err(anyhow!(
+            "`mlockall` failed: {}. Check the `RLIMIT_MEMLOCK` limit of the process",
+            std::io::Error::last_os_error()
+        ));
+    }
     Ok(())
 }
 
-/// Init logging using env variables LOG_LEVEL and LOG_FORMAT:
+#[cfg(not(unix))]
+fn lock_memory() -> Result<()> {
+    Err(anyhow!("`--mlock` is only supported on Unix"))
+}
+
+#[derive(Clone, Debug, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+    Pretty,
+}
+
+/// Init logging using env variables LOG_LEVEL or RUST_LOG:
 ///     - otlp_endpoint is an optional URL to an Open Telemetry collector
-///     - LOG_LEVEL may be TRACE, DEBUG, INFO, WARN or ERROR (default to INFO)
-///     - LOG_FORMAT may be TEXT or JSON (default to TEXT)
-fn init_logging(otlp_endpoint: Option<String>, json_output: bool) {
+///     - log_format may be TEXT, JSON or PRETTY
+///     - LOG_LEVEL (or RUST_LOG) may be TRACE, DEBUG, INFO, WARN or ERROR, or a per module
+///       filter such as `info,text_embeddings_core=debug` (default to INFO)
+fn init_logging(otlp_endpoint: Option<String>, log_format: LogFormat) {
     let mut layers = Vec::new();
 
     // STDOUT/STDERR layer
@@ -400,9 +1379,15 @@ fn init_logging(otlp_endpoint: Option<String>, json_output: bool) {
         .with_file(true)
         .with_line_number(true);
 
-    let fmt_layer = match json_output {
-        true => fmt_layer.json().flatten_event(true).boxed(),
-        false => fmt_layer.boxed(),
+    let fmt_layer = match log_format {
+        LogFormat::Text => fmt_layer.boxed(),
+        LogFormat::Json => fmt_layer
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+        LogFormat::Pretty => fmt_layer.pretty().boxed(),
     };
     layers.push(fmt_layer);
 
@@ -433,12 +1418,106 @@ fn init_logging(otlp_endpoint: Option<String>, json_output: bool) {
         };
     }
 
-    // Filter events with LOG_LEVEL
-    let env_filter =
-        EnvFilter::try_from_env("LOG_LEVEL").unwrap_or_else(|_| EnvFilter::new("info"));
+    // Filter events with LOG_LEVEL, falling back to RUST_LOG
+    let env_filter = EnvFilter::try_from_env("LOG_LEVEL")
+        .or_else(|_| EnvFilter::try_from_default_env())
+        .unwrap_or_else(|_| EnvFilter::new("info"));
 
     tracing_subscriber::registry()
         .with(env_filter)
         .with(layers)
         .init();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Write the safetensors header of a model with the given tensors, without their data
+    fn write_safetensors_header(model_root: &Path, tensors: &[&str]) {
+        let tensors: HashMap<&str, serde_json::Value> = tensors
+            .iter()
+            .map(|name| {
+                let info =
+                    serde_json::json!({"dtype": "F32", "shape": [0], "data_offsets": [0, 0]});
+                (*name, info)
+            })
+            .collect();
+        let header = serde_json::to_vec(&tensors).unwrap();
+
+        let mut file = fs::File::create(model_root.join("model.safetensors")).unwrap();
+        file.write_all(&(header.len() as u64).to_le_bytes())
+            .unwrap();
+        file.write_all(&header).unwrap();
+    }
+
+    #[test]
+    fn test_default_pooling() {
+        let model_root =
+            std::env::temp_dir().join(format!("tei-pooler-test-{}", std::process::id()));
+        fs::create_dir_all(&model_root).unwrap();
+        assert_eq!(has_pooler(&model_root), None);
+
+        // A model with a pooler
+        write_safetensors_header(
+            &model_root,
+            &[
+                "embeddings.word_embeddings.weight",
+                "pooler.dense.weight",
+                "pooler.dense.bias",
+            ],
+        );
+        assert_eq!(has_pooler(&model_root), Some(true));
+        let (pool, _) = default_pooling("bert", has_pooler(&model_root));
+        assert_eq!(pool, text_embeddings_backend::Pool::Cls);
+
+        // A model without a pooler
+        write_safetensors_header(&model_root, &["embeddings.word_embeddings.weight"]);
+        assert_eq!(has_pooler(&model_root), Some(false));
+        let (pool, _) = default_pooling("bert", has_pooler(&model_root));
+        assert_eq!(pool, text_embeddings_backend::Pool::Mean);
+
+        // Only BERT-family models are detected
+        let (pool, _) = default_pooling("nomic_bert", Some(true));
+        assert_eq!(pool, text_embeddings_backend::Pool::Mean);
+
+        fs::remove_dir_all(model_root).unwrap();
+    }
+
+    #[test]
+    fn test_read_warmup_file() {
+        let path = std::env::temp_dir().join(format!("tei-warmup-test-{}", std::process::id()));
+        fs::write(&path, "first input\n\nsecond input\nthird input\n").unwrap();
+
+        let inputs = read_warmup_file(&path, 2).unwrap();
+        assert_eq!(
+            inputs,
+            vec![
+                (1, "first input".to_string()),
+                (3, "second input".to_string())
+            ]
+        );
+
+        fs::write(&path, "\n \n").unwrap();
+        assert!(read_warmup_file(&path, 2).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_is_allowed_architecture() {
+        let architectures = vec!["XLMRobertaModel".to_string()];
+        let allowed = ["bert".to_string(), "xlm-roberta".to_string()];
+        assert!(is_allowed_architecture(
+            "xlm-roberta",
+            &architectures,
+            &allowed
+        ));
+        assert!(is_allowed_architecture(
+            "roberta",
+            &architectures,
+            &["XLMRobertaModel".to_string()]
+        ));
+        assert!(!is_allowed_architecture("nomic_bert", &[], &allowed));
+    }
+}
@@ -0,0 +1,145 @@
+/// Self-test logic
+use std::path::Path;
+use text_embeddings_core::infer::Infer;
+use text_embeddings_core::queue::Priority;
+use text_embeddings_core::tokenization::TruncationStrategy;
+use text_embeddings_core::TextEmbeddingsError;
+use thiserror::Error;
+
+/// Fixed inputs of the self-test.
+/// The reference file must hold the outputs of these inputs, in this order.
+pub const SELF_TEST_INPUTS: [&str; 2] = [
+    "What is Deep Learning?",
+    "The quick brown fox jumps over the lazy dog.",
+];
+
+#[derive(Debug, Error)]
+pub enum SelfTestError {
+    #[error(transparent)]
+    Inference(#[from] TextEmbeddingsError),
+    #[error("could not read the reference file: {0}")]
+    Reference(String),
+    #[error("input {index}: {message}")]
+    Check { index: usize, message: String },
+}
+
+/// Run the fixed inputs through the `Infer`/`Queue`/`Backend` stack and check that the outputs
+/// are finite, of `dimension` values and, if a reference file is given, within `tolerance` of the
+/// reference outputs.
+///
+/// Embeddings are normalized. The reference file is a JSON array with one array of values per
+/// input, i.e. the body of the `/embed` (or `/predict` with `raw_scores`) response for
+/// `SELF_TEST_INPUTS`.
+pub async fn run(
+    infer: Infer,
+    dimension: usize,
+    reference: Option<&Path>,
+    tolerance: f32,
+) -> Result<(), SelfTestError> {
+    let reference = reference.map(read_reference).transpose()?;
+    if let Some(reference) = &reference {
+        if reference.len() != SELF_TEST_INPUTS.len() {
+            return Err(SelfTestError::Reference(format!(
+                "expected {} reference outputs, got {}",
+                SELF_TEST_INPUTS.len(),
+                reference.len()
+            )));
+        }
+    }
+
+    tracing::info!("Starting self-test");
+
+    for (index, input) in SELF_TEST_INPUTS.into_iter().enumerate() {
+        let permit = infer.acquire_permit().await;
+        let response = match infer.is_classifier() {
+            true => {
+                infer
+                    .predict(
+                        input.to_string(),
+                        true,
+                        TruncationStrategy::LongestFirst,
+                        true,
+                        Priority::High,
+                        permit,
+                    )
+                    .await?
+            }
+            false => {
+                infer
+                    .embed(input.to_string(), true, true, true, Priority::High, permit)
+                    .await?
+            }
+        };
+
+        check_output(
+            &response.results,
+            dimension,
+            reference
+                .as_ref()
+                .map(|reference| reference[index].as_slice()),
+            tolerance,
+        )
+        .map_err(|message| SelfTestError::Check { index, message })?;
+    }
+
+    tracing::info!("Self-test passed");
+    Ok(())
+}
+
+fn read_reference(path: &Path) -> Result<Vec<Vec<f32>>, SelfTestError> {
+    let reference = std::fs::read_to_string(path)
+        .map_err(|err| SelfTestError::Reference(format!("{}: {err}", path.display())))?;
+    serde_json::from_str(&reference)
+        .map_err(|err| SelfTestError::Reference(format!("{}: {err}", path.display())))
+}
+
+/// Check a single output against the expected dimension and its reference
+fn check_output(
+    output: &[f32],
+    dimension: usize,
+    reference: Option<&[f32]>,
+    tolerance: f32,
+) -> Result<(), String> {
+    if output.len() != dimension {
+        return Err(format!("expected {dimension} values, got {}", output.len()));
+    }
+    if let Some(position) = output.iter().position(|v| !v.is_finite()) {
+        return Err(format!("value {position} is not finite"));
+    }
+    if let Some(reference) = reference {
+        if reference.len() != output.len() {
+            return Err(format!(
+                "the reference has {} values but the output has {}",
+                reference.len(),
+                output.len()
+            ));
+        }
+        let max_difference = output
+            .iter()
+            .zip(reference)
+            .map(|(v, r)| (v - r).abs())
+            .fold(0.0f32, f32::max);
+        if max_difference > tolerance {
+            return Err(format!(
+                "the output differs from the reference by up to {max_difference}, more than the tolerance of {tolerance}"
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_output() {
+        assert!(check_output(&[0.6, 0.8], 2, None, 0.0).is_ok());
+        assert!(check_output(&[0.6, 0.8], 3, None, 0.0).is_err());
+        assert!(check_output(&[0.6, f32::NAN], 2, None, 0.0).is_err());
+
+        assert!(check_output(&[0.6, 0.8], 2, Some(&[0.6005, 0.7995]), 1e-3).is_ok());
+        assert!(check_output(&[0.6, 0.8], 2, Some(&[0.8, 0.6]), 1e-3).is_err());
+        assert!(check_output(&[0.6, 0.8], 2, Some(&[0.6]), 1e-3).is_err());
+    }
+}
@@ -1,22 +1,53 @@
 /// HTTP Server logic
+use crate::arrow::{embeddings_to_arrow_stream, ARROW_STREAM_CONTENT_TYPE};
+use crate::connection::LimitedIncoming;
+use crate::idempotency::{
+    CachedResponse, IdempotencyCache, Lookup, IDEMPOTENCY_KEY, IDEMPOTENT_REPLAYED,
+    MAX_IDEMPOTENCY_KEY_LENGTH,
+};
+#[cfg(feature = "shm")]
+use crate::shm::{ShmEmbedResponse, ShmRing};
 use crate::{
-    ClassifierModel, EmbedRequest, EmbedResponse, EmbeddingModel, ErrorResponse, ErrorType, Info,
-    Input, ModelType, OpenAICompatEmbedding, OpenAICompatErrorResponse, OpenAICompatRequest,
-    OpenAICompatResponse, OpenAICompatUsage, PredictInput, PredictRequest, PredictResponse,
-    Prediction, Sequence,
+    AllModesEmbedding, ClassifierModel, EffectiveConfig, EmbedAllModesRequest,
+    EmbedAllModesResponse, EmbedInput, EmbedNgramsRequest, EmbedNgramsResponse, EmbedProgress,
+    EmbedRequest, EmbedResponse, EmbedSummary, EmbeddingData, EmbeddingGroup, EmbeddingModel,
+    EncodingFormat, ErrorDetail, ErrorResponse, ErrorType, ExtendedEmbedResponse,
+    GroupedEmbedResponse, HealthDetail, HealthParams, HiddenStateSummary, IdentifiedEmbedResponse,
+    IdentifiedEmbedding, Info, Input, LabeledPredictions, ModelType, NgramEmbedding,
+    OpenAICompatEmbedding, OpenAICompatErrorResponse, OpenAICompatModel, OpenAICompatModelList,
+    OpenAICompatRequest, OpenAICompatResponse, OpenAICompatUsage, PredictInput, PredictRequest,
+    PredictResponse, Prediction, ProblemType, Sequence, SimilarityMatrixRequest,
+    SimilarityMatrixResponse, SortBy, SortedEmbedResponse, SortedEmbedding, SparseValue,
+    SpecialToken, TokenizerInfo, TruncationStrategy,
 };
-use axum::extract::Extension;
-use axum::http::{HeaderMap, Method, StatusCode};
+use axum::body::{Body, Bytes, Full};
+use axum::extract::{DefaultBodyLimit, Extension, FromRequest, Multipart, Query, State};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
-use axum::{http, Json, Router};
+use axum::{async_trait, http, Json, Router};
 use axum_tracing_opentelemetry::middleware::OtelAxumLayer;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use futures::future::join_all;
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
+use hyper::server::conn::AddrIncoming;
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
-use std::time::{Duration, Instant};
-use text_embeddings_core::infer::{Infer, InferResponse};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use text_embeddings_backend::OutputDType;
+use text_embeddings_core::infer::{normalize_embedding, Infer, InferResponse};
+use text_embeddings_core::queue::Priority;
+use text_embeddings_core::tokenization::EncodingInput;
 use text_embeddings_core::TextEmbeddingsError;
 use tokio::signal;
+use tokio::sync::OwnedSemaphorePermit;
 use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing::instrument;
 use utoipa::OpenApi;
@@ -34,24 +65,162 @@ async fn get_model_info(info: Extension<Info>) -> Json<Info> {
     Json(info.0)
 }
 
+/// Dense vector of the `logit_bias` of a request, indexed by label id
+fn logit_bias_vector(
+    classifier: &ClassifierModel,
+    logit_bias: &HashMap<String, f32>,
+) -> Result<Vec<f32>, ErrorResponse> {
+    let mut bias = vec![0.0; classifier.id2label.len()];
+    for (label, value) in logit_bias {
+        let id = classifier
+            .id2label
+            .iter()
+            .find(|(_, l)| *l == label)
+            .and_then(|(id, _)| id.parse::<usize>().ok());
+        let Some(id) = id else {
+            let mut labels: Vec<&String> = classifier.id2label.values().collect();
+            labels.sort();
+            return Err(ErrorResponse {
+                error: format!(
+                    "`logit_bias` label `{label}` is not one of the model labels: {labels:?}"
+                ),
+                error_type: ErrorType::Validation,
+                details: None,
+            });
+        };
+        if !value.is_finite() {
+            return Err(ErrorResponse {
+                error: format!("`logit_bias` of label `{label}` must be finite"),
+                error_type: ErrorType::Validation,
+                details: None,
+            });
+        }
+        bias[id] = *value;
+    }
+    Ok(bias)
+}
+
+/// Softmax of logits, in place
+fn softmax(logits: &mut [f32]) {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut sum = 0.0;
+    for logit in logits.iter_mut() {
+        *logit = (*logit - max).exp();
+        sum += *logit;
+    }
+    for logit in logits.iter_mut() {
+        *logit /= sum;
+    }
+}
+
+/// Rescale probabilities so that they sum to 1.0 within float precision.
+/// The remaining rounding error is absorbed by the largest probability.
+fn renormalize_probabilities(probabilities: &mut [f32]) {
+    let sum: f64 = probabilities.iter().map(|p| *p as f64).sum();
+    if sum <= 0.0 || !sum.is_finite() {
+        return;
+    }
+    for p in probabilities.iter_mut() {
+        *p = (*p as f64 / sum) as f32;
+    }
+
+    let argmax = probabilities
+        .iter()
+        .enumerate()
+        .max_by(|(_, x), (_, y)| x.total_cmp(y))
+        .map(|(i, _)| i);
+    if let Some(argmax) = argmax {
+        let rest: f32 = probabilities
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != argmax)
+            .map(|(_, p)| *p)
+            .sum();
+        probabilities[argmax] = (1.0 - rest).max(0.0);
+    }
+}
+
+/// Token required by the admin routes
+#[derive(Clone)]
+struct AdminToken(String);
+
+/// Effective runtime configuration.
+/// Only available if the server was started with `--admin-token`.
+#[utoipa::path(
+get,
+tag = "Text Embeddings Inference",
+path = "/config",
+responses(
+(status = 200, description = "Effective runtime configuration", body = EffectiveConfig),
+(status = 401, description = "Missing or invalid admin token", body = ErrorResponse,
+example = json ! ({"error": "unauthorized", "error_type": "unauthorized"})),
+)
+)]
+#[instrument(skip_all)]
+async fn get_config(
+    config: Extension<EffectiveConfig>,
+    admin_token: Extension<AdminToken>,
+    headers: HeaderMap,
+) -> Result<Json<EffectiveConfig>, (StatusCode, Json<ErrorResponse>)> {
+    let authorized = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token == admin_token.0 .0)
+        .unwrap_or(false);
+    if !authorized {
+        Err(ErrorResponse {
+            error: "unauthorized".to_string(),
+            error_type: ErrorType::Unauthorized,
+            details: None,
+        })?;
+    }
+    Ok(Json(config.0))
+}
+
 #[utoipa::path(
 get,
 tag = "Text Embeddings Inference",
 path = "/health",
+params(
+("detail" = Option<bool>, Query, description = "Return the backend and queue diagnostics"),
+),
 responses(
-(status = 200, description = "Everything is working fine"),
+(status = 200, description = "Everything is working fine", body = Option<HealthDetail>),
 (status = 503, description = "Text embeddings Inference is down", body = ErrorResponse,
 example = json ! ({"error": "unhealthy", "error_type": "unhealthy"})),
 )
 )]
-#[instrument(skip(infer))]
+#[instrument(skip(infer, info))]
 /// Health check method
-async fn health(infer: Extension<Infer>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
-    match infer.health().await {
-        true => Ok(()),
+async fn health(
+    infer: Extension<Infer>,
+    info: Extension<Info>,
+    Query(params): Query<HealthParams>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let healthy = infer.health().await;
+
+    if params.detail {
+        let status = match healthy {
+            true => StatusCode::OK,
+            false => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        let detail = HealthDetail {
+            healthy,
+            model_id: info.model_id.clone(),
+            queue_size: infer.queue_size(),
+            in_flight_requests: infer.in_flight_requests(),
+            max_concurrent_requests: info.max_concurrent_requests,
+        };
+        return Ok((status, Json(detail)).into_response());
+    }
+
+    match healthy {
+        true => Ok(().into_response()),
         false => Err(ErrorResponse {
             error: "unhealthy".to_string(),
             error_type: ErrorType::Unhealthy,
+            details: None,
         })?,
     }
 }
@@ -62,8 +231,11 @@ post,
 tag = "Text Embeddings Inference",
 path = "/predict",
 request_body = PredictRequest,
+params(
+("X-Priority" = Option<String>, Header, description = "Scheduling priority of the request: `high` (default) or `low`"),
+),
 responses(
-(status = 200, description = "Predictions", body = PredictResponse),
+(status = 200, description = "Predictions. Requests with `single_label` return `LabeledPredictions`", body = PredictResponse),
 (status = 424, description = "Prediction Error", body = ErrorResponse,
 example = json ! ({"error": "Inference failed", "error_type": "backend"})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
@@ -81,27 +253,127 @@ example = json ! ({"error": "Batch size error", "error_type": "validation"})),
 async fn predict(
     infer: Extension<Infer>,
     info: Extension<Info>,
+    request_headers: HeaderMap,
     Json(req): Json<PredictRequest>,
 ) -> Result<(HeaderMap, Json<PredictResponse>), (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
     let start_time = Instant::now();
+    let priority = request_priority(&request_headers)?;
+
+    if req.top_k == Some(0) {
+        let message = "`top_k` must be greater than 0".to_string();
+        tracing::error!("{message}");
+        metrics::increment_counter!("te_request_failure", "err" => "validation");
+        Err(ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+            details: None,
+        })?;
+    }
+    let (threshold, top_k) = (req.threshold, req.top_k);
+
+    let logit_bias = match (&req.logit_bias, &info.model_type) {
+        (Some(logit_bias), ModelType::Classifier(classifier)) => {
+            match logit_bias_vector(classifier, logit_bias) {
+                Ok(bias) => Some(bias),
+                Err(err) => {
+                    tracing::error!("{}", err.error);
+                    metrics::increment_counter!("te_request_failure", "err" => "validation");
+                    Err(err)?
+                }
+            }
+        }
+        _ => None,
+    };
+    let logit_bias = logit_bias.as_deref();
+
+    // Catch misformatted pairs before running the model
+    let pairs_validation = match &req.inputs {
+        PredictInput::Single(input) => validate_pair(input, info.max_pair_length_ratio),
+        PredictInput::Batch(inputs) => collect_batch_results(
+            inputs
+                .iter()
+                .map(|input| validate_pair(input, info.max_pair_length_ratio))
+                .collect(),
+        )
+        .map(|_| ()),
+    };
+    if let Err(err) = pairs_validation {
+        tracing::error!("{}", err.error);
+        metrics::increment_counter!("te_request_failure", "err" => "validation");
+        Err(err)?;
+    }
 
     // Closure for predict
     let predict_inner = move |inputs: Sequence,
                               truncate: bool,
+                              truncation_strategy: TruncationStrategy,
                               raw_scores: bool,
                               infer: Infer,
                               info: Info| async move {
+        let classifier = match &info.model_type {
+            ModelType::Classifier(classifier) => classifier,
+            ModelType::Embedding(_) => {
+                let message = "model is not a classifier".to_string();
+                tracing::error!("{message}");
+                metrics::increment_counter!("te_request_failure", "err" => "validation");
+                return Err(ErrorResponse {
+                    error: message,
+                    error_type: ErrorType::Validation,
+                    details: None,
+                });
+            }
+        };
+
+        let single_label = classifier.problem_type == ProblemType::SingleLabelClassification;
         let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
-        let response = infer
-            .predict(inputs, truncate, raw_scores, permit)
+        let mut response = infer
+            .predict(
+                inputs,
+                truncate,
+                truncation_strategy.into(),
+                // The softmax of the backend only applies to single label classifiers, and the
+                // logit bias must be added before it
+                raw_scores || !single_label || logit_bias.is_some(),
+                priority,
+                permit,
+            )
             .await
             .map_err(ErrorResponse::from)?;
 
-        let id2label = match &info.model_type {
-            ModelType::Classifier(classifier) => &classifier.id2label,
-            _ => panic!(),
+        if let Some(logit_bias) = logit_bias {
+            response
+                .results
+                .iter_mut()
+                .zip(logit_bias)
+                .for_each(|(score, bias)| *score += bias);
+            if single_label && !raw_scores {
+                softmax(&mut response.results);
+            }
+        }
+
+        let sigmoid = match classifier.problem_type {
+            ProblemType::SingleLabelClassification => false,
+            ProblemType::MultiLabelClassification => true,
+            ProblemType::Regression => info.regression_sigmoid,
         };
+        if sigmoid && !raw_scores {
+            response
+                .results
+                .iter_mut()
+                .for_each(|score| *score = 1.0 / (1.0 + (-*score).exp()));
+        }
+
+        // Only softmax outputs are probabilities that must sum to 1
+        if info.renormalize_probabilities
+            && !raw_scores
+            && classifier.problem_type == ProblemType::SingleLabelClassification
+            && response.results.len() > 1
+        {
+            renormalize_probabilities(&mut response.results);
+        }
+
+        let id2label = &classifier.id2label;
 
         let mut predictions: Vec<Prediction> = {
             // Map score to label
@@ -111,16 +383,21 @@ async fn predict(
                 .enumerate()
                 .map(|(i, s)| Prediction {
                     score: s,
-                    label: id2label.get(&i.to_string()).unwrap().clone(),
+                    label: id2label
+                        .get(&i.to_string())
+                        .cloned()
+                        .unwrap_or_else(|| format!("LABEL_{i}")),
                 })
                 .collect()
         };
         // Reverse sort
         predictions.sort_by(|x, y| x.score.partial_cmp(&y.score).unwrap());
         predictions.reverse();
+        select_predictions(&mut predictions, threshold, top_k);
 
-        Ok::<(usize, Duration, Duration, Duration, Vec<Prediction>), ErrorResponse>((
+        Ok::<(usize, bool, Duration, Duration, Duration, Vec<Prediction>), ErrorResponse>((
             response.prompt_tokens,
+            response.truncated,
             response.tokenization,
             response.queue,
             response.inference,
@@ -128,14 +405,26 @@ async fn predict(
         ))
     };
 
+    let raw_scores = req.raw_scores.unwrap_or(info.default_raw_scores);
+
+    let mut truncated_count = 0;
     let (compute_chars, compute_tokens, tokenization_time, queue_time, inference_time, response) =
         match req.inputs {
             PredictInput::Single(inputs) => {
                 metrics::increment_counter!("te_request_count", "method" => "single");
 
                 let compute_chars = inputs.count_chars();
-                let (prompt_tokens, tokenization, queue, inference, predictions) =
-                    predict_inner(inputs, req.truncate, req.raw_scores, infer.0, info.0).await?;
+                let (prompt_tokens, truncated, tokenization, queue, inference, predictions) =
+                    predict_inner(
+                        inputs,
+                        req.truncate,
+                        req.truncation_strategy,
+                        raw_scores,
+                        infer.0,
+                        info.0,
+                    )
+                    .await?;
+                truncated_count += truncated as usize;
 
                 metrics::increment_counter!("te_request_success", "method" => "single");
 
@@ -161,6 +450,7 @@ async fn predict(
                     let err = ErrorResponse {
                         error: message,
                         error_type: ErrorType::Validation,
+                        details: None,
                     };
                     metrics::increment_counter!("te_request_failure", "err" => "batch_size");
                     Err(err)?;
@@ -176,15 +466,13 @@ async fn predict(
                     futures.push(predict_inner(
                         input,
                         req.truncate,
-                        req.raw_scores,
+                        req.truncation_strategy,
+                        raw_scores,
                         local_infer.0,
                         local_info.0,
                     ))
                 }
-                let results = join_all(futures).await.into_iter().collect::<Result<
-                    Vec<(usize, Duration, Duration, Duration, Vec<Prediction>)>,
-                    ErrorResponse,
-                >>()?;
+                let results = collect_batch_results(join_all(futures).await)?;
 
                 let mut predictions = Vec::with_capacity(batch_size);
                 let mut total_tokenization_time = 0;
@@ -194,10 +482,11 @@ async fn predict(
 
                 for r in results {
                     total_compute_tokens += r.0;
-                    total_tokenization_time += r.1.as_nanos() as u64;
-                    total_queue_time += r.2.as_nanos() as u64;
-                    total_inference_time += r.3.as_nanos() as u64;
-                    predictions.push(r.4);
+                    truncated_count += r.1 as usize;
+                    total_tokenization_time += r.2.as_nanos() as u64;
+                    total_queue_time += r.3.as_nanos() as u64;
+                    total_inference_time += r.4.as_nanos() as u64;
+                    predictions.push(r.5);
                 }
                 let batch_size = batch_size as u64;
 
@@ -214,6 +503,11 @@ async fn predict(
             }
         };
 
+    let response = match req.single_label {
+        true => response.with_top_label(),
+        false => response,
+    };
+
     let total_time = start_time.elapsed();
 
     // Tracing metadata
@@ -237,6 +531,10 @@ async fn predict(
         "x-compute-tokens",
         compute_tokens.to_string().parse().unwrap(),
     );
+    headers.insert(
+        "x-truncated-count",
+        truncated_count.to_string().parse().unwrap(),
+    );
     headers.insert(
         "x-total-time",
         total_time.as_millis().to_string().parse().unwrap(),
@@ -272,13 +570,30 @@ async fn predict(
 }
 
 /// Get Embeddings. Returns a 424 status code if the model is not an embedding model.
+///
+/// If the request `Accept` header contains `text/event-stream`, the progress of the request is
+/// streamed as Server-Sent Events. If some inputs fail, the stream ends with a `partial` event with
+/// the embeddings of the other inputs and an `error` event with the indices of the failed inputs.
+///
+/// If the request `Accept` header contains `application/vnd.apache.arrow.stream`, the embeddings
+/// are returned as an Arrow IPC stream with `index`, `custom_id` (if any) and `embedding` columns.
+///
+/// Inputs can also be uploaded as a `multipart/form-data` text file in a `file` part, with one
+/// input per line. The other parts of the form are the request fields, e.g. `truncate`.
+/// The inputs are subject to the same batch limits as JSON requests.
 #[utoipa::path(
 post,
 tag = "Text Embeddings Inference",
 path = "/embed",
 request_body = EmbedRequest,
+params(
+("X-Priority" = Option<String>, Header, description = "Scheduling priority of the request: `high` (default) or `low`"),
+),
 responses(
-(status = 200, description = "Embeddings", body = EmbedResponse),
+(status = 200, description = "Embeddings. Inputs sent with a `custom_id` return an `IdentifiedEmbedResponse`, inputs sent with a `group_id` a `GroupedEmbedResponse` and requests with `sort_by` a `SortedEmbedResponse`. Requests with `debug` also return the `HiddenStateSummary` of each input and requests with `return_summary` an `EmbedSummary`", content(
+("application/json" = EmbedResponse),
+("text/event-stream" = EmbedProgress),
+)),
 (status = 424, description = "Embedding Error", body = ErrorResponse,
 example = json ! ({"error": "Inference failed", "error_type": "backend"})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
@@ -296,24 +611,86 @@ example = json ! ({"error": "Batch size error", "error_type": "validation"})),
 async fn embed(
     infer: Extension<Infer>,
     info: Extension<Info>,
-    Json(req): Json<EmbedRequest>,
-) -> Result<(HeaderMap, Json<EmbedResponse>), (StatusCode, Json<ErrorResponse>)> {
+    #[cfg(feature = "shm")] shm: Extension<Option<Arc<ShmRing>>>,
+    request_headers: HeaderMap,
+    EmbedPayload(mut req): EmbedPayload,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let priority = request_priority(&request_headers)?;
+    let stream = accepts_event_stream(&request_headers);
+    #[cfg(feature = "shm")]
+    let shm = match (!stream && accepts_shm(&request_headers), shm.0) {
+        (true, Some(shm)) => Some(shm),
+        (true, None) => Err(shm_disabled())?,
+        (false, _) => None,
+    };
+    #[cfg(not(feature = "shm"))]
+    if !stream && accepts_shm(&request_headers) {
+        Err(shm_disabled())?;
+    }
+    check_embed_fields(&req, response_format(&request_headers))?;
+
+    if let Some(prompt) = resolve_prompt(&info, req.prompt_name.take(), req.prompt.take())? {
+        req.inputs = req.inputs.with_prompt(&prompt);
+    }
+    if let Some(language) = req.language.take() {
+        check_language(&info, &language)?;
+        // The language token is split from the text before the pre-tokenization
+        req.inputs = req.inputs.with_prompt(&format!("{language} "));
+    }
+
+    // Opt-in progress events
+    if stream {
+        return Ok(embed_sse(infer.0, info.0, req, priority).into_response());
+    }
+
     let span = tracing::Span::current();
     let start_time = Instant::now();
 
+    let (inputs, group_ids) = req.inputs.split_group_ids();
+    let (inputs, custom_ids) = inputs.split_custom_ids();
+    let add_special_tokens = add_special_tokens(&info, req.add_special_tokens);
+
+    let debug_inputs = if req.debug {
+        check_debug(&info)?;
+        match &inputs {
+            EmbedInput::Single(input) => Some(vec![input.clone().into()]),
+            EmbedInput::Batch(inputs) => Some(inputs.clone()),
+            EmbedInput::Identified(_) | EmbedInput::Grouped(_) => {
+                unreachable!("`custom_id`s and `group_id`s were split from the inputs")
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut truncated_count = 0;
+    let mut input_tokens = Vec::new();
     let (compute_chars, compute_tokens, tokenization_time, queue_time, inference_time, response) =
-        match req.inputs {
-            Input::Single(input) => {
+        match inputs {
+            EmbedInput::Single(input) => {
                 metrics::increment_counter!("te_request_count", "method" => "single");
+                check_response_size(&info, 1)?;
 
-                let compute_chars = input.chars().count();
+                let input = Sequence::from(input);
+                let compute_chars = input.count_chars();
 
                 let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
-                let response = infer
-                    .embed(input, req.truncate, req.normalize, permit)
-                    .await
-                    .map_err(ErrorResponse::from)?;
+                let response = embed_input(
+                    &infer,
+                    input,
+                    req.truncate,
+                    req.stride,
+                    req.layer,
+                    add_special_tokens,
+                    req.normalize,
+                    priority,
+                    permit,
+                )
+                .await
+                .map_err(ErrorResponse::from)?;
 
+                truncated_count += response.truncated as usize;
+                input_tokens.push(response.prompt_tokens);
                 metrics::increment_counter!("te_request_success", "method" => "single");
 
                 (
@@ -325,7 +702,7 @@ async fn embed(
                     EmbedResponse(vec![response.results]),
                 )
             }
-            Input::Batch(inputs) => {
+            EmbedInput::Batch(inputs) => {
                 metrics::increment_counter!("te_request_count", "method" => "batch");
 
                 let batch_size = inputs.len();
@@ -338,30 +715,55 @@ async fn embed(
                     let err = ErrorResponse {
                         error: message,
                         error_type: ErrorType::Validation,
+                        details: None,
                     };
                     metrics::increment_counter!("te_request_failure", "err" => "batch_size");
                     Err(err)?;
                 }
-
-                let mut futures = Vec::with_capacity(batch_size);
-                let mut compute_chars = 0;
-
-                for input in inputs {
-                    compute_chars += input.chars().count();
-
-                    let local_infer = infer.clone();
-                    futures.push(async move {
-                        let permit = local_infer.acquire_permit().await;
-                        local_infer
-                            .embed(input, req.truncate, req.normalize, permit)
+                check_response_size(&info, batch_size)?;
+
+                let compute_chars: usize = inputs.iter().map(|input| input.count_chars()).sum();
+
+                let results = match (req.stride, req.layer) {
+                    // The inputs are tokenized together
+                    (None, None) => {
+                        let inputs = inputs
+                            .into_iter()
+                            .enumerate()
+                            .map(|(index, input)| {
+                                (input, input_priority(priority, req.priority_prefix, index))
+                            })
+                            .collect();
+                        infer
+                            .embed_batch(inputs, req.truncate, add_special_tokens, req.normalize)
                             .await
-                    })
-                }
-                let results = join_all(futures)
-                    .await
-                    .into_iter()
-                    .collect::<Result<Vec<InferResponse>, TextEmbeddingsError>>()
-                    .map_err(ErrorResponse::from)?;
+                            .map_err(ErrorResponse::from)?
+                    }
+                    _ => {
+                        let mut futures = Vec::with_capacity(batch_size);
+                        for (index, input) in inputs.into_iter().enumerate() {
+                            let local_infer = infer.clone();
+                            let priority = input_priority(priority, req.priority_prefix, index);
+                            futures.push(async move {
+                                let permit = local_infer.acquire_permit().await;
+                                embed_input(
+                                    &local_infer,
+                                    input,
+                                    req.truncate,
+                                    req.stride,
+                                    req.layer,
+                                    add_special_tokens,
+                                    req.normalize,
+                                    priority,
+                                    permit,
+                                )
+                                .await
+                            })
+                        }
+                        join_all(futures).await
+                    }
+                };
+                let results = collect_batch_results(results)?;
 
                 let mut embeddings = Vec::with_capacity(batch_size);
                 let mut total_tokenization_time = 0;
@@ -374,6 +776,8 @@ async fn embed(
                     total_queue_time += r.queue.as_nanos() as u64;
                     total_inference_time += r.inference.as_nanos() as u64;
                     total_compute_tokens += r.prompt_tokens;
+                    truncated_count += r.truncated as usize;
+                    input_tokens.push(r.prompt_tokens);
                     embeddings.push(r.results);
                 }
                 let batch_size = batch_size as u64;
@@ -389,6 +793,9 @@ async fn embed(
                     EmbedResponse(embeddings),
                 )
             }
+            EmbedInput::Identified(_) | EmbedInput::Grouped(_) => {
+                unreachable!("`custom_id`s and `group_id`s were split from the inputs")
+            }
         };
 
     let total_time = start_time.elapsed();
@@ -414,6 +821,10 @@ async fn embed(
         "x-compute-tokens",
         compute_tokens.to_string().parse().unwrap(),
     );
+    headers.insert(
+        "x-truncated-count",
+        truncated_count.to_string().parse().unwrap(),
+    );
     headers.insert(
         "x-total-time",
         total_time.as_millis().to_string().parse().unwrap(),
@@ -443,9 +854,769 @@ async fn embed(
         inference_time.as_secs_f64()
     );
 
+    let round =
+        |embedding| round_embedding(embedding, info.embedding_precision, info.model_output_dtype);
+    let response = EmbedResponse(response.0.into_iter().map(round).collect());
+
+    // Colocated clients read the embeddings from shared memory
+    #[cfg(feature = "shm")]
+    if let Some(shm) = shm {
+        let response = shm.write(&response.0).map_err(|message| {
+            tracing::error!("{message}");
+            ErrorResponse {
+                error: message,
+                error_type: ErrorType::Backend,
+                details: None,
+            }
+        })?;
+        tracing::info!("Success");
+        return Ok((headers, Json(response)).into_response());
+    }
+
+    // Bulk export. The hidden state statistics are only returned in JSON
+    if accepts_arrow_stream(&request_headers) {
+        let body = embeddings_to_arrow_stream(response.0, custom_ids).map_err(|err| {
+            tracing::error!("{err}");
+            ErrorResponse {
+                error: format!("failed to serialize the embeddings to Arrow: {err}"),
+                error_type: ErrorType::Backend,
+                details: None,
+            }
+        })?;
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static(ARROW_STREAM_CONTENT_TYPE),
+        );
+        tracing::info!("Success");
+        return Ok((headers, body).into_response());
+    }
+
+    let hidden_states = match debug_inputs {
+        Some(inputs) => {
+            Some(hidden_state_summaries(&infer, inputs, req.truncate, add_special_tokens).await?)
+        }
+        None => None,
+    };
+    let summary = req.return_summary.then_some(EmbedSummary {
+        inputs: input_tokens.len(),
+        truncated: truncated_count,
+        tokens: compute_tokens,
+    });
+    let response = match (req.sort_by, custom_ids, group_ids) {
+        (_, _, Some(group_ids)) => {
+            let mut grouped =
+                GroupedEmbedResponse::new(group_ids, response, req.pool_groups, req.normalize);
+            for group in grouped.0.iter_mut() {
+                group.pooled = group.pooled.take().map(round);
+            }
+            extended_embed_response(grouped, hidden_states, summary)
+        }
+        (Some(SortBy::Length), custom_ids, None) => extended_embed_response(
+            SortedEmbedResponse::by_length(response, input_tokens, custom_ids),
+            hidden_states,
+            summary,
+        ),
+        (None, Some(custom_ids), None) => extended_embed_response(
+            IdentifiedEmbedResponse::new(custom_ids, response),
+            hidden_states,
+            summary,
+        ),
+        (None, None, None) => extended_embed_response(response, hidden_states, summary),
+    };
+
     tracing::info!("Success");
 
-    Ok((headers, Json(response)))
+    Ok((headers, response).into_response())
+}
+
+/// Collect the results of a batch.
+/// Instead of failing on the first invalid input, all the errors are returned at once with
+/// the index of their input.
+fn collect_batch_results<T, E: Into<ErrorResponse>>(
+    results: Vec<Result<T, E>>,
+) -> Result<Vec<T>, ErrorResponse> {
+    let mut values = Vec::with_capacity(results.len());
+    let mut details = Vec::new();
+
+    for (index, result) in results.into_iter().enumerate() {
+        match result {
+            Ok(value) => values.push(value),
+            Err(err) => details.push(error_detail(index, err.into())),
+        }
+    }
+
+    match batch_error(details) {
+        None => Ok(values),
+        Some(err) => Err(err),
+    }
+}
+
+fn error_detail(index: usize, err: ErrorResponse) -> ErrorDetail {
+    ErrorDetail {
+        index,
+        error: err.error,
+        error_type: err.error_type,
+    }
+}
+
+/// Error of a batch whose inputs failed with `details`, if any
+fn batch_error(details: Vec<ErrorDetail>) -> Option<ErrorResponse> {
+    match details.len() {
+        0 => None,
+        1 => Some(ErrorResponse {
+            error: details[0].error.clone(),
+            error_type: details[0].error_type.clone(),
+            details: Some(details),
+        }),
+        n => Some(ErrorResponse {
+            error: format!("{n} inputs of the batch are invalid"),
+            error_type: details[0].error_type.clone(),
+            details: Some(details),
+        }),
+    }
+}
+
+/// Reject the combinations of `/embed` fields that cannot be honored together, before running the
+/// model. The other combinations follow a fixed precedence:
+///  - `stride` takes precedence over `truncate`: the windows cover the whole input
+///  - `prompt` and `prompt_name` take precedence over the default prompt, and `language` is
+///    prepended before the prompt
+///  - `priority_prefix` has no effect on single inputs or when it is larger than the batch
+fn check_embed_fields(req: &EmbedRequest, format: Option<&str>) -> Result<(), ErrorResponse> {
+    let grouped = matches!(req.inputs, EmbedInput::Grouped(_));
+    let unsupported = [
+        (req.debug, "debug"),
+        (req.sort_by.is_some(), "sort_by"),
+        (req.return_summary, "return_summary"),
+        (grouped, "group_id"),
+    ]
+    .into_iter()
+    .find(|(set, _)| *set)
+    .map(|(_, field)| field);
+
+    let message = if req.stride.is_some() && req.layer.is_some() {
+        "`stride` and `layer` cannot be set at the same time".to_string()
+    } else if req.prompt.is_some() && req.prompt_name.is_some() {
+        "`prompt_name` and `prompt` cannot be set at the same time".to_string()
+    } else if grouped && req.sort_by.is_some() {
+        "`sort_by` is not supported for inputs with a `group_id`".to_string()
+    } else if req.pool_groups && !grouped {
+        "`pool_groups` requires inputs with a `group_id`".to_string()
+    } else if format == Some("shared memory") && matches!(req.inputs, EmbedInput::Identified(_)) {
+        "`custom_id` is not supported for shared memory responses".to_string()
+    } else if let (Some(format), Some(field)) = (format, unsupported) {
+        format!("`{field}` is not supported for {format} responses")
+    } else {
+        return Ok(());
+    };
+    tracing::error!("{message}");
+    metrics::increment_counter!("te_request_failure", "err" => "validation");
+    Err(ErrorResponse {
+        error: message,
+        error_type: ErrorType::Validation,
+        details: None,
+    })
+}
+
+/// Debug requests must be allowed by the server
+fn check_debug(info: &Info) -> Result<(), ErrorResponse> {
+    if !info.allow_debug {
+        let message = "`debug` is disabled. Start the server with `--allow-debug`".to_string();
+        tracing::error!("{message}");
+        metrics::increment_counter!("te_request_failure", "err" => "debug");
+        return Err(ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+            details: None,
+        });
+    }
+    Ok(())
+}
+
+/// Hidden state statistics of each input
+async fn hidden_state_summaries(
+    infer: &Infer,
+    inputs: Vec<Sequence>,
+    truncate: bool,
+    add_special_tokens: bool,
+) -> Result<Vec<HiddenStateSummary>, ErrorResponse> {
+    let mut summaries = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let permit = infer.acquire_permit().await;
+        let stats = infer
+            .hidden_state_stats(input, truncate, add_special_tokens, permit)
+            .await
+            .map_err(ErrorResponse::from)?;
+        summaries.push(stats.into());
+    }
+    Ok(summaries)
+}
+
+/// Wrap the embeddings with the hidden state statistics and the summary if they were requested
+fn extended_embed_response<T: serde::Serialize>(
+    embeddings: T,
+    hidden_states: Option<Vec<HiddenStateSummary>>,
+    summary: Option<EmbedSummary>,
+) -> Response {
+    match (hidden_states, summary) {
+        (None, None) => Json(embeddings).into_response(),
+        (hidden_states, summary) => Json(ExtendedEmbedResponse {
+            embeddings,
+            hidden_states,
+            summary,
+        })
+        .into_response(),
+    }
+}
+
+/// Embed a single input. If `stride` is set, inputs that are too long are embedded in overlapping
+/// windows instead of being truncated. If `layer` is set, the input is pooled from the hidden
+/// states of this layer.
+#[allow(clippy::too_many_arguments)]
+async fn embed_input<I: Into<EncodingInput> + std::fmt::Debug>(
+    infer: &Infer,
+    input: I,
+    truncate: bool,
+    stride: Option<usize>,
+    layer: Option<i32>,
+    add_special_tokens: bool,
+    normalize: bool,
+    priority: Priority,
+    permit: OwnedSemaphorePermit,
+) -> Result<InferResponse, TextEmbeddingsError> {
+    match (stride, layer) {
+        (Some(_), Some(_)) => Err(TextEmbeddingsError::Validation(
+            "`stride` and `layer` cannot be set at the same time".to_string(),
+        )),
+        (Some(stride), None) => {
+            infer
+                .embed_windows(
+                    input,
+                    stride,
+                    add_special_tokens,
+                    normalize,
+                    priority,
+                    permit,
+                )
+                .await
+        }
+        (None, Some(layer)) => {
+            infer
+                .embed_layer(
+                    input,
+                    truncate,
+                    add_special_tokens,
+                    normalize,
+                    layer,
+                    permit,
+                )
+                .await
+        }
+        (None, None) => {
+            infer
+                .embed(
+                    input,
+                    truncate,
+                    add_special_tokens,
+                    normalize,
+                    priority,
+                    permit,
+                )
+                .await
+        }
+    }
+}
+
+/// `add_special_tokens` of an embedding request. Defaults to `true`.
+fn add_special_tokens(info: &Info, add_special_tokens: Option<bool>) -> bool {
+    let add_special_tokens = add_special_tokens.unwrap_or(true);
+    if let (false, ModelType::Embedding(model)) = (add_special_tokens, &info.model_type) {
+        if model.pooling == "cls" {
+            tracing::warn!(
+                "`add_special_tokens` is false but the model uses CLS pooling: the first token of the input is used as the embedding"
+            );
+        }
+    }
+    add_special_tokens
+}
+
+/// Prompt to prepend to the inputs of an embedding request.
+/// `prompt_name` and `prompt` override the default prompt of the model.
+fn resolve_prompt(
+    info: &Info,
+    prompt_name: Option<String>,
+    prompt: Option<String>,
+) -> Result<Option<String>, ErrorResponse> {
+    let prompt = match (prompt_name, prompt) {
+        (Some(_), Some(_)) => {
+            Err("`prompt_name` and `prompt` cannot be set at the same time".to_string())
+        }
+        (None, Some(prompt)) => Ok(Some(prompt)),
+        (Some(prompt_name), None) => match info.prompts.get(&prompt_name) {
+            Some(prompt) => Ok(Some(prompt.clone())),
+            None => Err(format!(
+                "prompt `{prompt_name}` not found in the model prompts"
+            )),
+        },
+        (None, None) => Ok(default_prompt(info).map(str::to_string)),
+    };
+
+    prompt
+        .map(|prompt| prompt.filter(|prompt| !prompt.is_empty()))
+        .map_err(|message| {
+            tracing::error!("{message}");
+            metrics::increment_counter!("te_request_failure", "err" => "validation");
+            ErrorResponse {
+                error: message,
+                error_type: ErrorType::Validation,
+                details: None,
+            }
+        })
+}
+
+/// The language must be one of the language tokens of the tokenizer
+fn check_language(info: &Info, language: &str) -> Result<(), ErrorResponse> {
+    let language_tokens = &info.tokenizer.language_tokens;
+    if language_tokens.iter().any(|token| token == language) {
+        return Ok(());
+    }
+
+    let message = match language_tokens.is_empty() {
+        true => "`language` is not supported: the tokenizer does not have language tokens".to_string(),
+        false => format!(
+            "language `{language}` not found in the tokenizer language tokens. See `tokenizer.language_tokens` in `/info`"
+        ),
+    };
+    tracing::error!("{message}");
+    metrics::increment_counter!("te_request_failure", "err" => "validation");
+    Err(ErrorResponse {
+        error: message,
+        error_type: ErrorType::Validation,
+        details: None,
+    })
+}
+
+/// Default prompt of the model, from `config_sentence_transformers.json`
+fn default_prompt(info: &Info) -> Option<&str> {
+    info.default_prompt_name
+        .as_ref()
+        .and_then(|name| info.prompts.get(name))
+        .map(String::as_str)
+}
+
+/// Header setting the scheduling priority of a request
+const X_PRIORITY: &str = "x-priority";
+
+/// Scheduling priority of a request, from its `X-Priority: high|low` header.
+/// Requests without the header have a high priority.
+fn request_priority(headers: &HeaderMap) -> Result<Priority, ErrorResponse> {
+    let Some(value) = headers.get(X_PRIORITY) else {
+        return Ok(Priority::High);
+    };
+    match value.to_str().map(|v| v.trim().to_lowercase()).as_deref() {
+        Ok("high") => Ok(Priority::High),
+        Ok("low") => Ok(Priority::Low),
+        _ => {
+            let message = "`X-Priority` header must be `high` or `low`".to_string();
+            tracing::error!("{message}");
+            metrics::increment_counter!("te_request_failure", "err" => "validation");
+            Err(ErrorResponse {
+                error: message,
+                error_type: ErrorType::Validation,
+                details: None,
+            })
+        }
+    }
+}
+
+/// Rough upper bound of the JSON size of a `f32` and its separator, e.g. `-0.012345678,`
+const JSON_F32_BYTES: usize = 16;
+
+/// Reject embedding requests whose JSON response would exceed `max_response_bytes`
+fn check_response_size(info: &Info, batch_size: usize) -> Result<(), ErrorResponse> {
+    if let (Some(max_response_bytes), ModelType::Embedding(model)) =
+        (info.max_response_bytes, &info.model_type)
+    {
+        let estimate = batch_size * model.dimension * JSON_F32_BYTES;
+        if estimate > max_response_bytes {
+            let message = format!(
+                "estimated response size {estimate} bytes > maximum allowed response size {max_response_bytes} bytes"
+            );
+            tracing::error!("{message}");
+            metrics::increment_counter!("te_request_failure", "err" => "response_size");
+            return Err(ErrorResponse {
+                error: message,
+                error_type: ErrorType::Validation,
+                details: None,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// `/embed` request body: a JSON `EmbedRequest` or a `multipart/form-data` upload
+struct EmbedPayload(EmbedRequest);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequest<S, Body> for EmbedPayload {
+    type Rejection = Response;
+
+    async fn from_request(request: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        let is_form = request
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|mime| mime.trim().eq_ignore_ascii_case("multipart/form-data"))
+            .unwrap_or(false);
+
+        match is_form {
+            true => {
+                let form = Multipart::from_request(request, state)
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                form_fields(form)
+                    .await
+                    .and_then(embed_request_from_form)
+                    .map(Self)
+                    .map_err(|message| {
+                        tracing::error!("{message}");
+                        metrics::increment_counter!("te_request_failure", "err" => "validation");
+                        let err = ErrorResponse {
+                            error: message,
+                            error_type: ErrorType::Validation,
+                            details: None,
+                        };
+                        <(StatusCode, Json<ErrorResponse>)>::from(err).into_response()
+                    })
+            }
+            false => {
+                let Json(request) = Json::<EmbedRequest>::from_request(request, state)
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                Ok(Self(request))
+            }
+        }
+    }
+}
+
+/// Names and values of the fields of a form upload
+async fn form_fields(mut form: Multipart) -> Result<Vec<(String, String)>, String> {
+    let mut fields = Vec::new();
+    while let Some(field) = form
+        .next_field()
+        .await
+        .map_err(|err| format!("invalid form upload: {err}"))?
+    {
+        let name = field
+            .name()
+            .ok_or("form field is missing a `name`")?
+            .to_string();
+        let data = field
+            .bytes()
+            .await
+            .map_err(|err| format!("invalid form upload: {err}"))?;
+        let value = String::from_utf8(data.to_vec())
+            .map_err(|_| format!("form field `{name}` is not valid UTF-8"))?;
+        fields.push((name, value));
+    }
+    Ok(fields)
+}
+
+/// Build an `EmbedRequest` from the fields of a form upload.
+/// Each non empty line of the `file` field is an input. The other fields are the fields of the
+/// request, e.g. `truncate` or `normalize`.
+fn embed_request_from_form(fields: Vec<(String, String)>) -> Result<EmbedRequest, String> {
+    let mut request = serde_json::Map::new();
+    let mut inputs = None;
+
+    for (name, value) in fields {
+        match name.as_str() {
+            "file" => {
+                inputs = Some(
+                    value
+                        .lines()
+                        .filter(|line| !line.trim().is_empty())
+                        .map(|line| serde_json::Value::String(line.to_string()))
+                        .collect(),
+                )
+            }
+            "inputs" => {
+                return Err("`inputs` cannot be set in a form upload, use a `file` part".to_string())
+            }
+            _ => {
+                // Non JSON values such as prompts are strings
+                let value = serde_json::from_str(&value)
+                    .unwrap_or_else(|_| serde_json::Value::String(value.clone()));
+                request.insert(name, value);
+            }
+        }
+    }
+
+    let inputs = inputs.ok_or("form upload is missing a `file` part")?;
+    request.insert("inputs".to_string(), serde_json::Value::Array(inputs));
+    serde_json::from_value(serde_json::Value::Object(request))
+        .map_err(|err| format!("invalid form upload: {err}"))
+}
+
+/// Returns true if the client accepts Server-Sent Events
+fn accepts_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains("text/event-stream"))
+        .unwrap_or(false)
+}
+
+/// Accept header of the `/embed` requests whose embeddings are written to shared memory
+const SHM_CONTENT_TYPE: &str = "application/vnd.tei.shm+json";
+
+/// Returns true if the client asks for the embeddings to be written to shared memory
+fn accepts_shm(headers: &HeaderMap) -> bool {
+    headers
+        .get(http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains(SHM_CONTENT_TYPE))
+        .unwrap_or(false)
+}
+
+/// Error of the shared memory requests sent to a server started without `--shm`
+fn shm_disabled() -> ErrorResponse {
+    let message = "shared memory output is disabled. Start the server with `--shm`".to_string();
+    tracing::error!("{message}");
+    metrics::increment_counter!("te_request_failure", "err" => "validation");
+    ErrorResponse {
+        error: message,
+        error_type: ErrorType::Validation,
+        details: None,
+    }
+}
+
+/// Name of the response format asked by the client, if it is not a JSON body
+fn response_format(headers: &HeaderMap) -> Option<&'static str> {
+    if accepts_event_stream(headers) {
+        Some("streamed")
+    } else if accepts_shm(headers) {
+        Some("shared memory")
+    } else if accepts_arrow_stream(headers) {
+        Some("Arrow")
+    } else {
+        None
+    }
+}
+
+/// Returns true if the client accepts Arrow IPC streams
+fn accepts_arrow_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains(ARROW_STREAM_CONTENT_TYPE))
+        .unwrap_or(false)
+}
+
+/// Conditional requests for `/embed`.
+///
+/// Embeddings are deterministic: the ETag is a hash of the served model and of the request
+/// body. A request whose `If-None-Match` header matches the ETag returns a 304 without
+/// running the model.
+async fn embed_etag(
+    State(payload_limit): State<usize>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    // Progress streams are not cached, and shared memory records are overwritten once the ring
+    // buffer wraps around
+    if accepts_event_stream(request.headers()) || accepts_shm(request.headers()) {
+        return Ok(next.run(request).await);
+    }
+
+    let (parts, body) = request.into_parts();
+    let body = read_body(body, payload_limit).await?;
+
+    let mut hasher = DefaultHasher::new();
+    if let Some(info) = parts.extensions.get::<Info>() {
+        info.model_id.hash(&mut hasher);
+        info.model_sha.hash(&mut hasher);
+        info.version.hash(&mut hasher);
+    }
+    // Arrow and JSON representations of the same embeddings have different tags
+    response_format(&parts.headers).hash(&mut hasher);
+    body.hash(&mut hasher);
+    let etag = HeaderValue::from_str(&format!("\"{:016x}\"", hasher.finish())).unwrap();
+
+    let not_modified = parts
+        .headers
+        .get_all(http::header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim().trim_start_matches("W/").as_bytes() == etag.as_bytes());
+    if not_modified {
+        metrics::increment_counter!("te_request_not_modified");
+        let mut response = StatusCode::NOT_MODIFIED.into_response();
+        response.headers_mut().insert(http::header::ETAG, etag);
+        return Ok(response);
+    }
+
+    let mut response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    if response.status() == StatusCode::OK {
+        response.headers_mut().insert(http::header::ETAG, etag);
+    }
+    Ok(response)
+}
+
+/// Priority of the input at `index` of a batch: the inputs after the first `priority_prefix` ones
+/// wait for the other requests
+fn input_priority(priority: Priority, priority_prefix: Option<usize>, index: usize) -> Priority {
+    match priority_prefix {
+        Some(prefix) if index >= prefix => Priority::Low,
+        _ => priority,
+    }
+}
+
+/// Stream the progress of an `/embed` request as Server-Sent Events.
+///
+/// A `progress` event is sent each time an input is embedded. The stream ends with either an
+/// `embeddings` event containing the `EmbedResponse` or an `error` event containing an
+/// `ErrorResponse`.
+fn embed_sse(
+    infer: Infer,
+    info: Info,
+    req: EmbedRequest,
+    priority: Priority,
+) -> Sse<impl Stream<Item = Result<Event, serde_json::Error>>> {
+    let stream = async_stream::stream! {
+        metrics::increment_counter!("te_request_count", "method" => "stream");
+
+        let (inputs, custom_ids) = req.inputs.split_custom_ids();
+        let inputs = match inputs {
+            EmbedInput::Single(input) => vec![input.into()],
+            EmbedInput::Batch(inputs) => inputs,
+            EmbedInput::Identified(_) => unreachable!("`custom_id`s were split from the inputs"),
+            EmbedInput::Grouped(_) => {
+                unreachable!("inputs with a `group_id` are rejected for streamed responses")
+            }
+        };
+
+        let total = inputs.len();
+        if total > info.max_client_batch_size {
+            let message = format!(
+                "batch size {total} > maximum allowed batch size {}",
+                info.max_client_batch_size
+            );
+            tracing::error!("{message}");
+            metrics::increment_counter!("te_request_failure", "err" => "batch_size");
+            yield Event::default().event("error").json_data(ErrorResponse {
+                error: message,
+                error_type: ErrorType::Validation,
+                details: None,
+            });
+            return;
+        }
+        if let Err(err) = check_response_size(&info, total) {
+            yield Event::default().event("error").json_data(err);
+            return;
+        }
+
+        let truncate = req.truncate;
+        let stride = req.stride;
+        let layer = req.layer;
+        let add_special_tokens = add_special_tokens(&info, req.add_special_tokens);
+        let normalize = req.normalize;
+
+        let mut futures = FuturesUnordered::new();
+        for (index, input) in inputs.into_iter().enumerate() {
+            let local_infer = infer.clone();
+            let priority = input_priority(priority, req.priority_prefix, index);
+            futures.push(async move {
+                let permit = local_infer.acquire_permit().await;
+                let result = embed_input(
+                    &local_infer,
+                    input,
+                    truncate,
+                    stride,
+                    layer,
+                    add_special_tokens,
+                    normalize,
+                    priority,
+                    permit,
+                )
+                .await;
+                (index, result)
+            });
+        }
+
+        // A failed input does not cancel the others: the stream ends with the embeddings of the
+        // inputs that succeeded and the indices of the inputs to retry
+        let mut responses: Vec<Option<InferResponse>> = (0..total).map(|_| None).collect();
+        let mut details = Vec::new();
+        let mut completed = 0;
+        while let Some((index, result)) = futures.next().await {
+            match result {
+                Ok(response) => responses[index] = Some(response),
+                Err(err) => details.push(error_detail(index, err.into())),
+            }
+            completed += 1;
+            yield Event::default()
+                .event("progress")
+                .json_data(EmbedProgress { completed, total });
+        }
+
+        let round = |embedding| {
+            round_embedding(embedding, info.embedding_precision, info.model_output_dtype)
+        };
+
+        if let Some(err) = batch_error(details) {
+            let mut custom_ids = custom_ids.map(|ids| ids.into_iter());
+            let partial: Vec<SortedEmbedding> = responses
+                .into_iter()
+                .enumerate()
+                .filter_map(|(index, response)| {
+                    let custom_id = custom_ids.as_mut().and_then(|ids| ids.next());
+                    response.map(|response| SortedEmbedding {
+                        index,
+                        custom_id,
+                        tokens: response.prompt_tokens,
+                        embedding: round(response.results),
+                    })
+                })
+                .collect();
+            tracing::error!("{}", err.error);
+            yield Event::default().event("partial").json_data(partial);
+            yield Event::default().event("error").json_data(err);
+            return;
+        }
+
+        let embeddings: Vec<Vec<f32>> = responses
+            .into_iter()
+            .map(|response| round(response.expect("all the inputs succeeded").results))
+            .collect();
+
+        metrics::increment_counter!("te_request_success", "method" => "stream");
+        tracing::info!("Success");
+
+        let event = Event::default().event("embeddings");
+        yield match custom_ids {
+            Some(custom_ids) => {
+                event.json_data(IdentifiedEmbedResponse::new(custom_ids, EmbedResponse(embeddings)))
+            }
+            None => event.json_data(EmbedResponse(embeddings)),
+        };
+    };
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// OpenAI compatible route. List the models served by this deployment.
+#[utoipa::path(
+get,
+tag = "Text Embeddings Inference",
+path = "/models",
+responses((status = 200, description = "Served models", body = OpenAICompatModelList))
+)]
+#[instrument(skip_all)]
+async fn openai_models(models: Extension<OpenAICompatModelList>) -> Json<OpenAICompatModelList> {
+    Json(models.0)
 }
 
 /// OpenAI compatible route. Returns a 424 status code if the model is not an embedding model.
@@ -454,6 +1625,9 @@ post,
 tag = "Text Embeddings Inference",
 path = "/embeddings",
 request_body = OpenAICompatRequest,
+params(
+("X-Priority" = Option<String>, Header, description = "Scheduling priority of the request: `high` (default) or `low`"),
+),
 responses(
 (status = 200, description = "Embeddings", body = OpenAICompatResponse),
 (status = 424, description = "Embedding Error", body = OpenAICompatErrorResponse,
@@ -473,25 +1647,49 @@ example = json ! ({"message": "Batch size error", "type": "validation"})),
 async fn openai_embed(
     infer: Extension<Infer>,
     info: Extension<Info>,
+    request_headers: HeaderMap,
     Json(req): Json<OpenAICompatRequest>,
 ) -> Result<(HeaderMap, Json<OpenAICompatResponse>), (StatusCode, Json<OpenAICompatErrorResponse>)>
 {
     let span = tracing::Span::current();
     let start_time = Instant::now();
+    let priority = request_priority(&request_headers)?;
 
-    let (compute_chars, compute_tokens, tokenization_time, queue_time, inference_time, embeddings) =
-        match req.input {
-            Input::Single(input) => {
+    if let Some(dimensions) = req.dimensions {
+        check_dimensions(&info, dimensions)?;
+    }
+
+    let input = openai_input(&info, req.input, req.texts)?;
+    let input = match default_prompt(&info) {
+        Some(prompt) => match input {
+            Input::Single(input) => Input::Single(format!("{prompt}{input}")),
+            Input::Batch(inputs) => Input::Batch(
+                inputs
+                    .into_iter()
+                    .map(|input| format!("{prompt}{input}"))
+                    .collect(),
+            ),
+        },
+        None => input,
+    };
+
+    let mut truncated_count = 0;
+    let (compute_chars, compute_tokens, tokenization_time, queue_time, inference_time, embeddings) =
+        match input {
+            Input::Single(input) => {
                 metrics::increment_counter!("te_request_count", "method" => "single");
+                check_response_size(&info, 1)?;
 
                 let compute_chars = input.chars().count();
 
                 let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
+                // Normalization happens after the `dimensions` truncation
                 let response = infer
-                    .embed(input, false, true, permit)
+                    .embed(input, info.auto_truncate, true, false, priority, permit)
                     .await
-                    .map_err(ErrorResponse::from)?;
+                    .map_err(openai_embed_error)?;
 
+                truncated_count += response.truncated as usize;
                 metrics::increment_counter!("te_request_success", "method" => "single");
 
                 (
@@ -502,7 +1700,14 @@ async fn openai_embed(
                     response.inference,
                     vec![OpenAICompatEmbedding {
                         object: "embedding",
-                        embedding: response.results,
+                        embedding: openai_embedding_data(
+                            response.results,
+                            req.dimensions,
+                            req.return_raw_and_normalized,
+                            info.embedding_precision,
+                            info.model_output_dtype,
+                            req.encoding_format,
+                        ),
                         index: 0,
                     }],
                 )
@@ -520,28 +1725,29 @@ async fn openai_embed(
                     let err = ErrorResponse {
                         error: message,
                         error_type: ErrorType::Validation,
+                        details: None,
                     };
                     metrics::increment_counter!("te_request_failure", "err" => "batch_size");
                     Err(err)?;
                 }
-
-                let mut futures = Vec::with_capacity(batch_size);
-                let mut compute_chars = 0;
-
-                for input in inputs {
-                    compute_chars += input.chars().count();
-
-                    let local_infer = infer.clone();
-                    futures.push(async move {
-                        let permit = local_infer.acquire_permit().await;
-                        local_infer.embed(input, false, true, permit).await
-                    })
-                }
-                let results = join_all(futures)
+                check_response_size(&info, batch_size)?;
+
+                let compute_chars: usize = inputs.iter().map(|input| input.chars().count()).sum();
+
+                // Normalization happens after the `dimensions` truncation
+                let results = infer
+                    .embed_batch(
+                        inputs.into_iter().map(|input| (input, priority)).collect(),
+                        info.auto_truncate,
+                        true,
+                        false,
+                    )
                     .await
+                    .map_err(ErrorResponse::from)?
                     .into_iter()
-                    .collect::<Result<Vec<InferResponse>, TextEmbeddingsError>>()
-                    .map_err(ErrorResponse::from)?;
+                    .map(|result| result.map_err(openai_embed_error))
+                    .collect::<Vec<_>>();
+                let results = collect_batch_results(results)?;
 
                 let mut embeddings = Vec::with_capacity(batch_size);
                 let mut total_tokenization_time = 0;
@@ -554,9 +1760,17 @@ async fn openai_embed(
                     total_queue_time += r.queue.as_nanos() as u64;
                     total_inference_time += r.inference.as_nanos() as u64;
                     total_compute_tokens += r.prompt_tokens;
+                    truncated_count += r.truncated as usize;
                     embeddings.push(OpenAICompatEmbedding {
                         object: "embedding",
-                        embedding: r.results,
+                        embedding: openai_embedding_data(
+                            r.results,
+                            req.dimensions,
+                            req.return_raw_and_normalized,
+                            info.embedding_precision,
+                            info.model_output_dtype,
+                            req.encoding_format,
+                        ),
                         index: i,
                     });
                 }
@@ -598,6 +1812,10 @@ async fn openai_embed(
         "x-compute-tokens",
         compute_tokens.to_string().parse().unwrap(),
     );
+    headers.insert(
+        "x-truncated-count",
+        truncated_count.to_string().parse().unwrap(),
+    );
     headers.insert(
         "x-total-time",
         total_time.as_millis().to_string().parse().unwrap(),
@@ -641,6 +1859,518 @@ async fn openai_embed(
     Ok((headers, Json(response)))
 }
 
+/// Errors of the OpenAI compatible route. Inputs longer than the model maximum input length get
+/// the same message as the OpenAI API.
+fn openai_embed_error(err: TextEmbeddingsError) -> ErrorResponse {
+    match err {
+        TextEmbeddingsError::InputTooLong {
+            tokens,
+            max_input_length,
+        } => ErrorResponse {
+            error: format!(
+                "This model's maximum context length is {max_input_length} tokens, however you requested {tokens} tokens. Please reduce your input."
+            ),
+            error_type: ErrorType::Validation,
+            details: None,
+        },
+        err => ErrorResponse::from(err),
+    }
+}
+
+/// Inputs of an OpenAI compatible request, sent either as `input` or, if
+/// `--openai-accept-texts` is set, as `texts`
+fn openai_input(
+    info: &Info,
+    input: Option<Input>,
+    texts: Option<Input>,
+) -> Result<Input, ErrorResponse> {
+    let message = match (input, texts) {
+        (Some(input), None) => return Ok(input),
+        (None, Some(texts)) if info.openai_accept_texts => return Ok(texts),
+        (None, Some(_)) => {
+            "`texts` is not accepted by this server, send the inputs in `input` instead"
+        }
+        (Some(_), Some(_)) => "only one of `input` and `texts` can be set",
+        (None, None) => "missing field `input`",
+    };
+    tracing::error!("{message}");
+    metrics::increment_counter!("te_request_failure", "err" => "validation");
+    Err(ErrorResponse {
+        error: message.to_string(),
+        error_type: ErrorType::Validation,
+        details: None,
+    })
+}
+
+/// Validate the requested Matryoshka `dimensions` against the model hidden size
+fn check_dimensions(info: &Info, dimensions: usize) -> Result<(), ErrorResponse> {
+    let max_dimensions = match &info.model_type {
+        ModelType::Embedding(model) => model.dimension,
+        ModelType::Classifier(_) => return Ok(()),
+    };
+    if dimensions == 0 || dimensions > max_dimensions {
+        let message = format!("`dimensions` must be between 1 and {max_dimensions}");
+        tracing::error!("{message}");
+        metrics::increment_counter!("te_request_failure", "err" => "dimensions");
+        return Err(ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+            details: None,
+        });
+    }
+    Ok(())
+}
+
+/// OpenAI embeddings are normalized. The raw embedding is also returned if requested.
+fn openai_embedding_data(
+    embedding: Vec<f32>,
+    dimensions: Option<usize>,
+    return_raw_and_normalized: bool,
+    precision: Option<usize>,
+    output_dtype: OutputDType,
+    encoding_format: EncodingFormat,
+) -> EmbeddingData {
+    let normalized = |embedding| {
+        postprocess_embedding(
+            embedding,
+            dimensions,
+            true,
+            precision,
+            output_dtype,
+            encoding_format,
+        )
+    };
+    match return_raw_and_normalized {
+        true => EmbeddingData::RawAndNormalized {
+            raw: Box::new(postprocess_embedding(
+                embedding.clone(),
+                dimensions,
+                false,
+                precision,
+                output_dtype,
+                encoding_format,
+            )),
+            normalized: Box::new(normalized(embedding)),
+        },
+        false => normalized(embedding),
+    }
+}
+
+/// Post-process a pooled embedding.
+/// The order matters: the embedding is truncated to `dimensions` first, then normalized, rounded
+/// and finally encoded. Rounding to `precision` only applies to the float and pgvector encodings,
+/// and rounding to `output_dtype` to every encoding but int8.
+fn postprocess_embedding(
+    mut embedding: Vec<f32>,
+    dimensions: Option<usize>,
+    normalize: bool,
+    precision: Option<usize>,
+    output_dtype: OutputDType,
+    encoding_format: EncodingFormat,
+) -> EmbeddingData {
+    if let Some(dimensions) = dimensions {
+        embedding.truncate(dimensions);
+    }
+    if normalize {
+        normalize_embedding(&mut embedding);
+    }
+    match encoding_format {
+        EncodingFormat::Float => {
+            EmbeddingData::Float(round_embedding(embedding, precision, output_dtype))
+        }
+        EncodingFormat::Base64 => {
+            output_dtype.cast(&mut embedding);
+            let bytes: Vec<u8> = embedding.iter().flat_map(|v| v.to_le_bytes()).collect();
+            EmbeddingData::Base64(BASE64.encode(bytes))
+        }
+        EncodingFormat::Pgvector => {
+            let embedding = round_embedding(embedding, precision, output_dtype);
+            let values: Vec<String> = embedding.iter().map(|v| v.to_string()).collect();
+            EmbeddingData::Pgvector(format!("[{}]", values.join(",")))
+        }
+        EncodingFormat::Int8 => quantize_int8(&embedding),
+    }
+}
+
+/// Scalar quantize an embedding to int8 over its own `[min, max]` range.
+/// `value * scale + offset` gives back the original value within `scale / 2`.
+fn quantize_int8(embedding: &[f32]) -> EmbeddingData {
+    let min = embedding.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = embedding.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if embedding.is_empty() || max <= min {
+        // Constant embedding: every value is the offset
+        return EmbeddingData::Int8 {
+            values: vec![0; embedding.len()],
+            scale: 0.0,
+            offset: if embedding.is_empty() { 0.0 } else { min },
+        };
+    }
+
+    let scale = (max - min) / 255.0;
+    let values = embedding
+        .iter()
+        .map(|v| (((v - min) / scale).round() - 128.0).clamp(-128.0, 127.0) as i8)
+        .collect();
+    EmbeddingData::Int8 {
+        values,
+        scale,
+        offset: min + 128.0 * scale,
+    }
+}
+
+/// Round `embedding` to `precision` significant digits, then to `output_dtype`.
+/// This is the last step before serialization, after the normalization and the `dimensions`
+/// truncation.
+fn round_embedding(
+    embedding: Vec<f32>,
+    precision: Option<usize>,
+    output_dtype: OutputDType,
+) -> Vec<f32> {
+    let mut embedding = match precision {
+        Some(precision) => round_to_precision(embedding, precision),
+        None => embedding,
+    };
+    output_dtype.cast(&mut embedding);
+    embedding
+}
+
+/// Round each value of `embedding` to `precision` significant digits
+fn round_to_precision(mut embedding: Vec<f32>, precision: usize) -> Vec<f32> {
+    for v in embedding.iter_mut() {
+        if *v == 0.0 || !v.is_finite() {
+            continue;
+        }
+        let value = *v as f64;
+        let magnitude = value.abs().log10().floor() as i32;
+        let factor = 10f64.powi(precision as i32 - 1 - magnitude);
+        *v = ((value * factor).round() / factor) as f32;
+    }
+    embedding
+}
+
+/// Get the pairwise cosine similarity matrix of a batch of inputs.
+/// The matrix contains `n x n` values for `n` inputs so `n` is capped by
+/// `--max-client-batch-size`, and by `--max-response-bytes` if it is set.
+#[utoipa::path(
+post,
+tag = "Text Embeddings Inference",
+path = "/similarity/matrix",
+request_body = SimilarityMatrixRequest,
+params(
+("X-Priority" = Option<String>, Header, description = "Scheduling priority of the request: `high` (default) or `low`"),
+),
+responses(
+(status = 200, description = "Cosine similarity matrix", body = SimilarityMatrixResponse),
+(status = 424, description = "Embedding Error", body = ErrorResponse,
+example = json ! ({"error": "Inference failed", "error_type": "backend"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"})),
+(status = 422, description = "Tokenization error", body = ErrorResponse,
+example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
+(status = 413, description = "Batch size error", body = ErrorResponse,
+example = json ! ({"error": "Batch size error", "error_type": "validation"})),
+)
+)]
+#[instrument(skip_all)]
+async fn similarity_matrix(
+    infer: Extension<Infer>,
+    info: Extension<Info>,
+    request_headers: HeaderMap,
+    Json(req): Json<SimilarityMatrixRequest>,
+) -> Result<Json<SimilarityMatrixResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let priority = request_priority(&request_headers)?;
+    metrics::increment_counter!("te_request_count", "method" => "similarity_matrix");
+
+    let n = req.inputs.len();
+    let message = if n == 0 {
+        Some("`inputs` cannot be empty".to_string())
+    } else if n > info.max_client_batch_size {
+        Some(format!(
+            "batch size {n} > maximum allowed batch size {}",
+            info.max_client_batch_size
+        ))
+    } else {
+        match info.max_response_bytes {
+            Some(max_response_bytes) if n * n * JSON_F32_BYTES > max_response_bytes => Some(format!(
+                "a similarity matrix of {n} inputs is estimated to be larger than the maximum response size of {max_response_bytes} bytes"
+            )),
+            _ => None,
+        }
+    };
+    if let Some(message) = message {
+        tracing::error!("{message}");
+        metrics::increment_counter!("te_request_failure", "err" => "batch_size");
+        Err(ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+            details: None,
+        })?;
+    }
+
+    let truncate = req.truncate;
+    let prompt = default_prompt(&info).unwrap_or_default();
+    let futures = req.inputs.into_iter().map(|input| {
+        let local_infer = infer.clone();
+        let input = format!("{prompt}{input}");
+        async move {
+            let permit = local_infer.acquire_permit().await;
+            local_infer
+                .embed(input, truncate, true, true, priority, permit)
+                .await
+        }
+    });
+    let results = collect_batch_results(join_all(futures).await)?;
+    let embeddings: Vec<Vec<f32>> = results.into_iter().map(|r| r.results).collect();
+
+    metrics::increment_counter!("te_request_success", "method" => "similarity_matrix");
+    tracing::info!("Success");
+
+    Ok(Json(SimilarityMatrixResponse(cosine_similarity_matrix(
+        &embeddings,
+    ))))
+}
+
+/// Get the dense, sparse and ColBERT embeddings of the inputs from a single forward pass.
+/// Only supported by hybrid retrieval models, such as BGE-M3, with sparse and ColBERT heads.
+#[utoipa::path(
+post,
+tag = "Text Embeddings Inference",
+path = "/embed_all_modes",
+request_body = EmbedAllModesRequest,
+responses(
+(status = 200, description = "Dense, sparse and ColBERT embeddings", body = EmbedAllModesResponse),
+(status = 424, description = "Embedding Error", body = ErrorResponse,
+example = json ! ({"error": "Inference failed", "error_type": "backend"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"})),
+(status = 422, description = "Tokenization error", body = ErrorResponse,
+example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
+(status = 413, description = "Batch size error", body = ErrorResponse,
+example = json ! ({"error": "Batch size error", "error_type": "validation"})),
+)
+)]
+#[instrument(skip_all)]
+async fn embed_all_modes(
+    infer: Extension<Infer>,
+    info: Extension<Info>,
+    Json(req): Json<EmbedAllModesRequest>,
+) -> Result<Json<EmbedAllModesResponse>, (StatusCode, Json<ErrorResponse>)> {
+    metrics::increment_counter!("te_request_count", "method" => "all_modes");
+
+    let inputs = match req.inputs {
+        Input::Single(input) => vec![input],
+        Input::Batch(inputs) => inputs,
+    };
+    let batch_size = inputs.len();
+    if batch_size > info.max_client_batch_size {
+        let message = format!(
+            "batch size {batch_size} > maximum allowed batch size {}",
+            info.max_client_batch_size
+        );
+        tracing::error!("{message}");
+        metrics::increment_counter!("te_request_failure", "err" => "batch_size");
+        Err(ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+            details: None,
+        })?;
+    }
+
+    if req.prune_threshold.is_some_and(f32::is_nan) {
+        let message = "`prune_threshold` must be a number".to_string();
+        tracing::error!("{message}");
+        metrics::increment_counter!("te_request_failure", "err" => "validation");
+        Err(ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+            details: None,
+        })?;
+    }
+
+    let truncate = req.truncate;
+    let normalize = req.normalize;
+    let prompt = default_prompt(&info).unwrap_or_default();
+    let futures = inputs.into_iter().map(|input| {
+        let local_infer = infer.clone();
+        let input = format!("{prompt}{input}");
+        async move {
+            let permit = local_infer.acquire_permit().await;
+            local_infer
+                .embed_all_modes(input, truncate, normalize, permit)
+                .await
+        }
+    });
+    let results = collect_batch_results(join_all(futures).await)?;
+
+    let embeddings = results
+        .into_iter()
+        .map(|r| AllModesEmbedding {
+            dense: round_embedding(r.results.dense, None, info.model_output_dtype),
+            sparse: sparse_values(r.results.sparse, req.prune_threshold, |id| {
+                req.return_tokens.then(|| infer.id_to_token(id)).flatten()
+            }),
+            colbert: r
+                .results
+                .colbert
+                .into_iter()
+                .map(|embedding| round_embedding(embedding, None, info.model_output_dtype))
+                .collect(),
+        })
+        .collect();
+
+    metrics::increment_counter!("te_request_success", "method" => "all_modes");
+    tracing::info!("Success");
+
+    Ok(Json(EmbedAllModesResponse(embeddings)))
+}
+
+/// Sparse weights above `prune_threshold`, with the token returned by `token` for their id
+fn sparse_values(
+    sparse: Vec<(u32, f32)>,
+    prune_threshold: Option<f32>,
+    token: impl Fn(u32) -> Option<String>,
+) -> Vec<SparseValue> {
+    sparse
+        .into_iter()
+        .filter(|(_, value)| !prune_threshold.is_some_and(|threshold| *value < threshold))
+        .map(|(index, value)| SparseValue {
+            index,
+            value,
+            token: token(index),
+        })
+        .collect()
+}
+
+/// Embed every window of `ngram_size` tokens of a document, e.g. to search phrases or passages
+/// of the document. Each embedding is returned with the span of tokens of its window.
+#[utoipa::path(
+post,
+tag = "Text Embeddings Inference",
+path = "/embed_ngrams",
+request_body = EmbedNgramsRequest,
+params(
+("X-Priority" = Option<String>, Header, description = "Scheduling priority of the request: `high` (default) or `low`"),
+),
+responses(
+(status = 200, description = "Embeddings of the windows", body = EmbedNgramsResponse),
+(status = 424, description = "Embedding Error", body = ErrorResponse,
+example = json ! ({"error": "Inference failed", "error_type": "backend"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"})),
+(status = 422, description = "Tokenization error", body = ErrorResponse,
+example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
+(status = 413, description = "Input size error", body = ErrorResponse,
+example = json ! ({"error": "Input size error", "error_type": "validation"})),
+)
+)]
+#[instrument(skip_all)]
+async fn embed_ngrams(
+    infer: Extension<Infer>,
+    info: Extension<Info>,
+    request_headers: HeaderMap,
+    Json(req): Json<EmbedNgramsRequest>,
+) -> Result<Json<EmbedNgramsResponse>, (StatusCode, Json<ErrorResponse>)> {
+    metrics::increment_counter!("te_request_count", "method" => "ngrams");
+    let priority = request_priority(&request_headers)?;
+
+    // The default prompt is not prepended: it would end up in the windows and shift the spans
+    let add_special_tokens = add_special_tokens(&info, req.add_special_tokens);
+    let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
+    let response = infer
+        .embed_ngrams(
+            req.inputs,
+            req.ngram_size,
+            add_special_tokens,
+            req.normalize,
+            priority,
+            permit,
+        )
+        .await
+        .map_err(ErrorResponse::from)?;
+
+    let embeddings = response
+        .results
+        .into_iter()
+        .zip(response.spans)
+        .map(|(embedding, (start, end))| NgramEmbedding {
+            start,
+            end,
+            embedding: round_embedding(embedding, None, info.model_output_dtype),
+        })
+        .collect();
+
+    metrics::increment_counter!("te_request_success", "method" => "ngrams");
+    tracing::info!("Success");
+
+    Ok(Json(EmbedNgramsResponse(embeddings)))
+}
+
+/// Reject the pairs with an empty text, and the pairs whose first text is more than
+/// `max_length_ratio` times longer than the second one, which usually means that the query and
+/// the document were swapped.
+fn validate_pair(input: &Sequence, max_length_ratio: Option<f32>) -> Result<(), ErrorResponse> {
+    let Sequence::Pair(first, second) = input else {
+        return Ok(());
+    };
+    let validation_error = |error: String| ErrorResponse {
+        error,
+        error_type: ErrorType::Validation,
+        details: None,
+    };
+
+    match (first.trim().is_empty(), second.trim().is_empty()) {
+        (true, true) => Err(validation_error(
+            "both texts of the pair are empty".to_string(),
+        ))?,
+        (true, false) => Err(validation_error(
+            "the first text of the pair is empty".to_string(),
+        ))?,
+        (false, true) => Err(validation_error(
+            "the second text of the pair is empty".to_string(),
+        ))?,
+        (false, false) => {}
+    }
+
+    if let Some(max_length_ratio) = max_length_ratio {
+        let ratio = first.chars().count() as f32 / second.chars().count() as f32;
+        if ratio > max_length_ratio {
+            Err(validation_error(format!(
+                "the first text of the pair is {ratio:.1} times longer than the second one, which is more than the maximum ratio of {max_length_ratio}. Are the texts swapped?"
+            )))?;
+        }
+    }
+    Ok(())
+}
+
+/// Keep the predictions scoring above `threshold`, then the `top_k` first ones.
+/// `predictions` must be sorted by decreasing score.
+fn select_predictions(
+    predictions: &mut Vec<Prediction>,
+    threshold: Option<f32>,
+    top_k: Option<usize>,
+) {
+    if let Some(threshold) = threshold {
+        predictions.retain(|prediction| prediction.score > threshold);
+    }
+    if let Some(top_k) = top_k {
+        predictions.truncate(top_k);
+    }
+}
+
+/// Pairwise cosine similarities of L2 normalized embeddings
+fn cosine_similarity_matrix(embeddings: &[Vec<f32>]) -> Vec<Vec<f32>> {
+    let n = embeddings.len();
+    let mut matrix = vec![vec![0.0; n]; n];
+    for (i, a) in embeddings.iter().enumerate() {
+        for (j, b) in embeddings.iter().enumerate().skip(i) {
+            let similarity: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+            matrix[i][j] = similarity;
+            matrix[j][i] = similarity;
+        }
+    }
+    matrix
+}
+
 /// Prometheus metrics scrape endpoint
 #[utoipa::path(
 get,
@@ -652,11 +2382,202 @@ async fn metrics(prom_handle: Extension<PrometheusHandle>) -> String {
     prom_handle.render()
 }
 
+/// Add a `Retry-After` header to the responses of overloaded requests.
+/// The delay is the time the backend needs to drain the queue at its recent throughput, rounded up
+/// to the second and capped at `max_retry_after`.
+async fn retry_after(
+    State(max_retry_after): State<Duration>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let infer = request.extensions().get::<Infer>().cloned();
+    let mut response = next.run(request).await;
+
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        let drain_time = infer
+            .and_then(|infer| infer.estimated_drain_time())
+            .unwrap_or(Duration::from_secs(1));
+        let seconds = drain_time
+            .as_secs_f64()
+            .ceil()
+            .clamp(1.0, max_retry_after.as_secs_f64()) as u64;
+        response
+            .headers_mut()
+            .insert(http::header::RETRY_AFTER, HeaderValue::from(seconds));
+    }
+    response
+}
+
+/// Hold the inference requests received while the circuit breaker is open for up to `grace`, and
+/// fail them as unhealthy if the backend does not recover in time
+async fn wait_healthy(
+    State(grace): State<Duration>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    // Health, info and metrics routes are always served
+    if request.method() == Method::POST {
+        if let Some(infer) = request.extensions().get::<Infer>().cloned() {
+            if !infer.wait_closed_circuit(grace).await {
+                let message =
+                    format!("the model backend is unhealthy and did not recover within {grace:?}");
+                tracing::error!("{message}");
+                metrics::increment_counter!("te_request_failure", "err" => "unhealthy");
+                Err(ErrorResponse {
+                    error: message,
+                    error_type: ErrorType::Unhealthy,
+                    details: None,
+                })?;
+            }
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+/// Buffer a request body, failing once it is larger than `limit` bytes
+async fn read_body(body: Body, limit: usize) -> Result<Bytes, ErrorResponse> {
+    hyper::body::to_bytes(http_body::Limited::new(body, limit))
+        .await
+        .map_err(|err| {
+            let message = if err.is::<http_body::LengthLimitError>() {
+                metrics::increment_counter!("te_request_failure", "err" => "payload_limit");
+                format!("the request body is larger than {limit} bytes")
+            } else {
+                format!("failed to read the request body: {err}")
+            };
+            tracing::error!("{message}");
+            ErrorResponse {
+                error: message,
+                error_type: ErrorType::Validation,
+                details: None,
+            }
+        })
+}
+
+/// Fail the requests whose body is not fully received within `read_timeout`, so that slow
+/// clients trickling their body do not hold the server resources indefinitely.
+async fn read_body_timeout(
+    State((read_timeout, payload_limit)): State<(Duration, usize)>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let (parts, body) = request.into_parts();
+    let body = match tokio::time::timeout(read_timeout, read_body(body, payload_limit)).await {
+        Ok(body) => body?,
+        Err(_) => {
+            metrics::increment_counter!("te_request_failure", "err" => "read_timeout");
+            Err(ErrorResponse {
+                error: format!("the request body was not received within {read_timeout:?}"),
+                error_type: ErrorType::Timeout,
+                details: None,
+            })?
+        }
+    };
+    Ok(next.run(Request::from_parts(parts, Body::from(body))).await)
+}
+
+/// Replay the response of a previous request sent with the same `Idempotency-Key` header.
+///
+/// Only successful responses are kept, for the TTL of the cache: a failed request can be retried
+/// with the same key. A key can not be reused for a different request, and a retry sent while
+/// the first request is still running is rejected instead of computing the response twice.
+/// Progress streams are not replayed, nor are shared memory responses as their records are
+/// overwritten once the ring buffer wraps around.
+async fn idempotency(
+    State((cache, payload_limit)): State<(Arc<IdempotencyCache>, usize)>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    if request.method() != Method::POST
+        || accepts_event_stream(request.headers())
+        || accepts_shm(request.headers())
+    {
+        return Ok(next.run(request).await);
+    }
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY) else {
+        return Ok(next.run(request).await);
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH => key.to_string(),
+        _ => Err(ErrorResponse {
+            error: format!(
+                "`Idempotency-Key` must be a visible ASCII string of 1 to {MAX_IDEMPOTENCY_KEY_LENGTH} characters"
+            ),
+            error_type: ErrorType::Validation,
+            details: None,
+        })?,
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = read_body(body, payload_limit).await?;
+
+    let mut hasher = DefaultHasher::new();
+    parts.uri.path().hash(&mut hasher);
+    accepts_arrow_stream(&parts.headers).hash(&mut hasher);
+    body.hash(&mut hasher);
+
+    let reservation = match cache.begin(&key, hasher.finish()) {
+        Lookup::Reserved(reservation) => reservation,
+        Lookup::Hit(cached) => {
+            metrics::increment_counter!("te_request_idempotent_replay");
+            let mut response = (cached.status, cached.headers, cached.body).into_response();
+            response
+                .headers_mut()
+                .insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+            return Ok(response);
+        }
+        Lookup::InFlight => Err(ErrorResponse {
+            error: "a request with the same `Idempotency-Key` is still in progress".to_string(),
+            error_type: ErrorType::Conflict,
+            details: None,
+        })?,
+        Lookup::Mismatch => Err(ErrorResponse {
+            error: "`Idempotency-Key` was already used with a different request".to_string(),
+            error_type: ErrorType::Conflict,
+            details: None,
+        })?,
+    };
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(|err| ErrorResponse {
+            error: format!("failed to read the response body: {err}"),
+            error_type: ErrorType::Backend,
+            details: None,
+        })?;
+    reservation.complete(CachedResponse {
+        status: parts.status,
+        headers: parts.headers.clone(),
+        body: body.clone(),
+    });
+    Ok(Response::from_parts(
+        parts,
+        axum::body::boxed(Full::from(body)),
+    ))
+}
+
 /// Serving method
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
     infer: Infer,
     info: Info,
+    effective_config: EffectiveConfig,
+    admin_token: Option<String>,
     addr: SocketAddr,
+    max_connections: Option<usize>,
+    max_retry_after: Duration,
+    idempotency_ttl: Option<Duration>,
+    http_read_timeout: Duration,
+    http_write_timeout: Duration,
+    payload_limit: usize,
+    unhealthy_grace: Option<Duration>,
+    #[cfg(feature = "shm")] shm: Option<ShmRing>,
     allow_origin: Option<AllowOrigin>,
 ) -> Result<(), axum::BoxError> {
     // OpenAPI documentation
@@ -664,30 +2585,67 @@ pub async fn run(
     #[openapi(
     paths(
     get_model_info,
+    get_config,
     health,
     predict,
     embed,
     openai_embed,
+    openai_models,
+    similarity_matrix,
+    embed_all_modes,
+    embed_ngrams,
     metrics,
     ),
     components(
     schemas(
     PredictInput,
+    EmbedInput,
     Input,
     Info,
+    TokenizerInfo,
+    SpecialToken,
+    EffectiveConfig,
     ModelType,
     ClassifierModel,
+    ProblemType,
     EmbeddingModel,
     PredictRequest,
+    TruncationStrategy,
     Prediction,
+    LabeledPredictions,
     PredictResponse,
     OpenAICompatRequest,
+    HiddenStateSummary,
+    EmbedSummary,
+    EncodingFormat,
+    EmbeddingData,
     OpenAICompatEmbedding,
     OpenAICompatUsage,
     OpenAICompatResponse,
+    OpenAICompatModel,
+    OpenAICompatModelList,
     EmbedRequest,
     EmbedResponse,
+    EmbedProgress,
+    SimilarityMatrixRequest,
+    SimilarityMatrixResponse,
+    EmbedAllModesRequest,
+    EmbedAllModesResponse,
+    AllModesEmbedding,
+    SparseValue,
+    EmbedNgramsRequest,
+    EmbedNgramsResponse,
+    NgramEmbedding,
+    IdentifiedEmbedding,
+    IdentifiedEmbedResponse,
+    SortBy,
+    SortedEmbedding,
+    SortedEmbedResponse,
+    EmbeddingGroup,
+    GroupedEmbedResponse,
+    HealthDetail,
     ErrorResponse,
+    ErrorDetail,
     OpenAICompatErrorResponse,
     ErrorType,
     )
@@ -749,18 +2707,66 @@ pub async fn run(
     let allow_origin = allow_origin.unwrap_or(AllowOrigin::any());
     let cors_layer = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST])
-        .allow_headers([http::header::CONTENT_TYPE])
+        .allow_headers([
+            http::header::CONTENT_TYPE,
+            http::header::AUTHORIZATION,
+            HeaderName::from_static(X_PRIORITY),
+            HeaderName::from_static(IDEMPOTENCY_KEY),
+        ])
+        // Let browser clients read the latency headers
+        .expose_headers([
+            HeaderName::from_static("x-queue-time"),
+            HeaderName::from_static("x-inference-time"),
+            HeaderName::from_static(IDEMPOTENT_REPLAYED),
+        ])
         .allow_origin(allow_origin);
 
+    // OpenAI compatible model listing
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let models = OpenAICompatModelList {
+        object: "list",
+        data: vec![OpenAICompatModel {
+            id: info.model_id.clone(),
+            object: "model",
+            created,
+            owned_by: "text-embeddings-inference",
+        }],
+    };
+
+    #[allow(unused_mut)]
+    let mut openapi = ApiDoc::openapi();
+    // The shared memory response only exists in builds with the `shm` feature
+    #[cfg(feature = "shm")]
+    if let Some(components) = openapi.components.as_mut() {
+        let (name, schema) = <ShmEmbedResponse as utoipa::ToSchema>::schema();
+        components.schemas.insert(name.to_string(), schema);
+    }
+
     // Create router
     let app = Router::new()
-        .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", ApiDoc::openapi()))
+        .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", openapi))
         // Base routes
         .route("/info", get(get_model_info))
-        .route("/embed", post(embed))
+        .route(
+            "/embed",
+            post(embed).layer(axum::middleware::from_fn_with_state(
+                payload_limit,
+                embed_etag,
+            )),
+        )
         .route("/predict", post(predict))
-        // OpenAI compat route
+        .route("/similarity/matrix", post(similarity_matrix))
+        .route("/embed_all_modes", post(embed_all_modes))
+        .route("/embed_ngrams", post(embed_ngrams))
+        // OpenAI compat routes
         .route("/embeddings", post(openai_embed))
+        .route("/models", get(openai_models))
+        // OpenAI compat routes with the `/v1` prefix used by the OpenAI clients base url
+        .route("/v1/embeddings", post(openai_embed))
+        .route("/v1/models", get(openai_models))
         // Base Health route
         .route("/health", get(health))
         // Inference API health route
@@ -784,15 +2790,61 @@ pub async fn run(
         }
     };
 
+    // Admin routes
+    let app = match admin_token {
+        Some(admin_token) => app
+            .route("/config", get(get_config))
+            .layer(Extension(AdminToken(admin_token)))
+            .layer(Extension(effective_config)),
+        None => app,
+    };
+
+    // Replay the responses of retried requests
+    let app = match idempotency_ttl {
+        Some(ttl) => app.layer(axum::middleware::from_fn_with_state(
+            (Arc::new(IdempotencyCache::new(ttl)), payload_limit),
+            idempotency,
+        )),
+        None => app,
+    };
+
+    // Wait for the backend to recover from transient failures
+    let app = match unhealthy_grace {
+        Some(grace) => app.layer(axum::middleware::from_fn_with_state(grace, wait_healthy)),
+        None => app,
+    };
+
+    // Colocated clients read the embeddings from shared memory
+    #[cfg(feature = "shm")]
+    let app = app.layer(Extension(shm.map(Arc::new)));
+
     let app = app
+        .layer(axum::middleware::from_fn_with_state(
+            (http_read_timeout, payload_limit),
+            read_body_timeout,
+        ))
+        // Limit of the `Json` and `Bytes` extractors
+        .layer(DefaultBodyLimit::max(payload_limit))
+        .layer(axum::middleware::from_fn_with_state(
+            max_retry_after,
+            retry_after,
+        ))
         .layer(Extension(infer))
         .layer(Extension(info))
+        .layer(Extension(models))
         .layer(Extension(prom_handle.clone()))
         .layer(OtelAxumLayer::default())
         .layer(cors_layer);
 
     // Run server
-    axum::Server::bind(&addr)
+    let incoming = LimitedIncoming::new(
+        AddrIncoming::bind(&addr)?,
+        max_connections,
+        http_write_timeout,
+    );
+    axum::Server::builder(incoming)
+        // Close the connections of the clients that do not send their request headers in time
+        .http1_header_read_timeout(http_read_timeout)
         .serve(app.into_make_service())
         // Wait until all requests are finished to shut down
         .with_graceful_shutdown(shutdown_signal())
@@ -833,13 +2885,16 @@ impl From<TextEmbeddingsError> for ErrorResponse {
     fn from(err: TextEmbeddingsError) -> Self {
         let error_type = match err {
             TextEmbeddingsError::Tokenizer(_) => ErrorType::Tokenizer,
-            TextEmbeddingsError::Validation(_) => ErrorType::Validation,
+            TextEmbeddingsError::Validation(_) | TextEmbeddingsError::InputTooLong { .. } => {
+                ErrorType::Validation
+            }
             TextEmbeddingsError::Overloaded(_) => ErrorType::Overloaded,
             TextEmbeddingsError::Backend(_) => ErrorType::Backend,
         };
         Self {
             error: err.to_string(),
             error_type,
+            details: None,
         }
     }
 }
@@ -848,10 +2903,13 @@ impl From<&ErrorType> for StatusCode {
     fn from(value: &ErrorType) -> Self {
         match value {
             ErrorType::Unhealthy => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorType::Unauthorized => StatusCode::UNAUTHORIZED,
             ErrorType::Backend => StatusCode::FAILED_DEPENDENCY,
             ErrorType::Overloaded => StatusCode::TOO_MANY_REQUESTS,
             ErrorType::Tokenizer => StatusCode::UNPROCESSABLE_ENTITY,
             ErrorType::Validation => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorType::Conflict => StatusCode::CONFLICT,
+            ErrorType::Timeout => StatusCode::REQUEST_TIMEOUT,
         }
     }
 }
@@ -878,3 +2936,494 @@ impl From<ErrorResponse> for (StatusCode, Json<OpenAICompatErrorResponse>) {
         (StatusCode::from(&err.error_type), Json(err.into()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MAX_LABEL_LENGTH;
+    use std::collections::HashMap;
+
+    fn decode_base64(data: &EmbeddingData) -> Vec<f32> {
+        match data {
+            EmbeddingData::Base64(encoded) => BASE64
+                .decode(encoded)
+                .unwrap()
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            _ => panic!("expected a base64 embedding"),
+        }
+    }
+
+    #[test]
+    fn test_postprocess_embedding_order() {
+        let embedding = vec![3.0, 4.0, 12.0, 84.0];
+
+        for dimensions in [None, Some(2)] {
+            for normalize in [false, true] {
+                // Reference pipeline: truncate, then normalize
+                let mut expected = embedding.clone();
+                if let Some(dimensions) = dimensions {
+                    expected.truncate(dimensions);
+                }
+                if normalize {
+                    normalize_embedding(&mut expected);
+                }
+
+                let float = postprocess_embedding(
+                    embedding.clone(),
+                    dimensions,
+                    normalize,
+                    None,
+                    OutputDType::Float32,
+                    EncodingFormat::Float,
+                );
+                assert_eq!(float, EmbeddingData::Float(expected.clone()));
+
+                let base64 = postprocess_embedding(
+                    embedding.clone(),
+                    dimensions,
+                    normalize,
+                    None,
+                    OutputDType::Float32,
+                    EncodingFormat::Base64,
+                );
+                assert_eq!(decode_base64(&base64), expected);
+            }
+        }
+
+        // Truncated embeddings are re-normalized on the remaining dimensions
+        let float = postprocess_embedding(
+            embedding.clone(),
+            Some(2),
+            true,
+            None,
+            OutputDType::Float32,
+            EncodingFormat::Float,
+        );
+        let EmbeddingData::Float(values) = float else {
+            panic!("expected a float embedding")
+        };
+        assert!((values[0] - 0.6).abs() < 1e-6);
+        assert!((values[1] - 0.8).abs() < 1e-6);
+
+        // The normalized embedding is rounded to the output dtype, not the raw one
+        let mut expected = embedding.clone();
+        normalize_embedding(&mut expected);
+        OutputDType::Float16.cast(&mut expected);
+        let float = postprocess_embedding(
+            embedding,
+            None,
+            true,
+            None,
+            OutputDType::Float16,
+            EncodingFormat::Float,
+        );
+        assert_eq!(float, EmbeddingData::Float(expected));
+    }
+
+    #[test]
+    fn test_openai_embedding_data_raw_and_normalized() {
+        let embedding = vec![3.0, 4.0, 12.0];
+
+        for encoding_format in [EncodingFormat::Float, EncodingFormat::Base64] {
+            let data = openai_embedding_data(
+                embedding.clone(),
+                Some(2),
+                true,
+                None,
+                OutputDType::Float32,
+                encoding_format,
+            );
+            let expected = EmbeddingData::RawAndNormalized {
+                raw: Box::new(postprocess_embedding(
+                    embedding.clone(),
+                    Some(2),
+                    false,
+                    None,
+                    OutputDType::Float32,
+                    encoding_format,
+                )),
+                normalized: Box::new(postprocess_embedding(
+                    embedding.clone(),
+                    Some(2),
+                    true,
+                    None,
+                    OutputDType::Float32,
+                    encoding_format,
+                )),
+            };
+            assert_eq!(data, expected);
+        }
+
+        let data = openai_embedding_data(
+            embedding,
+            None,
+            false,
+            None,
+            OutputDType::Float32,
+            EncodingFormat::Float,
+        );
+        assert!(matches!(data, EmbeddingData::Float(_)));
+    }
+
+    #[test]
+    fn test_openai_embed_error() {
+        let err = openai_embed_error(TextEmbeddingsError::InputTooLong {
+            tokens: 600,
+            max_input_length: 512,
+        });
+        assert_eq!(
+            err.error,
+            "This model's maximum context length is 512 tokens, however you requested 600 tokens. Please reduce your input."
+        );
+        assert!(matches!(err.error_type, ErrorType::Validation));
+
+        let err = openai_embed_error(TextEmbeddingsError::Validation("empty".to_string()));
+        assert_eq!(err.error, "Input validation error: empty");
+    }
+
+    #[test]
+    fn test_embed_request_from_form() {
+        let field = |name: &str, value: &str| (name.to_string(), value.to_string());
+        let request = embed_request_from_form(vec![
+            field("truncate", "true"),
+            field("prompt", "query: "),
+            field("file", "first\nsecond\r\n\n"),
+        ])
+        .unwrap();
+        assert!(request.truncate);
+        assert_eq!(request.prompt.as_deref(), Some("query: "));
+        match request.inputs {
+            EmbedInput::Batch(inputs) => assert_eq!(inputs.len(), 2),
+            _ => panic!("expected a batch"),
+        }
+
+        assert!(embed_request_from_form(vec![field("truncate", "true")]).is_err());
+        assert!(embed_request_from_form(vec![field("inputs", "[]")]).is_err());
+    }
+
+    #[test]
+    fn test_embed_single_pair() {
+        let pair: EmbedInput = serde_json::from_str(r#"{"text": "a", "text_pair": "b"}"#).unwrap();
+        match pair.with_prompt("query: ") {
+            EmbedInput::Single(input) => match Sequence::from(input) {
+                Sequence::Pair(text, text_pair) => {
+                    assert_eq!(text, "query: a");
+                    assert_eq!(text_pair, "b");
+                }
+                _ => panic!("expected a pair"),
+            },
+            _ => panic!("expected a single input"),
+        }
+
+        let batch: EmbedInput = serde_json::from_str(r#"["a", "b"]"#).unwrap();
+        assert!(matches!(batch, EmbedInput::Batch(inputs) if inputs.len() == 2));
+    }
+
+    #[test]
+    fn test_pgvector_encoding() {
+        let data = postprocess_embedding(
+            vec![3.0, -4.0, 0.1234],
+            None,
+            false,
+            Some(2),
+            OutputDType::Float32,
+            EncodingFormat::Pgvector,
+        );
+        assert_eq!(data, EmbeddingData::Pgvector("[3,-4,0.12]".to_string()));
+    }
+
+    #[test]
+    fn test_int8_encoding() {
+        let embedding = vec![0.6, -0.8, 0.0, 0.1];
+        let data = postprocess_embedding(
+            embedding.clone(),
+            None,
+            false,
+            None,
+            OutputDType::Float32,
+            EncodingFormat::Int8,
+        );
+        let EmbeddingData::Int8 {
+            values,
+            scale,
+            offset,
+        } = data
+        else {
+            panic!("expected an int8 embedding")
+        };
+        assert_eq!(values[0], 127);
+        assert_eq!(values[1], -128);
+        for (value, expected) in values.iter().zip(embedding) {
+            let dequantized = *value as f32 * scale + offset;
+            assert!((dequantized - expected).abs() <= scale / 2.0 + 1e-6);
+        }
+
+        let data = postprocess_embedding(
+            vec![0.5; 3],
+            None,
+            false,
+            None,
+            OutputDType::Float32,
+            EncodingFormat::Int8,
+        );
+        assert_eq!(
+            data,
+            EmbeddingData::Int8 {
+                values: vec![0; 3],
+                scale: 0.0,
+                offset: 0.5
+            }
+        );
+    }
+
+    #[test]
+    fn test_validate_pair() {
+        let pair =
+            |first: &str, second: &str| Sequence::Pair(first.to_string(), second.to_string());
+
+        assert!(validate_pair(&Sequence::Single(String::new()), None).is_ok());
+        assert!(validate_pair(&pair("query", "a document"), None).is_ok());
+        let err = validate_pair(&pair("query", "  \n"), None).unwrap_err();
+        assert_eq!(err.error, "the second text of the pair is empty");
+        assert!(validate_pair(&pair("", "document"), None).is_err());
+
+        let swapped = pair("a long document about deep learning", "query");
+        assert!(validate_pair(&swapped, None).is_ok());
+        assert!(validate_pair(&swapped, Some(4.0)).is_err());
+        assert!(validate_pair(&swapped, Some(10.0)).is_ok());
+
+        let details = collect_batch_results(vec![
+            validate_pair(&pair("query", "document"), None),
+            validate_pair(&pair("query", ""), None),
+        ])
+        .unwrap_err()
+        .details
+        .unwrap();
+        assert_eq!(details[0].index, 1);
+    }
+
+    #[test]
+    fn test_check_embed_fields() {
+        let request =
+            |value: serde_json::Value| -> EmbedRequest { serde_json::from_value(value).unwrap() };
+
+        let req = request(serde_json::json!({"inputs": "test", "stride": 8, "truncate": true}));
+        assert!(check_embed_fields(&req, None).is_ok());
+        let req = request(serde_json::json!({"inputs": "test", "stride": 8, "layer": -2}));
+        assert!(check_embed_fields(&req, None).is_err());
+
+        let req = request(serde_json::json!({"inputs": ["a", "b"], "return_summary": true}));
+        assert!(check_embed_fields(&req, None).is_ok());
+        let err = check_embed_fields(&req, Some("streamed")).unwrap_err();
+        assert_eq!(
+            err.error,
+            "`return_summary` is not supported for streamed responses"
+        );
+        assert!(check_embed_fields(&req, Some("Arrow")).is_err());
+
+        // Duplicate fields are rejected when the request is deserialized
+        let duplicate = r#"{"inputs": "test", "truncate": true, "truncate": false}"#;
+        assert!(serde_json::from_str::<EmbedRequest>(duplicate).is_err());
+    }
+
+    #[test]
+    fn test_sparse_values() {
+        let sparse = vec![(3, 0.05), (7, 0.4), (12, 0.1)];
+
+        let values = sparse_values(sparse.clone(), None, |_| None);
+        assert_eq!(values.len(), 3);
+        assert!(values.iter().all(|v| v.token.is_none()));
+
+        let values = sparse_values(sparse, Some(0.1), |id| Some(format!("token_{id}")));
+        let values: Vec<(u32, Option<String>)> =
+            values.into_iter().map(|v| (v.index, v.token)).collect();
+        assert_eq!(
+            values,
+            vec![
+                (7, Some("token_7".to_string())),
+                (12, Some("token_12".to_string()))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_renormalize_probabilities() {
+        let mut probabilities = vec![0.1; 10];
+        assert_ne!(probabilities.iter().sum::<f32>(), 1.0);
+
+        renormalize_probabilities(&mut probabilities);
+        assert_eq!(probabilities.iter().sum::<f32>(), 1.0);
+    }
+
+    #[test]
+    fn test_select_predictions() {
+        let predictions = || {
+            [0.9, 0.6, 0.4, 0.1]
+                .into_iter()
+                .enumerate()
+                .map(|(i, score)| Prediction {
+                    score,
+                    label: format!("LABEL_{i}"),
+                })
+                .collect::<Vec<_>>()
+        };
+        let labels = |threshold, top_k| {
+            let mut predictions = predictions();
+            select_predictions(&mut predictions, threshold, top_k);
+            predictions.into_iter().map(|p| p.label).collect::<Vec<_>>()
+        };
+
+        assert_eq!(labels(None, None).len(), 4);
+        assert_eq!(labels(Some(0.5), None), vec!["LABEL_0", "LABEL_1"]);
+        assert_eq!(labels(None, Some(1)), vec!["LABEL_0"]);
+        // Both filters apply
+        assert_eq!(labels(Some(0.3), Some(2)), vec!["LABEL_0", "LABEL_1"]);
+        assert_eq!(labels(Some(0.95), Some(2)), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_cosine_similarity_matrix() {
+        let embeddings = vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![0.6, 0.8]];
+        let matrix = cosine_similarity_matrix(&embeddings);
+
+        assert_eq!(matrix.len(), 3);
+        for (i, row) in matrix.iter().enumerate() {
+            assert!((row[i] - 1.0).abs() < 1e-6);
+            for (j, similarity) in row.iter().enumerate() {
+                assert_eq!(*similarity, matrix[j][i]);
+            }
+        }
+        assert_eq!(matrix[0][1], 0.0);
+        assert!((matrix[0][2] - 0.6).abs() < 1e-6);
+        assert!((matrix[1][2] - 0.8).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_sorted_embed_response_by_length() {
+        let response = EmbedResponse(vec![vec![0.0], vec![1.0], vec![2.0]]);
+        let custom_ids = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+
+        let sorted = SortedEmbedResponse::by_length(response, vec![5, 2, 5], Some(custom_ids)).0;
+
+        let indices: Vec<usize> = sorted.iter().map(|e| e.index).collect();
+        assert_eq!(indices, vec![1, 0, 2]);
+        for embedding in sorted {
+            // Indices map back to the inputs of the request
+            assert_eq!(embedding.embedding, vec![embedding.index as f32]);
+            assert_eq!(
+                embedding.custom_id.as_deref(),
+                Some(["a", "b", "c"][embedding.index])
+            );
+        }
+    }
+
+    #[test]
+    fn test_grouped_embed_response() {
+        let req: EmbedRequest = serde_json::from_value(serde_json::json!({
+            "inputs": [
+                {"text": "a", "group_id": "doc-1"},
+                {"text": "b", "group_id": "doc-2"},
+                {"text": "c", "group_id": "doc-1"},
+            ],
+            "pool_groups": true,
+        }))
+        .unwrap();
+        assert!(check_embed_fields(&req, None).is_ok());
+        assert!(check_embed_fields(&req, Some("Arrow")).is_err());
+        let (_, group_ids) = req.inputs.split_group_ids();
+
+        let response = EmbedResponse(vec![vec![3.0, 0.0], vec![0.0, 1.0], vec![1.0, 0.0]]);
+        let groups = GroupedEmbedResponse::new(group_ids.unwrap(), response, true, false).0;
+        assert_eq!(
+            groups,
+            vec![
+                EmbeddingGroup {
+                    group_id: "doc-1".to_string(),
+                    indices: vec![0, 2],
+                    embeddings: vec![vec![3.0, 0.0], vec![1.0, 0.0]],
+                    pooled: Some(vec![2.0, 0.0]),
+                },
+                EmbeddingGroup {
+                    group_id: "doc-2".to_string(),
+                    indices: vec![1],
+                    embeddings: vec![vec![0.0, 1.0]],
+                    pooled: Some(vec![0.0, 1.0]),
+                },
+            ]
+        );
+
+        let req: EmbedRequest =
+            serde_json::from_value(serde_json::json!({"inputs": ["a"], "pool_groups": true}))
+                .unwrap();
+        assert!(check_embed_fields(&req, None).is_err());
+    }
+
+    #[test]
+    fn test_problem_type() {
+        assert_eq!(ProblemType::new(None, 1), Ok(ProblemType::Regression));
+        assert_eq!(
+            ProblemType::new(None, 3),
+            Ok(ProblemType::SingleLabelClassification)
+        );
+        // An explicit problem type wins over the number of labels
+        assert_eq!(
+            ProblemType::new(Some("single_label_classification"), 1),
+            Ok(ProblemType::SingleLabelClassification)
+        );
+        assert_eq!(
+            ProblemType::new(Some("multi_label_classification"), 3),
+            Ok(ProblemType::MultiLabelClassification)
+        );
+        assert!(ProblemType::new(Some("ranking"), 1).is_err());
+    }
+
+    #[test]
+    fn test_logit_bias() {
+        let id2label = [("0", "negative"), ("1", "positive")]
+            .into_iter()
+            .map(|(id, label)| (id.to_string(), label.to_string()))
+            .collect();
+        let classifier = ClassifierModel::new(id2label, HashMap::new()).unwrap();
+
+        let logit_bias = HashMap::from([("positive".to_string(), 0.5)]);
+        assert_eq!(
+            logit_bias_vector(&classifier, &logit_bias).ok(),
+            Some(vec![0.0, 0.5])
+        );
+        let logit_bias = HashMap::from([("neutral".to_string(), 0.5)]);
+        assert!(logit_bias_vector(&classifier, &logit_bias).is_err());
+        let logit_bias = HashMap::from([("positive".to_string(), f32::INFINITY)]);
+        assert!(logit_bias_vector(&classifier, &logit_bias).is_err());
+
+        let mut logits = vec![1.0, 1.0 + 2.0f32.ln()];
+        softmax(&mut logits);
+        assert!((logits[0] - 1.0 / 3.0).abs() < 1e-6);
+        assert!((logits[1] - 2.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_classifier_model_gapped_ids() {
+        let labels = |ids: &[&str]| -> HashMap<String, String> {
+            ids.iter()
+                .map(|id| (id.to_string(), format!("LABEL_{id}")))
+                .collect()
+        };
+
+        assert!(ClassifierModel::new(labels(&["0", "1", "2"]), HashMap::new()).is_ok());
+
+        let err = ClassifierModel::new(labels(&["0", "2"]), HashMap::new()).unwrap_err();
+        assert_eq!(
+            err,
+            "`id2label` ids must be contiguous from 0 to 1, but id 1 is missing"
+        );
+        assert!(ClassifierModel::new(labels(&["1"]), HashMap::new()).is_err());
+        assert!(ClassifierModel::new(labels(&["a"]), HashMap::new()).is_err());
+        assert!(ClassifierModel::new(HashMap::new(), HashMap::new()).is_err());
+
+        let mut long = labels(&["0"]);
+        long.insert("0".to_string(), "a".repeat(MAX_LABEL_LENGTH + 1));
+        assert!(ClassifierModel::new(long, HashMap::new()).is_err());
+    }
+}
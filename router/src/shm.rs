@@ -0,0 +1,149 @@
+//! Shared memory output of the embeddings for clients running on the same host
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Mutex;
+use utoipa::ToSchema;
+
+/// Size of the header of a record: its id and the size of its data in bytes, as little endian u64
+pub(crate) const RECORD_HEADER_SIZE: u64 = 16;
+
+/// Location of the embeddings of a request in the shared memory segment.
+///
+/// The record at `offset` starts with its `id` and `length` as little endian u64, followed by the
+/// `count` embeddings of `dimension` little endian f32 values. Records are overwritten once the
+/// ring buffer wraps around: clients must check that the record still starts with `id` after
+/// copying the embeddings.
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct ShmEmbedResponse {
+    #[schema(example = "42")]
+    pub id: u64,
+    #[schema(example = "4096")]
+    pub offset: u64,
+    /// Size of the embeddings in bytes, without the record header
+    #[schema(example = "6144")]
+    pub length: u64,
+    #[schema(example = "2")]
+    pub count: usize,
+    #[schema(example = "768")]
+    pub dimension: usize,
+}
+
+#[derive(Debug)]
+struct RingState {
+    /// Offset of the next record
+    cursor: u64,
+    /// Id of the next record
+    next_id: u64,
+}
+
+/// Ring buffer of embedding records in a file of a shared memory file system, e.g. `/dev/shm`
+#[derive(Debug)]
+pub struct ShmRing {
+    file: File,
+    size: u64,
+    state: Mutex<RingState>,
+}
+
+impl ShmRing {
+    /// Create, or truncate, the segment at `path` with a size of `size` bytes
+    pub fn create(path: &Path, size: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(size)?;
+        Ok(Self {
+            file,
+            size,
+            state: Mutex::new(RingState {
+                cursor: 0,
+                next_id: 1,
+            }),
+        })
+    }
+
+    /// Write the embeddings of a request in a new record
+    pub(crate) fn write(&self, embeddings: &[Vec<f32>]) -> Result<ShmEmbedResponse, String> {
+        let dimension = embeddings.first().map_or(0, |e| e.len());
+        let length = (embeddings.len() * dimension * std::mem::size_of::<f32>()) as u64;
+        let record_size = RECORD_HEADER_SIZE + length;
+        if record_size > self.size {
+            return Err(format!(
+                "the embeddings take {record_size} bytes, more than the {} bytes of the shared memory segment",
+                self.size
+            ));
+        }
+
+        let mut record = Vec::with_capacity(record_size as usize);
+        record.extend_from_slice(&0u64.to_le_bytes());
+        record.extend_from_slice(&length.to_le_bytes());
+        for value in embeddings.iter().flatten() {
+            record.extend_from_slice(&value.to_le_bytes());
+        }
+
+        // The record is written under the lock: two requests writing at the same time could
+        // otherwise overlap once the ring buffer wraps around, and leave a record whose id does
+        // not match its embeddings. Records are 8 bytes aligned and never wrap around the end of
+        // the segment.
+        let mut state = self.state.lock().unwrap();
+        let mut offset = state.cursor;
+        if offset + record_size > self.size {
+            offset = 0;
+        }
+        let id = state.next_id;
+        record[..8].copy_from_slice(&id.to_le_bytes());
+        self.file
+            .write_all_at(&record, offset)
+            .map_err(|err| format!("failed to write to the shared memory segment: {err}"))?;
+        state.cursor = (offset + record_size + 7) / 8 * 8;
+        state.next_id += 1;
+        drop(state);
+
+        Ok(ShmEmbedResponse {
+            id,
+            offset,
+            length,
+            count: embeddings.len(),
+            dimension,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shm_ring() {
+        let path = std::env::temp_dir().join(format!("tei-shm-test-{}", std::process::id()));
+        let ring = ShmRing::create(&path, 64).unwrap();
+
+        let first = ring.write(&[vec![1.0, 2.0], vec![3.0, 4.0]]).unwrap();
+        assert_eq!(
+            first,
+            ShmEmbedResponse {
+                id: 1,
+                offset: 0,
+                length: 16,
+                count: 2,
+                dimension: 2,
+            }
+        );
+        let mut record = [0u8; 32];
+        ring.file.read_exact_at(&mut record, first.offset).unwrap();
+        assert_eq!(u64::from_le_bytes(record[..8].try_into().unwrap()), 1);
+        assert_eq!(f32::from_le_bytes(record[28..].try_into().unwrap()), 4.0);
+
+        // The second record does not fit after the first one and overwrites it
+        let second = ring.write(&[vec![5.0, 6.0, 7.0, 8.0, 9.0]]).unwrap();
+        assert_eq!((second.id, second.offset), (2, 0));
+        assert!(ring.write(&[vec![0.0; 13]]).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}